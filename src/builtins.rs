@@ -0,0 +1,419 @@
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    fmt, fs,
+    io::{self, BufRead, BufReader, Write},
+    rc::Rc,
+};
+
+use super::{
+    error::CrystalError,
+    memories::{Dict, Memory},
+    span::Span,
+};
+
+// Where a program's output goes and its input comes from; swapped out to capture it in tests
+pub struct Io {
+    pub stdout: Box<dyn Write>,
+    pub stdin: Box<dyn BufRead>,
+}
+
+impl Io {
+    pub fn new(stdout: Box<dyn Write>, stdin: Box<dyn BufRead>) -> Self {
+        Io { stdout, stdin }
+    }
+}
+
+impl Default for Io {
+    fn default() -> Self {
+        Io::new(
+            Box::new(io::stdout()),
+            Box::new(BufReader::new(io::stdin())),
+        )
+    }
+}
+
+impl fmt::Debug for Io {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Io")
+    }
+}
+
+// What a builtin needs from whichever backend is running it
+pub trait Runtime {
+    fn io(&mut self) -> &mut Io;
+    // Calls a function value, as `map` and `filter` do with theirs
+    fn call(
+        &mut self,
+        callee: &Memory,
+        args: Vec<Memory>,
+        span: &Span,
+    ) -> Result<Memory, CrystalError>;
+}
+
+pub type Builtin = fn(Vec<Memory>, &mut dyn Runtime, &Span) -> Result<Memory, CrystalError>;
+
+// Builtin Function Registry
+pub fn lookup(name: &str) -> Option<Builtin> {
+    let builtin: Builtin = match name {
+        "print" => print,
+        "println" => println,
+        "input" => input,
+        "read_file" => read_file,
+        "write_file" => write_file,
+        "len" => len,
+        "push" => push,
+        "pop" => pop,
+        "map" => map,
+        "filter" => filter,
+        "sort" => sort,
+        "join" => join,
+        "keys" => keys,
+        "values" => values,
+        "has" => has,
+        _ => return None,
+    };
+    Some(builtin)
+}
+
+// Calls a builtin that was used as a value
+pub fn call(
+    name: &str,
+    args: Vec<Memory>,
+    runtime: &mut dyn Runtime,
+    span: &Span,
+) -> Result<Memory, CrystalError> {
+    let builtin = lookup(name).expect("only builtins are bound as builtin values");
+    builtin(args, runtime, span)
+}
+
+// Builtins that change the collection passed first, refused on `final` bindings
+pub fn mutates_first_arg(name: &str) -> bool {
+    matches!(name, "push" | "pop")
+}
+
+fn arity(name: &str, args: &[Memory], expected: usize, span: &Span) -> Result<(), CrystalError> {
+    if args.len() == expected {
+        Ok(())
+    } else {
+        Err(CrystalError::runtime(
+            format!(
+                "'{name}' takes {expected} argument(s) but {} were given",
+                args.len()
+            ),
+            span.clone(),
+        ))
+    }
+}
+
+fn string_arg<'a>(name: &str, arg: &'a Memory, span: &Span) -> Result<&'a str, CrystalError> {
+    match arg {
+        Memory::String(s) => Ok(s),
+        other => Err(CrystalError::type_error(
+            format!("'{name}' expects a string, found {}", other.type_name()),
+            span.clone(),
+        )),
+    }
+}
+
+fn list_arg<'a>(
+    name: &str,
+    arg: &'a Memory,
+    span: &Span,
+) -> Result<&'a Rc<RefCell<Vec<Memory>>>, CrystalError> {
+    match arg {
+        Memory::List(items) => Ok(items),
+        other => Err(CrystalError::type_error(
+            format!("'{name}' expects a list, found {}", other.type_name()),
+            span.clone(),
+        )),
+    }
+}
+
+fn map_arg<'a>(
+    name: &str,
+    arg: &'a Memory,
+    span: &Span,
+) -> Result<&'a Rc<RefCell<Dict>>, CrystalError> {
+    match arg {
+        Memory::Map(map) => Ok(map),
+        other => Err(CrystalError::type_error(
+            format!("'{name}' expects a map, found {}", other.type_name()),
+            span.clone(),
+        )),
+    }
+}
+
+fn write_out(env: &mut dyn Runtime, text: &str, span: &Span) -> Result<(), CrystalError> {
+    let stdout = &mut env.io().stdout;
+    stdout
+        .write_all(text.as_bytes())
+        .and_then(|_| stdout.flush())
+        .map_err(|err| {
+            CrystalError::runtime(format!("Could not write output: {err}"), span.clone())
+        })
+}
+
+fn joined(args: &[Memory]) -> String {
+    args.iter()
+        .map(Memory::to_string)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn print(args: Vec<Memory>, env: &mut dyn Runtime, span: &Span) -> Result<Memory, CrystalError> {
+    write_out(env, &joined(&args), span)?;
+    Ok(Memory::Nil)
+}
+
+fn println(args: Vec<Memory>, env: &mut dyn Runtime, span: &Span) -> Result<Memory, CrystalError> {
+    write_out(env, &format!("{}\n", joined(&args)), span)?;
+    Ok(Memory::Nil)
+}
+
+// input() or input(prompt): reads one line from stdin, without its line ending
+fn input(args: Vec<Memory>, env: &mut dyn Runtime, span: &Span) -> Result<Memory, CrystalError> {
+    if args.len() > 1 {
+        arity("input", &args, 1, span)?;
+    }
+    if let Some(prompt) = args.first() {
+        write_out(env, &prompt.to_string(), span)?;
+    }
+    let mut line = String::new();
+    env.io().stdin.read_line(&mut line).map_err(|err| {
+        CrystalError::runtime(format!("Could not read input: {err}"), span.clone())
+    })?;
+    let line = line.trim_end_matches(['\n', '\r']);
+    Ok(Memory::String(line.to_string()))
+}
+
+fn read_file(
+    args: Vec<Memory>,
+    _env: &mut dyn Runtime,
+    span: &Span,
+) -> Result<Memory, CrystalError> {
+    arity("read_file", &args, 1, span)?;
+    let path = string_arg("read_file", &args[0], span)?;
+    fs::read_to_string(path).map(Memory::String).map_err(|err| {
+        CrystalError::runtime(format!("Could not read file '{path}': {err}"), span.clone())
+    })
+}
+
+fn write_file(
+    args: Vec<Memory>,
+    _env: &mut dyn Runtime,
+    span: &Span,
+) -> Result<Memory, CrystalError> {
+    arity("write_file", &args, 2, span)?;
+    let path = string_arg("write_file", &args[0], span)?;
+    fs::write(path, args[1].to_string()).map_err(|err| {
+        CrystalError::runtime(
+            format!("Could not write file '{path}': {err}"),
+            span.clone(),
+        )
+    })?;
+    Ok(Memory::Nil)
+}
+
+fn len(args: Vec<Memory>, _env: &mut dyn Runtime, span: &Span) -> Result<Memory, CrystalError> {
+    arity("len", &args, 1, span)?;
+    let len = match &args[0] {
+        Memory::List(items) => items.borrow().len(),
+        Memory::String(s) => s.chars().count(),
+        Memory::Map(map) => map.borrow().len(),
+        other => {
+            return Err(CrystalError::type_error(
+                format!(
+                    "'len' expects a list, map or string, found {}",
+                    other.type_name()
+                ),
+                span.clone(),
+            ))
+        }
+    };
+    Ok(Memory::Int(len as i64))
+}
+
+fn push(args: Vec<Memory>, _env: &mut dyn Runtime, span: &Span) -> Result<Memory, CrystalError> {
+    arity("push", &args, 2, span)?;
+    let items = list_arg("push", &args[0], span)?;
+    items.borrow_mut().push(args[1].clone());
+    Ok(Memory::Nil)
+}
+
+// Removes and returns the last item
+fn pop(args: Vec<Memory>, _env: &mut dyn Runtime, span: &Span) -> Result<Memory, CrystalError> {
+    arity("pop", &args, 1, span)?;
+    let items = list_arg("pop", &args[0], span)?;
+    let last = items.borrow_mut().pop();
+    last.ok_or_else(|| CrystalError::runtime("Cannot pop from an empty list", span.clone()))
+}
+
+// map(list, fn): a new list of fn(item) for each item
+fn map(args: Vec<Memory>, env: &mut dyn Runtime, span: &Span) -> Result<Memory, CrystalError> {
+    arity("map", &args, 2, span)?;
+    let items = list_arg("map", &args[0], span)?.borrow().clone();
+    let mapped = items
+        .into_iter()
+        .map(|item| env.call(&args[1], vec![item], span))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Memory::list(mapped))
+}
+
+// filter(list, fn): a new list of the items for which fn(item) is truthy
+fn filter(args: Vec<Memory>, env: &mut dyn Runtime, span: &Span) -> Result<Memory, CrystalError> {
+    arity("filter", &args, 2, span)?;
+    let items = list_arg("filter", &args[0], span)?.borrow().clone();
+    let mut kept = Vec::new();
+    for item in items {
+        if env.call(&args[1], vec![item.clone()], span)?.is_truthy() {
+            kept.push(item);
+        }
+    }
+    Ok(Memory::list(kept))
+}
+
+// A sorted copy of a list of numbers or of strings
+fn sort(args: Vec<Memory>, _env: &mut dyn Runtime, span: &Span) -> Result<Memory, CrystalError> {
+    arity("sort", &args, 1, span)?;
+    let mut items = list_arg("sort", &args[0], span)?.borrow().clone();
+    let comparable = items.iter().all(|item| item.as_float().is_some())
+        || items.iter().all(|item| matches!(item, Memory::String(_)));
+    if !comparable {
+        return Err(CrystalError::type_error(
+            "'sort' expects a list of only numbers or only strings",
+            span.clone(),
+        ));
+    }
+    items.sort_by(|a, b| match (a, b) {
+        (Memory::Int(x), Memory::Int(y)) => x.cmp(y),
+        (Memory::String(x), Memory::String(y)) => x.cmp(y),
+        _ => a
+            .as_float()
+            .partial_cmp(&b.as_float())
+            .unwrap_or(Ordering::Equal),
+    });
+    Ok(Memory::list(items))
+}
+
+// join(list, separator)
+fn join(args: Vec<Memory>, _env: &mut dyn Runtime, span: &Span) -> Result<Memory, CrystalError> {
+    arity("join", &args, 2, span)?;
+    let items = list_arg("join", &args[0], span)?;
+    let separator = string_arg("join", &args[1], span)?;
+    let parts: Vec<_> = items.borrow().iter().map(Memory::to_string).collect();
+    Ok(Memory::String(parts.join(separator)))
+}
+
+// The keys of a map as a list, in insertion order
+fn keys(args: Vec<Memory>, _env: &mut dyn Runtime, span: &Span) -> Result<Memory, CrystalError> {
+    arity("keys", &args, 1, span)?;
+    let map = map_arg("keys", &args[0], span)?.borrow();
+    let keys = map.iter().map(|(key, _)| Memory::String(key.clone()));
+    Ok(Memory::list(keys.collect()))
+}
+
+fn values(args: Vec<Memory>, _env: &mut dyn Runtime, span: &Span) -> Result<Memory, CrystalError> {
+    arity("values", &args, 1, span)?;
+    let map = map_arg("values", &args[0], span)?.borrow();
+    let values = map.iter().map(|(_, value)| value.clone());
+    Ok(Memory::list(values.collect()))
+}
+
+// has(map, key)
+fn has(args: Vec<Memory>, _env: &mut dyn Runtime, span: &Span) -> Result<Memory, CrystalError> {
+    arity("has", &args, 2, span)?;
+    let map = map_arg("has", &args[0], span)?;
+    let key = string_arg("has", &args[1], span)?;
+    Ok(Memory::Bool(map.borrow().contains(key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        fs,
+        io::{self, Cursor, Write},
+        rc::Rc,
+    };
+
+    use super::Io;
+    use crate::{execute, execute_vm, parse};
+
+    // A stdout the test can still read after handing it to a program
+    #[derive(Clone, Default)]
+    struct Captured(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Runs a program on both backends with `stdin` as its input, giving what it printed
+    // and how it failed, if it did
+    fn run(source: &str, stdin: &str) -> (String, Option<String>) {
+        let ast = parse("test.cry".to_string(), source.to_string()).expect("the program parses");
+        let mut runs = Vec::new();
+        for on_vm in [false, true] {
+            let stdout = Captured::default();
+            let stdin = Cursor::new(stdin.as_bytes().to_vec());
+            let io = Io::new(Box::new(stdout.clone()), Box::new(stdin));
+            let result = if on_vm {
+                execute_vm(&ast, io)
+            } else {
+                execute(&ast, io)
+            };
+            let printed = String::from_utf8(stdout.0.borrow().clone()).expect("utf-8 output");
+            runs.push((printed, result.err().map(|err| err.to_string())));
+        }
+        let vm = runs.pop().expect("two runs");
+        assert_eq!(runs[0], vm, "the tree-walker and the VM disagree");
+        vm
+    }
+
+    #[test]
+    fn print_and_println_write_to_the_given_stdout() {
+        let (printed, err) = run("print(\"a\", 1);\nprintln(\" b\", 2.5);\nprintln();\n", "");
+        assert_eq!(printed, "a 1 b 2.5\n\n");
+        assert_eq!(err, None);
+    }
+
+    #[test]
+    fn input_reads_lines_until_eof_then_gives_empty_strings() {
+        let source = "println(input(\"> \"));\nprintln(input());\nprintln(len(input()));\n";
+        let (printed, err) = run(source, "first\r\nsecond");
+        assert_eq!(printed, "> first\nsecond\n0\n");
+        assert_eq!(err, None);
+    }
+
+    #[test]
+    fn read_file_of_a_missing_file_is_a_runtime_error() {
+        let (printed, err) = run("println(read_file(\"/no/such/crystal/file\"));\n", "");
+        assert_eq!(printed, "");
+        let err = err.expect("reading fails");
+        assert!(
+            err.starts_with("CRY.RuntimeError: Could not read file '/no/such/crystal/file': "),
+            "{err}"
+        );
+        assert!(err.ends_with(" at test.cry:1:9"), "{err}");
+    }
+
+    #[test]
+    fn write_file_then_read_file_gives_back_the_text() {
+        let path = std::env::temp_dir().join(format!("crystal-io-{}.txt", std::process::id()));
+        let path = path.to_str().expect("utf-8 path").replace('\\', "/");
+        let source = format!(
+            "write_file(\"{path}\", \"line ${{1 + 1}}\\n\");\nprint(read_file(\"{path}\"));\n"
+        );
+        let (printed, err) = run(&source, "");
+        fs::remove_file(&path).ok();
+        assert_eq!(printed, "line 2\n");
+        assert_eq!(err, None);
+    }
+}
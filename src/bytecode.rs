@@ -0,0 +1,191 @@
+use std::rc::Rc;
+
+use super::{
+    error::CrystalError,
+    lexer::{CompareToken, MathToken},
+    memories::{Declaration, Memory},
+    parser::Pattern,
+    span::{Span, Spanned},
+};
+
+// Where a name resolved to when its function was compiled
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Place {
+    // A slot of the running frame
+    Local(u32),
+    // A cell captured by the running closure
+    Upvalue(u32),
+    // The top-level scope, looked up by name as it runs
+    Global,
+}
+
+// One VM instruction. Operands index the tables of the chunk the op is in, except jump
+// targets, which are positions in its code, and slots, which are positions in its frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Const(u32),
+    Nil,
+    Pop,
+    GetLocal(u32),
+    // Pops into a slot
+    SetLocal(u32),
+    GetUpvalue(u32),
+    SetUpvalue(u32),
+    GetGlobal(u32),
+    // Pops into an existing `let` global
+    SetGlobal(u32),
+    // Like GetGlobal, but fails unless the global can be assigned, for `x += y`
+    GetGlobalMut(u32),
+    // Pops into a new global, declared the given way
+    DefineGlobal(u32, Declaration),
+    // Gives slots first..first + count new empty cells, as a block starts
+    Fresh(u32, u32),
+    // Pushes what a name refers to (or nil) and whether it was found
+    Find(u32, Place),
+    // Raises a fault found while compiling, once the program gets that far
+    Fail(u32),
+    Math(MathToken),
+    Compare(CompareToken),
+    Neg,
+    Not,
+    // Turns the top of the stack into a bool of its truthiness
+    Truthy,
+    Jump(u32),
+    // Jumps if the popped value is falsy
+    JumpIfFalse(u32),
+    // Calls by name: the callee was pushed by Find before the arguments
+    CallNamed(u32, u32),
+    // Calls the value pushed before the arguments, for callees that aren't plain names
+    Call(u32),
+    // Before `push`/`pop`, when no memory shadows them: fails if the named root of the
+    // first argument is final. The flag is true when that root is a final local.
+    Guard(u32, bool),
+    // Fails if the named global is final, before assigning through it
+    Writable(u32),
+    Return,
+    List(u32),
+    Map(u32),
+    // Fails unless the top of the stack is a string, the only kind of map key
+    Key,
+    // Joins that many values into one string
+    Concat(u32),
+    // The operand is the span of the indexed expression
+    Index(u32),
+    SetIndex(u32),
+    // Fails unless the top of the stack can be sliced, before the bounds are evaluated
+    Sliceable(u32),
+    Slice(u32),
+    // Field name, then the span of the expression the field is on
+    GetField(u32, u32),
+    SetField(u32, u32),
+    // Pops what Find gave for a struct literal's name and pushes its checked type
+    CheckStruct(u32),
+    MakeStruct(u32),
+    Struct(u32),
+    // Pushes every variant of an enum, then the enum
+    Enum(u32),
+    Closure(u32),
+    ExpectInt,
+    // Pops a collection and pushes the lists of what each pass of a `for` binds
+    Iter(bool),
+    // Steps the loop whose state starts at a slot, pushing the next values or jumping out
+    ForNext(u32, u32),
+    RangeNext(u32, u32),
+    // Match table, then the span of the subject
+    Match(u32, u32),
+}
+
+// The kind of error a fault raises
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FaultKind {
+    Type,
+    Mutability,
+    Runtime,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fault {
+    pub kind: FaultKind,
+    pub message: String,
+}
+
+impl Fault {
+    pub fn error(&self, span: &Span) -> CrystalError {
+        let (message, span) = (self.message.clone(), span.clone());
+        match self.kind {
+            FaultKind::Type => CrystalError::type_error(message, span),
+            FaultKind::Mutability => CrystalError::mutability(message, span),
+            FaultKind::Runtime => CrystalError::runtime(message, span),
+        }
+    }
+}
+
+// A struct literal: the struct's name, and its fields with the spans of their values
+#[derive(Debug, Clone)]
+pub struct StructLit {
+    pub name: String,
+    pub fields: Vec<(String, u32)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MatchArm {
+    pub pattern: Spanned<Pattern>,
+    // The names the arm's code reads from the stack, in push order
+    pub names: Vec<String>,
+    pub target: u32,
+}
+
+// The arms of a `match`, and where each name its patterns mention is found
+#[derive(Debug, Clone)]
+pub struct MatchTable {
+    pub lookups: Vec<(String, Place)>,
+    pub arms: Vec<MatchArm>,
+}
+
+// An enum's variants, each with its field names
+pub type Variants = Vec<(String, Vec<String>)>;
+
+// Compiled code with the tables its ops refer to
+#[derive(Debug, Clone, Default)]
+pub struct Chunk {
+    pub code: Vec<Op>,
+    // Index into `spans` of the source each op was compiled from
+    pub lines: Vec<u32>,
+    pub spans: Vec<Span>,
+    pub constants: Vec<Memory>,
+    pub names: Vec<String>,
+    pub faults: Vec<Fault>,
+    pub protos: Vec<Rc<Proto>>,
+    pub structs: Vec<(String, Vec<String>)>,
+    pub enums: Vec<(String, Variants)>,
+    pub literals: Vec<StructLit>,
+    pub matches: Vec<MatchTable>,
+}
+
+impl Chunk {
+    pub fn span(&self, ip: usize) -> &Span {
+        &self.spans[self.lines[ip] as usize]
+    }
+
+    pub fn name(&self, index: u32) -> &str {
+        &self.names[index as usize]
+    }
+}
+
+// A captured variable: a slot of the enclosing frame, or one of its own upvalues
+#[derive(Debug, Clone, PartialEq)]
+pub struct Capture {
+    pub name: String,
+    pub local: bool,
+    pub index: u32,
+}
+
+// A compiled function, or the whole program as a function of no arguments
+#[derive(Debug, Clone)]
+pub struct Proto {
+    pub name: Option<String>,
+    pub arity: u32,
+    pub slots: u32,
+    pub captures: Vec<Capture>,
+    pub chunk: Chunk,
+}
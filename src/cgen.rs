@@ -0,0 +1,1386 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt::Write,
+};
+
+use super::{
+    builtins,
+    diagnostic::Diagnostic,
+    lexer::{CompareToken, MathToken, Token},
+    memories::{Declaration, MAX_CALL_DEPTH},
+    parser::{ASTNode, MatchArm, Node, Pattern},
+    patterns,
+    span::{Span, Spanned},
+};
+
+// The runtime every emitted C file includes, written next to it
+pub const RUNTIME_HEADER: &str = "crystal.h";
+pub const RUNTIME: &str = include_str!("crystal.h");
+
+// A variable of a block scope, as a C local. Names a nested function mentions live in
+// heap cells the function can capture; the rest are plain values.
+struct Local {
+    name: String,
+    var: String,
+    cell: bool,
+    declaration: Declaration,
+    declared: bool,
+}
+
+// Where a name resolved to, as the bytecode compiler's Place but with C variables
+enum Place {
+    Local { var: String, cell: bool },
+    Upvalue(usize),
+    Global,
+}
+
+// A captured variable: the C expression that gives its cell in the enclosing function
+struct Capture {
+    name: String,
+    from: String,
+    is_final: bool,
+}
+
+// A function being emitted
+struct Function {
+    name: Option<String>,
+    c_name: String,
+    arity: usize,
+    code: String,
+    indent: usize,
+    scopes: Vec<Vec<Local>>,
+    captures: Vec<Capture>,
+    // Counter for temporaries and locals, so every C name in the function is unique
+    next: usize,
+    loops: usize,
+}
+
+impl Function {
+    fn new(name: Option<String>, c_name: String, arity: usize) -> Self {
+        Function {
+            name,
+            c_name,
+            arity,
+            code: String::new(),
+            indent: 1,
+            scopes: Vec::new(),
+            captures: Vec::new(),
+            next: 0,
+            loops: 0,
+        }
+    }
+
+    fn signature(&self) -> String {
+        format!(
+            "static CryValue {}(CryCell **env, CryValue *args)",
+            self.c_name
+        )
+    }
+}
+
+// Lowers a whole program to one C99 file. Every subexpression gets its own temporary, so
+// side effects happen in the order the interpreter has them; C leaves the order of
+// arguments unspecified.
+pub fn emit(ast: &Node) -> String {
+    let mut emitter = Emitter {
+        functions: vec![Function::new(None, "cry_script".to_string(), 0)],
+        finished: Vec::new(),
+        globals: BTreeSet::new(),
+        sites: Vec::new(),
+        site_index: HashMap::new(),
+        statics: String::new(),
+        function_count: 0,
+        static_count: 0,
+    };
+    if let ASTNode::Program(nodes) = &ast.node {
+        for node in nodes {
+            emitter.statement(node);
+        }
+    }
+    let script = emitter
+        .functions
+        .pop()
+        .expect("the script is always emitted");
+    emitter.finish(script);
+
+    let mut c = format!(
+        "/* Generated by `crystal emit-c` from {} */\n",
+        ast.span.source.name.replace("*/", "* /")
+    );
+    let _ = writeln!(c, "#define CRY_MAX_CALL_DEPTH {MAX_CALL_DEPTH}");
+    let _ = writeln!(c, "#include \"{RUNTIME_HEADER}\"\n");
+    for global in &emitter.globals {
+        let _ = writeln!(c, "static CryGlobal cry_g_{global};");
+    }
+    // What the collector scans besides the stack
+    c.push_str("\nstatic CryGlobal *const cry_global_table[] = {\n");
+    if emitter.globals.is_empty() {
+        c.push_str("    NULL,\n");
+    }
+    for global in &emitter.globals {
+        let _ = writeln!(c, "    &cry_g_{global},");
+    }
+    c.push_str("};\n");
+    c.push_str("\nstatic const char *const cry_site_table[] = {\n");
+    if emitter.sites.is_empty() {
+        c.push_str("    \"\",\n");
+    }
+    for site in &emitter.sites {
+        let _ = writeln!(c, "    {},", c_string(site));
+    }
+    c.push_str("};\n\n");
+    c.push_str(&emitter.statics);
+    for (signature, _) in &emitter.finished {
+        let _ = writeln!(c, "{signature};");
+    }
+    for (signature, body) in &emitter.finished {
+        let _ = write!(c, "\n{signature} {{\n{body}}}\n");
+    }
+    let _ = write!(
+        c,
+        "\nint main(void) {{\n    cry_sites = cry_site_table;\n    \
+         cry_run(cry_script, cry_global_table, {});\n    fflush(stdout);\n    return 0;\n}}\n",
+        emitter.globals.len()
+    );
+    c
+}
+
+struct Emitter {
+    functions: Vec<Function>,
+    // Signature and body of every function emitted so far
+    finished: Vec<(String, String)>,
+    globals: BTreeSet<String>,
+    // The source excerpt of every place an error can be raised
+    sites: Vec<String>,
+    site_index: HashMap<(usize, usize, usize), usize>,
+    // Shapes of declarations and patterns of matches, as static C data
+    statics: String,
+    function_count: usize,
+    static_count: usize,
+}
+
+impl Emitter {
+    fn function(&mut self) -> &mut Function {
+        self.functions
+            .last_mut()
+            .expect("always emitting a function")
+    }
+
+    fn line(&mut self, text: &str) {
+        let function = self.function();
+        for _ in 0..function.indent {
+            function.code.push_str("    ");
+        }
+        function.code.push_str(text);
+        function.code.push('\n');
+    }
+
+    fn open(&mut self, text: &str) {
+        self.line(text);
+        self.function().indent += 1;
+    }
+
+    fn close(&mut self, text: &str) {
+        self.function().indent -= 1;
+        self.line(text);
+    }
+
+    // A C name no other variable of the function has
+    fn fresh(&mut self, prefix: &str) -> String {
+        let function = self.function();
+        function.next += 1;
+        format!("{prefix}{}", function.next)
+    }
+
+    // Stores a C expression in a new temporary, fixing when it is evaluated
+    fn temp(&mut self, expression: String) -> String {
+        let temp = self.fresh("t");
+        self.line(&format!("CryValue {temp} = {expression};"));
+        temp
+    }
+
+    fn site(&mut self, span: &Span) -> usize {
+        let key = (span.start, span.end, span.line);
+        if let Some(&site) = self.site_index.get(&key) {
+            return site;
+        }
+        let excerpt = Diagnostic::new("", span.clone()).plain_excerpt();
+        self.sites.push(excerpt);
+        self.site_index.insert(key, self.sites.len() - 1);
+        self.sites.len() - 1
+    }
+
+    fn global(&mut self, name: &str) -> String {
+        self.globals.insert(name.to_string());
+        format!("&cry_g_{name}")
+    }
+
+    fn fail(&mut self, kind: &str, message: &str, span: &Span) {
+        let site = self.site(span);
+        let message = c_string(message);
+        self.line(&format!("cry_fail({kind}, {site}, \"%s\", {message});"));
+    }
+
+    fn cannot_modify(&mut self, name: &str, span: &Span) {
+        let message = format!("Cannot modify final variable '{name}'");
+        self.fail("CRY_MUTABILITY_ERROR", &message, span);
+    }
+
+    fn finish(&mut self, function: Function) {
+        self.finished.push((
+            function.signature(),
+            function.code + "    return cry_nil();\n",
+        ));
+    }
+
+    // Opens a block scope holding `names`, giving each its C variable; true names are
+    // bound by the caller straight away
+    fn open_scope(
+        &mut self,
+        names: Vec<(String, Declaration)>,
+        declared: bool,
+        cells: &HashSet<String>,
+    ) {
+        let mut scope = Vec::new();
+        for (name, declaration) in names {
+            let cell = cells.contains(&name);
+            let var = self.fresh(&format!("{}_{name}_", if cell { "c" } else { "v" }));
+            if cell {
+                self.line(&format!("CryCell *{var} = cry_cell();"));
+            } else {
+                self.line(&format!("CryValue {var} = cry_nil();"));
+            }
+            scope.push(Local {
+                name,
+                var,
+                cell,
+                declaration,
+                declared,
+            });
+        }
+        self.function().scopes.push(scope);
+    }
+
+    fn close_scope(&mut self) {
+        self.function().scopes.pop();
+    }
+
+    // Finds a name as seen from the function at `depth`; the bool is true for finals.
+    // Names an enclosing function declares later in a scope still count, as the inner
+    // function may run after they are bound.
+    fn resolve_in(&mut self, depth: usize, name: &str) -> (Place, bool) {
+        let current = depth + 1 == self.functions.len();
+        let function = &self.functions[depth];
+        for scope in function.scopes.iter().rev() {
+            let found = scope
+                .iter()
+                .rev()
+                .find(|local| local.name == name && (local.declared || !current));
+            if let Some(local) = found {
+                let place = Place::Local {
+                    var: local.var.clone(),
+                    cell: local.cell,
+                };
+                return (place, local.declaration != Declaration::Let);
+            }
+        }
+        if let Some(i) = function.captures.iter().position(|c| c.name == name) {
+            return (Place::Upvalue(i), function.captures[i].is_final);
+        }
+        if depth == 0 {
+            return (Place::Global, false);
+        }
+        let (from, is_final) = match self.resolve_in(depth - 1, name) {
+            (Place::Global, _) => return (Place::Global, false),
+            (Place::Local { var, .. }, is_final) => (var, is_final),
+            (Place::Upvalue(i), is_final) => (format!("env[{i}]"), is_final),
+        };
+        let captures = &mut self.functions[depth].captures;
+        captures.push(Capture {
+            name: name.to_string(),
+            from,
+            is_final,
+        });
+        (Place::Upvalue(captures.len() - 1), is_final)
+    }
+
+    fn resolve(&mut self, name: &str) -> (Place, bool) {
+        self.resolve_in(self.functions.len() - 1, name)
+    }
+
+    fn get(&mut self, name: &str, span: &Span) -> String {
+        let place = self.resolve(name).0;
+        if builtins::lookup(name).is_some() && !matches!(place, Place::Local { .. }) {
+            return self.builtin_value(name, place);
+        }
+        match place {
+            Place::Local { var, cell: false } => var,
+            Place::Local { var, cell: true } => self.temp(format!("{var}->value")),
+            Place::Upvalue(i) => {
+                let (global, site) = (self.global(name), self.site(span));
+                let name = c_string(name);
+                self.temp(format!(
+                    "cry_get_upvalue(env[{i}], {global}, {name}, {site})"
+                ))
+            }
+            Place::Global => {
+                let (global, site) = (self.global(name), self.site(span));
+                let name = c_string(name);
+                self.temp(format!("cry_get_global({global}, {name}, {site})"))
+            }
+        }
+    }
+
+    // A name that falls back to a builtin when nothing is bound to it, as in `map(xs, len)`
+    fn builtin_value(&mut self, name: &str, place: Place) -> String {
+        let global = self.global(name);
+        let value = self.fresh("f");
+        let find = match place {
+            Place::Upvalue(i) => format!("cry_find_upvalue(env[{i}], {global}, &{value})"),
+            _ => format!("cry_find_global({global}, &{value})"),
+        };
+        self.line(&format!("CryValue {value} = cry_nil();"));
+        let builtin = format!("cry_builtin_value(\"<builtin {name}>\", cry_builtin_{name})");
+        self.line(&format!("if (!{find}) {value} = {builtin};"));
+        value
+    }
+
+    // Stores a value into the variable `name` refers to
+    fn set(&mut self, place: Place, name: &str, value: &str, span: &Span) {
+        let line = match place {
+            Place::Local { var, cell: false } => format!("{var} = {value};"),
+            Place::Local { var, cell: true } => format!("{var}->value = {value};"),
+            Place::Upvalue(i) => {
+                let (global, site) = (self.global(name), self.site(span));
+                let name = c_string(name);
+                format!("cry_set_upvalue(env[{i}], {global}, {value}, {name}, {site});")
+            }
+            Place::Global => {
+                let (global, site) = (self.global(name), self.site(span));
+                let name = c_string(name);
+                format!("cry_set_global({global}, {value}, {name}, {site});")
+            }
+        };
+        self.line(&line);
+    }
+
+    // Binds a value to `name` in the innermost scope
+    fn declare(&mut self, name: &str, declaration: Declaration, value: &str, span: &Span) {
+        if self.function().scopes.is_empty() {
+            let (global, site) = (self.global(name), self.site(span));
+            let state = match declaration {
+                Declaration::Let => "CRY_LET",
+                Declaration::Final => "CRY_FINAL",
+                Declaration::Function => "CRY_FUNCTION",
+                Declaration::Struct => "CRY_STRUCT_DECL",
+                Declaration::Enum => "CRY_ENUM_DECL",
+                Declaration::Variant => "CRY_VARIANT_DECL",
+            };
+            let name = c_string(name);
+            self.line(&format!(
+                "cry_define({global}, {value}, {state}, {name}, {site});"
+            ));
+            return;
+        }
+        let scope = self.function().scopes.last().expect("inside a block");
+        let found = scope.iter().rposition(|local| local.name == name);
+        let Some(i) = found else {
+            // Only block statements are scanned up front; anything else gets a new cell
+            let var = self.fresh(&format!("c_{name}_"));
+            self.line(&format!("CryCell *{var} = cry_cell_of({value});"));
+            let scope = self.function().scopes.last_mut().expect("inside a block");
+            scope.push(Local {
+                name: name.to_string(),
+                var,
+                cell: true,
+                declaration,
+                declared: true,
+            });
+            return;
+        };
+        let local = &self.function().scopes.last().expect("inside a block")[i];
+        if local.declared && !declaration.may_replace(local.declaration) {
+            let message = local.declaration.redeclared(name);
+            return self.fail("CRY_MUTABILITY_ERROR", &message, span);
+        }
+        let local = &mut self.function().scopes.last_mut().expect("inside a block")[i];
+        local.declared = true;
+        local.declaration = declaration;
+        let line = if local.cell {
+            format!("cry_bind({}, {value});", local.var)
+        } else {
+            format!("{} = {value};", local.var)
+        };
+        self.line(&line);
+    }
+
+    // Checks `node` could be modified in place, as `writable` does for the tree-walker
+    fn writable(&mut self, node: &Node) {
+        let Some((root, span)) = root(node) else {
+            return;
+        };
+        match self.resolve(root) {
+            (Place::Global, _) => {
+                let (global, site) = (self.global(root), self.site(span));
+                let root = c_string(root);
+                self.line(&format!("cry_writable({global}, {root}, {site});"));
+            }
+            (_, true) => self.cannot_modify(root, span),
+            (_, false) => {}
+        }
+    }
+
+    // The new value of `x op= value`, or None once a fault is emitted instead
+    fn compound(&mut self, op: &Token, current: &str, value: &Node, span: &Span) -> Option<String> {
+        let Some(Token::Arithmetic(base)) = op.compound_base() else {
+            self.fail("CRY_RUNTIME_ERROR", "Invalid binary operation", span);
+            return None;
+        };
+        let value = self.expression(value);
+        let site = self.site(span);
+        Some(self.temp(format!(
+            "cry_math({}, {current}, {value}, {site})",
+            math_op(base).expect("compound operators have a plain form")
+        )))
+    }
+
+    fn statements(&mut self, nodes: &[Node]) {
+        self.open("{");
+        self.open_scope(declarations(nodes), false, &captured(nodes));
+        for node in nodes {
+            self.statement(node);
+        }
+        self.close_scope();
+        self.close("}");
+    }
+
+    fn statement(&mut self, node: &Node) {
+        let span = &node.span;
+        match &node.node {
+            ASTNode::Let(ident, value) => {
+                let value = self.expression(value);
+                self.declare(ident, Declaration::Let, &value, span);
+            }
+            ASTNode::Final(ident, value) => {
+                let value = self.expression(value);
+                self.declare(ident, Declaration::Final, &value, span);
+            }
+            ASTNode::Assign { ident, value } => {
+                let value = self.expression(value);
+                match self.resolve(ident) {
+                    (Place::Global, _) => self.set(Place::Global, ident, &value, span),
+                    (_, true) => self.cannot_modify(ident, span),
+                    (place, false) => self.set(place, ident, &value, span),
+                }
+            }
+            ASTNode::IndexAssign {
+                target,
+                index,
+                value,
+            } => {
+                self.writable(target);
+                let collection = self.expression(target);
+                let i = self.expression(index);
+                let value = self.expression(value);
+                let (target, index) = (self.site(&target.span), self.site(&index.span));
+                self.line(&format!(
+                    "cry_set_index({collection}, {i}, {value}, {target}, {index});"
+                ));
+            }
+            ASTNode::CompoundIndexAssign {
+                target,
+                index,
+                op,
+                value,
+            } => {
+                self.writable(target);
+                let collection = self.expression(target);
+                let i = self.expression(index);
+                let (target, index) = (self.site(&target.span), self.site(&index.span));
+                let current = self.temp(format!("cry_index({collection}, {i}, {target}, {index})"));
+                let Some(value) = self.compound(op, &current, value, span) else {
+                    return;
+                };
+                self.line(&format!(
+                    "cry_set_index({collection}, {i}, {value}, {target}, {index});"
+                ));
+            }
+            ASTNode::CompoundAssign { ident, op, value } => {
+                let (place, current) = match self.resolve(ident) {
+                    (Place::Global, _) => {
+                        let (global, site) = (self.global(ident), self.site(span));
+                        let name = c_string(ident);
+                        let current = format!("cry_get_global_mut({global}, {name}, {site})");
+                        (Place::Global, self.temp(current))
+                    }
+                    (_, true) => return self.cannot_modify(ident, span),
+                    (place, false) => (place, self.get(ident, span)),
+                };
+                if let Some(result) = self.compound(op, &current, value, span) {
+                    self.set(place, ident, &result, span);
+                }
+            }
+            ASTNode::FieldAssign {
+                target,
+                field,
+                value,
+            } => {
+                self.writable(target);
+                let instance = self.expression(target);
+                let value = self.expression(value);
+                let (site, target) = (self.site(span), self.site(&target.span));
+                let field = c_string(field);
+                self.line(&format!(
+                    "cry_set_field({instance}, {field}, {value}, {site}, {target});"
+                ));
+            }
+            ASTNode::CompoundFieldAssign {
+                target,
+                field,
+                op,
+                value,
+            } => {
+                self.writable(target);
+                let instance = self.expression(target);
+                let (site, target) = (self.site(span), self.site(&target.span));
+                let field = c_string(field);
+                let current =
+                    self.temp(format!("cry_field({instance}, {field}, {site}, {target})"));
+                let Some(value) = self.compound(op, &current, value, span) else {
+                    return;
+                };
+                self.line(&format!(
+                    "cry_set_field({instance}, {field}, {value}, {site}, {target});"
+                ));
+            }
+            ASTNode::StructDef { name, fields } => {
+                let parts: Vec<_> = fields
+                    .iter()
+                    .map(|field| (field.clone(), Vec::new()))
+                    .collect();
+                let shape = self.shape(name, &parts);
+                let def = self.temp(format!("cry_struct_type({shape})"));
+                self.declare(name, Declaration::Struct, &def, span);
+            }
+            ASTNode::EnumDef { name, variants } => {
+                let shape = self.shape(name, variants);
+                let def = self.temp(format!("cry_enum({shape})"));
+                for (i, (variant, _)) in variants.iter().enumerate() {
+                    let value = self.temp(format!("cry_variant({def}, {i})"));
+                    self.declare(variant, Declaration::Variant, &value, span);
+                }
+                self.declare(name, Declaration::Enum, &def, span);
+            }
+            ASTNode::Match { subject, arms } => {
+                self.matching(node, subject, arms, false);
+            }
+            ASTNode::FunDef {
+                name: Some(name), ..
+            } => {
+                let function = self.expression(node);
+                self.declare(name, Declaration::Function, &function, span);
+            }
+            ASTNode::Block(nodes) => self.statements(nodes),
+            ASTNode::Return(value) => {
+                let value = match value {
+                    Some(value) => self.expression(value),
+                    None => "cry_nil()".to_string(),
+                };
+                self.line(&format!("return {value};"));
+            }
+            // Outside a loop they end the function, or the program
+            ASTNode::Break | ASTNode::Continue if self.function().loops == 0 => {
+                self.line("return cry_nil();")
+            }
+            ASTNode::Break => self.line("break;"),
+            ASTNode::Continue => self.line("continue;"),
+            ASTNode::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                let cond = self.expression(cond);
+                self.open(&format!("if (cry_truthy({cond})) {{"));
+                self.statement(then);
+                match otherwise {
+                    Some(otherwise) => {
+                        self.close("} else {");
+                        self.function().indent += 1;
+                        self.statement(otherwise);
+                        self.close("}");
+                    }
+                    None => self.close("}"),
+                }
+            }
+            ASTNode::While { cond, body } => {
+                self.open("for (;;) {");
+                let cond = self.expression(cond);
+                self.line(&format!("if (!cry_truthy({cond})) break;"));
+                self.looping(body);
+                self.close("}");
+            }
+            ASTNode::For {
+                ident,
+                value,
+                iterable,
+                body,
+            } => self.for_loop(ident, value.as_deref(), iterable, body),
+            _ => {
+                let value = self.expression(node);
+                self.line(&format!("(void){value};"));
+            }
+        }
+    }
+
+    fn looping(&mut self, body: &Node) {
+        self.function().loops += 1;
+        self.statement(body);
+        self.function().loops -= 1;
+    }
+
+    fn for_loop(&mut self, ident: &str, value: Option<&str>, iterable: &Node, body: &Node) {
+        self.open("{");
+        let pass = match &iterable.node {
+            ASTNode::Range { start, end } => {
+                if value.is_some() {
+                    let message = "A range gives one loop variable, not two";
+                    self.fail("CRY_TYPE_ERROR", message, &iterable.span);
+                    return self.close("}");
+                }
+                let (from, to, i) = (self.fresh("from"), self.fresh("to"), self.fresh("i"));
+                let start_value = self.expression(start);
+                let site = self.site(&start.span);
+                self.line(&format!(
+                    "int64_t {from} = cry_expect_int({start_value}, {site});"
+                ));
+                let end_value = self.expression(end);
+                let site = self.site(&end.span);
+                self.line(&format!(
+                    "int64_t {to} = cry_expect_int({end_value}, {site});"
+                ));
+                self.open(&format!("for (int64_t {i} = {from}; {i} < {to}; {i}++) {{"));
+                vec![format!("cry_int({i})")]
+            }
+            _ => {
+                let collection = self.expression(iterable);
+                let (passes, k) = (self.fresh("passes"), self.fresh("k"));
+                let site = self.site(&iterable.span);
+                let two = u8::from(value.is_some());
+                self.line(&format!(
+                    "CryPasses {passes} = cry_passes({collection}, {two}, {site});"
+                ));
+                self.open(&format!(
+                    "for (size_t {k} = 0; {k} < {passes}.len; {k}++) {{"
+                ));
+                vec![
+                    format!("{passes}.first[{k}]"),
+                    format!("{passes}.second[{k}]"),
+                ]
+            }
+        };
+        // Each pass gets fresh variables, so closures capture that pass's values
+        let mut names = vec![(ident.to_string(), Declaration::Let)];
+        names.extend(value.map(|value| (value.to_string(), Declaration::Let)));
+        let cells = captured(std::slice::from_ref(body));
+        let count = names.len();
+        self.open_scope(names, true, &cells);
+        let scope = self.function().scopes.last().expect("just opened");
+        let binds: Vec<_> = scope
+            .iter()
+            .zip(&pass)
+            .take(count)
+            .map(|(local, value)| match local.cell {
+                true => format!("cry_bind({}, {value});", local.var),
+                false => format!("{} = {value};", local.var),
+            })
+            .collect();
+        for bind in binds {
+            self.line(&bind);
+        }
+        self.looping(body);
+        self.close_scope();
+        self.close("}");
+        self.close("}");
+    }
+
+    // Lowers an expression, returning a C expression for its value that is safe to use
+    // anywhere after the statements emitted for it
+    fn expression(&mut self, node: &Node) -> String {
+        let span = &node.span;
+        match &node.node {
+            ASTNode::Int(i64::MIN) => "cry_int(INT64_MIN)".to_string(),
+            ASTNode::Int(n) => format!("cry_int(INT64_C({n}))"),
+            ASTNode::Float(n) => format!("cry_float({n:?})"),
+            ASTNode::String(s) => format!("cry_str({}, {})", c_string(s), s.len()),
+            ASTNode::Bool(b) => format!("cry_bool({})", u8::from(*b)),
+            ASTNode::Nil => "cry_nil()".to_string(),
+            ASTNode::Interpolation(parts) => {
+                let parts: Vec<_> = parts.iter().map(|part| self.expression(part)).collect();
+                let array = self.array(&parts);
+                self.temp(format!("cry_concat({}, {array})", parts.len()))
+            }
+            ASTNode::Identifier(ident) => self.get(ident, span),
+            // Both give a bool: the left side's if it decides, else the right side's
+            ASTNode::BinaryOp {
+                left,
+                op: op @ (Token::And | Token::Or),
+                right,
+            } => {
+                let lhs = self.expression(left);
+                let result = self.fresh("t");
+                self.line(&format!("CryValue {result};"));
+                self.open(&format!("if (cry_truthy({lhs})) {{"));
+                if *op == Token::And {
+                    let rhs = self.expression(right);
+                    self.line(&format!("{result} = cry_bool(cry_truthy({rhs}));"));
+                    self.close("} else {");
+                    self.function().indent += 1;
+                    self.line(&format!("{result} = cry_bool(0);"));
+                } else {
+                    self.line(&format!("{result} = cry_bool(1);"));
+                    self.close("} else {");
+                    self.function().indent += 1;
+                    let rhs = self.expression(right);
+                    self.line(&format!("{result} = cry_bool(cry_truthy({rhs}));"));
+                }
+                self.close("}");
+                result
+            }
+            ASTNode::BinaryOp { left, op, right } => {
+                let lhs = self.expression(left);
+                let rhs = self.expression(right);
+                let site = self.site(span);
+                let operation = match op {
+                    Token::Arithmetic(math) => math_op(*math).map(|op| ("cry_math", op)),
+                    Token::Compare(compare) => Some(("cry_compare", compare_op(*compare))),
+                    _ => None,
+                };
+                match operation {
+                    Some((function, op)) => {
+                        self.temp(format!("{function}({op}, {lhs}, {rhs}, {site})"))
+                    }
+                    None => {
+                        self.fail("CRY_RUNTIME_ERROR", "Invalid binary operation", span);
+                        "cry_nil()".to_string()
+                    }
+                }
+            }
+            ASTNode::UnaryOp { op, operand } => {
+                let value = self.expression(operand);
+                match op {
+                    Token::Not => self.temp(format!("cry_bool(!cry_truthy({value}))")),
+                    _ => {
+                        let site = self.site(span);
+                        self.temp(format!("cry_negate({value}, {site})"))
+                    }
+                }
+            }
+            ASTNode::List(items) => {
+                let items: Vec<_> = items.iter().map(|item| self.expression(item)).collect();
+                let array = self.array(&items);
+                self.temp(format!("cry_list({}, {array})", items.len()))
+            }
+            ASTNode::Map(entries) => {
+                let map = self.fresh("m");
+                self.line(&format!("CryMap *{map} = cry_map_new();"));
+                for (key, value) in entries {
+                    let key_value = self.expression(key);
+                    let site = self.site(&key.span);
+                    let key = self.fresh("k");
+                    self.line(&format!(
+                        "CryString *{key} = cry_map_key({key_value}, {site});"
+                    ));
+                    let value = self.expression(value);
+                    self.line(&format!("cry_map_insert({map}, {key}, {value});"));
+                }
+                self.temp(format!("cry_map_value({map})"))
+            }
+            ASTNode::Index { target, index } => {
+                let value = self.expression(target);
+                let (target, index_site) = (self.site(&target.span), self.site(&index.span));
+                let ASTNode::Range { start, end } = &index.node else {
+                    let i = self.expression(index);
+                    return self.temp(format!("cry_index({value}, {i}, {target}, {index_site})"));
+                };
+                self.line(&format!("cry_sliceable({value}, {target}, {index_site});"));
+                let mut bounds = Vec::new();
+                for bound in [start, end] {
+                    let bound_value = self.expression(bound);
+                    let (int, site) = (self.fresh("n"), self.site(&bound.span));
+                    self.line(&format!(
+                        "int64_t {int} = cry_expect_int({bound_value}, {site});"
+                    ));
+                    bounds.push(int);
+                }
+                self.temp(format!(
+                    "cry_slice({value}, {}, {}, {target}, {index_site})",
+                    bounds[0], bounds[1]
+                ))
+            }
+            // Checked against the declaration before any field value is evaluated
+            ASTNode::StructLit { name, fields } => {
+                let (found, def) = self.find(name);
+                let given: Vec<_> = fields.iter().map(|(field, _)| field.clone()).collect();
+                let given = self.names(&given);
+                let sites: Vec<_> = fields
+                    .iter()
+                    .map(|(_, value)| self.site(&value.span).to_string())
+                    .collect();
+                let sites = match sites.is_empty() {
+                    true => "NULL".to_string(),
+                    false => {
+                        let array = self.fresh("sites");
+                        self.line(&format!("const int {array}[] = {{{}}};", sites.join(", ")));
+                        array
+                    }
+                };
+                let (shape, count, site) = (self.fresh("shape"), fields.len(), self.site(span));
+                let name = c_string(name);
+                self.line(&format!(
+                    "const CryShape *{shape} = \
+                     cry_struct_def({found}, {def}, {name}, {count}, {given}, {sites}, {site});"
+                ));
+                let values: Vec<_> = fields
+                    .iter()
+                    .map(|(_, value)| self.expression(value))
+                    .collect();
+                let array = self.array(&values);
+                self.temp(format!("cry_struct({shape}, {count}, {given}, {array})"))
+            }
+            ASTNode::Field { target, field } => {
+                let value = self.expression(target);
+                let (site, target) = (self.site(span), self.site(&target.span));
+                let field = c_string(field);
+                self.temp(format!("cry_field({value}, {field}, {site}, {target})"))
+            }
+            ASTNode::Match { subject, arms } => self.matching(node, subject, arms, true),
+            ASTNode::FunCall(callee, args) => match &callee.node {
+                ASTNode::Identifier(name) => self.call(name, args, span),
+                _ => {
+                    let callee = self.expression(callee);
+                    let args: Vec<_> = args.iter().map(|arg| self.expression(arg)).collect();
+                    let array = self.array(&args);
+                    let (argc, site) = (args.len(), self.site(span));
+                    self.temp(format!("cry_call({callee}, {argc}, {array}, {site})"))
+                }
+            },
+            ASTNode::FunDef { name, params, body } => self.function_def(name, params, body),
+            ASTNode::Range { .. } => {
+                let message = "A range can only be used in a for loop";
+                self.fail("CRY_TYPE_ERROR", message, span);
+                "cry_nil()".to_string()
+            }
+            _ => {
+                let message = "Statement used where a value was expected";
+                self.fail("CRY_RUNTIME_ERROR", message, span);
+                "cry_nil()".to_string()
+            }
+        }
+    }
+
+    // A C array of already evaluated values, which lives as long as the block it is in
+    fn array(&mut self, values: &[String]) -> String {
+        if values.is_empty() {
+            return "NULL".to_string();
+        }
+        let array = self.fresh("a");
+        self.line(&format!("CryValue {array}[] = {{{}}};", values.join(", ")));
+        array
+    }
+
+    // A C array of names, which lives as long as the block it is in
+    fn names(&mut self, names: &[String]) -> String {
+        if names.is_empty() {
+            return "NULL".to_string();
+        }
+        let array = self.fresh("names");
+        let names: Vec<_> = names.iter().map(|name| c_string(name)).collect();
+        self.line(&format!(
+            "const char *const {array}[] = {{{}}};",
+            names.join(", ")
+        ));
+        array
+    }
+
+    // A static C array of initializers, or NULL for none
+    fn static_array(&mut self, kind: &str, prefix: &str, entries: &[String]) -> String {
+        if entries.is_empty() {
+            return "NULL".to_string();
+        }
+        self.static_count += 1;
+        let array = format!("{prefix}_{}", self.static_count);
+        let _ = writeln!(
+            self.statics,
+            "static const {kind} {array}[] = {{{}}};",
+            entries.join(", ")
+        );
+        array
+    }
+
+    // A `struct` or `enum` declaration as a static CryShape: its name, and its fields or
+    // its variants with theirs
+    fn shape(&mut self, name: &str, parts: &[(String, Vec<String>)]) -> String {
+        let mut entries = Vec::new();
+        for (part, fields) in parts {
+            let fields: Vec<_> = fields
+                .iter()
+                .map(|field| format!("{{{}, 0, NULL}}", c_string(field)))
+                .collect();
+            let array = self.static_array("CryShape", "cry_fields", &fields);
+            entries.push(format!("{{{}, {}, {array}}}", c_string(part), fields.len()));
+        }
+        let array = self.static_array("CryShape", "cry_parts", &entries);
+        self.static_count += 1;
+        let shape = format!("cry_shape_{}", self.static_count);
+        let _ = writeln!(
+            self.statics,
+            "static const CryShape {shape} = {{{}, {}, {array}}};",
+            c_string(name),
+            entries.len()
+        );
+        format!("&{shape}")
+    }
+
+    // What `name` refers to, with no builtin fallback: a C int that is true if it is
+    // bound, and its value
+    fn find(&mut self, name: &str) -> (String, String) {
+        let place = match self.resolve(name).0 {
+            Place::Local { var, cell: false } => return ("1".to_string(), var),
+            Place::Local { var, cell: true } => {
+                return ("1".to_string(), self.temp(format!("{var}->value")))
+            }
+            place => place,
+        };
+        let (global, value, found) = (self.global(name), self.fresh("v"), self.fresh("found"));
+        let find = match place {
+            Place::Upvalue(i) => format!("cry_find_upvalue(env[{i}], {global}, &{value})"),
+            _ => format!("cry_find_global({global}, &{value})"),
+        };
+        self.line(&format!("CryValue {value} = cry_nil();"));
+        self.line(&format!("int {found} = {find};"));
+        (found, value)
+    }
+
+    // A `match`, giving the chosen arm's value if it is an expression. Patterns are
+    // looked up as it runs, as the interpreter does, since a name in one may be a variant
+    // or a new binding depending on what it refers to.
+    fn matching(&mut self, node: &Node, subject: &Node, arms: &[MatchArm], value: bool) -> String {
+        let subject_value = self.expression(subject);
+        let mut names: Vec<String> = Vec::new();
+        for arm in arms {
+            for name in patterns::mentioned(&arm.pattern) {
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+        // C has no empty arrays, so a match that mentions no names gets an unused slot
+        let (mut found_list, mut value_list) =
+            (vec!["0".to_string()], vec!["cry_nil()".to_string()]);
+        if !names.is_empty() {
+            (found_list, value_list) = names.iter().map(|name| self.find(name)).unzip();
+        }
+        let (values, found) = (self.fresh("m"), self.fresh("found"));
+        self.line(&format!("int {found}[] = {{{}}};", found_list.join(", ")));
+        self.line(&format!(
+            "CryValue {values}[] = {{{}}};",
+            value_list.join(", ")
+        ));
+        let entries: Vec<_> = arms
+            .iter()
+            .map(|arm| self.pattern(&arm.pattern, &names))
+            .collect();
+        let table = self.static_array("CryPattern", "cry_arms", &entries);
+        let list = self.names(&names);
+        let (arm, site, subject_site) = (
+            self.fresh("arm"),
+            self.site(&node.span),
+            self.site(&subject.span),
+        );
+        self.line(&format!(
+            "int {arm} = cry_match({subject_value}, {}, {table}, {}, {list}, {found}, {values}, \
+             {site}, {subject_site});",
+            arms.len(),
+            names.len()
+        ));
+        let result = self.fresh("t");
+        if value {
+            self.line(&format!("CryValue {result} = cry_nil();"));
+        }
+        for (i, match_arm) in arms.iter().enumerate() {
+            if i == 0 {
+                self.open(&format!("if ({arm} == 0) {{"));
+            } else {
+                self.close(&format!("}} else if ({arm} == {i}) {{"));
+                self.function().indent += 1;
+            }
+            // What the pattern binds; a name that turned out to be a unit variant reads
+            // the same as outside
+            let mut bound = Vec::new();
+            patterns::binding_names(&match_arm.pattern, &mut bound);
+            let cells = captured(std::slice::from_ref(&match_arm.body));
+            let scope = bound.iter().map(|name| (name.clone(), Declaration::Let));
+            self.open_scope(scope.collect(), true, &cells);
+            let scope = self.function().scopes.last().expect("just opened");
+            let binds: Vec<_> = scope
+                .iter()
+                .map(|local| {
+                    let i = names.iter().position(|name| *name == local.name);
+                    let value = format!("{values}[{}]", i.expect("bound names are mentioned"));
+                    match local.cell {
+                        true => format!("cry_bind({}, {value});", local.var),
+                        false => format!("{} = {value};", local.var),
+                    }
+                })
+                .collect();
+            for bind in binds {
+                self.line(&bind);
+            }
+            if value {
+                let body = self.expression(&match_arm.body);
+                self.line(&format!("{result} = {body};"));
+            } else {
+                self.statement(&match_arm.body);
+            }
+            self.close_scope();
+        }
+        if !arms.is_empty() {
+            self.close("}");
+        }
+        match value {
+            true => result,
+            false => "cry_nil()".to_string(),
+        }
+    }
+
+    // A pattern as a static CryPattern initializer, naming each name by its index in `names`
+    fn pattern(&mut self, pattern: &Spanned<Pattern>, names: &[String]) -> String {
+        let index = |name: &String| {
+            let i = names.iter().position(|known| known == name);
+            i.expect("every mentioned name is listed")
+        };
+        let nil = "{CRY_NIL, {0}}".to_string();
+        let (kind, name, literal, fields) = match &pattern.node {
+            Pattern::Wildcard => ("CRY_PAT_WILD", 0, nil, &[][..]),
+            Pattern::Binding(name) => ("CRY_PAT_BIND", index(name), nil, &[][..]),
+            Pattern::Literal(literal) => ("CRY_PAT_LITERAL", 0, self.literal(literal), &[][..]),
+            Pattern::Variant(name, fields) => ("CRY_PAT_VARIANT", index(name), nil, &fields[..]),
+        };
+        let entries: Vec<_> = fields
+            .iter()
+            .map(|field| self.pattern(field, names))
+            .collect();
+        let fields = self.static_array("CryPattern", "cry_fields", &entries);
+        let site = self.site(&pattern.span);
+        let count = entries.len();
+        format!("{{{kind}, {name}, {literal}, {count}, {fields}, {site}}}")
+    }
+
+    // A literal pattern's value as a static CryValue initializer
+    fn literal(&mut self, literal: &ASTNode) -> String {
+        match literal {
+            ASTNode::Int(i64::MIN) => "{CRY_INT, {.i = INT64_MIN}}".to_string(),
+            ASTNode::Int(n) => format!("{{CRY_INT, {{.i = INT64_C({n})}}}}"),
+            ASTNode::Float(n) => format!("{{CRY_FLOAT, {{.f = {n:?}}}}}"),
+            ASTNode::Bool(b) => format!("{{CRY_BOOL, {{.b = {}}}}}", u8::from(*b)),
+            ASTNode::String(s) => {
+                self.static_count += 1;
+                let text = format!("cry_text_{}", self.static_count);
+                let _ = writeln!(
+                    self.statics,
+                    "static CryString {text} = {{{}, {}}};",
+                    s.len(),
+                    c_string(s)
+                );
+                format!("{{CRY_STRING, {{.s = &{text}}}}}")
+            }
+            _ => "{CRY_NIL, {0}}".to_string(),
+        }
+    }
+
+    // Calls by name: memories shadow builtins, so a program may define its own `print`
+    fn call(&mut self, name: &str, args: &[Node], span: &Span) -> String {
+        let callee = self.fresh("f");
+        let found = match self.resolve(name).0 {
+            Place::Local { var, cell } => {
+                let value = if cell { format!("{var}->value") } else { var };
+                self.line(&format!("CryValue {callee} = {value};"));
+                None
+            }
+            place => {
+                let global = self.global(name);
+                let find = match place {
+                    Place::Upvalue(i) => format!("cry_find_upvalue(env[{i}], {global}, &{callee})"),
+                    _ => format!("cry_find_global({global}, &{callee})"),
+                };
+                let found = self.fresh("found");
+                self.line(&format!("CryValue {callee} = cry_nil();"));
+                self.line(&format!("int {found} = {find};"));
+                Some(found)
+            }
+        };
+        if let (Some(found), true) = (&found, builtins::mutates_first_arg(name)) {
+            if let Some((root, root_span)) = args.first().and_then(root) {
+                match self.resolve(root) {
+                    (Place::Global, _) => {
+                        let (global, site) = (self.global(root), self.site(root_span));
+                        let root = c_string(root);
+                        self.line(&format!(
+                            "if (!{found}) cry_writable({global}, {root}, {site});"
+                        ));
+                    }
+                    (_, true) => {
+                        self.open(&format!("if (!{found}) {{"));
+                        self.cannot_modify(root, root_span);
+                        self.close("}");
+                    }
+                    (_, false) => {}
+                }
+            }
+        }
+        let args: Vec<_> = args.iter().map(|arg| self.expression(arg)).collect();
+        let array = self.array(&args);
+        let (argc, site) = (args.len(), self.site(span));
+        let invoke = format!("cry_call({callee}, {argc}, {array}, {site})");
+        let Some(found) = found else {
+            return self.temp(invoke);
+        };
+        let fallback = match builtins::lookup(name) {
+            Some(_) => format!("cry_builtin_{name}({argc}, {array}, {site})"),
+            None => format!("cry_no_function({}, {site})", c_string(name)),
+        };
+        self.temp(format!("{found} ? {invoke} : {fallback}"))
+    }
+
+    fn function_def(&mut self, name: &Option<String>, params: &[String], body: &Node) -> String {
+        self.function_count += 1;
+        let c_name = match name {
+            Some(name) => format!("cry_fn_{}_{name}", self.function_count),
+            None => format!("cry_fn_{}", self.function_count),
+        };
+        self.functions
+            .push(Function::new(name.clone(), c_name, params.len()));
+        let body = match &body.node {
+            ASTNode::Block(nodes) => nodes.as_slice(),
+            _ => &[],
+        };
+        // Parameters and the body's own declarations share the call's scope
+        let cells = captured(body);
+        let mut scope = Vec::new();
+        for (i, param) in params.iter().enumerate() {
+            let cell = cells.contains(param);
+            let var = self.fresh(&format!("{}_{param}_", if cell { "c" } else { "v" }));
+            if cell {
+                self.line(&format!("CryCell *{var} = cry_cell_of(args[{i}]);"));
+            } else {
+                self.line(&format!("CryValue {var} = args[{i}];"));
+            }
+            scope.push(Local {
+                name: param.clone(),
+                var,
+                cell,
+                declaration: Declaration::Let,
+                declared: true,
+            });
+        }
+        let declared: Vec<_> = declarations(body)
+            .into_iter()
+            .filter(|(name, _)| !params.contains(name))
+            .collect();
+        self.open_scope(declared, false, &cells);
+        let mut body_scope = self.function().scopes.pop().expect("just opened");
+        scope.append(&mut body_scope);
+        self.function().scopes.push(scope);
+        for statement in body {
+            self.statement(statement);
+        }
+
+        let function = self.functions.pop().expect("just pushed");
+        let shown = match &function.name {
+            Some(name) => format!("<fn {name}>"),
+            None => "<fn>".to_string(),
+        };
+        let env = match function.captures.len() {
+            0 => "NULL".to_string(),
+            _ => {
+                let cells: Vec<_> = function.captures.iter().map(|c| c.from.as_str()).collect();
+                format!("(CryCell *[]){{{}}}", cells.join(", "))
+            }
+        };
+        let closure = format!(
+            "cry_closure({}, {}, {}, {env}, {})",
+            c_string(&shown),
+            function.arity,
+            function.c_name,
+            function.captures.len()
+        );
+        self.finish(function);
+        self.temp(closure)
+    }
+}
+
+fn math_op(math: MathToken) -> Option<&'static str> {
+    let op = match math {
+        MathToken::Plus => "CRY_ADD",
+        MathToken::Minus => "CRY_SUB",
+        MathToken::Divide => "CRY_DIV",
+        MathToken::Multiply => "CRY_MUL",
+        MathToken::IntDivide => "CRY_INT_DIV",
+        MathToken::Modulo => "CRY_MOD",
+        MathToken::Power => "CRY_POW",
+        _ => return None,
+    };
+    Some(op)
+}
+
+fn compare_op(compare: CompareToken) -> &'static str {
+    match compare {
+        CompareToken::Equal => "CRY_EQ",
+        CompareToken::NotEqual => "CRY_NE",
+        CompareToken::Less => "CRY_LT",
+        CompareToken::LessEq => "CRY_LE",
+        CompareToken::Greater => "CRY_GT",
+        CompareToken::GreaterEq => "CRY_GE",
+    }
+}
+
+// A C string literal of any text; `?` is escaped so no trigraph can form
+fn c_string(text: &str) -> String {
+    let mut literal = String::from("\"");
+    for byte in text.bytes() {
+        match byte {
+            b'"' => literal.push_str("\\\""),
+            b'\\' => literal.push_str("\\\\"),
+            b'?' => literal.push_str("\\?"),
+            b' '..=b'~' => literal.push(byte as char),
+            _ => {
+                let _ = write!(literal, "\\{byte:03o}");
+            }
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+// Every name a run of statements binds in its own scope, with how it is first declared
+fn declarations(nodes: &[Node]) -> Vec<(String, Declaration)> {
+    let mut names: Vec<(String, Declaration)> = Vec::new();
+    let mut add = |name: &String, declaration| {
+        if !names.iter().any(|(known, _)| known == name) {
+            names.push((name.clone(), declaration));
+        }
+    };
+    for node in nodes {
+        match &node.node {
+            ASTNode::Let(name, _) => add(name, Declaration::Let),
+            ASTNode::Final(name, _) => add(name, Declaration::Final),
+            ASTNode::FunDef {
+                name: Some(name), ..
+            } => add(name, Declaration::Function),
+            ASTNode::StructDef { name, .. } => add(name, Declaration::Struct),
+            ASTNode::EnumDef { name, variants } => {
+                for (variant, _) in variants {
+                    add(variant, Declaration::Variant);
+                }
+                add(name, Declaration::Enum);
+            }
+            _ => {}
+        }
+    }
+    names
+}
+
+// Every name mentioned inside a function nested in `nodes`, which therefore may be
+// captured and needs a cell
+fn captured(nodes: &[Node]) -> HashSet<String> {
+    let mut names = HashSet::new();
+    for node in nodes {
+        mentioned(node, false, &mut names);
+    }
+    names
+}
+
+fn mentioned(node: &Node, in_function: bool, names: &mut HashSet<String>) {
+    let in_function = in_function || matches!(node.node, ASTNode::FunDef { .. });
+    if in_function {
+        match &node.node {
+            ASTNode::Identifier(name)
+            | ASTNode::Assign { ident: name, .. }
+            | ASTNode::CompoundAssign { ident: name, .. }
+            | ASTNode::StructLit { name, .. } => {
+                names.insert(name.clone());
+            }
+            // Patterns look their names up, to tell variants from new bindings
+            ASTNode::Match { arms, .. } => {
+                names.extend(
+                    arms.iter()
+                        .flat_map(|arm| patterns::mentioned(&arm.pattern)),
+                );
+            }
+            _ => {}
+        }
+    }
+    for child in children(node) {
+        mentioned(child, in_function, names);
+    }
+}
+
+// The nodes directly inside a node
+fn children(node: &Node) -> Vec<&Node> {
+    match &node.node {
+        ASTNode::Program(nodes)
+        | ASTNode::Block(nodes)
+        | ASTNode::Interpolation(nodes)
+        | ASTNode::List(nodes) => nodes.iter().collect(),
+        ASTNode::FunCall(callee, args) => std::iter::once(callee.as_ref()).chain(args).collect(),
+        ASTNode::Map(entries) => entries.iter().flat_map(|(k, v)| [k, v]).collect(),
+        ASTNode::StructLit { fields, .. } => fields.iter().map(|(_, value)| value).collect(),
+        ASTNode::Match { subject, arms } => std::iter::once(subject.as_ref())
+            .chain(arms.iter().map(|arm| &arm.body))
+            .collect(),
+        ASTNode::Let(_, value)
+        | ASTNode::Final(_, value)
+        | ASTNode::Assign { value, .. }
+        | ASTNode::CompoundAssign { value, .. }
+        | ASTNode::Field { target: value, .. }
+        | ASTNode::UnaryOp { operand: value, .. }
+        | ASTNode::Return(Some(value)) => vec![value],
+        ASTNode::Index { target, index } => vec![target, index],
+        ASTNode::BinaryOp { left, right, .. } => vec![left, right],
+        ASTNode::Range { start, end } => vec![start, end],
+        ASTNode::IndexAssign {
+            target,
+            index,
+            value,
+        }
+        | ASTNode::CompoundIndexAssign {
+            target,
+            index,
+            value,
+            ..
+        } => vec![target, index, value],
+        ASTNode::FieldAssign { target, value, .. }
+        | ASTNode::CompoundFieldAssign { target, value, .. } => vec![target, value],
+        ASTNode::FunDef { body, .. } => vec![body],
+        ASTNode::If {
+            cond,
+            then,
+            otherwise,
+        } => std::iter::once(cond.as_ref())
+            .chain(std::iter::once(then.as_ref()))
+            .chain(otherwise.as_deref())
+            .collect(),
+        ASTNode::While { cond, body } => vec![cond, body],
+        ASTNode::For { iterable, body, .. } => vec![iterable, body],
+        _ => Vec::new(),
+    }
+}
+
+// The variable an identifier, index or field expression starts from
+fn root(node: &Node) -> Option<(&str, &Span)> {
+    match &node.node {
+        ASTNode::Identifier(ident) => Some((ident, &node.span)),
+        ASTNode::Index { target, .. } | ASTNode::Field { target, .. } => root(target),
+        _ => None,
+    }
+}
@@ -0,0 +1,910 @@
+use std::rc::Rc;
+
+use super::{
+    builtins,
+    bytecode::{
+        Capture, Chunk, Fault, FaultKind, MatchArm, MatchTable, Op, Place, Proto, StructLit,
+    },
+    lexer::Token,
+    memories::{Declaration, Memory},
+    parser::{self, ASTNode, Node},
+    patterns,
+    span::Span,
+};
+
+// A variable of a block scope. Every name a block declares gets its slot as the block
+// starts, so functions declared in it can capture names declared after them.
+struct Local {
+    name: String,
+    slot: u32,
+    declaration: Declaration,
+    declared: bool,
+}
+
+struct Loop {
+    // Where `continue` jumps to
+    start: u32,
+    // `break` jumps, patched once the loop's end is known
+    breaks: Vec<usize>,
+}
+
+// A function being compiled
+struct Function {
+    name: Option<String>,
+    arity: u32,
+    chunk: Chunk,
+    scopes: Vec<Vec<Local>>,
+    captures: Vec<(Capture, bool)>,
+    next_slot: u32,
+    slots: u32,
+    loops: Vec<Loop>,
+}
+
+impl Function {
+    fn new(name: Option<String>, arity: u32) -> Self {
+        Function {
+            name,
+            arity,
+            chunk: Chunk::default(),
+            scopes: Vec::new(),
+            captures: Vec::new(),
+            next_slot: 0,
+            slots: 0,
+            loops: Vec::new(),
+        }
+    }
+
+    fn finish(self) -> Proto {
+        Proto {
+            name: self.name,
+            arity: self.arity,
+            slots: self.slots,
+            captures: self
+                .captures
+                .into_iter()
+                .map(|(capture, _)| capture)
+                .collect(),
+            chunk: self.chunk,
+        }
+    }
+}
+
+// Compiles a whole program into a function of no arguments. The program's top level is
+// the VM's global scope; everything nested in it lives in slots.
+pub fn compile(ast: &Node) -> Rc<Proto> {
+    let mut compiler = Compiler {
+        functions: vec![Function::new(None, 0)],
+    };
+    if let ASTNode::Program(nodes) = &ast.node {
+        for node in nodes {
+            compiler.statement(node);
+        }
+    }
+    compiler.emit(Op::Nil, &ast.span);
+    compiler.emit(Op::Return, &ast.span);
+    let script = compiler
+        .functions
+        .pop()
+        .expect("the script is always compiled");
+    Rc::new(script.finish())
+}
+
+struct Compiler {
+    functions: Vec<Function>,
+}
+
+impl Compiler {
+    fn function(&mut self) -> &mut Function {
+        self.functions
+            .last_mut()
+            .expect("always compiling a function")
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.function().chunk
+    }
+
+    fn emit(&mut self, op: Op, span: &Span) -> usize {
+        let chunk = self.chunk();
+        let same = chunk.spans.last().is_some_and(|last| {
+            last.start == span.start && last.end == span.end && last.line == span.line
+        });
+        if !same {
+            chunk.spans.push(span.clone());
+        }
+        chunk.lines.push(chunk.spans.len() as u32 - 1);
+        chunk.code.push(op);
+        chunk.code.len() - 1
+    }
+
+    fn here(&mut self) -> u32 {
+        self.chunk().code.len() as u32
+    }
+
+    // Points the jump at `at` to the next op
+    fn patch(&mut self, at: usize) {
+        let target = self.here();
+        match &mut self.chunk().code[at] {
+            Op::Jump(to) | Op::JumpIfFalse(to) | Op::ForNext(_, to) | Op::RangeNext(_, to) => {
+                *to = target
+            }
+            op => unreachable!("{op:?} is not a jump"),
+        }
+    }
+
+    fn span_index(&mut self, span: &Span) -> u32 {
+        let chunk = self.chunk();
+        chunk.spans.push(span.clone());
+        chunk.spans.len() as u32 - 1
+    }
+
+    fn constant(&mut self, value: Memory) -> u32 {
+        let chunk = self.chunk();
+        chunk.constants.push(value);
+        chunk.constants.len() as u32 - 1
+    }
+
+    fn name(&mut self, name: &str) -> u32 {
+        let chunk = self.chunk();
+        match chunk.names.iter().position(|known| known == name) {
+            Some(i) => i as u32,
+            None => {
+                chunk.names.push(name.to_string());
+                chunk.names.len() as u32 - 1
+            }
+        }
+    }
+
+    fn fail(&mut self, kind: FaultKind, message: String, span: &Span) {
+        let chunk = self.chunk();
+        chunk.faults.push(Fault { kind, message });
+        let fault = chunk.faults.len() as u32 - 1;
+        self.emit(Op::Fail(fault), span);
+    }
+
+    // Opens a block scope holding `names`, giving them fresh cells; true names are bound
+    // by the caller straight away
+    fn open_scope(&mut self, names: Vec<(String, Declaration)>, declared: bool, span: &Span) {
+        let function = self.function();
+        let first = function.next_slot;
+        let scope: Vec<Local> = names
+            .into_iter()
+            .enumerate()
+            .map(|(i, (name, declaration))| Local {
+                name,
+                slot: first + i as u32,
+                declaration,
+                declared,
+            })
+            .collect();
+        let count = scope.len() as u32;
+        function.next_slot += count;
+        function.slots = function.slots.max(function.next_slot);
+        function.scopes.push(scope);
+        if count > 0 {
+            self.emit(Op::Fresh(first, count), span);
+        }
+    }
+
+    fn close_scope(&mut self) {
+        let function = self.function();
+        let scope = function.scopes.pop().expect("scopes are balanced");
+        function.next_slot -= scope.len() as u32;
+    }
+
+    // Finds a name as seen from the function at `depth`; the bool is true for finals.
+    // Names an enclosing function declares later in a scope still count, as the inner
+    // function may run after they are bound.
+    fn resolve_in(&mut self, depth: usize, name: &str) -> (Place, bool) {
+        let current = depth + 1 == self.functions.len();
+        let function = &mut self.functions[depth];
+        for scope in function.scopes.iter().rev() {
+            let found = scope
+                .iter()
+                .rev()
+                .find(|local| local.name == name && (local.declared || !current));
+            if let Some(local) = found {
+                let is_final = local.declaration != Declaration::Let;
+                return (Place::Local(local.slot), is_final);
+            }
+        }
+        if let Some(i) = function.captures.iter().position(|(c, _)| c.name == name) {
+            return (Place::Upvalue(i as u32), function.captures[i].1);
+        }
+        if depth == 0 {
+            return (Place::Global, false);
+        }
+        let (local, index, is_final) = match self.resolve_in(depth - 1, name) {
+            (Place::Global, _) => return (Place::Global, false),
+            (Place::Local(slot), is_final) => (true, slot, is_final),
+            (Place::Upvalue(i), is_final) => (false, i, is_final),
+        };
+        let captures = &mut self.functions[depth].captures;
+        let capture = Capture {
+            name: name.to_string(),
+            local,
+            index,
+        };
+        captures.push((capture, is_final));
+        (Place::Upvalue(captures.len() as u32 - 1), is_final)
+    }
+
+    fn resolve(&mut self, name: &str) -> (Place, bool) {
+        self.resolve_in(self.functions.len() - 1, name)
+    }
+
+    fn get(&mut self, name: &str, span: &Span) {
+        let op = match self.resolve(name).0 {
+            Place::Local(slot) => Op::GetLocal(slot),
+            Place::Upvalue(i) => Op::GetUpvalue(i),
+            Place::Global => Op::GetGlobal(self.name(name)),
+        };
+        self.emit(op, span);
+    }
+
+    // Stores the top of the stack into the variable `name` refers to
+    fn set(&mut self, place: Place, name: &str, span: &Span) {
+        let op = match place {
+            Place::Local(slot) => Op::SetLocal(slot),
+            Place::Upvalue(i) => Op::SetUpvalue(i),
+            Place::Global => Op::SetGlobal(self.name(name)),
+        };
+        self.emit(op, span);
+    }
+
+    fn cannot_modify(&mut self, name: &str, span: &Span) {
+        let message = format!("Cannot modify final variable '{name}'");
+        self.fail(FaultKind::Mutability, message, span);
+    }
+
+    // Binds the top of the stack to `name` in the innermost scope
+    fn declare(&mut self, name: &str, declaration: Declaration, span: &Span) {
+        if self.function().scopes.is_empty() {
+            let name = self.name(name);
+            self.emit(Op::DefineGlobal(name, declaration), span);
+            return;
+        }
+        let function = self.function();
+        let scope = function.scopes.last_mut().expect("inside a block");
+        let found = scope.iter().rposition(|local| local.name == name);
+        let Some(i) = found else {
+            // Only block statements are scanned up front; anything else gets a new slot
+            let slot = function.next_slot;
+            function.next_slot += 1;
+            function.slots = function.slots.max(function.next_slot);
+            scope.push(Local {
+                name: name.to_string(),
+                slot,
+                declaration,
+                declared: true,
+            });
+            self.emit(Op::Fresh(slot, 1), span);
+            self.emit(Op::SetLocal(slot), span);
+            return;
+        };
+        let local = &mut scope[i];
+        if local.declared && !declaration.may_replace(local.declaration) {
+            let message = local.declaration.redeclared(name);
+            self.fail(FaultKind::Mutability, message, span);
+            return;
+        }
+        local.declared = true;
+        local.declaration = declaration;
+        let slot = local.slot;
+        self.emit(Op::SetLocal(slot), span);
+    }
+
+    // Checks `node` could be modified in place, as `writable` does for the tree-walker
+    fn writable(&mut self, node: &Node) {
+        let Some((root, span)) = root(node) else {
+            return;
+        };
+        match self.resolve(root) {
+            (Place::Global, _) => {
+                let name = self.name(root);
+                self.emit(Op::Writable(name), span);
+            }
+            (_, true) => self.cannot_modify(root, span),
+            (_, false) => {}
+        }
+    }
+
+    // Applies `op=` to the current value on the stack
+    fn compound(&mut self, op: &Token, value: &Node, span: &Span) {
+        let Some(Token::Arithmetic(base)) = op.compound_base() else {
+            let message = "Invalid binary operation".to_string();
+            return self.fail(FaultKind::Runtime, message, span);
+        };
+        self.expression(value);
+        self.emit(Op::Math(base), span);
+    }
+
+    fn statements(&mut self, nodes: &[Node], span: &Span) {
+        self.open_scope(declarations(nodes), false, span);
+        for node in nodes {
+            self.statement(node);
+        }
+        self.close_scope();
+    }
+
+    fn statement(&mut self, node: &Node) {
+        let span = &node.span;
+        match &node.node {
+            ASTNode::Let(ident, value) => {
+                self.expression(value);
+                self.declare(ident, Declaration::Let, span);
+            }
+            ASTNode::Final(ident, value) => {
+                self.expression(value);
+                self.declare(ident, Declaration::Final, span);
+            }
+            ASTNode::Assign { ident, value } => {
+                self.expression(value);
+                match self.resolve(ident) {
+                    (Place::Global, _) => self.set(Place::Global, ident, span),
+                    (_, true) => self.cannot_modify(ident, span),
+                    (place, false) => self.set(place, ident, span),
+                }
+            }
+            ASTNode::IndexAssign {
+                target,
+                index,
+                value,
+            } => {
+                self.writable(target);
+                self.expression(target);
+                self.expression(index);
+                self.expression(value);
+                let target = self.span_index(&target.span);
+                self.emit(Op::SetIndex(target), &index.span);
+            }
+            ASTNode::FieldAssign {
+                target,
+                field,
+                value,
+            } => {
+                self.writable(target);
+                self.expression(target);
+                self.expression(value);
+                let (field, target) = (self.name(field), self.span_index(&target.span));
+                self.emit(Op::SetField(field, target), span);
+            }
+            // The target and index wait in hidden slots, so each is evaluated once
+            ASTNode::CompoundIndexAssign {
+                target,
+                index,
+                op,
+                value,
+            } => {
+                self.writable(target);
+                self.open_scope(vec![(String::new(), Declaration::Let); 2], true, span);
+                let slots = self.function().next_slot - 2;
+                self.expression(target);
+                self.emit(Op::SetLocal(slots), span);
+                self.expression(index);
+                self.emit(Op::SetLocal(slots + 1), span);
+                let target = self.span_index(&target.span);
+                for _ in 0..2 {
+                    self.emit(Op::GetLocal(slots), span);
+                    self.emit(Op::GetLocal(slots + 1), span);
+                }
+                self.emit(Op::Index(target), &index.span);
+                self.compound(op, value, span);
+                self.emit(Op::SetIndex(target), &index.span);
+                self.close_scope();
+            }
+            ASTNode::CompoundFieldAssign {
+                target,
+                field,
+                op,
+                value,
+            } => {
+                self.writable(target);
+                self.open_scope(vec![(String::new(), Declaration::Let)], true, span);
+                let slot = self.function().next_slot - 1;
+                self.expression(target);
+                self.emit(Op::SetLocal(slot), span);
+                let (field, target) = (self.name(field), self.span_index(&target.span));
+                self.emit(Op::GetLocal(slot), span);
+                self.emit(Op::GetLocal(slot), span);
+                self.emit(Op::GetField(field, target), span);
+                self.compound(op, value, span);
+                self.emit(Op::SetField(field, target), span);
+                self.close_scope();
+            }
+            ASTNode::CompoundAssign { ident, op, value } => {
+                let place = match self.resolve(ident) {
+                    (Place::Global, _) => {
+                        let name = self.name(ident);
+                        self.emit(Op::GetGlobalMut(name), span);
+                        Place::Global
+                    }
+                    (_, true) => return self.cannot_modify(ident, span),
+                    (place, false) => {
+                        self.get(ident, span);
+                        place
+                    }
+                };
+                self.compound(op, value, span);
+                self.set(place, ident, span);
+            }
+            ASTNode::StructDef { name, fields } => {
+                let chunk = self.chunk();
+                chunk.structs.push((name.clone(), fields.clone()));
+                let def = chunk.structs.len() as u32 - 1;
+                self.emit(Op::Struct(def), span);
+                self.declare(name, Declaration::Struct, span);
+            }
+            ASTNode::EnumDef { name, variants } => {
+                let chunk = self.chunk();
+                chunk.enums.push((name.clone(), variants.clone()));
+                let def = chunk.enums.len() as u32 - 1;
+                self.emit(Op::Enum(def), span);
+                for (variant, _) in variants {
+                    self.declare(variant, Declaration::Variant, span);
+                }
+                self.declare(name, Declaration::Enum, span);
+            }
+            ASTNode::Match { subject, arms } => self.matching(node, subject, arms, false),
+            ASTNode::FunDef {
+                name: Some(name), ..
+            } => {
+                self.expression(node);
+                self.declare(name, Declaration::Function, span);
+            }
+            ASTNode::Block(nodes) => self.statements(nodes, span),
+            ASTNode::Return(value) => {
+                match value {
+                    Some(value) => self.expression(value),
+                    None => {
+                        self.emit(Op::Nil, span);
+                    }
+                }
+                self.emit(Op::Return, span);
+            }
+            ASTNode::Break | ASTNode::Continue => {
+                let jump = match (&node.node, self.function().loops.last()) {
+                    (ASTNode::Break, Some(_)) => Op::Jump(0),
+                    (_, Some(inner)) => Op::Jump(inner.start),
+                    // Outside a loop they end the function, or the program
+                    (_, None) => {
+                        self.emit(Op::Nil, span);
+                        self.emit(Op::Return, span);
+                        return;
+                    }
+                };
+                let at = self.emit(jump, span);
+                if matches!(node.node, ASTNode::Break) {
+                    let inner = self.function().loops.last_mut().expect("inside a loop");
+                    inner.breaks.push(at);
+                }
+            }
+            ASTNode::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                self.expression(cond);
+                let skip = self.emit(Op::JumpIfFalse(0), span);
+                self.statement(then);
+                match otherwise {
+                    Some(otherwise) => {
+                        let end = self.emit(Op::Jump(0), span);
+                        self.patch(skip);
+                        self.statement(otherwise);
+                        self.patch(end);
+                    }
+                    None => self.patch(skip),
+                }
+            }
+            ASTNode::While { cond, body } => {
+                let start = self.here();
+                self.expression(cond);
+                let exit = self.emit(Op::JumpIfFalse(0), span);
+                self.looping(start, body);
+                self.emit(Op::Jump(start), span);
+                self.end_loop(exit);
+            }
+            ASTNode::For {
+                ident,
+                value,
+                iterable,
+                body,
+            } => self.for_loop(node, ident, value.as_deref(), iterable, body),
+            _ => {
+                self.expression(node);
+                self.emit(Op::Pop, span);
+            }
+        }
+    }
+
+    // Compiles a loop body, with `continue` going to `start`
+    fn looping(&mut self, start: u32, body: &Node) {
+        let breaks = Vec::new();
+        self.function().loops.push(Loop { start, breaks });
+        self.statement(body);
+    }
+
+    fn end_loop(&mut self, exit: usize) {
+        let inner = self.function().loops.pop().expect("inside a loop");
+        self.patch(exit);
+        for at in inner.breaks {
+            self.patch(at);
+        }
+    }
+
+    fn for_loop(
+        &mut self,
+        node: &Node,
+        ident: &str,
+        value: Option<&str>,
+        iterable: &Node,
+        body: &Node,
+    ) {
+        let span = &node.span;
+        // Hidden slots hold the loop's state: the next int and the end of a range, or
+        // the snapshot of what each pass binds and a count of passes so far
+        let hidden = |count| vec![(String::new(), Declaration::Let); count];
+        let step = match &iterable.node {
+            ASTNode::Range { start, end } => {
+                if value.is_some() {
+                    let message = "A range gives one loop variable, not two".to_string();
+                    return self.fail(FaultKind::Type, message, &iterable.span);
+                }
+                self.open_scope(hidden(2), true, span);
+                let state = self.function().next_slot - 2;
+                self.expression(start);
+                self.emit(Op::ExpectInt, &start.span);
+                self.expression(end);
+                self.emit(Op::ExpectInt, &end.span);
+                self.emit(Op::SetLocal(state + 1), span);
+                self.emit(Op::SetLocal(state), span);
+                Op::RangeNext(state, 0)
+            }
+            _ => {
+                self.open_scope(hidden(3), true, span);
+                let state = self.function().next_slot - 3;
+                self.expression(iterable);
+                self.emit(Op::Iter(value.is_some()), &iterable.span);
+                self.emit(Op::SetLocal(state + 1), span);
+                self.emit(Op::SetLocal(state), span);
+                let zero = self.constant(Memory::Int(0));
+                self.emit(Op::Const(zero), span);
+                self.emit(Op::SetLocal(state + 2), span);
+                Op::ForNext(state, 0)
+            }
+        };
+        let start = self.here();
+        let exit = self.emit(step, span);
+        // Each pass gets fresh cells, so closures capture that pass's values
+        let mut names = vec![(ident.to_string(), Declaration::Let)];
+        names.extend(value.map(|value| (value.to_string(), Declaration::Let)));
+        self.open_scope(names, true, span);
+        let first = self.function().next_slot - if value.is_some() { 2 } else { 1 };
+        match (&step, value) {
+            (Op::ForNext(..), Some(_)) => {
+                self.emit(Op::SetLocal(first + 1), span);
+            }
+            (Op::ForNext(..), None) => {
+                self.emit(Op::Pop, span);
+            }
+            _ => {}
+        }
+        self.emit(Op::SetLocal(first), span);
+        self.looping(start, body);
+        self.close_scope();
+        self.emit(Op::Jump(start), span);
+        self.end_loop(exit);
+        self.close_scope();
+    }
+
+    // A `match`, leaving the chosen arm's value on the stack if it is an expression
+    fn matching(&mut self, node: &Node, subject: &Node, arms: &[parser::MatchArm], value: bool) {
+        self.expression(subject);
+        let mut lookups = Vec::new();
+        for arm in arms {
+            for name in patterns::mentioned(&arm.pattern) {
+                if !lookups.iter().any(|(known, _)| *known == name) {
+                    let place = self.resolve(&name).0;
+                    lookups.push((name, place));
+                }
+            }
+        }
+        let table_arms = arms
+            .iter()
+            .map(|arm| {
+                let mut names = Vec::new();
+                patterns::binding_names(&arm.pattern, &mut names);
+                MatchArm {
+                    pattern: arm.pattern.clone(),
+                    names,
+                    target: 0,
+                }
+            })
+            .collect();
+        let chunk = self.chunk();
+        chunk.matches.push(MatchTable {
+            lookups,
+            arms: table_arms,
+        });
+        let table = chunk.matches.len() as u32 - 1;
+        let subject_span = self.span_index(&subject.span);
+        self.emit(Op::Match(table, subject_span), &node.span);
+
+        let mut ends = Vec::new();
+        for (i, arm) in arms.iter().enumerate() {
+            let target = self.here();
+            let names = self.chunk().matches[table as usize].arms[i].names.clone();
+            self.chunk().matches[table as usize].arms[i].target = target;
+            let count = names.len();
+            let scope = names
+                .into_iter()
+                .map(|name| (name, Declaration::Let))
+                .collect();
+            self.open_scope(scope, true, &arm.pattern.span);
+            let first = self.function().next_slot - count as u32;
+            for slot in (first..first + count as u32).rev() {
+                self.emit(Op::SetLocal(slot), &arm.pattern.span);
+            }
+            if value {
+                self.expression(&arm.body);
+            } else {
+                self.statement(&arm.body);
+            }
+            self.close_scope();
+            ends.push(self.emit(Op::Jump(0), &node.span));
+        }
+        for end in ends {
+            self.patch(end);
+        }
+    }
+
+    fn expression(&mut self, node: &Node) {
+        let span = &node.span;
+        match &node.node {
+            ASTNode::Int(n) => self.push(Memory::Int(*n), span),
+            ASTNode::Float(n) => self.push(Memory::Float(*n), span),
+            ASTNode::String(s) => self.push(Memory::String(s.clone()), span),
+            ASTNode::Bool(b) => self.push(Memory::Bool(*b), span),
+            ASTNode::Nil => {
+                self.emit(Op::Nil, span);
+            }
+            ASTNode::Interpolation(parts) => {
+                for part in parts {
+                    self.expression(part);
+                }
+                self.emit(Op::Concat(parts.len() as u32), span);
+            }
+            ASTNode::Identifier(ident) => self.get(ident, span),
+            ASTNode::BinaryOp {
+                left,
+                op: op @ (Token::And | Token::Or),
+                right,
+            } => {
+                // Both give a bool: the left side's if it decides, else the right side's
+                self.expression(left);
+                let short = self.emit(Op::JumpIfFalse(0), span);
+                if *op == Token::And {
+                    self.expression(right);
+                    self.emit(Op::Truthy, span);
+                    let end = self.emit(Op::Jump(0), span);
+                    self.patch(short);
+                    self.push(Memory::Bool(false), span);
+                    self.patch(end);
+                } else {
+                    self.push(Memory::Bool(true), span);
+                    let end = self.emit(Op::Jump(0), span);
+                    self.patch(short);
+                    self.expression(right);
+                    self.emit(Op::Truthy, span);
+                    self.patch(end);
+                }
+            }
+            ASTNode::BinaryOp { left, op, right } => {
+                self.expression(left);
+                self.expression(right);
+                match op {
+                    Token::Arithmetic(math) => {
+                        self.emit(Op::Math(*math), span);
+                    }
+                    Token::Compare(compare) => {
+                        self.emit(Op::Compare(*compare), span);
+                    }
+                    _ => {
+                        let message = "Invalid binary operation".to_string();
+                        self.fail(FaultKind::Runtime, message, span);
+                    }
+                }
+            }
+            ASTNode::UnaryOp { op, operand } => {
+                self.expression(operand);
+                let op = match op {
+                    Token::Not => Op::Not,
+                    _ => Op::Neg,
+                };
+                self.emit(op, span);
+            }
+            ASTNode::List(items) => {
+                for item in items {
+                    self.expression(item);
+                }
+                self.emit(Op::List(items.len() as u32), span);
+            }
+            ASTNode::Map(entries) => {
+                for (key, value) in entries {
+                    self.expression(key);
+                    self.emit(Op::Key, &key.span);
+                    self.expression(value);
+                }
+                self.emit(Op::Map(entries.len() as u32), span);
+            }
+            ASTNode::Index { target, index } => {
+                self.expression(target);
+                let target = self.span_index(&target.span);
+                if let ASTNode::Range { start, end } = &index.node {
+                    self.emit(Op::Sliceable(target), &index.span);
+                    self.expression(start);
+                    self.emit(Op::ExpectInt, &start.span);
+                    self.expression(end);
+                    self.emit(Op::ExpectInt, &end.span);
+                    self.emit(Op::Slice(target), &index.span);
+                } else {
+                    self.expression(index);
+                    self.emit(Op::Index(target), &index.span);
+                }
+            }
+            ASTNode::StructLit { name, fields } => {
+                let place = self.resolve(name).0;
+                let ident = self.name(name);
+                self.emit(Op::Find(ident, place), span);
+                let given = fields
+                    .iter()
+                    .map(|(field, value)| (field.clone(), self.span_index(&value.span)))
+                    .collect();
+                let chunk = self.chunk();
+                chunk.literals.push(StructLit {
+                    name: name.clone(),
+                    fields: given,
+                });
+                let literal = chunk.literals.len() as u32 - 1;
+                self.emit(Op::CheckStruct(literal), span);
+                for (_, value) in fields {
+                    self.expression(value);
+                }
+                self.emit(Op::MakeStruct(literal), span);
+            }
+            ASTNode::Match { subject, arms } => self.matching(node, subject, arms, true),
+            ASTNode::Field { target, field } => {
+                self.expression(target);
+                let (field, target) = (self.name(field), self.span_index(&target.span));
+                self.emit(Op::GetField(field, target), span);
+            }
+            ASTNode::FunCall(callee, args) => {
+                let ASTNode::Identifier(name) = &callee.node else {
+                    self.expression(callee);
+                    for arg in args {
+                        self.expression(arg);
+                    }
+                    self.emit(Op::Call(args.len() as u32), span);
+                    return;
+                };
+                // Memories shadow builtins, so a program may define its own `print`
+                let place = self.resolve(name).0;
+                let ident = self.name(name);
+                self.emit(Op::Find(ident, place), span);
+                if builtins::mutates_first_arg(name) {
+                    if let Some((root, root_span)) = args.first().and_then(root) {
+                        let guard = match self.resolve(root) {
+                            (Place::Global, _) => Some(false),
+                            (_, true) => Some(true),
+                            (_, false) => None,
+                        };
+                        if let Some(final_local) = guard {
+                            let root = self.name(root);
+                            self.emit(Op::Guard(root, final_local), root_span);
+                        }
+                    }
+                }
+                for arg in args {
+                    self.expression(arg);
+                }
+                self.emit(Op::CallNamed(ident, args.len() as u32), span);
+            }
+            ASTNode::FunDef { name, params, body } => self.function_def(node, name, params, body),
+            ASTNode::Range { .. } => {
+                let message = "A range can only be used in a for loop".to_string();
+                self.fail(FaultKind::Type, message, span);
+            }
+            _ => {
+                let message = "Statement used where a value was expected".to_string();
+                self.fail(FaultKind::Runtime, message, span);
+            }
+        }
+    }
+
+    fn push(&mut self, value: Memory, span: &Span) {
+        let constant = self.constant(value);
+        self.emit(Op::Const(constant), span);
+    }
+
+    fn function_def(&mut self, node: &Node, name: &Option<String>, params: &[String], body: &Node) {
+        let mut function = Function::new(name.clone(), params.len() as u32);
+        // Parameters and the body's own declarations share the call's scope
+        let mut scope: Vec<Local> = params
+            .iter()
+            .enumerate()
+            .map(|(slot, param)| Local {
+                name: param.clone(),
+                slot: slot as u32,
+                declaration: Declaration::Let,
+                declared: true,
+            })
+            .collect();
+        let body = match &body.node {
+            ASTNode::Block(nodes) => nodes.as_slice(),
+            _ => &[],
+        };
+        for (name, declaration) in declarations(body) {
+            if !params.contains(&name) {
+                let slot = scope.len() as u32;
+                scope.push(Local {
+                    name,
+                    slot,
+                    declaration,
+                    declared: false,
+                });
+            }
+        }
+        function.next_slot = scope.len() as u32;
+        function.slots = function.next_slot;
+        function.scopes.push(scope);
+        self.functions.push(function);
+        for statement in body {
+            self.statement(statement);
+        }
+        self.emit(Op::Nil, &node.span);
+        self.emit(Op::Return, &node.span);
+        let proto = self.functions.pop().expect("just pushed").finish();
+        let chunk = self.chunk();
+        chunk.protos.push(Rc::new(proto));
+        let proto = chunk.protos.len() as u32 - 1;
+        self.emit(Op::Closure(proto), &node.span);
+    }
+}
+
+// Every name a run of statements binds in its own scope, with how it is first declared
+fn declarations(nodes: &[Node]) -> Vec<(String, Declaration)> {
+    let mut names: Vec<(String, Declaration)> = Vec::new();
+    let mut add = |name: &String, declaration| {
+        if !names.iter().any(|(known, _)| known == name) {
+            names.push((name.clone(), declaration));
+        }
+    };
+    for node in nodes {
+        match &node.node {
+            ASTNode::Let(name, _) => add(name, Declaration::Let),
+            ASTNode::Final(name, _) => add(name, Declaration::Final),
+            ASTNode::FunDef {
+                name: Some(name), ..
+            } => add(name, Declaration::Function),
+            ASTNode::StructDef { name, .. } => add(name, Declaration::Struct),
+            ASTNode::EnumDef { name, variants } => {
+                for (variant, _) in variants {
+                    add(variant, Declaration::Variant);
+                }
+                add(name, Declaration::Enum);
+            }
+            _ => {}
+        }
+    }
+    names
+}
+
+// The variable an identifier, index or field expression starts from
+fn root(node: &Node) -> Option<(&str, &Span)> {
+    match &node.node {
+        ASTNode::Identifier(ident) => Some((ident, &node.span)),
+        ASTNode::Index { target, .. } | ASTNode::Field { target, .. } => root(target),
+        _ => None,
+    }
+}
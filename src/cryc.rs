@@ -0,0 +1,722 @@
+use std::rc::Rc;
+
+use super::{
+    bytecode::{
+        Capture, Chunk, Fault, FaultKind, MatchArm, MatchTable, Op, Place, Proto, StructLit,
+    },
+    lexer::{CompareToken, MathToken},
+    memories::{Declaration, Memory},
+    parser::{ASTNode, Pattern},
+    span::{Source, Span, Spanned},
+};
+
+// Layout of a .cryc file, all integers little-endian:
+//   magic "CRYC", format version (u16), FNV-1a checksum of the body (u64), body length (u64)
+//   body: the source's name and text for error messages, then the program's function
+// A function is its name, arity, slot count and captures, then its chunk: code, the debug
+// line table (the span of each op), the span pool, the constant pool and the other tables.
+const MAGIC: &[u8; 4] = b"CRYC";
+// Bump whenever the layout or the meaning of an op changes
+pub const FORMAT_VERSION: u16 = 1;
+const HEADER_LEN: usize = 4 + 2 + 8 + 8;
+
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+// Serializes a compiled program, with the source it came from for error messages
+pub fn encode(script: &Proto, source: &Source) -> Vec<u8> {
+    let mut body = Writer::default();
+    body.str(&source.name);
+    body.str(&source.text);
+    body.proto(script);
+
+    let mut bytes = Vec::with_capacity(HEADER_LEN + body.bytes.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&checksum(&body.bytes).to_le_bytes());
+    bytes.extend_from_slice(&(body.bytes.len() as u64).to_le_bytes());
+    bytes.extend(body.bytes);
+    bytes
+}
+
+// Loads a program written by `encode`; the error explains why the file can't be run
+pub fn decode(bytes: &[u8]) -> Result<Rc<Proto>, String> {
+    if !is_bytecode(bytes) {
+        return Err("not a CRYSTAL bytecode file".to_string());
+    }
+    if bytes.len() < HEADER_LEN {
+        return Err("file is truncated".to_string());
+    }
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    if version != FORMAT_VERSION {
+        return Err(format!(
+            "built for bytecode format {version}, but this CRYSTAL runs format {FORMAT_VERSION}. Rebuild it from source with 'crystal build'"
+        ));
+    }
+    let expected = u64::from_le_bytes(bytes[6..14].try_into().expect("8 bytes"));
+    let len = u64::from_le_bytes(bytes[14..22].try_into().expect("8 bytes"));
+    let body = &bytes[HEADER_LEN..];
+    if body.len() as u64 != len {
+        return Err("file is truncated".to_string());
+    }
+    if checksum(body) != expected {
+        return Err("file is corrupted (checksum mismatch)".to_string());
+    }
+
+    let mut reader = Reader {
+        bytes: body,
+        at: 0,
+        source: Source::new("", ""),
+    };
+    let name = reader.str()?;
+    let text = reader.str()?;
+    reader.source = Source::new(name, text);
+    let script = reader.proto()?;
+    if reader.at != body.len() {
+        return Err("file has trailing data".to_string());
+    }
+    Ok(Rc::new(script))
+}
+
+// 64-bit FNV-1a
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[derive(Default)]
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, n: u8) {
+        self.bytes.push(n);
+    }
+
+    fn u32(&mut self, n: u32) {
+        self.bytes.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn u64(&mut self, n: u64) {
+        self.bytes.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn len(&mut self, n: usize) {
+        self.u32(n as u32);
+    }
+
+    fn bool(&mut self, b: bool) {
+        self.u8(b as u8);
+    }
+
+    fn str(&mut self, s: &str) {
+        self.len(s.len());
+        self.bytes.extend_from_slice(s.as_bytes());
+    }
+
+    fn strs(&mut self, strs: &[String]) {
+        self.len(strs.len());
+        for s in strs {
+            self.str(s);
+        }
+    }
+
+    fn span(&mut self, span: &Span) {
+        for n in [span.start, span.end, span.line, span.column] {
+            self.len(n);
+        }
+    }
+
+    fn value(&mut self, value: &Memory) {
+        match value {
+            Memory::Nil => self.u8(0),
+            Memory::Int(n) => {
+                self.u8(1);
+                self.u64(*n as u64);
+            }
+            Memory::Float(n) => {
+                self.u8(2);
+                self.u64(n.to_bits());
+            }
+            Memory::String(s) => {
+                self.u8(3);
+                self.str(s);
+            }
+            Memory::Bool(b) => {
+                self.u8(4);
+                self.bool(*b);
+            }
+            other => unreachable!("the compiler never makes a {} constant", other.type_name()),
+        }
+    }
+
+    fn place(&mut self, place: Place) {
+        match place {
+            Place::Local(slot) => {
+                self.u8(0);
+                self.u32(slot);
+            }
+            Place::Upvalue(i) => {
+                self.u8(1);
+                self.u32(i);
+            }
+            Place::Global => self.u8(2),
+        }
+    }
+
+    fn pattern(&mut self, pattern: &Spanned<Pattern>) {
+        self.span(&pattern.span);
+        match &pattern.node {
+            Pattern::Wildcard => self.u8(0),
+            Pattern::Binding(name) => {
+                self.u8(1);
+                self.str(name);
+            }
+            Pattern::Literal(literal) => {
+                self.u8(2);
+                self.value(&match literal {
+                    ASTNode::Int(n) => Memory::Int(*n),
+                    ASTNode::Float(n) => Memory::Float(*n),
+                    ASTNode::String(s) => Memory::String(s.clone()),
+                    ASTNode::Bool(b) => Memory::Bool(*b),
+                    _ => Memory::Nil,
+                });
+            }
+            Pattern::Variant(name, fields) => {
+                self.u8(3);
+                self.str(name);
+                self.len(fields.len());
+                for field in fields {
+                    self.pattern(field);
+                }
+            }
+        }
+    }
+
+    fn op(&mut self, op: Op) {
+        let (code, operands): (u8, &[u32]) = match op {
+            Op::Const(i) => (0, &[i]),
+            Op::Nil => (1, &[]),
+            Op::Pop => (2, &[]),
+            Op::GetLocal(slot) => (3, &[slot]),
+            Op::SetLocal(slot) => (4, &[slot]),
+            Op::GetUpvalue(i) => (5, &[i]),
+            Op::SetUpvalue(i) => (6, &[i]),
+            Op::GetGlobal(name) => (7, &[name]),
+            Op::SetGlobal(name) => (8, &[name]),
+            Op::GetGlobalMut(name) => (9, &[name]),
+            Op::DefineGlobal(name, declaration) => (10, &[name, declaration_code(declaration)]),
+            Op::Fresh(first, count) => (11, &[first, count]),
+            Op::Find(name, place) => {
+                self.u8(12);
+                self.u32(name);
+                self.place(place);
+                return;
+            }
+            Op::Fail(fault) => (13, &[fault]),
+            Op::Math(math) => (14, &[math_code(math)]),
+            Op::Compare(compare) => (15, &[compare_code(compare)]),
+            Op::Neg => (16, &[]),
+            Op::Not => (17, &[]),
+            Op::Truthy => (18, &[]),
+            Op::Jump(target) => (19, &[target]),
+            Op::JumpIfFalse(target) => (20, &[target]),
+            Op::CallNamed(name, argc) => (21, &[name, argc]),
+            Op::Guard(root, final_local) => (22, &[root, final_local as u32]),
+            Op::Writable(root) => (23, &[root]),
+            Op::Return => (24, &[]),
+            Op::List(count) => (25, &[count]),
+            Op::Map(count) => (26, &[count]),
+            Op::Key => (27, &[]),
+            Op::Concat(count) => (28, &[count]),
+            Op::Index(target) => (29, &[target]),
+            Op::SetIndex(target) => (30, &[target]),
+            Op::Sliceable(target) => (31, &[target]),
+            Op::Slice(target) => (32, &[target]),
+            Op::GetField(field, target) => (33, &[field, target]),
+            Op::SetField(field, target) => (34, &[field, target]),
+            Op::CheckStruct(literal) => (35, &[literal]),
+            Op::MakeStruct(literal) => (36, &[literal]),
+            Op::Struct(def) => (37, &[def]),
+            Op::Enum(def) => (38, &[def]),
+            Op::Closure(proto) => (39, &[proto]),
+            Op::ExpectInt => (40, &[]),
+            Op::Iter(two) => (41, &[two as u32]),
+            Op::ForNext(state, exit) => (42, &[state, exit]),
+            Op::RangeNext(state, exit) => (43, &[state, exit]),
+            Op::Match(table, subject) => (44, &[table, subject]),
+            Op::Call(argc) => (45, &[argc]),
+        };
+        self.u8(code);
+        for &operand in operands {
+            self.u32(operand);
+        }
+    }
+
+    fn chunk(&mut self, chunk: &Chunk) {
+        self.len(chunk.code.len());
+        for &op in &chunk.code {
+            self.op(op);
+        }
+        for &line in &chunk.lines {
+            self.u32(line);
+        }
+        self.len(chunk.spans.len());
+        for span in &chunk.spans {
+            self.span(span);
+        }
+        self.len(chunk.constants.len());
+        for value in &chunk.constants {
+            self.value(value);
+        }
+        self.strs(&chunk.names);
+        self.len(chunk.faults.len());
+        for fault in &chunk.faults {
+            self.u8(fault.kind as u8);
+            self.str(&fault.message);
+        }
+        self.len(chunk.protos.len());
+        for proto in &chunk.protos {
+            self.proto(proto);
+        }
+        self.len(chunk.structs.len());
+        for (name, fields) in &chunk.structs {
+            self.str(name);
+            self.strs(fields);
+        }
+        self.len(chunk.enums.len());
+        for (name, variants) in &chunk.enums {
+            self.str(name);
+            self.len(variants.len());
+            for (variant, fields) in variants {
+                self.str(variant);
+                self.strs(fields);
+            }
+        }
+        self.len(chunk.literals.len());
+        for literal in &chunk.literals {
+            self.str(&literal.name);
+            self.len(literal.fields.len());
+            for (field, span) in &literal.fields {
+                self.str(field);
+                self.u32(*span);
+            }
+        }
+        self.len(chunk.matches.len());
+        for table in &chunk.matches {
+            self.len(table.lookups.len());
+            for (name, place) in &table.lookups {
+                self.str(name);
+                self.place(*place);
+            }
+            self.len(table.arms.len());
+            for arm in &table.arms {
+                self.pattern(&arm.pattern);
+                self.strs(&arm.names);
+                self.u32(arm.target);
+            }
+        }
+    }
+
+    fn proto(&mut self, proto: &Proto) {
+        self.bool(proto.name.is_some());
+        if let Some(name) = &proto.name {
+            self.str(name);
+        }
+        self.u32(proto.arity);
+        self.u32(proto.slots);
+        self.len(proto.captures.len());
+        for capture in &proto.captures {
+            self.str(&capture.name);
+            self.bool(capture.local);
+            self.u32(capture.index);
+        }
+        self.chunk(&proto.chunk);
+    }
+}
+
+const MATH: [MathToken; 11] = [
+    MathToken::Plus,
+    MathToken::Minus,
+    MathToken::Divide,
+    MathToken::Multiply,
+    MathToken::PlusEq,
+    MathToken::MinusEq,
+    MathToken::MultiplyEq,
+    MathToken::DivideEq,
+    MathToken::IntDivide,
+    MathToken::Modulo,
+    MathToken::Power,
+];
+
+const COMPARE: [CompareToken; 6] = [
+    CompareToken::Equal,
+    CompareToken::NotEqual,
+    CompareToken::Less,
+    CompareToken::LessEq,
+    CompareToken::Greater,
+    CompareToken::GreaterEq,
+];
+
+// `final` and `let` come first, as they were once written as a flag
+const DECLARATIONS: [Declaration; 6] = [
+    Declaration::Final,
+    Declaration::Let,
+    Declaration::Function,
+    Declaration::Struct,
+    Declaration::Enum,
+    Declaration::Variant,
+];
+
+fn declaration_code(declaration: Declaration) -> u32 {
+    let position = DECLARATIONS.iter().position(|&known| known == declaration);
+    position.expect("every declaration is listed") as u32
+}
+
+fn math_code(math: MathToken) -> u32 {
+    MATH.iter()
+        .position(|&known| known == math)
+        .expect("every token is listed") as u32
+}
+
+fn compare_code(compare: CompareToken) -> u32 {
+    let position = COMPARE.iter().position(|&known| known == compare);
+    position.expect("every token is listed") as u32
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+    // Every span in the file points into this
+    source: Rc<Source>,
+}
+
+type Read<T> = Result<T, String>;
+
+fn corrupt<T>() -> Read<T> {
+    Err("file is corrupted".to_string())
+}
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> Read<&[u8]> {
+        let end = self
+            .at
+            .checked_add(n)
+            .filter(|&end| end <= self.bytes.len());
+        let Some(end) = end else {
+            return corrupt();
+        };
+        let taken = &self.bytes[self.at..end];
+        self.at = end;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Read<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Read<u32> {
+        let bytes = self.take(4)?.try_into().expect("4 bytes");
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Read<u64> {
+        let bytes = self.take(8)?.try_into().expect("8 bytes");
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn len(&mut self) -> Read<usize> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn bool(&mut self) -> Read<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => corrupt(),
+        }
+    }
+
+    fn str(&mut self) -> Read<String> {
+        let len = self.len()?;
+        let bytes = self.take(len)?.to_vec();
+        String::from_utf8(bytes).or_else(|_| corrupt())
+    }
+
+    fn strs(&mut self) -> Read<Vec<String>> {
+        (0..self.len()?).map(|_| self.str()).collect()
+    }
+
+    fn span(&mut self) -> Read<Span> {
+        let (start, end, line, column) = (self.len()?, self.len()?, self.len()?, self.len()?);
+        Ok(Span::new(self.source.clone(), start, end, line, column))
+    }
+
+    fn value(&mut self) -> Read<Memory> {
+        Ok(match self.u8()? {
+            0 => Memory::Nil,
+            1 => Memory::Int(self.u64()? as i64),
+            2 => Memory::Float(f64::from_bits(self.u64()?)),
+            3 => Memory::String(self.str()?),
+            4 => Memory::Bool(self.bool()?),
+            _ => return corrupt(),
+        })
+    }
+
+    fn place(&mut self) -> Read<Place> {
+        Ok(match self.u8()? {
+            0 => Place::Local(self.u32()?),
+            1 => Place::Upvalue(self.u32()?),
+            2 => Place::Global,
+            _ => return corrupt(),
+        })
+    }
+
+    fn pattern(&mut self) -> Read<Spanned<Pattern>> {
+        let span = self.span()?;
+        let pattern = match self.u8()? {
+            0 => Pattern::Wildcard,
+            1 => Pattern::Binding(self.str()?),
+            2 => Pattern::Literal(match self.value()? {
+                Memory::Int(n) => ASTNode::Int(n),
+                Memory::Float(n) => ASTNode::Float(n),
+                Memory::String(s) => ASTNode::String(s),
+                Memory::Bool(b) => ASTNode::Bool(b),
+                _ => ASTNode::Nil,
+            }),
+            3 => {
+                let name = self.str()?;
+                let fields = (0..self.len()?)
+                    .map(|_| self.pattern())
+                    .collect::<Read<_>>()?;
+                Pattern::Variant(name, fields)
+            }
+            _ => return corrupt(),
+        };
+        Ok(Spanned::new(pattern, span))
+    }
+
+    fn op(&mut self) -> Read<Op> {
+        let code = self.u8()?;
+        let op = match code {
+            1 => Op::Nil,
+            2 => Op::Pop,
+            12 => Op::Find(self.u32()?, self.place()?),
+            16 => Op::Neg,
+            17 => Op::Not,
+            18 => Op::Truthy,
+            24 => Op::Return,
+            27 => Op::Key,
+            40 => Op::ExpectInt,
+            14 => match MATH.get(self.len()?) {
+                Some(&math) => Op::Math(math),
+                None => return corrupt(),
+            },
+            15 => match COMPARE.get(self.len()?) {
+                Some(&compare) => Op::Compare(compare),
+                None => return corrupt(),
+            },
+            // Ops with one plain operand
+            0 | 3..=9 | 13 | 19 | 20 | 23 | 25 | 26 | 28..=32 | 35..=39 | 41 | 45 => {
+                let n = self.u32()?;
+                match code {
+                    0 => Op::Const(n),
+                    3 => Op::GetLocal(n),
+                    4 => Op::SetLocal(n),
+                    5 => Op::GetUpvalue(n),
+                    6 => Op::SetUpvalue(n),
+                    7 => Op::GetGlobal(n),
+                    8 => Op::SetGlobal(n),
+                    9 => Op::GetGlobalMut(n),
+                    13 => Op::Fail(n),
+                    19 => Op::Jump(n),
+                    20 => Op::JumpIfFalse(n),
+                    23 => Op::Writable(n),
+                    25 => Op::List(n),
+                    26 => Op::Map(n),
+                    28 => Op::Concat(n),
+                    29 => Op::Index(n),
+                    30 => Op::SetIndex(n),
+                    31 => Op::Sliceable(n),
+                    32 => Op::Slice(n),
+                    35 => Op::CheckStruct(n),
+                    36 => Op::MakeStruct(n),
+                    37 => Op::Struct(n),
+                    38 => Op::Enum(n),
+                    39 => Op::Closure(n),
+                    45 => Op::Call(n),
+                    _ => Op::Iter(n != 0),
+                }
+            }
+            // Ops with two
+            10 | 11 | 21 | 22 | 33 | 34 | 42..=44 => {
+                let (a, b) = (self.u32()?, self.u32()?);
+                match code {
+                    10 => match DECLARATIONS.get(b as usize) {
+                        Some(&declaration) => Op::DefineGlobal(a, declaration),
+                        None => return corrupt(),
+                    },
+                    11 => Op::Fresh(a, b),
+                    21 => Op::CallNamed(a, b),
+                    22 => Op::Guard(a, b != 0),
+                    33 => Op::GetField(a, b),
+                    34 => Op::SetField(a, b),
+                    42 => Op::ForNext(a, b),
+                    43 => Op::RangeNext(a, b),
+                    _ => Op::Match(a, b),
+                }
+            }
+            _ => return corrupt(),
+        };
+        Ok(op)
+    }
+
+    fn chunk(&mut self) -> Read<Chunk> {
+        let mut chunk = Chunk::default();
+        let len = self.len()?;
+        chunk.code = (0..len).map(|_| self.op()).collect::<Read<_>>()?;
+        chunk.lines = (0..len).map(|_| self.u32()).collect::<Read<_>>()?;
+        chunk.spans = (0..self.len()?).map(|_| self.span()).collect::<Read<_>>()?;
+        chunk.constants = (0..self.len()?)
+            .map(|_| self.value())
+            .collect::<Read<_>>()?;
+        chunk.names = self.strs()?;
+        for _ in 0..self.len()? {
+            let kind = match self.u8()? {
+                0 => FaultKind::Type,
+                1 => FaultKind::Mutability,
+                2 => FaultKind::Runtime,
+                _ => return corrupt(),
+            };
+            let message = self.str()?;
+            chunk.faults.push(Fault { kind, message });
+        }
+        for _ in 0..self.len()? {
+            chunk.protos.push(Rc::new(self.proto()?));
+        }
+        for _ in 0..self.len()? {
+            chunk.structs.push((self.str()?, self.strs()?));
+        }
+        for _ in 0..self.len()? {
+            let name = self.str()?;
+            let variants = (0..self.len()?)
+                .map(|_| Ok((self.str()?, self.strs()?)))
+                .collect::<Read<_>>()?;
+            chunk.enums.push((name, variants));
+        }
+        for _ in 0..self.len()? {
+            let name = self.str()?;
+            let fields = (0..self.len()?)
+                .map(|_| Ok((self.str()?, self.u32()?)))
+                .collect::<Read<_>>()?;
+            chunk.literals.push(StructLit { name, fields });
+        }
+        for _ in 0..self.len()? {
+            let lookups = (0..self.len()?)
+                .map(|_| Ok((self.str()?, self.place()?)))
+                .collect::<Read<_>>()?;
+            let arms = (0..self.len()?)
+                .map(|_| {
+                    Ok(MatchArm {
+                        pattern: self.pattern()?,
+                        names: self.strs()?,
+                        target: self.u32()?,
+                    })
+                })
+                .collect::<Read<_>>()?;
+            chunk.matches.push(MatchTable { lookups, arms });
+        }
+        check(&chunk)?;
+        Ok(chunk)
+    }
+
+    fn proto(&mut self) -> Read<Proto> {
+        let name = if self.bool()? {
+            Some(self.str()?)
+        } else {
+            None
+        };
+        let arity = self.u32()?;
+        let slots = self.u32()?;
+        let captures: Vec<_> = (0..self.len()?)
+            .map(|_| {
+                Ok(Capture {
+                    name: self.str()?,
+                    local: self.bool()?,
+                    index: self.u32()?,
+                })
+            })
+            .collect::<Read<_>>()?;
+        let chunk = self.chunk()?;
+        let slot_ok = |slot: u32| slot < slots;
+        let valid = chunk.code.iter().all(|op| match *op {
+            Op::GetLocal(slot) | Op::SetLocal(slot) => slot_ok(slot),
+            Op::Fresh(first, count) => first.checked_add(count).is_some_and(|end| end <= slots),
+            Op::ForNext(state, _) => state.checked_add(3).is_some_and(|end| end <= slots),
+            Op::RangeNext(state, _) => state.checked_add(2).is_some_and(|end| end <= slots),
+            Op::GetUpvalue(i) | Op::SetUpvalue(i) => (i as usize) < captures.len(),
+            Op::Find(_, Place::Local(slot)) => slot_ok(slot),
+            Op::Find(_, Place::Upvalue(i)) => (i as usize) < captures.len(),
+            _ => true,
+        });
+        if !valid || arity > slots {
+            return corrupt();
+        }
+        Ok(Proto {
+            name,
+            arity,
+            slots,
+            captures,
+            chunk,
+        })
+    }
+}
+
+// Rejects a chunk whose ops refer past the end of its tables, so the VM can index freely
+fn check(chunk: &Chunk) -> Read<()> {
+    let within = |index: u32, len: usize| (index as usize) < len;
+    let spans = chunk.spans.len();
+    let code = chunk.code.len();
+    let valid = chunk.code.last() == Some(&Op::Return)
+        && chunk.lines.iter().all(|&line| within(line, spans))
+        && chunk.code.iter().all(|op| match *op {
+            Op::Const(i) => within(i, chunk.constants.len()),
+            Op::GetGlobal(n)
+            | Op::SetGlobal(n)
+            | Op::GetGlobalMut(n)
+            | Op::DefineGlobal(n, _)
+            | Op::Find(n, _)
+            | Op::CallNamed(n, _)
+            | Op::Guard(n, _)
+            | Op::Writable(n) => within(n, chunk.names.len()),
+            Op::Fail(i) => within(i, chunk.faults.len()),
+            Op::Jump(to) | Op::JumpIfFalse(to) | Op::ForNext(_, to) | Op::RangeNext(_, to) => {
+                within(to, code)
+            }
+            Op::Index(s) | Op::SetIndex(s) | Op::Sliceable(s) | Op::Slice(s) => within(s, spans),
+            Op::GetField(n, s) | Op::SetField(n, s) => {
+                within(n, chunk.names.len()) && within(s, spans)
+            }
+            Op::CheckStruct(i) | Op::MakeStruct(i) => within(i, chunk.literals.len()),
+            Op::Struct(i) => within(i, chunk.structs.len()),
+            Op::Enum(i) => within(i, chunk.enums.len()),
+            Op::Closure(i) => within(i, chunk.protos.len()),
+            Op::Match(i, s) => {
+                within(i, chunk.matches.len())
+                    && within(s, spans)
+                    && chunk.matches[i as usize]
+                        .arms
+                        .iter()
+                        .all(|arm| within(arm.target, code))
+            }
+            _ => true,
+        });
+    if valid {
+        Ok(())
+    } else {
+        corrupt()
+    }
+}
@@ -0,0 +1,2320 @@
+/*
+ * The runtime every C file written by `crystal emit-c` includes: the Memory value model,
+ * the operators and the builtins, behaving as the interpreter does, errors included.
+ *
+ * Memory is reclaimed by a conservative mark and sweep collector, so the generated code
+ * needs no bookkeeping: anything reachable from the C stack, the registers or a global
+ * is kept, and every other block is freed.
+ */
+#ifndef CRYSTAL_H
+#define CRYSTAL_H
+
+#include <errno.h>
+#include <inttypes.h>
+#include <math.h>
+#include <setjmp.h>
+#include <stdarg.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#ifndef CRY_MAX_CALL_DEPTH
+#define CRY_MAX_CALL_DEPTH 10000
+#endif
+
+typedef enum {
+    CRY_NIL,
+    CRY_INT,
+    CRY_FLOAT,
+    CRY_BOOL,
+    CRY_STRING,
+    CRY_LIST,
+    CRY_MAP,
+    CRY_FN,
+    CRY_STRUCT_TYPE,
+    CRY_STRUCT,
+    CRY_ENUM,
+    CRY_CONSTRUCTOR,
+    CRY_VARIANT
+} CryTag;
+
+/* Strings are immutable UTF-8, always followed by a NUL that `len` doesn't count */
+typedef struct {
+    size_t len;
+    char *bytes;
+} CryString;
+
+typedef struct CryList CryList;
+typedef struct CryMap CryMap;
+typedef struct CryFn CryFn;
+typedef struct CryCell CryCell;
+typedef struct CryShape CryShape;
+typedef struct CryEnum CryEnum;
+typedef struct CryInstance CryInstance;
+typedef struct CryVariant CryVariant;
+
+typedef struct {
+    CryTag tag;
+    union {
+        int64_t i;
+        double f;
+        int b;
+        CryString *s;
+        CryList *list;
+        CryMap *map;
+        CryFn *fn;
+        const CryShape *shape;
+        CryInstance *instance;
+        CryEnum *def;
+        CryVariant *variant;
+    } as;
+} CryValue;
+
+/* Lists, maps and functions are shared by reference, like their Memory counterparts */
+struct CryList {
+    size_t len, cap;
+    CryValue *items;
+};
+
+/* Entries stay in insertion order, as a Dict's do */
+struct CryMap {
+    size_t len, cap;
+    CryString **keys;
+    CryValue *values;
+};
+
+/* A variable captured by a function; unset until its declaration runs */
+struct CryCell {
+    int set;
+    CryValue value;
+};
+
+typedef CryValue (*CryCode)(CryCell **env, CryValue *args);
+typedef CryValue (*CryBuiltin)(int argc, CryValue *args, int site);
+
+struct CryFn {
+    /* How the function prints, `<fn name>`, `<fn>` or `<builtin name>` */
+    const char *name;
+    int arity;
+    CryCode code;
+    CryCell **env;
+    /* Set instead of code for a builtin used as a value, which checks its own arguments */
+    CryBuiltin builtin;
+};
+
+/* A `struct` or `enum` declaration as written: its name, and its fields, or its variants
+ * each with their own fields */
+struct CryShape {
+    const char *name;
+    int count;
+    const CryShape *parts;
+};
+
+/* One run of an `enum` declaration; its variants only equal variants of the same run */
+struct CryEnum {
+    const CryShape *shape;
+};
+
+/* A struct value; fields keep their declaration order */
+struct CryInstance {
+    const CryShape *shape;
+    CryValue *values;
+};
+
+/* A variant value, or a variant constructor, which has no values */
+struct CryVariant {
+    CryEnum *def;
+    int index;
+    CryValue *values;
+};
+
+/* A top-level binding, looked up as the program runs like the interpreter's globals */
+enum {
+    CRY_UNBOUND,
+    CRY_LET,
+    CRY_FINAL,
+    CRY_FUNCTION,
+    CRY_STRUCT_DECL,
+    CRY_ENUM_DECL,
+    /* The variants an `enum` declaration binds */
+    CRY_VARIANT_DECL
+};
+
+typedef struct {
+    int state;
+    CryValue value;
+} CryGlobal;
+
+/* What a `for` over a list or map binds on each pass */
+typedef struct {
+    size_t len;
+    CryValue *first;
+    CryValue *second;
+} CryPasses;
+
+/* ---- errors ---- */
+
+enum { CRY_NAME_ERROR, CRY_TYPE_ERROR, CRY_MUTABILITY_ERROR, CRY_RUNTIME_ERROR };
+
+/* The source excerpt of every place an error can be raised, indexed by site */
+static const char *const *cry_sites;
+
+/* Reports an error the way `crystal run` does, without colors, and exits with its code */
+static void cry_fail(int kind, int site, const char *format, ...) {
+    static const char *const titles[] = {
+        "CRY.NameError", "CRY.TypeError", "CRY.MutabilityError", "CRY.RuntimeError"};
+    static const int codes[] = {4, 5, 6, 7};
+    va_list args;
+    fflush(stdout);
+    fprintf(stderr, "%s: ", titles[kind]);
+    va_start(args, format);
+    vfprintf(stderr, format, args);
+    va_end(args);
+    fprintf(stderr, "\n%s\n\n", cry_sites[site]);
+    exit(codes[kind]);
+}
+
+/* ---- memory ---- */
+
+/* Sits before every block; the union keeps the block as aligned as malloc's */
+typedef union {
+    struct {
+        size_t size;
+        /* Where the block is in cry_heap.blocks */
+        size_t index;
+        /* Atomic blocks hold no pointers, so they aren't scanned */
+        int atomic, marked;
+    } block;
+    long double align;
+} CryHeader;
+
+/* Collect once this much has been allocated, or as much as the last collection kept */
+#define CRY_GC_STEP ((size_t)8 << 20)
+
+static struct {
+    CryHeader **blocks;
+    size_t len, cap;
+    size_t allocated, live;
+    /* The blocks marked but not yet scanned */
+    CryHeader **pending;
+    size_t pending_len, pending_cap;
+    /* The stack frame the program runs from, and the globals of the generated code */
+    char *bottom;
+    CryGlobal *const *globals;
+    size_t global_count;
+} cry_heap;
+
+static void cry_out_of_memory(void) {
+    fflush(stdout);
+    fputs("CRYSTAL.Error: Out of memory.\n", stderr);
+    exit(1);
+}
+
+static int cry_by_address(const void *a, const void *b) {
+    uintptr_t x = (uintptr_t)*(CryHeader *const *)a, y = (uintptr_t)*(CryHeader *const *)b;
+    return x < y ? -1 : x > y;
+}
+
+/* The block a word points into, end included, if it is one; the blocks are sorted */
+static CryHeader *cry_block_at(uintptr_t word) {
+    size_t low = 0, high = cry_heap.len;
+    while (low < high) {
+        size_t middle = low + (high - low) / 2;
+        CryHeader *header = cry_heap.blocks[middle];
+        uintptr_t start = (uintptr_t)(header + 1);
+        if (word < start) {
+            high = middle;
+        } else if (word > start + header->block.size) {
+            low = middle + 1;
+        } else {
+            return header;
+        }
+    }
+    return NULL;
+}
+
+/* Marks every block a word in from..to may point to */
+static void cry_scan(const char *from, const char *to) {
+    const char *at = (const char *)(((uintptr_t)from + sizeof(void *) - 1) &
+                                    ~(uintptr_t)(sizeof(void *) - 1));
+    for (; at + sizeof(void *) <= to; at += sizeof(void *)) {
+        uintptr_t word;
+        CryHeader *header;
+        memcpy(&word, at, sizeof word);
+        header = cry_block_at(word);
+        if (!header || header->block.marked) {
+            continue;
+        }
+        header->block.marked = 1;
+        if (header->block.atomic) {
+            continue;
+        }
+        if (cry_heap.pending_len == cry_heap.pending_cap) {
+            cry_heap.pending_cap = cry_heap.pending_cap ? cry_heap.pending_cap * 2 : 256;
+            cry_heap.pending = realloc(cry_heap.pending,
+                                       cry_heap.pending_cap * sizeof *cry_heap.pending);
+            if (!cry_heap.pending) {
+                cry_out_of_memory();
+            }
+        }
+        cry_heap.pending[cry_heap.pending_len++] = header;
+    }
+}
+
+static void cry_scan_stack(void) {
+    char top;
+    if (&top < cry_heap.bottom) {
+        cry_scan(&top, cry_heap.bottom);
+    } else {
+        cry_scan(cry_heap.bottom, &top);
+    }
+}
+
+/* Called through a volatile pointer so it can't be inlined, and its frame is below
+ * cry_collect's, which holds the saved registers */
+static void (*volatile cry_stack_scanner)(void) = cry_scan_stack;
+
+static void cry_collect(void) {
+    jmp_buf registers;
+    size_t i, kept = 0;
+    if (!cry_heap.bottom) {
+        return;
+    }
+#if defined(__GNUC__)
+    /* Spills every callee-saved register, some of which setjmp stores mangled */
+    __builtin_unwind_init();
+#endif
+    setjmp(registers);
+    qsort(cry_heap.blocks, cry_heap.len, sizeof *cry_heap.blocks, cry_by_address);
+    cry_stack_scanner();
+    for (i = 0; i < cry_heap.global_count; i++) {
+        const CryGlobal *global = cry_heap.globals[i];
+        cry_scan((const char *)global, (const char *)(global + 1));
+    }
+    while (cry_heap.pending_len) {
+        CryHeader *header = cry_heap.pending[--cry_heap.pending_len];
+        const char *start = (const char *)(header + 1);
+        cry_scan(start, start + header->block.size);
+    }
+    cry_heap.live = 0;
+    for (i = 0; i < cry_heap.len; i++) {
+        CryHeader *header = cry_heap.blocks[i];
+        if (!header->block.marked) {
+            free(header);
+            continue;
+        }
+        header->block.marked = 0;
+        header->block.index = kept;
+        cry_heap.live += header->block.size;
+        cry_heap.blocks[kept++] = header;
+    }
+    cry_heap.len = kept;
+    cry_heap.allocated = 0;
+}
+
+/* Collects first if enough has been allocated since the last collection */
+static void cry_maybe_collect(size_t size) {
+    cry_heap.allocated += size;
+    if (cry_heap.allocated > CRY_GC_STEP && cry_heap.allocated > cry_heap.live) {
+        cry_collect();
+    }
+}
+
+static void *cry_alloc_block(size_t size, int atomic) {
+    CryHeader *header;
+    cry_maybe_collect(size);
+    header = malloc(sizeof *header + size);
+    if (!header) {
+        cry_collect();
+        header = malloc(sizeof *header + size);
+    }
+    if (cry_heap.len == cry_heap.cap) {
+        CryHeader **blocks;
+        cry_heap.cap = cry_heap.cap ? cry_heap.cap * 2 : 1024;
+        blocks = realloc(cry_heap.blocks, cry_heap.cap * sizeof *cry_heap.blocks);
+        if (!blocks) {
+            free(header);
+            header = NULL;
+        } else {
+            cry_heap.blocks = blocks;
+        }
+    }
+    if (!header) {
+        cry_out_of_memory();
+    }
+    header->block.size = size;
+    header->block.index = cry_heap.len;
+    header->block.atomic = atomic;
+    header->block.marked = 0;
+    cry_heap.blocks[cry_heap.len++] = header;
+    return header + 1;
+}
+
+static void *cry_alloc(size_t size) {
+    return cry_alloc_block(size, 0);
+}
+
+/* For text, which holds no pointers */
+static void *cry_alloc_atomic(size_t size) {
+    return cry_alloc_block(size, 1);
+}
+
+static void *cry_grow(void *memory, size_t size) {
+    CryHeader *header, *grown;
+    if (!memory) {
+        return cry_alloc(size);
+    }
+    header = (CryHeader *)memory - 1;
+    if (size > header->block.size) {
+        cry_maybe_collect(size - header->block.size);
+    }
+    grown = realloc(header, sizeof *header + size);
+    if (!grown) {
+        cry_collect();
+        grown = realloc(header, sizeof *header + size);
+        if (!grown) {
+            cry_out_of_memory();
+        }
+    }
+    grown->block.size = size;
+    cry_heap.blocks[grown->block.index] = grown;
+    return grown + 1;
+}
+
+/* Runs the program's top level, with the stack above this frame scanned for values */
+static void cry_run(CryCode script, CryGlobal *const *globals, size_t count) {
+    char bottom;
+    cry_heap.bottom = &bottom;
+    cry_heap.globals = globals;
+    cry_heap.global_count = count;
+    script(NULL, NULL);
+}
+
+/* ---- values ---- */
+
+static CryValue cry_nil(void) {
+    CryValue value;
+    value.tag = CRY_NIL;
+    value.as.i = 0;
+    return value;
+}
+
+static CryValue cry_int(int64_t i) {
+    CryValue value;
+    value.tag = CRY_INT;
+    value.as.i = i;
+    return value;
+}
+
+static CryValue cry_float(double f) {
+    CryValue value;
+    value.tag = CRY_FLOAT;
+    value.as.f = f;
+    return value;
+}
+
+static CryValue cry_bool(int b) {
+    CryValue value;
+    value.tag = CRY_BOOL;
+    value.as.b = b != 0;
+    return value;
+}
+
+static CryString *cry_string_of(const char *bytes, size_t len) {
+    CryString *s = cry_alloc(sizeof *s);
+    s->bytes = cry_alloc_atomic(len + 1);
+    memcpy(s->bytes, bytes, len);
+    s->bytes[len] = '\0';
+    s->len = len;
+    return s;
+}
+
+static CryValue cry_string(CryString *s) {
+    CryValue value;
+    value.tag = CRY_STRING;
+    value.as.s = s;
+    return value;
+}
+
+static CryValue cry_str(const char *bytes, size_t len) {
+    return cry_string(cry_string_of(bytes, len));
+}
+
+static CryList *cry_list_new(size_t cap) {
+    CryList *list = cry_alloc(sizeof *list);
+    list->len = 0;
+    list->cap = cap;
+    list->items = cry_alloc(cap * sizeof *list->items);
+    return list;
+}
+
+static void cry_list_push(CryList *list, CryValue item) {
+    if (list->len == list->cap) {
+        list->cap = list->cap ? list->cap * 2 : 4;
+        list->items = cry_grow(list->items, list->cap * sizeof *list->items);
+    }
+    list->items[list->len++] = item;
+}
+
+static CryValue cry_list_value(CryList *list) {
+    CryValue value;
+    value.tag = CRY_LIST;
+    value.as.list = list;
+    return value;
+}
+
+/* A list literal */
+static CryValue cry_list(size_t count, const CryValue *items) {
+    CryList *list = cry_list_new(count);
+    size_t i;
+    for (i = 0; i < count; i++) {
+        cry_list_push(list, items[i]);
+    }
+    return cry_list_value(list);
+}
+
+static CryMap *cry_map_new(void) {
+    CryMap *map = cry_alloc(sizeof *map);
+    map->len = 0;
+    map->cap = 0;
+    map->keys = NULL;
+    map->values = NULL;
+    return map;
+}
+
+static CryValue cry_map_value(CryMap *map) {
+    CryValue value;
+    value.tag = CRY_MAP;
+    value.as.map = map;
+    return value;
+}
+
+static int cry_same_string(const CryString *a, const CryString *b) {
+    return a->len == b->len && memcmp(a->bytes, b->bytes, a->len) == 0;
+}
+
+static CryValue *cry_map_get(CryMap *map, const CryString *key) {
+    size_t i;
+    for (i = 0; i < map->len; i++) {
+        if (cry_same_string(map->keys[i], key)) {
+            return &map->values[i];
+        }
+    }
+    return NULL;
+}
+
+/* Updating a key keeps its original place in the order */
+static void cry_map_insert(CryMap *map, CryString *key, CryValue value) {
+    CryValue *found = cry_map_get(map, key);
+    if (found) {
+        *found = value;
+        return;
+    }
+    if (map->len == map->cap) {
+        map->cap = map->cap ? map->cap * 2 : 4;
+        map->keys = cry_grow(map->keys, map->cap * sizeof *map->keys);
+        map->values = cry_grow(map->values, map->cap * sizeof *map->values);
+    }
+    map->keys[map->len] = key;
+    map->values[map->len] = value;
+    map->len++;
+}
+
+static CryCell *cry_cell(void) {
+    CryCell *cell = cry_alloc(sizeof *cell);
+    cell->set = 0;
+    cell->value = cry_nil();
+    return cell;
+}
+
+static CryCell *cry_cell_of(CryValue value) {
+    CryCell *cell = cry_cell();
+    cell->set = 1;
+    cell->value = value;
+    return cell;
+}
+
+/* A function value; every evaluation of a definition makes a new one */
+static CryValue cry_closure(const char *name, int arity, CryCode code, CryCell **env,
+                            size_t captures) {
+    CryFn *fn = cry_alloc(sizeof *fn);
+    CryValue value;
+    fn->name = name;
+    fn->arity = arity;
+    fn->code = code;
+    fn->env = NULL;
+    fn->builtin = NULL;
+    if (captures) {
+        fn->env = cry_alloc(captures * sizeof *fn->env);
+        memcpy(fn->env, env, captures * sizeof *fn->env);
+    }
+    value.tag = CRY_FN;
+    value.as.fn = fn;
+    return value;
+}
+
+static CryValue cry_builtin_value(const char *name, CryBuiltin builtin) {
+    CryFn *fn = cry_alloc(sizeof *fn);
+    CryValue value;
+    fn->name = name;
+    fn->arity = 0;
+    fn->code = NULL;
+    fn->env = NULL;
+    fn->builtin = builtin;
+    value.tag = CRY_FN;
+    value.as.fn = fn;
+    return value;
+}
+
+static CryValue cry_struct_type(const CryShape *shape) {
+    CryValue value;
+    value.tag = CRY_STRUCT_TYPE;
+    value.as.shape = shape;
+    return value;
+}
+
+/* An enum; every run of its declaration makes a new one */
+static CryValue cry_enum(const CryShape *shape) {
+    CryEnum *def = cry_alloc(sizeof *def);
+    CryValue value;
+    def->shape = shape;
+    value.tag = CRY_ENUM;
+    value.as.def = def;
+    return value;
+}
+
+static int cry_variant_arity(const CryVariant *variant) {
+    return variant->def->shape->parts[variant->index].count;
+}
+
+static const char *cry_variant_name(const CryVariant *variant) {
+    return variant->def->shape->parts[variant->index].name;
+}
+
+static CryValue cry_variant_value(CryEnum *def, int index, CryValue *values) {
+    CryVariant *variant = cry_alloc(sizeof *variant);
+    CryValue value;
+    variant->def = def;
+    variant->index = index;
+    variant->values = values;
+    value.tag = CRY_VARIANT;
+    value.as.variant = variant;
+    return value;
+}
+
+/* What an `enum` declaration binds to a variant's name: the value itself if the variant
+ * has no fields, else its constructor */
+static CryValue cry_variant(CryValue def, int index) {
+    CryValue value = cry_variant_value(def.as.def, index, NULL);
+    if (cry_variant_arity(value.as.variant)) {
+        value.tag = CRY_CONSTRUCTOR;
+    }
+    return value;
+}
+
+static const char *cry_type_name(CryValue value) {
+    switch (value.tag) {
+    case CRY_INT:
+        return "int";
+    case CRY_FLOAT:
+        return "float";
+    case CRY_STRING:
+        return "string";
+    case CRY_BOOL:
+        return "bool";
+    case CRY_FN:
+        return "function";
+    case CRY_LIST:
+        return "list";
+    case CRY_MAP:
+        return "map";
+    case CRY_STRUCT_TYPE:
+        return "struct type";
+    case CRY_STRUCT:
+        return "struct";
+    case CRY_ENUM:
+        return "enum";
+    case CRY_CONSTRUCTOR:
+        return "variant constructor";
+    case CRY_VARIANT:
+        return "variant";
+    default:
+        return "nil";
+    }
+}
+
+/* Only `false` and `nil` count as false in conditions and logic */
+static int cry_truthy(CryValue value) {
+    return !(value.tag == CRY_NIL || (value.tag == CRY_BOOL && !value.as.b));
+}
+
+/* ---- text ---- */
+
+/* A growing byte buffer, for building strings and messages */
+typedef struct {
+    size_t len, cap;
+    char *bytes;
+} CryBuffer;
+
+static void cry_buffer_add(CryBuffer *buffer, const char *bytes, size_t len) {
+    if (buffer->len + len + 1 > buffer->cap) {
+        buffer->cap = (buffer->len + len + 1) * 2;
+        buffer->bytes = cry_grow(buffer->bytes, buffer->cap);
+    }
+    memcpy(buffer->bytes + buffer->len, bytes, len);
+    buffer->len += len;
+    buffer->bytes[buffer->len] = '\0';
+}
+
+static void cry_buffer_text(CryBuffer *buffer, const char *text) {
+    cry_buffer_add(buffer, text, strlen(text));
+}
+
+/* NUL-terminated, so it can go straight into a message */
+static const char *cry_buffer_done(CryBuffer *buffer) {
+    if (!buffer->bytes) {
+        cry_buffer_add(buffer, "", 0);
+    }
+    return buffer->bytes;
+}
+
+/* Whether `count` digits with the point after the first, times 10^exponent, read back as x */
+static int cry_reads_back(const char *digits, int count, int exponent, double x) {
+    char text[40];
+    snprintf(text, sizeof text, "%c.%.*se%d", digits[0], count - 1, digits + 1, exponent);
+    return strtod(text, NULL) == x;
+}
+
+/* Writes a float as a Memory displays it: the fewest digits that read back as the same
+ * float, placed around the point, or in exponent form (`1e16`, `5e-324`) outside
+ * 1e-4..1e16. Like Rust, of two shortest forms it takes the one nearer the exact value,
+ * and the greater on a tie, so it can't just round with printf. */
+static void cry_buffer_float(CryBuffer *out, double x) {
+    /* A double has at most 767 significant digits, so this holds all of them */
+    char exact[800], text[40], digits[20], up[20];
+    int exponent, up_exponent, count, point, i, j, down_ok, up_ok;
+    char *e;
+    if (signbit(x)) {
+        cry_buffer_text(out, "-");
+        x = -x;
+    }
+    if (x == 0.0) {
+        cry_buffer_text(out, "0");
+        return;
+    }
+    snprintf(exact, sizeof exact, "%.766e", x);
+    e = strchr(exact, 'e');
+    exponent = atoi(e + 1);
+    /* Drop the point, so exact holds just the digits */
+    memmove(exact + 1, exact + 2, (size_t)(e - exact - 2));
+    exact[e - exact - 1] = '\0';
+    /* Seventeen digits always read back, so this stops there at the latest */
+    for (count = 1; count <= 17; count++) {
+        /* The digits cut short, and those plus one in the last place */
+        memcpy(digits, exact, (size_t)count);
+        memcpy(up, exact, (size_t)count);
+        up_exponent = exponent;
+        for (j = count - 1; j >= 0 && up[j] == '9'; j--) {
+            up[j] = '0';
+        }
+        if (j < 0) {
+            up[0] = '1';
+            up_exponent++;
+        } else {
+            up[j]++;
+        }
+        down_ok = cry_reads_back(digits, count, exponent, x);
+        up_ok = cry_reads_back(up, count, up_exponent, x);
+        if (up_ok && (!down_ok || exact[count] >= '5')) {
+            memcpy(digits, up, (size_t)count);
+            exponent = up_exponent;
+            break;
+        }
+        if (down_ok) {
+            break;
+        }
+    }
+    while (count > 1 && digits[count - 1] == '0') {
+        count--;
+    }
+    if (exponent < -4 || exponent >= 16) {
+        cry_buffer_add(out, digits, 1);
+        if (count > 1) {
+            cry_buffer_text(out, ".");
+            cry_buffer_add(out, digits + 1, (size_t)(count - 1));
+        }
+        snprintf(text, sizeof text, "e%d", exponent);
+        cry_buffer_text(out, text);
+        return;
+    }
+    point = exponent + 1;
+    if (point <= 0) {
+        cry_buffer_text(out, "0.");
+        for (i = 0; i < -point; i++) {
+            cry_buffer_text(out, "0");
+        }
+        cry_buffer_add(out, digits, (size_t)count);
+    } else if (point >= count) {
+        cry_buffer_add(out, digits, (size_t)count);
+        for (i = count; i < point; i++) {
+            cry_buffer_text(out, "0");
+        }
+    } else {
+        cry_buffer_add(out, digits, (size_t)point);
+        cry_buffer_text(out, ".");
+        cry_buffer_add(out, digits + point, (size_t)(count - point));
+    }
+}
+
+/* A string as Rust's `{:?}` writes it, in double quotes with escapes */
+static void cry_buffer_quoted(CryBuffer *out, const CryString *s) {
+    size_t i;
+    char escape[16];
+    cry_buffer_text(out, "\"");
+    for (i = 0; i < s->len; i++) {
+        unsigned char c = (unsigned char)s->bytes[i];
+        switch (c) {
+        case '\0':
+            cry_buffer_text(out, "\\0");
+            break;
+        case '\t':
+            cry_buffer_text(out, "\\t");
+            break;
+        case '\r':
+            cry_buffer_text(out, "\\r");
+            break;
+        case '\n':
+            cry_buffer_text(out, "\\n");
+            break;
+        case '"':
+            cry_buffer_text(out, "\\\"");
+            break;
+        case '\\':
+            cry_buffer_text(out, "\\\\");
+            break;
+        default:
+            if (c < 0x20 || c == 0x7f) {
+                snprintf(escape, sizeof escape, "\\u{%x}", c);
+                cry_buffer_text(out, escape);
+            } else {
+                cry_buffer_add(out, (const char *)&c, 1);
+            }
+        }
+    }
+    cry_buffer_text(out, "\"");
+}
+
+/* The lists, maps and structs being written, innermost first */
+typedef struct CrySeen {
+    const void *container;
+    const struct CrySeen *outer;
+} CrySeen;
+
+static int cry_seen(const CrySeen *seen, const void *container) {
+    for (; seen; seen = seen->outer) {
+        if (seen->container == container) {
+            return 1;
+        }
+    }
+    return 0;
+}
+
+/* A list, map or struct that holds itself is written as `[...]`, `{...}` or `Name { ... }`
+ * where it repeats */
+static void cry_buffer_shown(CryBuffer *out, CryValue value, int quoted, const CrySeen *seen) {
+    char number[32];
+    size_t i;
+    int field;
+    const CryShape *shape;
+    CrySeen inner;
+    switch (value.tag) {
+    case CRY_INT:
+        snprintf(number, sizeof number, "%" PRId64, value.as.i);
+        cry_buffer_text(out, number);
+        break;
+    case CRY_FLOAT:
+        /* Whole floats keep a `.0` so they can't be mistaken for ints */
+        if (value.as.f == trunc(value.as.f) && fabs(value.as.f) < 1e16) {
+            snprintf(number, sizeof number, "%.1f", value.as.f);
+            cry_buffer_text(out, number);
+        } else {
+            cry_buffer_float(out, value.as.f);
+        }
+        break;
+    case CRY_STRING:
+        if (quoted) {
+            cry_buffer_quoted(out, value.as.s);
+        } else {
+            cry_buffer_add(out, value.as.s->bytes, value.as.s->len);
+        }
+        break;
+    case CRY_BOOL:
+        cry_buffer_text(out, value.as.b ? "true" : "false");
+        break;
+    case CRY_FN:
+        cry_buffer_text(out, value.as.fn->name);
+        break;
+    case CRY_LIST:
+        if (cry_seen(seen, value.as.list)) {
+            cry_buffer_text(out, "[...]");
+            break;
+        }
+        inner.container = value.as.list;
+        inner.outer = seen;
+        cry_buffer_text(out, "[");
+        for (i = 0; i < value.as.list->len; i++) {
+            if (i) {
+                cry_buffer_text(out, ", ");
+            }
+            cry_buffer_shown(out, value.as.list->items[i], 1, &inner);
+        }
+        cry_buffer_text(out, "]");
+        break;
+    case CRY_MAP:
+        if (cry_seen(seen, value.as.map)) {
+            cry_buffer_text(out, "{...}");
+            break;
+        }
+        inner.container = value.as.map;
+        inner.outer = seen;
+        cry_buffer_text(out, "{");
+        for (i = 0; i < value.as.map->len; i++) {
+            if (i) {
+                cry_buffer_text(out, ", ");
+            }
+            cry_buffer_quoted(out, value.as.map->keys[i]);
+            cry_buffer_text(out, ": ");
+            cry_buffer_shown(out, value.as.map->values[i], 1, &inner);
+        }
+        cry_buffer_text(out, "}");
+        break;
+    case CRY_STRUCT_TYPE:
+        cry_buffer_text(out, "<struct ");
+        cry_buffer_text(out, value.as.shape->name);
+        cry_buffer_text(out, ">");
+        break;
+    case CRY_ENUM:
+        cry_buffer_text(out, "<enum ");
+        cry_buffer_text(out, value.as.def->shape->name);
+        cry_buffer_text(out, ">");
+        break;
+    case CRY_CONSTRUCTOR:
+        cry_buffer_text(out, "<variant ");
+        cry_buffer_text(out, cry_variant_name(value.as.variant));
+        cry_buffer_text(out, ">");
+        break;
+    case CRY_VARIANT:
+        cry_buffer_text(out, cry_variant_name(value.as.variant));
+        if (!cry_variant_arity(value.as.variant)) {
+            break;
+        }
+        cry_buffer_text(out, "(");
+        for (field = 0; field < cry_variant_arity(value.as.variant); field++) {
+            if (field) {
+                cry_buffer_text(out, ", ");
+            }
+            cry_buffer_shown(out, value.as.variant->values[field], 1, seen);
+        }
+        cry_buffer_text(out, ")");
+        break;
+    case CRY_STRUCT:
+        shape = value.as.instance->shape;
+        cry_buffer_text(out, shape->name);
+        if (cry_seen(seen, value.as.instance)) {
+            cry_buffer_text(out, " { ... }");
+            break;
+        }
+        inner.container = value.as.instance;
+        inner.outer = seen;
+        cry_buffer_text(out, " { ");
+        for (field = 0; field < shape->count; field++) {
+            if (field) {
+                cry_buffer_text(out, ", ");
+            }
+            cry_buffer_text(out, shape->parts[field].name);
+            cry_buffer_text(out, ": ");
+            cry_buffer_shown(out, value.as.instance->values[field], 1, &inner);
+        }
+        cry_buffer_text(out, " }");
+        break;
+    default:
+        cry_buffer_text(out, "nil");
+    }
+}
+
+static void cry_buffer_value(CryBuffer *out, CryValue value, int quoted) {
+    cry_buffer_shown(out, value, quoted, NULL);
+}
+
+/* How `print` shows a value */
+static CryString *cry_display(CryValue value) {
+    CryBuffer buffer = {0, 0, NULL};
+    cry_buffer_value(&buffer, value, 0);
+    cry_buffer_done(&buffer);
+    return cry_string_of(buffer.bytes, buffer.len);
+}
+
+/* Rust's `{}` of a float, for messages */
+static const char *cry_float_text(double x) {
+    CryBuffer buffer = {0, 0, NULL};
+    cry_buffer_float(&buffer, x);
+    return cry_buffer_done(&buffer);
+}
+
+/* A value as a list shows it, for messages */
+static const char *cry_repr_text(CryValue value) {
+    CryBuffer buffer = {0, 0, NULL};
+    cry_buffer_value(&buffer, value, 1);
+    return cry_buffer_done(&buffer);
+}
+
+/* Rust's `{:?}` of a string, for messages */
+static const char *cry_quoted_text(const CryString *s) {
+    CryBuffer buffer = {0, 0, NULL};
+    cry_buffer_quoted(&buffer, s);
+    return cry_buffer_done(&buffer);
+}
+
+/* "text ${expr} text" */
+static CryValue cry_concat(size_t count, const CryValue *parts) {
+    CryBuffer buffer = {0, 0, NULL};
+    size_t i;
+    for (i = 0; i < count; i++) {
+        cry_buffer_value(&buffer, parts[i], 0);
+    }
+    cry_buffer_done(&buffer);
+    return cry_str(buffer.bytes, buffer.len);
+}
+
+static size_t cry_char_count(const CryString *s) {
+    size_t i, count = 0;
+    for (i = 0; i < s->len; i++) {
+        if (((unsigned char)s->bytes[i] & 0xC0) != 0x80) {
+            count++;
+        }
+    }
+    return count;
+}
+
+/* Byte offset of the char at position `n`, or the length past the last one */
+static size_t cry_char_offset(const CryString *s, size_t n) {
+    size_t i;
+    for (i = 0; i < s->len; i++) {
+        if (((unsigned char)s->bytes[i] & 0xC0) != 0x80 && n-- == 0) {
+            return i;
+        }
+    }
+    return s->len;
+}
+
+/* ---- operators ---- */
+
+enum { CRY_ADD, CRY_SUB, CRY_DIV, CRY_MUL, CRY_INT_DIV, CRY_MOD, CRY_POW };
+
+static const char *const cry_math_symbols[] = {"+", "-", "/", "*", "div", "%", "**"};
+
+enum { CRY_EQ, CRY_NE, CRY_LT, CRY_LE, CRY_GT, CRY_GE };
+
+static const char *const cry_compare_symbols[] = {"==", "!=", "<", "<=", ">", ">="};
+
+/* Float arithmetic; results that would be inf or NaN are errors instead */
+static CryValue cry_float_op(int op, double x, double y, int site) {
+    double result;
+    if (y == 0.0 && (op == CRY_DIV || op == CRY_INT_DIV || op == CRY_MOD)) {
+        cry_fail(CRY_RUNTIME_ERROR, site, "Division by zero");
+    }
+    switch (op) {
+    case CRY_ADD:
+        result = x + y;
+        break;
+    case CRY_SUB:
+        result = x - y;
+        break;
+    case CRY_MUL:
+        result = x * y;
+        break;
+    case CRY_DIV:
+        result = x / y;
+        break;
+    case CRY_INT_DIV:
+        result = trunc(x / y);
+        break;
+    case CRY_MOD:
+        result = fmod(x, y);
+        break;
+    default:
+        result = pow(x, y);
+    }
+    if (isnan(result)) {
+        const char *left = cry_float_text(x);
+        cry_fail(CRY_RUNTIME_ERROR, site, "'%s' has no real result for %s and %s",
+                 cry_math_symbols[op], left, cry_float_text(y));
+    }
+    if (isinf(result)) {
+        cry_fail(CRY_RUNTIME_ERROR, site, "Float overflow in '%s'", cry_math_symbols[op]);
+    }
+    return cry_float(result);
+}
+
+/* x * y, or 0 with *overflow set if it doesn't fit */
+static int64_t cry_checked_mul(int64_t x, int64_t y, int *overflow) {
+    if (x > 0) {
+        if (y > 0 ? x > INT64_MAX / y : y < INT64_MIN / x) {
+            *overflow = 1;
+            return 0;
+        }
+    } else if (y > 0 ? x < INT64_MIN / y : x != 0 && y < INT64_MAX / x) {
+        *overflow = 1;
+        return 0;
+    }
+    return x * y;
+}
+
+/* Int arithmetic stays in ints, except `/` and negative powers which give floats */
+static CryValue cry_int_op(int op, int64_t x, int64_t y, int site) {
+    int overflow = 0;
+    int64_t result = 0;
+    if (y == 0 && (op == CRY_DIV || op == CRY_INT_DIV || op == CRY_MOD)) {
+        cry_fail(CRY_RUNTIME_ERROR, site, "Division by zero");
+    }
+    switch (op) {
+    case CRY_ADD:
+        overflow = (y > 0 && x > INT64_MAX - y) || (y < 0 && x < INT64_MIN - y);
+        result = overflow ? 0 : x + y;
+        break;
+    case CRY_SUB:
+        overflow = (y < 0 && x > INT64_MAX + y) || (y > 0 && x < INT64_MIN + y);
+        result = overflow ? 0 : x - y;
+        break;
+    case CRY_MUL:
+        result = cry_checked_mul(x, y, &overflow);
+        break;
+    case CRY_INT_DIV:
+        overflow = x == INT64_MIN && y == -1;
+        result = overflow ? 0 : x / y;
+        break;
+    case CRY_MOD:
+        overflow = x == INT64_MIN && y == -1;
+        result = overflow ? 0 : x % y;
+        break;
+    case CRY_DIV:
+        return cry_float_op(op, (double)x, (double)y, site);
+    default:
+        if (y < 0) {
+            return cry_float_op(op, (double)x, (double)y, site);
+        }
+        if (y > UINT32_MAX) {
+            overflow = 1;
+            break;
+        }
+        /* Squares the base only while bits of the exponent are left, as checked_pow does */
+        result = 1;
+        if (y > 0) {
+            int64_t base = x;
+            for (;;) {
+                if (y & 1) {
+                    result = cry_checked_mul(result, base, &overflow);
+                    if (overflow || y == 1) {
+                        break;
+                    }
+                }
+                y /= 2;
+                base = cry_checked_mul(base, base, &overflow);
+                if (overflow) {
+                    break;
+                }
+            }
+        }
+    }
+    if (overflow) {
+        cry_fail(CRY_RUNTIME_ERROR, site, "Integer overflow in '%s'", cry_math_symbols[op]);
+    }
+    return cry_int(result);
+}
+
+static int cry_is_number(CryValue value) {
+    return value.tag == CRY_INT || value.tag == CRY_FLOAT;
+}
+
+static double cry_as_float(CryValue value) {
+    return value.tag == CRY_INT ? (double)value.as.i : value.as.f;
+}
+
+static CryValue cry_math(int op, CryValue lhs, CryValue rhs, int site) {
+    if (op == CRY_ADD && lhs.tag == CRY_STRING && rhs.tag == CRY_STRING) {
+        CryBuffer buffer = {0, 0, NULL};
+        cry_buffer_add(&buffer, lhs.as.s->bytes, lhs.as.s->len);
+        cry_buffer_add(&buffer, rhs.as.s->bytes, rhs.as.s->len);
+        cry_buffer_done(&buffer);
+        return cry_str(buffer.bytes, buffer.len);
+    }
+    if (lhs.tag == CRY_INT && rhs.tag == CRY_INT) {
+        return cry_int_op(op, lhs.as.i, rhs.as.i, site);
+    }
+    if (cry_is_number(lhs) && cry_is_number(rhs)) {
+        return cry_float_op(op, cry_as_float(lhs), cry_as_float(rhs), site);
+    }
+    cry_fail(CRY_TYPE_ERROR, site, "Cannot apply '%s' to %s and %s", cry_math_symbols[op],
+             cry_type_name(lhs), cry_type_name(rhs));
+    return cry_nil();
+}
+
+/* `-x` */
+static CryValue cry_negate(CryValue value, int site) {
+    if (value.tag == CRY_INT) {
+        if (value.as.i == INT64_MIN) {
+            cry_fail(CRY_RUNTIME_ERROR, site, "Integer overflow in '-'");
+        }
+        return cry_int(-value.as.i);
+    }
+    if (value.tag == CRY_FLOAT) {
+        return cry_float(-value.as.f);
+    }
+    cry_fail(CRY_TYPE_ERROR, site, "Cannot negate a %s", cry_type_name(value));
+    return cry_nil();
+}
+
+/* Equality as Memory's PartialEq has it: same kind and same contents */
+/* The pairs of lists, maps or structs being compared, innermost first. They are taken to be equal
+ * there, so values that hold themselves compare without recursing forever. */
+typedef struct CryPair {
+    const void *a, *b;
+    const struct CryPair *outer;
+} CryPair;
+
+static int cry_comparing(const CryPair *seen, const void *a, const void *b) {
+    for (; seen; seen = seen->outer) {
+        if (seen->a == a && seen->b == b) {
+            return 1;
+        }
+    }
+    return 0;
+}
+
+/* Two declarations written the same, as StructType and EnumType compare */
+static int cry_same_shape(const CryShape *a, const CryShape *b) {
+    int i;
+    if (a == b) {
+        return 1;
+    }
+    if (strcmp(a->name, b->name) != 0 || a->count != b->count) {
+        return 0;
+    }
+    for (i = 0; i < a->count; i++) {
+        if (!cry_same_shape(&a->parts[i], &b->parts[i])) {
+            return 0;
+        }
+    }
+    return 1;
+}
+
+/* The value of a struct's field, or NULL if it has no such field */
+static CryValue *cry_field_of(const CryInstance *instance, const char *field) {
+    int i;
+    for (i = 0; i < instance->shape->count; i++) {
+        if (strcmp(instance->shape->parts[i].name, field) == 0) {
+            return &instance->values[i];
+        }
+    }
+    return NULL;
+}
+
+static int cry_equal_in(CryValue a, CryValue b, const CryPair *seen) {
+    size_t i;
+    int field;
+    CryPair inner;
+    /* 1 == 1.0: numbers compare by value whichever kind they are, in lists and maps too */
+    if (cry_is_number(a) && cry_is_number(b) && a.tag != b.tag) {
+        return cry_as_float(a) == cry_as_float(b);
+    }
+    if (a.tag != b.tag) {
+        return 0;
+    }
+    switch (a.tag) {
+    case CRY_INT:
+        return a.as.i == b.as.i;
+    case CRY_FLOAT:
+        return a.as.f == b.as.f;
+    case CRY_BOOL:
+        return a.as.b == b.as.b;
+    case CRY_STRING:
+        return cry_same_string(a.as.s, b.as.s);
+    case CRY_FN:
+        /* Builtins are made again each time they're named, but are the same function */
+        return a.as.fn == b.as.fn || (a.as.fn->builtin && a.as.fn->builtin == b.as.fn->builtin);
+    case CRY_LIST:
+        if (a.as.list == b.as.list || cry_comparing(seen, a.as.list, b.as.list)) {
+            return 1;
+        }
+        if (a.as.list->len != b.as.list->len) {
+            return 0;
+        }
+        inner.a = a.as.list;
+        inner.b = b.as.list;
+        inner.outer = seen;
+        for (i = 0; i < a.as.list->len; i++) {
+            if (!cry_equal_in(a.as.list->items[i], b.as.list->items[i], &inner)) {
+                return 0;
+            }
+        }
+        return 1;
+    case CRY_MAP:
+        /* Maps with the same entries are equal whatever order they were built in */
+        if (a.as.map == b.as.map || cry_comparing(seen, a.as.map, b.as.map)) {
+            return 1;
+        }
+        if (a.as.map->len != b.as.map->len) {
+            return 0;
+        }
+        inner.a = a.as.map;
+        inner.b = b.as.map;
+        inner.outer = seen;
+        for (i = 0; i < a.as.map->len; i++) {
+            CryValue *other = cry_map_get(b.as.map, a.as.map->keys[i]);
+            if (!other || !cry_equal_in(a.as.map->values[i], *other, &inner)) {
+                return 0;
+            }
+        }
+        return 1;
+    case CRY_STRUCT_TYPE:
+        return cry_same_shape(a.as.shape, b.as.shape);
+    case CRY_ENUM:
+        return cry_same_shape(a.as.def->shape, b.as.def->shape);
+    case CRY_CONSTRUCTOR:
+        return cry_same_shape(a.as.variant->def->shape, b.as.variant->def->shape) &&
+               a.as.variant->index == b.as.variant->index;
+    case CRY_VARIANT:
+        /* Variants of two separately declared enums are never equal, even with the same names */
+        if (a.as.variant->def != b.as.variant->def || a.as.variant->index != b.as.variant->index) {
+            return 0;
+        }
+        for (field = 0; field < cry_variant_arity(a.as.variant); field++) {
+            if (!cry_equal_in(a.as.variant->values[field], b.as.variant->values[field], seen)) {
+                return 0;
+            }
+        }
+        return 1;
+    case CRY_STRUCT:
+        /* Structs of the same name with the same fields, in any order */
+        if (a.as.instance == b.as.instance || cry_comparing(seen, a.as.instance, b.as.instance)) {
+            return 1;
+        }
+        if (strcmp(a.as.instance->shape->name, b.as.instance->shape->name) != 0 ||
+            a.as.instance->shape->count != b.as.instance->shape->count) {
+            return 0;
+        }
+        inner.a = a.as.instance;
+        inner.b = b.as.instance;
+        inner.outer = seen;
+        for (field = 0; field < a.as.instance->shape->count; field++) {
+            CryValue *other = cry_field_of(b.as.instance, a.as.instance->shape->parts[field].name);
+            if (!other || !cry_equal_in(a.as.instance->values[field], *other, &inner)) {
+                return 0;
+            }
+        }
+        return 1;
+    default:
+        return 1;
+    }
+}
+
+static int cry_equal(CryValue a, CryValue b) {
+    return cry_equal_in(a, b, NULL);
+}
+
+static int cry_string_order(const CryString *a, const CryString *b) {
+    size_t shorter = a->len < b->len ? a->len : b->len;
+    int order = memcmp(a->bytes, b->bytes, shorter);
+    if (order) {
+        return order < 0 ? -1 : 1;
+    }
+    return a->len < b->len ? -1 : a->len > b->len;
+}
+
+/* Orders two numbers or two strings into *order; false if they can't be ordered */
+static int cry_order(CryValue a, CryValue b, int *order) {
+    if (a.tag == CRY_INT && b.tag == CRY_INT) {
+        *order = a.as.i < b.as.i ? -1 : a.as.i > b.as.i;
+        return 1;
+    }
+    if (a.tag == CRY_STRING && b.tag == CRY_STRING) {
+        *order = cry_string_order(a.as.s, b.as.s);
+        return 1;
+    }
+    if (cry_is_number(a) && cry_is_number(b)) {
+        double x = cry_as_float(a), y = cry_as_float(b);
+        *order = x < y ? -1 : x > y;
+        return 1;
+    }
+    return 0;
+}
+
+static CryValue cry_compare(int op, CryValue lhs, CryValue rhs, int site) {
+    int order = 0, comparable = cry_order(lhs, rhs, &order), equal = cry_equal(lhs, rhs);
+    switch (op) {
+    case CRY_EQ:
+        return cry_bool(equal);
+    case CRY_NE:
+        return cry_bool(!equal);
+    default:
+        break;
+    }
+    if (!comparable) {
+        cry_fail(CRY_TYPE_ERROR, site, "Cannot compare %s and %s with '%s'",
+                 cry_type_name(lhs), cry_type_name(rhs), cry_compare_symbols[op]);
+    }
+    switch (op) {
+    case CRY_LT:
+        return cry_bool(order < 0);
+    case CRY_LE:
+        return cry_bool(order <= 0);
+    case CRY_GT:
+        return cry_bool(order > 0);
+    default:
+        return cry_bool(order >= 0);
+    }
+}
+
+/* ---- variables ---- */
+
+static CryValue cry_get_global(const CryGlobal *global, const char *name, int site) {
+    if (global->state == CRY_UNBOUND) {
+        cry_fail(CRY_NAME_ERROR, site, "Memory '%s' not found", name);
+    }
+    return global->value;
+}
+
+/* Binds a top-level name. Anything may shadow a `let`, and a function, struct, enum or
+ * variant may replace one of its own kind, but a `final` can't be redeclared. */
+static void cry_define(CryGlobal *global, CryValue value, int state, const char *name,
+                       int site) {
+    static const char *const kinds[] = {"",       "",     "final variable", "function",
+                                        "struct", "enum", "variant"};
+    if (global->state != CRY_UNBOUND && global->state != CRY_LET &&
+        (global->state == CRY_FINAL || global->state != state)) {
+        cry_fail(CRY_MUTABILITY_ERROR, site, "Cannot redeclare %s '%s' in the same scope",
+                 kinds[global->state], name);
+    }
+    global->state = state;
+    global->value = value;
+}
+
+/* Fails unless the global exists and can be assigned */
+static void cry_writable(const CryGlobal *global, const char *name, int site) {
+    if (global->state != CRY_UNBOUND && global->state != CRY_LET) {
+        cry_fail(CRY_MUTABILITY_ERROR, site, "Cannot modify final variable '%s'", name);
+    }
+}
+
+/* The value of a `let` global, for `x += y` */
+static CryValue cry_get_global_mut(const CryGlobal *global, const char *name, int site) {
+    CryValue value = cry_get_global(global, name, site);
+    cry_writable(global, name, site);
+    return value;
+}
+
+static void cry_set_global(CryGlobal *global, CryValue value, const char *name, int site) {
+    cry_get_global(global, name, site);
+    cry_writable(global, name, site);
+    global->value = value;
+}
+
+/* Binds a captured variable as its declaration runs */
+static void cry_bind(CryCell *cell, CryValue value) {
+    cell->set = 1;
+    cell->value = value;
+}
+
+/* A captured variable that isn't bound yet falls back to the global of the same name */
+static CryValue cry_get_upvalue(const CryCell *cell, const CryGlobal *global, const char *name,
+                                int site) {
+    return cell->set ? cell->value : cry_get_global(global, name, site);
+}
+
+static void cry_set_upvalue(CryCell *cell, CryGlobal *global, CryValue value, const char *name,
+                            int site) {
+    if (cell->set) {
+        cell->value = value;
+    } else {
+        cry_set_global(global, value, name, site);
+    }
+}
+
+/* What a called name refers to, if anything, before falling back to a builtin */
+static int cry_find_global(const CryGlobal *global, CryValue *found) {
+    if (global->state != CRY_UNBOUND) {
+        *found = global->value;
+        return 1;
+    }
+    return 0;
+}
+
+static int cry_find_upvalue(const CryCell *cell, const CryGlobal *global, CryValue *found) {
+    if (cell->set) {
+        *found = cell->value;
+        return 1;
+    }
+    return cry_find_global(global, found);
+}
+
+/* ---- calls ---- */
+
+static int cry_depth;
+
+static CryValue cry_call(CryValue callee, int argc, CryValue *args, int site) {
+    CryValue result;
+    if (callee.tag == CRY_CONSTRUCTOR) {
+        CryVariant *variant = callee.as.variant;
+        CryValue *values;
+        if (argc != cry_variant_arity(variant)) {
+            cry_fail(CRY_RUNTIME_ERROR, site, "Variant '%s' takes %d argument(s) but %d were given",
+                     cry_variant_name(variant), cry_variant_arity(variant), argc);
+        }
+        values = cry_alloc((size_t)argc * sizeof *values);
+        memcpy(values, args, (size_t)argc * sizeof *values);
+        return cry_variant_value(variant->def, variant->index, values);
+    }
+    if (callee.tag != CRY_FN) {
+        cry_fail(CRY_TYPE_ERROR, site, "A %s is not callable", cry_type_name(callee));
+    }
+    if (callee.as.fn->builtin) {
+        return callee.as.fn->builtin(argc, args, site);
+    }
+    if (argc != callee.as.fn->arity) {
+        cry_fail(CRY_RUNTIME_ERROR, site, "%s takes %d argument(s) but %d were given",
+                 callee.as.fn->name, callee.as.fn->arity, argc);
+    }
+    if (cry_depth >= CRY_MAX_CALL_DEPTH) {
+        cry_fail(CRY_RUNTIME_ERROR, site, "Maximum call depth exceeded");
+    }
+    cry_depth++;
+    result = callee.as.fn->code(callee.as.fn->env, args);
+    cry_depth--;
+    return result;
+}
+
+/* A call to a name that is neither a memory nor a builtin */
+static CryValue cry_no_function(const char *name, int site) {
+    cry_fail(CRY_NAME_ERROR, site, "Function '%s' not found", name);
+    return cry_nil();
+}
+
+/* ---- collections ---- */
+
+static int64_t cry_expect_int(CryValue value, int site) {
+    if (value.tag != CRY_INT) {
+        cry_fail(CRY_TYPE_ERROR, site, "Expected an int, found %s", cry_type_name(value));
+    }
+    return value.as.i;
+}
+
+static CryString *cry_map_key(CryValue key, int site) {
+    if (key.tag != CRY_STRING) {
+        cry_fail(CRY_TYPE_ERROR, site, "Map keys must be strings, found %s",
+                 cry_type_name(key));
+    }
+    return key.as.s;
+}
+
+/* Length of a list or string, the values that can be indexed by position */
+static size_t cry_indexable_len(CryValue value, int site) {
+    if (value.tag == CRY_LIST) {
+        return value.as.list->len;
+    }
+    if (value.tag == CRY_STRING) {
+        return cry_char_count(value.as.s);
+    }
+    cry_fail(CRY_TYPE_ERROR, site, "Cannot index into a %s", cry_type_name(value));
+    return 0;
+}
+
+/* Checks an index value against a length, giving the position it refers to */
+static size_t cry_position(CryValue index, size_t len, int site) {
+    if (index.tag != CRY_INT) {
+        cry_fail(CRY_TYPE_ERROR, site, "Index must be an int, found %s", cry_type_name(index));
+    }
+    if (index.as.i < 0 || (uint64_t)index.as.i >= len) {
+        cry_fail(CRY_RUNTIME_ERROR, site, "Index %" PRId64 " out of range for length %lu",
+                 index.as.i, (unsigned long)len);
+    }
+    return (size_t)index.as.i;
+}
+
+/* `xs[i]`, `s[i]` and `m[key]` */
+static CryValue cry_index(CryValue value, CryValue index, int target_site, int index_site) {
+    size_t i, start;
+    if (value.tag == CRY_MAP) {
+        CryString *key = cry_map_key(index, index_site);
+        CryValue *found = cry_map_get(value.as.map, key);
+        if (!found) {
+            cry_fail(CRY_RUNTIME_ERROR, index_site, "Key %s not found in map",
+                     cry_quoted_text(key));
+        }
+        return *found;
+    }
+    i = cry_position(index, cry_indexable_len(value, target_site), index_site);
+    if (value.tag == CRY_LIST) {
+        return value.as.list->items[i];
+    }
+    start = cry_char_offset(value.as.s, i);
+    return cry_str(value.as.s->bytes + start, cry_char_offset(value.as.s, i + 1) - start);
+}
+
+/* Checks `value` can be sliced, before the bounds are evaluated; a map can't take a range */
+static void cry_sliceable(CryValue value, int target_site, int range_site) {
+    if (value.tag == CRY_MAP) {
+        cry_fail(CRY_TYPE_ERROR, range_site, "A range can only be used in a for loop");
+    }
+    cry_indexable_len(value, target_site);
+}
+
+/* `xs[a..b]` and `s[a..b]` */
+static CryValue cry_slice(CryValue value, int64_t start, int64_t end, int target_site,
+                          int index_site) {
+    size_t len = cry_indexable_len(value, target_site), from, to;
+    if (start < 0 || start > end || (uint64_t)end > len) {
+        cry_fail(CRY_RUNTIME_ERROR, index_site,
+                 "Slice %" PRId64 "..%" PRId64 " out of range for length %lu", start, end,
+                 (unsigned long)len);
+    }
+    if (value.tag == CRY_LIST) {
+        return cry_list((size_t)(end - start), value.as.list->items + start);
+    }
+    from = cry_char_offset(value.as.s, (size_t)start);
+    to = cry_char_offset(value.as.s, (size_t)end);
+    return cry_str(value.as.s->bytes + from, to - from);
+}
+
+/* `xs[i] = value` and `m[key] = value` */
+static void cry_set_index(CryValue collection, CryValue index, CryValue value, int target_site,
+                          int index_site) {
+    if (collection.tag == CRY_LIST) {
+        size_t i = cry_position(index, collection.as.list->len, index_site);
+        collection.as.list->items[i] = value;
+    } else if (collection.tag == CRY_MAP) {
+        cry_map_insert(collection.as.map, cry_map_key(index, index_site), value);
+    } else {
+        cry_fail(CRY_TYPE_ERROR, target_site, "Cannot assign to an index of a %s",
+                 cry_type_name(collection));
+    }
+}
+
+/* The values a `for` over a list or map binds; `two` is true for `for a, b in`. They are
+ * a snapshot, so changing the collection in the body can't loop forever. */
+static CryPasses cry_passes(CryValue collection, int two, int site) {
+    CryPasses passes;
+    size_t i;
+    if (collection.tag == CRY_LIST) {
+        passes.len = collection.as.list->len;
+    } else if (collection.tag == CRY_MAP) {
+        passes.len = collection.as.map->len;
+    } else {
+        cry_fail(CRY_TYPE_ERROR, site, "Cannot iterate over a %s", cry_type_name(collection));
+        passes.len = 0;
+    }
+    passes.first = cry_alloc(passes.len * sizeof *passes.first);
+    passes.second = cry_alloc(passes.len * sizeof *passes.second);
+    for (i = 0; i < passes.len; i++) {
+        if (collection.tag == CRY_MAP) {
+            passes.first[i] = cry_string(collection.as.map->keys[i]);
+            passes.second[i] = collection.as.map->values[i];
+        } else if (two) {
+            passes.first[i] = cry_int((int64_t)i);
+            passes.second[i] = collection.as.list->items[i];
+        } else {
+            passes.first[i] = collection.as.list->items[i];
+            passes.second[i] = cry_nil();
+        }
+    }
+    return passes;
+}
+
+/* ---- structs and enums ---- */
+
+/* Checks a struct literal before any field value is evaluated, giving the struct's shape.
+ * `def` is what the literal's name refers to, if `found`; `sites` locate each given
+ * field's value. */
+static const CryShape *cry_struct_def(int found, CryValue def, const char *name, int count,
+                                      const char *const *given, const int *sites, int site) {
+    const CryShape *shape;
+    int i, j;
+    if (!found) {
+        cry_fail(CRY_NAME_ERROR, site, "Struct '%s' not found", name);
+    }
+    if (def.tag != CRY_STRUCT_TYPE) {
+        cry_fail(CRY_TYPE_ERROR, site, "'%s' is a %s, not a struct", name, cry_type_name(def));
+    }
+    shape = def.as.shape;
+    for (i = 0; i < count; i++) {
+        for (j = 0; j < shape->count && strcmp(shape->parts[j].name, given[i]) != 0; j++) {
+        }
+        if (j == shape->count) {
+            cry_fail(CRY_NAME_ERROR, sites[i], "Struct '%s' has no field '%s'", name, given[i]);
+        }
+    }
+    for (j = 0; j < shape->count; j++) {
+        for (i = 0; i < count && strcmp(shape->parts[j].name, given[i]) != 0; i++) {
+        }
+        if (i == count) {
+            cry_fail(CRY_TYPE_ERROR, site, "Missing field '%s' in '%s'", shape->parts[j].name,
+                     name);
+        }
+    }
+    return shape;
+}
+
+/* Builds a struct from values given in source order, storing them in declaration order;
+ * a field given twice keeps its last value */
+static CryValue cry_struct(const CryShape *shape, int count, const char *const *given,
+                           const CryValue *values) {
+    CryInstance *instance = cry_alloc(sizeof *instance);
+    CryValue value;
+    int i, j;
+    instance->shape = shape;
+    instance->values = cry_alloc((size_t)shape->count * sizeof *instance->values);
+    for (j = 0; j < shape->count; j++) {
+        for (i = count - 1; strcmp(shape->parts[j].name, given[i]) != 0; i--) {
+        }
+        instance->values[j] = values[i];
+    }
+    value.tag = CRY_STRUCT;
+    value.as.instance = instance;
+    return value;
+}
+
+/* `value.field`, of a struct or a variant */
+static CryValue cry_field(CryValue value, const char *field, int site, int target_site) {
+    if (value.tag == CRY_VARIANT) {
+        const CryShape *variant = &value.as.variant->def->shape->parts[value.as.variant->index];
+        int i;
+        for (i = 0; i < variant->count; i++) {
+            if (strcmp(variant->parts[i].name, field) == 0) {
+                return value.as.variant->values[i];
+            }
+        }
+        cry_fail(CRY_NAME_ERROR, site, "Variant '%s' has no field '%s'", variant->name, field);
+    }
+    if (value.tag == CRY_STRUCT) {
+        CryValue *found = cry_field_of(value.as.instance, field);
+        if (!found) {
+            cry_fail(CRY_NAME_ERROR, site, "Struct '%s' has no field '%s'",
+                     value.as.instance->shape->name, field);
+        }
+        return *found;
+    }
+    cry_fail(CRY_TYPE_ERROR, target_site, "Cannot read field '%s' of a %s", field,
+             cry_type_name(value));
+    return cry_nil();
+}
+
+/* `instance.field = value` */
+static void cry_set_field(CryValue instance, const char *field, CryValue value, int site,
+                          int target_site) {
+    CryValue *found;
+    if (instance.tag != CRY_STRUCT) {
+        cry_fail(CRY_TYPE_ERROR, target_site, "Cannot set field '%s' on a %s", field,
+                 cry_type_name(instance));
+    }
+    found = cry_field_of(instance.as.instance, field);
+    if (!found) {
+        cry_fail(CRY_NAME_ERROR, site, "Struct '%s' has no field '%s'",
+                 instance.as.instance->shape->name, field);
+    }
+    *found = value;
+}
+
+/* ---- patterns ---- */
+
+enum { CRY_PAT_WILD, CRY_PAT_BIND, CRY_PAT_BOOL, CRY_PAT_LITERAL, CRY_PAT_VARIANT };
+
+/* A pattern as written, where a bare name may be a binding or a variant. `name` indexes
+ * the names the patterns of its `match` mention. */
+typedef struct CryPattern {
+    int kind;
+    int name;
+    CryValue literal;
+    int count;
+    const struct CryPattern *fields;
+    int site;
+} CryPattern;
+
+/* A pattern with its names looked up, so variants are told apart from bindings */
+typedef struct CryPat {
+    int kind;
+    int name;
+    CryValue literal;
+    CryEnum *def;
+    int index;
+    int count;
+    const struct CryPat **fields;
+} CryPat;
+
+/* One row of patterns for the exhaustiveness check, a pattern per column */
+typedef const CryPat **CryRow;
+
+static const CryPat cry_wild = {CRY_PAT_WILD, 0, {CRY_NIL, {0}}, NULL, 0, 0, NULL};
+
+/* Looks up the names of a pattern. `values` holds what each name refers to where `found`
+ * is set; `bound` marks the names the pattern binds so far. */
+static const CryPat *cry_resolve(const CryPattern *pattern, const char *const *names,
+                                 const int *found, const CryValue *values, int *bound) {
+    CryPat *pat = cry_alloc(sizeof *pat);
+    const char *name = "";
+    CryValue value = cry_nil();
+    int i;
+    /* Only names and variants mention a name */
+    if (pattern->kind == CRY_PAT_BIND || pattern->kind == CRY_PAT_VARIANT) {
+        name = names[pattern->name];
+        value = values[pattern->name];
+    }
+    pat->kind = pattern->kind;
+    pat->name = pattern->name;
+    pat->literal = pattern->literal;
+    pat->def = NULL;
+    pat->index = 0;
+    pat->count = 0;
+    pat->fields = NULL;
+    switch (pattern->kind) {
+    case CRY_PAT_BIND:
+        /* A bare name is a variant only if it is bound to the field-less variant it names */
+        if (found[pattern->name] && value.tag == CRY_VARIANT &&
+            !cry_variant_arity(value.as.variant) &&
+            strcmp(cry_variant_name(value.as.variant), name) == 0) {
+            pat->kind = CRY_PAT_VARIANT;
+            pat->def = value.as.variant->def;
+            pat->index = value.as.variant->index;
+            break;
+        }
+        if (bound[pattern->name]) {
+            cry_fail(CRY_NAME_ERROR, pattern->site, "'%s' is bound twice in this pattern", name);
+        }
+        bound[pattern->name] = 1;
+        break;
+    case CRY_PAT_LITERAL:
+        if (pattern->literal.tag == CRY_BOOL) {
+            pat->kind = CRY_PAT_BOOL;
+        }
+        break;
+    case CRY_PAT_VARIANT:
+        if (found[pattern->name] &&
+            (value.tag == CRY_CONSTRUCTOR ||
+             (value.tag == CRY_VARIANT && strcmp(cry_variant_name(value.as.variant), name) == 0))) {
+            pat->def = value.as.variant->def;
+            pat->index = value.as.variant->index;
+        } else {
+            cry_fail(CRY_NAME_ERROR, pattern->site, "Enum variant '%s' not found", name);
+        }
+        if (pattern->count != cry_variant_arity(value.as.variant)) {
+            cry_fail(CRY_TYPE_ERROR, pattern->site,
+                     "Variant '%s' has %d field(s) but the pattern gives %d", name,
+                     cry_variant_arity(value.as.variant), pattern->count);
+        }
+        pat->count = pattern->count;
+        pat->fields = cry_alloc((size_t)pat->count * sizeof *pat->fields);
+        for (i = 0; i < pat->count; i++) {
+            pat->fields[i] = cry_resolve(&pattern->fields[i], names, found, values, bound);
+        }
+        break;
+    default:
+        break;
+    }
+    return pat;
+}
+
+/* Whether `value` matches a pattern, storing what it binds into `bound` */
+static int cry_matches(const CryPat *pat, CryValue value, CryValue *bound) {
+    int i;
+    switch (pat->kind) {
+    case CRY_PAT_WILD:
+        return 1;
+    case CRY_PAT_BIND:
+        bound[pat->name] = value;
+        return 1;
+    case CRY_PAT_BOOL:
+        return value.tag == CRY_BOOL && value.as.b == pat->literal.as.b;
+    case CRY_PAT_LITERAL:
+        /* Numbers match by value, so `1` matches `1.0` as with `==` */
+        return cry_equal(pat->literal, value);
+    default:
+        if (value.tag != CRY_VARIANT || value.as.variant->def != pat->def ||
+            value.as.variant->index != pat->index) {
+            return 0;
+        }
+        for (i = 0; i < pat->count; i++) {
+            if (!cry_matches(pat->fields[i], value.as.variant->values[i], bound)) {
+                return 0;
+            }
+        }
+        return 1;
+    }
+}
+
+/* How many constructors the type a column's patterns test has: an enum's variants, in
+ * `*def`, or true and false. Zero when the patterns don't pin down a type with a listable
+ * set of values. */
+static int cry_signature(const CryRow *rows, size_t count, CryEnum **def) {
+    size_t i;
+    for (i = 0; i < count; i++) {
+        if (rows[i][0]->kind == CRY_PAT_VARIANT) {
+            *def = rows[i][0]->def;
+            return (*def)->shape->count;
+        }
+        if (rows[i][0]->kind == CRY_PAT_BOOL) {
+            *def = NULL;
+            return 2;
+        }
+    }
+    return 0;
+}
+
+static const char *cry_ctor_name(const CryEnum *def, int ctor) {
+    if (def) {
+        return def->shape->parts[ctor].name;
+    }
+    return ctor == 0 ? "true" : "false";
+}
+
+static int cry_ctor_arity(const CryEnum *def, int ctor) {
+    return def ? def->shape->parts[ctor].count : 0;
+}
+
+/* The rows that can match a value built with constructor `ctor`, with its fields spliced
+ * in place of the first column, into `out`; gives how many there are */
+static size_t cry_specialize(const CryRow *rows, size_t count, size_t width, const CryEnum *def,
+                             int ctor, CryRow *out) {
+    int arity = cry_ctor_arity(def, ctor), i;
+    size_t row, kept = 0;
+    for (row = 0; row < count; row++) {
+        const CryPat *head = rows[row][0];
+        CryRow spliced;
+        if (head->kind == CRY_PAT_VARIANT) {
+            if (head->def != def || head->index != ctor) {
+                continue;
+            }
+        } else if (head->kind == CRY_PAT_BOOL) {
+            if (def || head->literal.as.b != (ctor == 0)) {
+                continue;
+            }
+        } else if (head->kind != CRY_PAT_WILD && head->kind != CRY_PAT_BIND) {
+            continue;
+        }
+        spliced = cry_alloc(((size_t)arity + width - 1) * sizeof *spliced);
+        for (i = 0; i < arity; i++) {
+            spliced[i] = head->kind == CRY_PAT_VARIANT ? head->fields[i] : &cry_wild;
+        }
+        memcpy(spliced + arity, rows[row] + 1, (width - 1) * sizeof *spliced);
+        out[kept++] = spliced;
+    }
+    return kept;
+}
+
+/* Writes a constructor applied to the first `arity` parts of a witness `width` long */
+static const char **cry_apply(const CryEnum *def, int ctor, const char **witness, size_t width) {
+    int arity = cry_ctor_arity(def, ctor), i;
+    const char **applied = cry_alloc((width - (size_t)arity + 1) * sizeof *applied);
+    CryBuffer head = {0, 0, NULL};
+    cry_buffer_text(&head, cry_ctor_name(def, ctor));
+    if (arity) {
+        cry_buffer_text(&head, "(");
+        for (i = 0; i < arity; i++) {
+            if (i) {
+                cry_buffer_text(&head, ", ");
+            }
+            cry_buffer_text(&head, witness[i]);
+        }
+        cry_buffer_text(&head, ")");
+    }
+    applied[0] = cry_buffer_done(&head);
+    memcpy(applied + 1, witness + arity, (width - (size_t)arity) * sizeof *applied);
+    return applied;
+}
+
+/* A row of values, written as patterns, that no row of `rows` matches; NULL if every
+ * value is matched */
+static const char **cry_witness(const CryRow *rows, size_t count, size_t width) {
+    CryEnum *def = NULL;
+    CryRow *narrowed;
+    const char **witness;
+    size_t row, kept = 0;
+    int ctors, ctor;
+    if (width == 0) {
+        return count ? NULL : cry_alloc(sizeof *witness);
+    }
+    ctors = cry_signature(rows, count, &def);
+    narrowed = cry_alloc(count * sizeof *narrowed);
+    if (!ctors) {
+        const char **rest;
+        for (row = 0; row < count; row++) {
+            if (rows[row][0]->kind == CRY_PAT_WILD || rows[row][0]->kind == CRY_PAT_BIND) {
+                narrowed[kept++] = rows[row] + 1;
+            }
+        }
+        rest = cry_witness(narrowed, kept, width - 1);
+        if (!rest) {
+            return NULL;
+        }
+        witness = cry_alloc(width * sizeof *witness);
+        witness[0] = "_";
+        memcpy(witness + 1, rest, (width - 1) * sizeof *witness);
+        return witness;
+    }
+    for (ctor = 0; ctor < ctors; ctor++) {
+        size_t arity = (size_t)cry_ctor_arity(def, ctor);
+        kept = cry_specialize(rows, count, width, def, ctor, narrowed);
+        witness = cry_witness(narrowed, kept, arity + width - 1);
+        if (witness) {
+            return cry_apply(def, ctor, witness, arity + width - 1);
+        }
+    }
+    return NULL;
+}
+
+/* The arms a match would need to cover every value, one per missing top-level case, as
+ * a list for a message; NULL if none are missing */
+static const char *cry_missing_arms(const CryPat **pats, size_t count) {
+    CryBuffer missing = {0, 0, NULL};
+    CryEnum *def = NULL;
+    CryRow *rows = cry_alloc(count * sizeof *rows), *narrowed = cry_alloc(count * sizeof *rows);
+    const char **witness;
+    size_t i;
+    int ctors, ctor;
+    for (i = 0; i < count; i++) {
+        rows[i] = &pats[i];
+    }
+    ctors = cry_signature(rows, count, &def);
+    if (!ctors) {
+        witness = cry_witness(rows, count, 1);
+        return witness ? witness[0] : NULL;
+    }
+    for (ctor = 0; ctor < ctors; ctor++) {
+        size_t arity = (size_t)cry_ctor_arity(def, ctor);
+        size_t kept = cry_specialize(rows, count, 1, def, ctor, narrowed);
+        witness = cry_witness(narrowed, kept, arity);
+        if (witness) {
+            if (missing.len) {
+                cry_buffer_text(&missing, ", ");
+            }
+            cry_buffer_text(&missing, cry_apply(def, ctor, witness, arity)[0]);
+        }
+    }
+    return missing.len ? cry_buffer_done(&missing) : NULL;
+}
+
+/* Picks the arm of a `match` as the interpreter does: looks up every pattern's names,
+ * checks the patterns cover every value, then takes the first arm `subject` matches.
+ * `values` holds what each name the patterns mention refers to, where `found` is set; on
+ * return it holds what the chosen arm binds instead. */
+static int cry_match(CryValue subject, int arms, const CryPattern *patterns, int count,
+                     const char *const *names, const int *found, CryValue *values, int site,
+                     int subject_site) {
+    const CryPat **pats = cry_alloc((size_t)arms * sizeof *pats);
+    int *bound = cry_alloc((size_t)count * sizeof *bound);
+    CryValue *attempt = cry_alloc((size_t)count * sizeof *attempt);
+    const char *missing;
+    int arm;
+    for (arm = 0; arm < arms; arm++) {
+        memset(bound, 0, (size_t)count * sizeof *bound);
+        pats[arm] = cry_resolve(&patterns[arm], names, found, values, bound);
+    }
+    missing = cry_missing_arms(pats, (size_t)arms);
+    if (missing) {
+        cry_fail(CRY_TYPE_ERROR, site, "Match is not exhaustive, missing %s", missing);
+    }
+    for (arm = 0; arm < arms; arm++) {
+        memcpy(attempt, values, (size_t)count * sizeof *attempt);
+        if (cry_matches(pats[arm], subject, attempt)) {
+            memcpy(values, attempt, (size_t)count * sizeof *values);
+            return arm;
+        }
+    }
+    cry_fail(CRY_RUNTIME_ERROR, subject_site, "No match arm matches %s", cry_repr_text(subject));
+    return 0;
+}
+
+/* ---- builtins ---- */
+
+static void cry_arity(const char *name, int argc, int expected, int site) {
+    if (argc != expected) {
+        cry_fail(CRY_RUNTIME_ERROR, site, "'%s' takes %d argument(s) but %d were given", name,
+                 expected, argc);
+    }
+}
+
+static CryString *cry_string_arg(const char *name, CryValue arg, int site) {
+    if (arg.tag != CRY_STRING) {
+        cry_fail(CRY_TYPE_ERROR, site, "'%s' expects a string, found %s", name,
+                 cry_type_name(arg));
+    }
+    return arg.as.s;
+}
+
+static CryList *cry_list_arg(const char *name, CryValue arg, int site) {
+    if (arg.tag != CRY_LIST) {
+        cry_fail(CRY_TYPE_ERROR, site, "'%s' expects a list, found %s", name,
+                 cry_type_name(arg));
+    }
+    return arg.as.list;
+}
+
+static CryMap *cry_map_arg(const char *name, CryValue arg, int site) {
+    if (arg.tag != CRY_MAP) {
+        cry_fail(CRY_TYPE_ERROR, site, "'%s' expects a map, found %s", name,
+                 cry_type_name(arg));
+    }
+    return arg.as.map;
+}
+
+static void cry_write_out(const char *bytes, size_t len) {
+    fwrite(bytes, 1, len, stdout);
+    fflush(stdout);
+}
+
+static CryString *cry_joined(int argc, const CryValue *args) {
+    CryBuffer buffer = {0, 0, NULL};
+    int i;
+    for (i = 0; i < argc; i++) {
+        if (i) {
+            cry_buffer_text(&buffer, " ");
+        }
+        cry_buffer_value(&buffer, args[i], 0);
+    }
+    cry_buffer_done(&buffer);
+    return cry_string_of(buffer.bytes, buffer.len);
+}
+
+static CryValue cry_builtin_print(int argc, CryValue *args, int site) {
+    CryString *text = cry_joined(argc, args);
+    (void)site;
+    cry_write_out(text->bytes, text->len);
+    return cry_nil();
+}
+
+static CryValue cry_builtin_println(int argc, CryValue *args, int site) {
+    CryString *text = cry_joined(argc, args);
+    (void)site;
+    cry_write_out(text->bytes, text->len);
+    cry_write_out("\n", 1);
+    return cry_nil();
+}
+
+/* input() or input(prompt): reads one line from stdin, without its line ending */
+static CryValue cry_builtin_input(int argc, CryValue *args, int site) {
+    CryBuffer line = {0, 0, NULL};
+    int c;
+    if (argc > 1) {
+        cry_arity("input", argc, 1, site);
+    }
+    if (argc == 1) {
+        CryString *prompt = cry_display(args[0]);
+        cry_write_out(prompt->bytes, prompt->len);
+    }
+    while ((c = getchar()) != EOF) {
+        char byte = (char)c;
+        cry_buffer_add(&line, &byte, 1);
+        if (c == '\n') {
+            break;
+        }
+    }
+    while (line.len && (line.bytes[line.len - 1] == '\n' || line.bytes[line.len - 1] == '\r')) {
+        line.len--;
+    }
+    return cry_str(cry_buffer_done(&line), line.len);
+}
+
+/* An I/O error as Rust's io::Error shows it */
+static const char *cry_os_error(int code) {
+    static char text[256];
+    snprintf(text, sizeof text, "%s (os error %d)", strerror(code), code);
+    return text;
+}
+
+static CryValue cry_builtin_read_file(int argc, CryValue *args, int site) {
+    CryBuffer contents = {0, 0, NULL};
+    char chunk[4096];
+    size_t read;
+    CryString *path;
+    FILE *file;
+    cry_arity("read_file", argc, 1, site);
+    path = cry_string_arg("read_file", args[0], site);
+    file = fopen(path->bytes, "rb");
+    if (!file) {
+        cry_fail(CRY_RUNTIME_ERROR, site, "Could not read file '%s': %s", path->bytes,
+                 cry_os_error(errno));
+    }
+    while ((read = fread(chunk, 1, sizeof chunk, file)) > 0) {
+        cry_buffer_add(&contents, chunk, read);
+    }
+    fclose(file);
+    return cry_str(cry_buffer_done(&contents), contents.len);
+}
+
+static CryValue cry_builtin_write_file(int argc, CryValue *args, int site) {
+    CryString *path, *text;
+    FILE *file;
+    cry_arity("write_file", argc, 2, site);
+    path = cry_string_arg("write_file", args[0], site);
+    text = cry_display(args[1]);
+    file = fopen(path->bytes, "wb");
+    if (!file || fwrite(text->bytes, 1, text->len, file) != text->len || fclose(file) != 0) {
+        cry_fail(CRY_RUNTIME_ERROR, site, "Could not write file '%s': %s", path->bytes,
+                 cry_os_error(errno));
+    }
+    return cry_nil();
+}
+
+static CryValue cry_builtin_len(int argc, CryValue *args, int site) {
+    cry_arity("len", argc, 1, site);
+    switch (args[0].tag) {
+    case CRY_LIST:
+        return cry_int((int64_t)args[0].as.list->len);
+    case CRY_STRING:
+        return cry_int((int64_t)cry_char_count(args[0].as.s));
+    case CRY_MAP:
+        return cry_int((int64_t)args[0].as.map->len);
+    default:
+        cry_fail(CRY_TYPE_ERROR, site, "'len' expects a list, map or string, found %s",
+                 cry_type_name(args[0]));
+        return cry_nil();
+    }
+}
+
+static CryValue cry_builtin_push(int argc, CryValue *args, int site) {
+    cry_arity("push", argc, 2, site);
+    cry_list_push(cry_list_arg("push", args[0], site), args[1]);
+    return cry_nil();
+}
+
+/* Removes and returns the last item */
+static CryValue cry_builtin_pop(int argc, CryValue *args, int site) {
+    CryList *items;
+    cry_arity("pop", argc, 1, site);
+    items = cry_list_arg("pop", args[0], site);
+    if (!items->len) {
+        cry_fail(CRY_RUNTIME_ERROR, site, "Cannot pop from an empty list");
+    }
+    return items->items[--items->len];
+}
+
+/* map(list, fn): a new list of fn(item) for each item */
+static CryValue cry_builtin_map(int argc, CryValue *args, int site) {
+    CryList *items, *mapped;
+    size_t i, len;
+    cry_arity("map", argc, 2, site);
+    items = cry_list_arg("map", args[0], site);
+    len = items->len;
+    items = cry_list(len, items->items).as.list;
+    mapped = cry_list_new(len);
+    for (i = 0; i < len; i++) {
+        cry_list_push(mapped, cry_call(args[1], 1, &items->items[i], site));
+    }
+    return cry_list_value(mapped);
+}
+
+/* filter(list, fn): a new list of the items for which fn(item) is truthy */
+static CryValue cry_builtin_filter(int argc, CryValue *args, int site) {
+    CryList *items, *kept;
+    size_t i;
+    cry_arity("filter", argc, 2, site);
+    items = cry_list_arg("filter", args[0], site);
+    items = cry_list(items->len, items->items).as.list;
+    kept = cry_list_new(0);
+    for (i = 0; i < items->len; i++) {
+        if (cry_truthy(cry_call(args[1], 1, &items->items[i], site))) {
+            cry_list_push(kept, items->items[i]);
+        }
+    }
+    return cry_list_value(kept);
+}
+
+/* A stable merge sort, as Rust's sort_by is stable */
+static void cry_merge_sort(CryValue *items, CryValue *scratch, size_t len) {
+    size_t middle = len / 2, i = 0, j = middle, k = 0;
+    int order;
+    if (len < 2) {
+        return;
+    }
+    cry_merge_sort(items, scratch, middle);
+    cry_merge_sort(items + middle, scratch, len - middle);
+    while (i < middle && j < len) {
+        cry_order(items[j], items[i], &order);
+        scratch[k++] = order < 0 ? items[j++] : items[i++];
+    }
+    while (i < middle) {
+        scratch[k++] = items[i++];
+    }
+    while (j < len) {
+        scratch[k++] = items[j++];
+    }
+    memcpy(items, scratch, len * sizeof *items);
+}
+
+/* A sorted copy of a list of numbers or of strings */
+static CryValue cry_builtin_sort(int argc, CryValue *args, int site) {
+    CryList *items;
+    size_t i, numbers = 0, strings = 0;
+    cry_arity("sort", argc, 1, site);
+    items = cry_list_arg("sort", args[0], site);
+    items = cry_list(items->len, items->items).as.list;
+    for (i = 0; i < items->len; i++) {
+        numbers += cry_is_number(items->items[i]);
+        strings += items->items[i].tag == CRY_STRING;
+    }
+    if (numbers != items->len && strings != items->len) {
+        cry_fail(CRY_TYPE_ERROR, site, "'sort' expects a list of only numbers or only strings");
+    }
+    cry_merge_sort(items->items, cry_alloc(items->len * sizeof *items->items), items->len);
+    return cry_list_value(items);
+}
+
+/* join(list, separator) */
+static CryValue cry_builtin_join(int argc, CryValue *args, int site) {
+    CryBuffer buffer = {0, 0, NULL};
+    CryList *items;
+    CryString *separator;
+    size_t i;
+    cry_arity("join", argc, 2, site);
+    items = cry_list_arg("join", args[0], site);
+    separator = cry_string_arg("join", args[1], site);
+    for (i = 0; i < items->len; i++) {
+        if (i) {
+            cry_buffer_add(&buffer, separator->bytes, separator->len);
+        }
+        cry_buffer_value(&buffer, items->items[i], 0);
+    }
+    cry_buffer_done(&buffer);
+    return cry_str(buffer.bytes, buffer.len);
+}
+
+/* The keys of a map as a list, in insertion order */
+static CryValue cry_builtin_keys(int argc, CryValue *args, int site) {
+    CryMap *map;
+    CryList *keys;
+    size_t i;
+    cry_arity("keys", argc, 1, site);
+    map = cry_map_arg("keys", args[0], site);
+    keys = cry_list_new(map->len);
+    for (i = 0; i < map->len; i++) {
+        cry_list_push(keys, cry_string(map->keys[i]));
+    }
+    return cry_list_value(keys);
+}
+
+static CryValue cry_builtin_values(int argc, CryValue *args, int site) {
+    CryMap *map;
+    cry_arity("values", argc, 1, site);
+    map = cry_map_arg("values", args[0], site);
+    return cry_list(map->len, map->values);
+}
+
+/* has(map, key) */
+static CryValue cry_builtin_has(int argc, CryValue *args, int site) {
+    CryMap *map;
+    CryString *key;
+    cry_arity("has", argc, 2, site);
+    map = cry_map_arg("has", args[0], site);
+    key = cry_string_arg("has", args[1], site);
+    return cry_bool(cry_map_get(map, key) != NULL);
+}
+
+#endif
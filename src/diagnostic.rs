@@ -0,0 +1,84 @@
+use colored::*;
+
+use super::span::Span;
+
+// An error message tied to a location in a .cry file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub span: Span,
+}
+
+impl Diagnostic {
+    pub fn new(message: impl Into<String>, span: Span) -> Self {
+        Diagnostic {
+            message: message.into(),
+            span,
+        }
+    }
+
+    // Renders the message under `title`, followed by the offending line with a caret underline:
+    //
+    // CRY.ParseError: Expected ';' after expression
+    //   --> app.cry:3:15
+    //    |
+    //  3 | let z = 5 + 10
+    //    |               ^
+    pub fn render(&self, title: &str) -> String {
+        let excerpt = self.excerpt();
+        let pad = " ".repeat(excerpt.gutter.len());
+        format!(
+            "{title}\n{pad}{arrow} {span}\n{pad} {bar}\n{gutter} {bar} {line}\n{pad} {bar} {indent}{carets}",
+            title = format!("{title}: {}", self.message).bright_red().bold(),
+            arrow = "-->".bright_blue().bold(),
+            span = self.span,
+            bar = "|".bright_blue().bold(),
+            gutter = excerpt.gutter.bright_blue().bold(),
+            line = excerpt.line,
+            indent = excerpt.indent,
+            carets = "^".repeat(excerpt.width).bright_red().bold(),
+        )
+    }
+
+    // Everything `render` prints below the title, without colors, for native programs
+    // that report their own errors
+    pub fn plain_excerpt(&self) -> String {
+        let excerpt = self.excerpt();
+        let pad = " ".repeat(excerpt.gutter.len());
+        format!(
+            "{pad}--> {span}\n{pad} |\n{gutter} | {line}\n{pad} | {indent}{carets}",
+            span = self.span,
+            gutter = excerpt.gutter,
+            line = excerpt.line,
+            indent = excerpt.indent,
+            carets = "^".repeat(excerpt.width),
+        )
+    }
+
+    fn excerpt(&self) -> Excerpt<'_> {
+        let span = &self.span;
+        let line = span.source.line(span.line);
+
+        // Keep tabs so the caret lines up with the source as the terminal shows it
+        let indent: String = line
+            .chars()
+            .take(span.column.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let remaining = line.chars().count().saturating_sub(span.column - 1);
+        Excerpt {
+            gutter: span.line.to_string(),
+            line,
+            indent,
+            width: span.len().min(remaining).max(1),
+        }
+    }
+}
+
+// The source line under a diagnostic and where its carets go
+struct Excerpt<'a> {
+    gutter: String,
+    line: &'a str,
+    indent: String,
+    width: usize,
+}
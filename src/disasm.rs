@@ -0,0 +1,157 @@
+use colored::*;
+
+use super::bytecode::{Op, Place, Proto};
+
+// Lists every function of a compiled program, the program itself first, then the
+// functions declared in it in the order the compiler met them
+pub fn disassemble(script: &Proto) -> String {
+    let mut out = String::new();
+    function(script, "<script>", &mut out);
+    out
+}
+
+fn function(proto: &Proto, title: &str, out: &mut String) {
+    let captures: Vec<_> = proto.captures.iter().map(|c| c.name.as_str()).collect();
+    let mut about = format!(
+        "{} argument(s), {} slot(s), {} op(s)",
+        proto.arity,
+        proto.slots,
+        proto.chunk.code.len()
+    );
+    if !captures.is_empty() {
+        about.push_str(&format!(", captures {}", captures.join(", ")));
+    }
+    out.push_str(&format!(
+        "{} {}\n",
+        format!("== {title} ==").bold().cyan(),
+        about.dimmed()
+    ));
+
+    let chunk = &proto.chunk;
+    let mut last_line = None;
+    for (ip, &op) in chunk.code.iter().enumerate() {
+        // The source line, only where it changes
+        let line = chunk.span(ip).line;
+        let line = if last_line == Some(line) {
+            "|".to_string()
+        } else {
+            last_line = Some(line);
+            line.to_string()
+        };
+        let (name, operands, note) = describe(op, proto);
+        // Padded before coloring, which would otherwise count the escape codes
+        let (offset, line, name) = (
+            format!("{ip:04}"),
+            format!("{line:>4}"),
+            format!("{name:<13}"),
+        );
+        let mut row = format!(
+            "{} {} {} {operands:<10}",
+            offset.dimmed(),
+            line.bright_blue(),
+            name.bold().yellow(),
+        );
+        if !note.is_empty() {
+            row.push_str(&format!(" {}", format!("; {note}").green()));
+        }
+        out.push_str(row.trim_end());
+        out.push('\n');
+    }
+
+    for nested in &chunk.protos {
+        out.push('\n');
+        let title = match &nested.name {
+            Some(name) => format!("fn {name}"),
+            None => "fn <anonymous>".to_string(),
+        };
+        function(nested, &title, out);
+    }
+}
+
+fn place(place: Place) -> String {
+    match place {
+        Place::Local(slot) => format!("local {slot}"),
+        Place::Upvalue(i) => format!("upvalue {i}"),
+        Place::Global => "global".to_string(),
+    }
+}
+
+// An op's name, its operands, and what they refer to
+fn describe(op: Op, proto: &Proto) -> (String, String, String) {
+    let chunk = &proto.chunk;
+    let debug = format!("{op:?}");
+    let name = debug.split('(').next().unwrap_or(&debug).to_string();
+    let name_of = |index: u32| chunk.name(index).to_string();
+    let target = |to: u32| format!("-> {to:04}");
+
+    let (operands, note) = match op {
+        Op::Const(i) => (i.to_string(), chunk.constants[i as usize].repr()),
+        Op::GetLocal(slot) | Op::SetLocal(slot) => (slot.to_string(), String::new()),
+        Op::GetUpvalue(i) | Op::SetUpvalue(i) => {
+            (i.to_string(), proto.captures[i as usize].name.clone())
+        }
+        Op::GetGlobal(n) | Op::SetGlobal(n) | Op::GetGlobalMut(n) | Op::Writable(n) => {
+            (n.to_string(), name_of(n))
+        }
+        Op::DefineGlobal(n, declaration) => (
+            n.to_string(),
+            format!("{} {}", declaration.keyword(), name_of(n)),
+        ),
+        Op::Fresh(first, count) => (
+            format!("{first} {count}"),
+            format!("slots {first}..{}", first + count),
+        ),
+        Op::Find(n, at) => (n.to_string(), format!("{} in {}", name_of(n), place(at))),
+        Op::Fail(i) => (i.to_string(), chunk.faults[i as usize].message.clone()),
+        Op::Math(math) => (String::new(), math.to_string()),
+        Op::Compare(compare) => (String::new(), compare.to_string()),
+        Op::Jump(to) | Op::JumpIfFalse(to) => (to.to_string(), target(to)),
+        Op::CallNamed(n, argc) => (
+            format!("{n} {argc}"),
+            format!("{}({argc} argument(s))", name_of(n)),
+        ),
+        Op::Call(argc) => (argc.to_string(), format!("({argc} argument(s))")),
+        Op::Guard(n, final_local) => {
+            let root = if final_local { "final local" } else { "global" };
+            (n.to_string(), format!("{} is a {root}", name_of(n)))
+        }
+        Op::List(count) | Op::Map(count) | Op::Concat(count) => (count.to_string(), String::new()),
+        Op::GetField(n, _) | Op::SetField(n, _) => (n.to_string(), format!(".{}", name_of(n))),
+        Op::CheckStruct(i) | Op::MakeStruct(i) => {
+            (i.to_string(), chunk.literals[i as usize].name.clone())
+        }
+        Op::Struct(i) => (
+            i.to_string(),
+            format!("struct {}", chunk.structs[i as usize].0),
+        ),
+        Op::Enum(i) => (i.to_string(), format!("enum {}", chunk.enums[i as usize].0)),
+        Op::Closure(i) => {
+            let name = chunk.protos[i as usize].name.as_deref();
+            (
+                i.to_string(),
+                format!("fn {}", name.unwrap_or("<anonymous>")),
+            )
+        }
+        Op::Iter(two) => (
+            String::new(),
+            if two { "two variables" } else { "one variable" }.to_string(),
+        ),
+        Op::ForNext(state, exit) | Op::RangeNext(state, exit) => (
+            format!("{state} {exit}"),
+            format!("state in slot {state}, done {}", target(exit)),
+        ),
+        Op::Match(table, _) => {
+            let arms = &chunk.matches[table as usize].arms;
+            let targets: Vec<_> = arms
+                .iter()
+                .map(|arm| format!("{:04}", arm.target))
+                .collect();
+            (
+                table.to_string(),
+                format!("{} arm(s) -> {}", arms.len(), targets.join(", ")),
+            )
+        }
+        _ => (String::new(), String::new()),
+    };
+    (name, operands, note)
+}
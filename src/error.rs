@@ -0,0 +1,93 @@
+use std::fmt;
+
+use super::{diagnostic::Diagnostic, span::Span};
+
+// Every way lexing, parsing or running a Crystal program can fail
+#[derive(Debug, Clone, PartialEq)]
+pub enum CrystalError {
+    Lex(Diagnostic),
+    Parse(Diagnostic),
+    Name(Diagnostic),
+    Type(Diagnostic),
+    Mutability(Diagnostic),
+    Runtime(Diagnostic),
+}
+
+impl CrystalError {
+    pub fn lex(message: impl Into<String>, span: Span) -> Self {
+        CrystalError::Lex(Diagnostic::new(message, span))
+    }
+
+    pub fn parse(message: impl Into<String>, span: Span) -> Self {
+        CrystalError::Parse(Diagnostic::new(message, span))
+    }
+
+    pub fn name(message: impl Into<String>, span: Span) -> Self {
+        CrystalError::Name(Diagnostic::new(message, span))
+    }
+
+    pub fn type_error(message: impl Into<String>, span: Span) -> Self {
+        CrystalError::Type(Diagnostic::new(message, span))
+    }
+
+    pub fn mutability(message: impl Into<String>, span: Span) -> Self {
+        CrystalError::Mutability(Diagnostic::new(message, span))
+    }
+
+    pub fn runtime(message: impl Into<String>, span: Span) -> Self {
+        CrystalError::Runtime(Diagnostic::new(message, span))
+    }
+
+    pub fn diagnostic(&self) -> &Diagnostic {
+        match self {
+            CrystalError::Lex(d)
+            | CrystalError::Parse(d)
+            | CrystalError::Name(d)
+            | CrystalError::Type(d)
+            | CrystalError::Mutability(d)
+            | CrystalError::Runtime(d) => d,
+        }
+    }
+
+    pub fn title(&self) -> &'static str {
+        match self {
+            CrystalError::Lex(_) => "CRY.LexError",
+            CrystalError::Parse(_) => "CRY.ParseError",
+            CrystalError::Name(_) => "CRY.NameError",
+            CrystalError::Type(_) => "CRY.TypeError",
+            CrystalError::Mutability(_) => "CRY.MutabilityError",
+            CrystalError::Runtime(_) => "CRY.RuntimeError",
+        }
+    }
+
+    // Process exit code used by `crystal run`; 1 is reserved for CLI/file errors
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            CrystalError::Lex(_) => 2,
+            CrystalError::Parse(_) => 3,
+            CrystalError::Name(_) => 4,
+            CrystalError::Type(_) => 5,
+            CrystalError::Mutability(_) => 6,
+            CrystalError::Runtime(_) => 7,
+        }
+    }
+
+    pub fn render(&self) -> String {
+        self.diagnostic().render(self.title())
+    }
+}
+
+impl fmt::Display for CrystalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let diagnostic = self.diagnostic();
+        write!(
+            f,
+            "{}: {} at {}",
+            self.title(),
+            diagnostic.message,
+            diagnostic.span
+        )
+    }
+}
+
+impl std::error::Error for CrystalError {}
@@ -1,4 +1,11 @@
-#[derive(Debug, PartialEq, Clone)]
+use std::{fmt, rc::Rc};
+
+use super::{
+    error::CrystalError,
+    span::{Source, Span, Spanned},
+};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum MathToken {
     Plus,
     Minus,
@@ -8,48 +15,252 @@ pub enum MathToken {
     MinusEq,
     MultiplyEq,
     DivideEq,
+    // `div`: integer division, truncating toward zero
+    IntDivide,
+    Modulo,
+    Power,
+}
+
+impl fmt::Display for MathToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = match self {
+            MathToken::Plus => "+",
+            MathToken::Minus => "-",
+            MathToken::Divide => "/",
+            MathToken::Multiply => "*",
+            MathToken::PlusEq => "+=",
+            MathToken::MinusEq => "-=",
+            MathToken::MultiplyEq => "*=",
+            MathToken::DivideEq => "/=",
+            MathToken::IntDivide => "div",
+            MathToken::Modulo => "%",
+            MathToken::Power => "**",
+        };
+        f.write_str(symbol)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CompareToken {
+    Equal,
+    NotEqual,
+    Less,
+    LessEq,
+    Greater,
+    GreaterEq,
+}
+
+impl fmt::Display for CompareToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = match self {
+            CompareToken::Equal => "==",
+            CompareToken::NotEqual => "!=",
+            CompareToken::Less => "<",
+            CompareToken::LessEq => "<=",
+            CompareToken::Greater => ">",
+            CompareToken::GreaterEq => ">=",
+        };
+        f.write_str(symbol)
+    }
+}
+
+// Piece of a string literal containing `${...}`
+#[derive(Debug, PartialEq, Clone)]
+pub enum TemplatePart {
+    Text(String),
+    // Tokens of the embedded expression, ending with `Token::Eof`
+    Code(Vec<Spanned<Token>>),
 }
 
 // Token Enum
 #[derive(Debug, PartialEq, Clone)]
 pub enum Token {
     Identifier(String),
-    Number(f64),
+    Int(i64),
+    Float(f64),
     Equals,
+    FatArrow,
     String(String),
+    Template(Vec<TemplatePart>),
     LParen,
     RParen,
+    LBrace,
+    RBrace,
+    LBracket,
+    RBracket,
+    Comma,
+    Colon,
+    Dot,
+    DotDot,
     Semicolon,
-    EOF,
+    Eof,
     Arithmetic(MathToken),
+    Compare(CompareToken),
     Let,
     Final,
+    Fn,
+    Struct,
+    Enum,
+    Match,
+    Return,
+    If,
+    Else,
+    While,
+    For,
+    In,
+    Break,
+    Continue,
+    And,
+    Or,
+    Not,
+    True,
+    False,
+    Nil,
+    // `/// text`, kept for tools that attach docs to declarations; the parser skips it
+    DocComment(String),
+}
+
+impl Token {
+    // Tokens that carry no meaning for the parser
+    pub fn is_trivia(&self) -> bool {
+        matches!(self, Token::DocComment(_))
+    }
+
+    // `x += y` is evaluated as `x = x + y`; this maps the compound operator to its plain one
+    pub fn compound_base(&self) -> Option<Token> {
+        let base = match self {
+            Token::Arithmetic(MathToken::PlusEq) => MathToken::Plus,
+            Token::Arithmetic(MathToken::MinusEq) => MathToken::Minus,
+            Token::Arithmetic(MathToken::MultiplyEq) => MathToken::Multiply,
+            Token::Arithmetic(MathToken::DivideEq) => MathToken::Divide,
+            _ => return None,
+        };
+        Some(Token::Arithmetic(base))
+    }
+}
+
+// How a token is described in error messages
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Identifier(ident) => write!(f, "identifier '{ident}'"),
+            Token::Int(n) => write!(f, "number '{n}'"),
+            Token::Float(n) => write!(f, "number '{n}'"),
+            Token::String(_) | Token::Template(_) => f.write_str("string"),
+            Token::Eof => f.write_str("end of input"),
+            Token::DocComment(_) => f.write_str("doc comment"),
+            Token::Arithmetic(op) => write!(f, "'{op}'"),
+            Token::Compare(op) => write!(f, "'{op}'"),
+            other => {
+                let symbol = match other {
+                    Token::Equals => "=",
+                    Token::FatArrow => "=>",
+                    Token::LParen => "(",
+                    Token::RParen => ")",
+                    Token::LBrace => "{",
+                    Token::RBrace => "}",
+                    Token::LBracket => "[",
+                    Token::RBracket => "]",
+                    Token::Comma => ",",
+                    Token::Colon => ":",
+                    Token::Dot => ".",
+                    Token::DotDot => "..",
+                    Token::Semicolon => ";",
+                    Token::Let => "let",
+                    Token::Final => "final",
+                    Token::Fn => "fn",
+                    Token::Struct => "struct",
+                    Token::Enum => "enum",
+                    Token::Match => "match",
+                    Token::Return => "return",
+                    Token::If => "if",
+                    Token::Else => "else",
+                    Token::While => "while",
+                    Token::For => "for",
+                    Token::In => "in",
+                    Token::Break => "break",
+                    Token::Continue => "continue",
+                    Token::And => "and",
+                    Token::Or => "or",
+                    Token::Not => "not",
+                    Token::True => "true",
+                    Token::False => "false",
+                    _ => "nil",
+                };
+                write!(f, "'{symbol}'")
+            }
+        }
+    }
 }
 
 // Lexer struct
 pub struct Lexer {
+    source: Rc<Source>,
     input: Vec<char>,
     position: usize,
     current_char: Option<char>,
+    line: usize,
+    column: usize,
 }
 
 // Implementation for Crystal Lexer with all lexing functions
 impl Lexer {
-    pub fn new(input: String) -> Self {
+    pub fn new(name: impl Into<String>, input: String) -> Self {
         let mut lexer = Lexer {
             input: input.chars().collect(),
+            source: Source::new(name, input),
             position: 0,
             current_char: None,
+            line: 1,
+            column: 1,
         };
-        lexer.current_char = lexer.input.get(0).cloned();
+        lexer.current_char = lexer.input.first().cloned();
         lexer
     }
 
     pub fn advance(&mut self) {
+        if self.current_char == Some('\n') {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
         self.position += 1;
         self.current_char = self.input.get(self.position).cloned();
     }
 
+    pub fn peek(&self) -> Option<char> {
+        self.input.get(self.position + 1).cloned()
+    }
+
+    // Span from a saved (position, line, column) mark up to the current position
+    fn span_from(&self, (start, line, column): (usize, usize, usize)) -> Span {
+        Span::new(self.source.clone(), start, self.position, line, column)
+    }
+
+    fn mark(&self) -> (usize, usize, usize) {
+        (self.position, self.line, self.column)
+    }
+
+    fn error(&self, message: String, mark: (usize, usize, usize)) -> CrystalError {
+        let mut span = self.span_from(mark);
+        span.end = span.end.max(span.start + 1);
+        CrystalError::lex(message, span)
+    }
+
+    // Lexes the whole input; the last token is always `Token::Eof`
+    pub fn tokenize(&mut self) -> Result<Vec<Spanned<Token>>, CrystalError> {
+        let mut tokens = Vec::new();
+        loop {
+            let token = self.next_token()?;
+            let is_eof = token.node == Token::Eof;
+            tokens.push(token);
+            if is_eof {
+                return Ok(tokens);
+            }
+        }
+    }
+
     pub fn skip_whitespace(&mut self) {
         while let Some(c) = self.current_char {
             if c.is_whitespace() {
@@ -60,12 +271,132 @@ impl Lexer {
         }
     }
 
-    pub fn next_token(&mut self) -> Token {
-        self.skip_whitespace();
-        match self.current_char {
+    // Skips whitespace, `//` line comments and `/* */` block comments, stopping at a `///` doc comment
+    fn skip_trivia(&mut self) -> Result<(), CrystalError> {
+        loop {
+            self.skip_whitespace();
+            match (self.current_char, self.peek()) {
+                (Some('/'), Some('/')) if !self.at_doc_comment() => self.skip_line(),
+                (Some('/'), Some('*')) => self.skip_block_comment()?,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    // Exactly three slashes; `////` and more are plain comments
+    fn at_doc_comment(&self) -> bool {
+        let slashes = self.input[self.position..]
+            .iter()
+            .take_while(|&&c| c == '/')
+            .count();
+        slashes == 3
+    }
+
+    fn skip_line(&mut self) {
+        while let Some(c) = self.current_char {
+            if c == '\n' {
+                break;
+            }
+            self.advance();
+        }
+    }
+
+    // Block comments nest, so `/* a /* b */ c */` is one comment
+    fn skip_block_comment(&mut self) -> Result<(), CrystalError> {
+        let mark = self.mark();
+        let mut depth = 0;
+        loop {
+            match (self.current_char, self.peek()) {
+                (Some('/'), Some('*')) => {
+                    depth += 1;
+                    self.advance();
+                }
+                (Some('*'), Some('/')) => {
+                    depth -= 1;
+                    self.advance();
+                    if depth == 0 {
+                        self.advance();
+                        return Ok(());
+                    }
+                }
+                (None, _) => {
+                    let mut span = self.span_from(mark);
+                    span.end = span.start + 2;
+                    return Err(CrystalError::lex("Unterminated block comment", span));
+                }
+                _ => {}
+            }
+            self.advance();
+        }
+    }
+
+    fn doc_comment(&mut self) -> Token {
+        for _ in 0..3 {
+            self.advance();
+        }
+        if self.current_char == Some(' ') {
+            self.advance();
+        }
+        let start = self.position;
+        self.skip_line();
+        let text: String = self.input[start..self.position].iter().collect();
+        Token::DocComment(text.trim_end().to_string())
+    }
+
+    pub fn next_token(&mut self) -> Result<Spanned<Token>, CrystalError> {
+        self.skip_trivia()?;
+        let mark = self.mark();
+        let token = self.token(mark)?;
+        Ok(Spanned::new(token, self.span_from(mark)))
+    }
+
+    fn token(&mut self, mark: (usize, usize, usize)) -> Result<Token, CrystalError> {
+        let token = match self.current_char {
             Some('=') => {
                 self.advance();
-                Token::Equals
+                match self.current_char {
+                    Some('=') => {
+                        self.advance();
+                        Token::Compare(CompareToken::Equal)
+                    }
+                    Some('>') => {
+                        self.advance();
+                        Token::FatArrow
+                    }
+                    _ => Token::Equals,
+                }
+            }
+            Some('!') if self.peek() == Some('=') => {
+                self.advance();
+                self.advance();
+                Token::Compare(CompareToken::NotEqual)
+            }
+            Some('<') => {
+                self.advance();
+                if let Some('=') = self.current_char {
+                    self.advance();
+                    Token::Compare(CompareToken::LessEq)
+                } else {
+                    Token::Compare(CompareToken::Less)
+                }
+            }
+            Some('>') => {
+                self.advance();
+                if let Some('=') = self.current_char {
+                    self.advance();
+                    Token::Compare(CompareToken::GreaterEq)
+                } else {
+                    Token::Compare(CompareToken::Greater)
+                }
+            }
+            Some('.') if self.peek() == Some('.') => {
+                self.advance();
+                self.advance();
+                Token::DotDot
+            }
+            Some('.') => {
+                self.advance();
+                Token::Dot
             }
             Some('(') => {
                 self.advance();
@@ -75,6 +406,30 @@ impl Lexer {
                 self.advance();
                 Token::RParen
             }
+            Some('{') => {
+                self.advance();
+                Token::LBrace
+            }
+            Some('}') => {
+                self.advance();
+                Token::RBrace
+            }
+            Some('[') => {
+                self.advance();
+                Token::LBracket
+            }
+            Some(']') => {
+                self.advance();
+                Token::RBracket
+            }
+            Some(':') => {
+                self.advance();
+                Token::Colon
+            }
+            Some(',') => {
+                self.advance();
+                Token::Comma
+            }
             Some(';') => {
                 self.advance();
                 Token::Semicolon
@@ -97,6 +452,15 @@ impl Lexer {
                     Token::Arithmetic(MathToken::Minus)
                 }
             }
+            Some('*') if self.peek() == Some('*') => {
+                self.advance();
+                self.advance();
+                Token::Arithmetic(MathToken::Power)
+            }
+            Some('%') => {
+                self.advance();
+                Token::Arithmetic(MathToken::Modulo)
+            }
             Some('*') => {
                 self.advance();
                 if let Some('=') = self.current_char {
@@ -106,6 +470,7 @@ impl Lexer {
                     Token::Arithmetic(MathToken::Multiply)
                 }
             }
+            Some('/') if self.peek() == Some('/') => self.doc_comment(),
             Some('/') => {
                 self.advance();
                 if let Some('=') = self.current_char {
@@ -115,25 +480,154 @@ impl Lexer {
                     Token::Arithmetic(MathToken::Divide)
                 }
             }
-            Some(c) if c.is_alphabetic() => self.identifier(),
-            Some('"') => self.string(),
-            Some(c) if c.is_digit(10) => self.number(),
-            None => Token::EOF,
-            _ => panic!("Unexpected character: {}", self.current_char.unwrap()),
+            Some(c) if c.is_alphabetic() || c == '_' => self.identifier(),
+            Some('"') => return self.string(mark),
+            Some('\'') => return self.char_literal(mark),
+            Some(c) if c.is_ascii_digit() => return self.number(mark),
+            None => Token::Eof,
+            Some(c) => return Err(self.error(format!("Unexpected character '{c}'"), mark)),
+        };
+        Ok(token)
+    }
+
+    // Decimal (`1_000`, `2.5e-3`), hex `0xFF`, octal `0o17` and binary `0b1010` literals
+    pub fn number(&mut self, mark: (usize, usize, usize)) -> Result<Token, CrystalError> {
+        let radix = match (self.current_char, self.peek()) {
+            (Some('0'), Some('x' | 'X')) => 16,
+            (Some('0'), Some('o' | 'O')) => 8,
+            (Some('0'), Some('b' | 'B')) => 2,
+            _ => 10,
+        };
+        if radix != 10 {
+            self.advance();
+            self.advance();
+            let digits = self.digits(radix, mark)?;
+            if digits.is_empty() {
+                let prefix: String = self.input[mark.0..self.position].iter().collect();
+                return Err(self.error(format!("Expected digits after '{prefix}'"), mark));
+            }
+            self.no_suffix(radix, mark)?;
+            return match i64::from_str_radix(&digits, radix) {
+                Ok(n) => Ok(Token::Int(n)),
+                Err(_) => Err(self.error("Integer literal is too large".to_string(), mark)),
+            };
+        }
+
+        let mut number = self.digits(10, mark)?;
+        let mut is_float = false;
+        // A '.' only continues the number when a digit follows, so `0..10` is a range
+        if self.current_char == Some('.') && self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.advance();
+            is_float = true;
+            number.push('.');
+            number.push_str(&self.digits(10, mark)?);
+            if self.current_char == Some('.') && self.peek().is_some_and(|c| c.is_ascii_digit()) {
+                self.skip_literal();
+                let literal: String = self.input[mark.0..self.position].iter().collect();
+                return Err(self.error(format!("Invalid number literal '{literal}'"), mark));
+            }
+        }
+        if let Some(e @ ('e' | 'E')) = self.current_char {
+            self.advance();
+            is_float = true;
+            number.push(e);
+            if let Some(sign @ ('+' | '-')) = self.current_char {
+                self.advance();
+                number.push(sign);
+            }
+            let exponent = self.digits(10, mark)?;
+            if exponent.is_empty() {
+                return Err(self.error("Expected digits in exponent".to_string(), mark));
+            }
+            number.push_str(&exponent);
+        }
+        self.no_suffix(10, mark)?;
+        if !is_float {
+            return match number.parse() {
+                Ok(n) => Ok(Token::Int(n)),
+                Err(_) => Err(self.error("Integer literal is too large".to_string(), mark)),
+            };
+        }
+        match number.parse::<f64>() {
+            Ok(n) if n.is_finite() => Ok(Token::Float(n)),
+            _ => Err(self.error(format!("Invalid number literal '{number}'"), mark)),
+        }
+    }
+
+    // A run of digits in the given radix; `_` may only sit between two digits
+    fn digits(&mut self, radix: u32, mark: (usize, usize, usize)) -> Result<String, CrystalError> {
+        let mut digits = String::new();
+        while let Some(c) = self.current_char {
+            if c.is_digit(radix) {
+                digits.push(c);
+            } else if c == '_' {
+                let between = !digits.is_empty() && self.peek().is_some_and(|n| n.is_digit(radix));
+                if !between {
+                    self.skip_literal();
+                    return Err(self.error(
+                        "Digit separator '_' must sit between two digits".to_string(),
+                        mark,
+                    ));
+                }
+            } else {
+                break;
+            }
+            self.advance();
         }
+        Ok(digits)
     }
 
-    pub fn number(&mut self) -> Token {
-        let mut number = String::new();
+    // Rejects letters or digits glued to the end of a literal, as in `12abc` or `0b102`
+    fn no_suffix(&mut self, radix: u32, mark: (usize, usize, usize)) -> Result<(), CrystalError> {
+        match self.current_char {
+            Some(c) if c.is_ascii_digit() => {
+                self.skip_literal();
+                let kind = if radix == 8 { "octal" } else { "binary" };
+                Err(self.error(format!("Invalid digit '{c}' in {kind} literal"), mark))
+            }
+            Some(c) if c.is_alphanumeric() || c == '_' => {
+                self.skip_literal();
+                let literal: String = self.input[mark.0..self.position].iter().collect();
+                Err(self.error(format!("Invalid number literal '{literal}'"), mark))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Moves past the rest of a malformed literal so the error underlines all of it
+    fn skip_literal(&mut self) {
         while let Some(c) = self.current_char {
-            if c.is_digit(10) || c == '.' {
-                number.push(c);
+            let fraction = c == '.' && self.peek().is_some_and(|n| n.is_ascii_digit());
+            if c.is_alphanumeric() || c == '_' || fraction {
                 self.advance();
             } else {
                 break;
             }
         }
-        Token::Number(number.parse().unwrap())
+    }
+
+    // 'a' or '\n': the character's code point as an integer
+    pub fn char_literal(&mut self, mark: (usize, usize, usize)) -> Result<Token, CrystalError> {
+        self.advance();
+        let c = match self.current_char {
+            Some('\\') => self.escape()?,
+            Some('\'') => return Err(self.error("Empty character literal".to_string(), mark)),
+            Some('\n') | None => {
+                return Err(self.error("Unterminated character literal".to_string(), mark))
+            }
+            Some(c) => {
+                self.advance();
+                c
+            }
+        };
+        if self.current_char != Some('\'') {
+            return Err(self.error(
+                "Character literal must contain exactly one character".to_string(),
+                mark,
+            ));
+        }
+        self.advance();
+        Ok(Token::Int(c as i64))
     }
 
     pub fn identifier(&mut self) -> Token {
@@ -149,24 +643,273 @@ impl Lexer {
         match ident.as_str() {
             "let" => Token::Let,
             "final" => Token::Final,
+            "fn" => Token::Fn,
+            "struct" => Token::Struct,
+            "enum" => Token::Enum,
+            "match" => Token::Match,
+            "return" => Token::Return,
+            "if" => Token::If,
+            "else" => Token::Else,
+            "while" => Token::While,
+            "for" => Token::For,
+            "in" => Token::In,
+            "break" => Token::Break,
+            "continue" => Token::Continue,
+            "and" => Token::And,
+            "or" => Token::Or,
+            "not" => Token::Not,
+            "true" => Token::True,
+            "false" => Token::False,
+            "nil" => Token::Nil,
+            "div" => Token::Arithmetic(MathToken::IntDivide),
             _ => Token::Identifier(ident),
         }
     }
 
-    pub fn string(&mut self) -> Token {
-        let mut value = String::new();
+    // A string literal; one containing `${...}` becomes a Template
+    pub fn string(&mut self, mark: (usize, usize, usize)) -> Result<Token, CrystalError> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
         self.advance();
 
-        while let Some(c) = self.current_char {
-            if c == '"' {
+        loop {
+            match self.current_char {
+                None => return Err(self.error("Unterminated string".to_string(), mark)),
+                Some('"') => {
+                    self.advance();
+                    break;
+                }
+                Some('\\') => text.push(self.escape()?),
+                Some('$') if self.peek() == Some('{') => {
+                    self.advance();
+                    self.advance();
+                    parts.push(TemplatePart::Text(std::mem::take(&mut text)));
+                    parts.push(TemplatePart::Code(self.interpolation(mark)?));
+                }
+                Some(c) => {
+                    text.push(c);
+                    self.advance();
+                }
+            }
+        }
+
+        if parts.is_empty() {
+            return Ok(Token::String(text));
+        }
+        parts.push(TemplatePart::Text(text));
+        parts.retain(|part| *part != TemplatePart::Text(String::new()));
+        Ok(Token::Template(parts))
+    }
+
+    // The tokens of a `${...}` up to its matching '}', which is consumed but not returned
+    fn interpolation(
+        &mut self,
+        mark: (usize, usize, usize),
+    ) -> Result<Vec<Spanned<Token>>, CrystalError> {
+        let mut tokens = Vec::new();
+        let mut depth = 0;
+        loop {
+            let token = self.next_token()?;
+            match token.node {
+                Token::Eof => {
+                    return Err(self.error("Unterminated string interpolation".to_string(), mark))
+                }
+                Token::LBrace => depth += 1,
+                Token::RBrace if depth == 0 => {
+                    tokens.push(Spanned::new(Token::Eof, token.span));
+                    return Ok(tokens);
+                }
+                Token::RBrace => depth -= 1,
+                _ => {}
+            }
+            tokens.push(token);
+        }
+    }
+
+    // Reads one backslash escape inside a string
+    fn escape(&mut self) -> Result<char, CrystalError> {
+        let mark = self.mark();
+        self.advance();
+        let c = match self.current_char {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some('"') => '"',
+            Some('\'') => '\'',
+            Some('\\') => '\\',
+            Some('$') => '$',
+            Some('u') => return self.unicode_escape(mark),
+            Some(c) => {
                 self.advance();
+                return Err(self.error(format!("Unknown escape sequence '\\{c}'"), mark));
+            }
+            None => return Err(self.error("Unterminated string".to_string(), mark)),
+        };
+        self.advance();
+        Ok(c)
+    }
+
+    // \u{1F600}
+    fn unicode_escape(&mut self, mark: (usize, usize, usize)) -> Result<char, CrystalError> {
+        self.advance();
+        if self.current_char != Some('{') {
+            return Err(self.error("Expected '{' after '\\u'".to_string(), mark));
+        }
+        self.advance();
+        let mut hex = String::new();
+        while let Some(c) = self.current_char {
+            if c == '}' {
                 break;
-            } else {
-                value.push(c);
-                self.advance();
             }
+            hex.push(c);
+            self.advance();
+        }
+        if self.current_char != Some('}') {
+            return Err(self.error("Unterminated unicode escape".to_string(), mark));
         }
+        self.advance();
+        u32::from_str_radix(&hex, 16)
+            .ok()
+            .filter(|_| (1..=6).contains(&hex.len()))
+            .and_then(char::from_u32)
+            .ok_or_else(|| self.error(format!("Invalid unicode escape '\\u{{{hex}}}'"), mark))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Lexer, Token};
+
+    fn tokens(source: &str) -> Vec<Token> {
+        let tokens = Lexer::new("test.cry", source.to_string())
+            .tokenize()
+            .expect("the source lexes");
+        tokens.into_iter().map(|token| token.node).collect()
+    }
+
+    // The message of the error lexing `source` gives, and the line, column and width it
+    // underlines
+    fn error(source: &str) -> (String, usize, usize, usize) {
+        let err = Lexer::new("test.cry", source.to_string())
+            .tokenize()
+            .expect_err("the source fails to lex");
+        let diagnostic = err.diagnostic();
+        let span = &diagnostic.span;
+        (
+            diagnostic.message.clone(),
+            span.line,
+            span.column,
+            span.end - span.start,
+        )
+    }
+
+    fn expect(source: &str, message: &str, line: usize, column: usize, width: usize) {
+        let expected = (message.to_string(), line, column, width);
+        assert_eq!(error(source), expected, "lexing {source:?}");
+    }
+
+    #[test]
+    fn malformed_number_literals_underline_the_whole_literal() {
+        expect("let x = 1.2.3;", "Invalid number literal '1.2.3'", 1, 9, 5);
+        expect("0x;", "Expected digits after '0x'", 1, 1, 2);
+        expect("x = 0b;", "Expected digits after '0b'", 1, 5, 2);
+        expect("12abc", "Invalid number literal '12abc'", 1, 1, 5);
+        expect("1e+;", "Expected digits in exponent", 1, 1, 3);
+        expect("1e999", "Invalid number literal '1e999'", 1, 1, 5);
+        expect(
+            "9223372036854775808",
+            "Integer literal is too large",
+            1,
+            1,
+            19,
+        );
+    }
+
+    #[test]
+    fn digit_separators_sit_between_two_digits() {
+        assert_eq!(
+            tokens("1_000 0xFF_FF"),
+            [Token::Int(1000), Token::Int(0xFFFF), Token::Eof]
+        );
+        let message = "Digit separator '_' must sit between two digits";
+        expect("1__000", message, 1, 1, 6);
+        expect("\n  100_;", message, 2, 3, 4);
+        expect("0x_FF", message, 1, 1, 5);
+        expect("1_.5", message, 1, 1, 4);
+    }
+
+    #[test]
+    fn prefixed_literals_reject_digits_of_a_larger_radix() {
+        expect("0b102", "Invalid digit '2' in binary literal", 1, 1, 5);
+        expect("0o78", "Invalid digit '8' in octal literal", 1, 1, 4);
+        expect("0b1z", "Invalid number literal '0b1z'", 1, 1, 4);
+    }
+
+    #[test]
+    fn char_literals_hold_exactly_one_character() {
+        assert_eq!(
+            tokens("'a' '\\n'"),
+            [Token::Int(97), Token::Int(10), Token::Eof]
+        );
+        expect("''", "Empty character literal", 1, 1, 1);
+        expect(
+            "x = 'ab'",
+            "Character literal must contain exactly one character",
+            1,
+            5,
+            2,
+        );
+        expect("'", "Unterminated character literal", 1, 1, 1);
+        expect("'\n'", "Unterminated character literal", 1, 1, 1);
+    }
+
+    #[test]
+    fn string_errors_point_at_the_escape_or_the_opening_quote() {
+        expect("\"a\\qb\"", "Unknown escape sequence '\\q'", 1, 3, 2);
+        expect("\"\\u41\"", "Expected '{' after '\\u'", 1, 2, 2);
+        expect(
+            "\"\\u{110000}\"",
+            "Invalid unicode escape '\\u{110000}'",
+            1,
+            2,
+            10,
+        );
+        expect("let s = \"abc", "Unterminated string", 1, 9, 4);
+        expect("\"${x", "Unterminated string interpolation", 1, 1, 4);
+        expect(
+            "\"a ${ \"b\" ",
+            "Unterminated string interpolation",
+            1,
+            1,
+            10,
+        );
+    }
+
+    #[test]
+    fn block_comments_nest() {
+        assert_eq!(tokens("/* a /* b */ c */ 1"), [Token::Int(1), Token::Eof]);
+        expect("1 /* a /* b */", "Unterminated block comment", 1, 3, 2);
+        expect("/* a */\n/*", "Unterminated block comment", 2, 1, 2);
+    }
 
-        Token::String(value)
+    #[test]
+    fn only_three_slashes_make_a_doc_comment() {
+        assert_eq!(
+            tokens("/// Adds one  \n// plain\n//// also plain\nfn"),
+            [
+                Token::DocComment("Adds one".to_string()),
+                Token::Fn,
+                Token::Eof
+            ]
+        );
+        assert_eq!(
+            tokens("///\n///no space"),
+            [
+                Token::DocComment(String::new()),
+                Token::DocComment("no space".to_string()),
+                Token::Eof
+            ]
+        );
     }
 }
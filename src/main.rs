@@ -1,107 +1,332 @@
 use colored::*;
 use std::{
-    collections::HashMap,
+    env,
     env::args,
     fs::{self, read_to_string},
-    process::exit,
+    path::Path,
+    process::{self, exit},
+    rc::Rc,
+    thread,
 };
 
-use lexer::{Lexer, MathToken, Token};
-use memories::{ast_to_memory, binary_op, Memory};
-use parser::{ASTNode, Parser};
+use builtins::Io;
+use bytecode::Proto;
+use compiler::compile;
+use diagnostic::Diagnostic;
+use error::CrystalError;
+use lexer::Lexer;
+use memories::{limit_call_depth, run_program, Env, MAX_CALL_DEPTH};
+use parser::{Node, Parser};
+use repl::Repl;
+use vm::Vm;
 
+mod builtins;
+mod bytecode;
+mod cgen;
+mod compiler;
+mod cryc;
+mod diagnostic;
+mod disasm;
+mod error;
 mod lexer;
 mod memories;
 mod parser;
+mod patterns;
+mod repl;
+mod span;
+mod vm;
+mod wasm;
+
+// Recursion in a Crystal program recurses in the interpreter too, so programs run on a
+// roomy stack, or on a smaller one with fewer nested calls where the system won't give
+// that much
+const INTERPRETER_STACK_SIZES: [usize; 4] = [512 << 20, 256 << 20, 128 << 20, 64 << 20];
 
 fn get_args() -> Vec<String> {
     let run_args: Vec<String> = args().collect();
     run_args[1..run_args.len()].to_vec()
 }
 
-fn run(path: String) {
+fn run(path: String, debug: bool, on_vm: bool) {
+    if path.ends_with(".cryc") {
+        return run_bytecode(path, debug);
+    }
     let file = read_to_string(path.clone());
-    if let Result::Err(_) = file {
+    if file.is_err() {
         println!(
-            "{}",
-            format!(
-                "{ce}{path}{ca}",
-                ce = "CRYSTAL.Error: File '".bright_red(),
-                ca = "' not found.".bright_red(),
-                path = path.clone().bright_yellow(),
-            )
+            "{ce}{path}{ca}",
+            ce = "CRYSTAL.Error: File '".bright_red(),
+            ca = "' not found.".bright_red(),
+            path = path.clone().bright_yellow(),
         );
         exit(1)
     }
-    let mut lexer = Lexer::new(file.expect("CRYSTAL.Error: File error."));
-    let mut tokens = Vec::new();
-    while let token = lexer.next_token() {
-        if token == Token::EOF {
-            break;
+
+    let ast = match parse(path, file.expect("CRYSTAL.Error: File error.")) {
+        Ok(ast) => ast,
+        Err(errors) => report(&errors),
+    };
+    let executed = if on_vm {
+        execute_vm(&ast, Io::default())
+    } else {
+        execute(&ast, Io::default())
+    };
+    match executed {
+        Ok(virtual_brain) => {
+            if debug {
+                println!("{:#?}", virtual_brain.scope.borrow().context);
+                println!("{ast:#?}");
+            }
         }
-        tokens.push(token);
-    }
-
-    let mut parser = Parser::new(tokens);
-    let ast = parser.parse();
-    let mut virtual_brain: HashMap<String, Box<Memory>> = HashMap::new();
-    if let ASTNode::Program(nodes) = &ast {
-        for node in nodes {
-            match node {
-                ASTNode::Let(ident, val) => {
-                    virtual_brain.insert(
-                        ident.to_string(),
-                        Box::new(ast_to_memory(*val.clone(), true, &virtual_brain)),
-                    );
-                }
-                ASTNode::Final(ident, val) => {
-                    virtual_brain.insert(
-                        ident.to_string(),
-                        Box::new(ast_to_memory(*val.clone(), false, &virtual_brain)),
-                    );
-                }
-                ASTNode::CompoundAssign { ident, op, value } => {
-                    if let Some(mem) = virtual_brain.get(ident) {
-                        if let Memory::Number(n, is_mut) = &**mem {
-                            if *is_mut {
-                                println!("{op:?}");
-                                let new_value = match op {
-                                    Token::Arithmetic(MathToken::PlusEq) => {
-                                        n + binary_op(*value.clone(), &virtual_brain)
-                                    }
-                                    Token::Arithmetic(MathToken::MinusEq) => {
-                                        n - binary_op(*value.clone(), &virtual_brain)
-                                    }
-                                    Token::Arithmetic(MathToken::MultiplyEq) => {
-                                        n * binary_op(*value.clone(), &virtual_brain)
-                                    }
-                                    Token::Arithmetic(MathToken::DivideEq) => {
-                                        n / binary_op(*value.clone(), &virtual_brain)
-                                    }
-                                    _ => panic!("CRY.ERROR: Invalid binary operation"),
-                                };
-
-                                virtual_brain.insert(
-                                    ident.to_string(),
-                                    Box::new(Memory::Number(new_value, *is_mut)),
-                                );
-                            } else {
-                                panic!("CRY.ERROR: Cannot modify a final variable");
-                            }
-                        } else {
-                            panic!("CRY.ERROR: Invalid memory type found for compound assignment");
-                        }
-                    } else {
-                        panic!("CRY.ERROR: Memory '{ident}' not found");
-                    }
-                }
-                _ => {}
+        Err(err) => report(&[err]),
+    }
+}
+
+// Reads a program built by `crystal build`, or exits explaining why it can't be used
+fn load_bytecode(path: &str, action: &str) -> Rc<Proto> {
+    let script = match fs::read(path) {
+        Ok(bytes) => cryc::decode(&bytes),
+        Err(_) => Err("file not found".to_string()),
+    };
+    script.unwrap_or_else(|reason| {
+        println!(
+            "{}",
+            format!("CRYSTAL.Error: Cannot {action} '{path}': {reason}.").bright_red()
+        );
+        exit(1)
+    })
+}
+
+// Runs a program built by `crystal build`, without lexing or parsing it again
+fn run_bytecode(path: String, debug: bool) {
+    let script = load_bytecode(&path, "run");
+    let mut vm = Vm::new(Io::default());
+    match vm.run(script) {
+        Ok(()) => {
+            if debug {
+                println!("{:#?}", vm.env.scope.borrow().context);
             }
         }
+        Err(err) => report(&[err]),
     }
+}
 
-    println!("{virtual_brain:#?}");
-    println!("{ast:#?}");
+// Compiles a .cry file to bytecode and writes it where `crystal run` can load it
+fn build(path: String, output: Option<String>) {
+    let Ok(source) = read_to_string(&path) else {
+        println!(
+            "{}",
+            format!("CRYSTAL.Error: File '{path}' not found.").bright_red()
+        );
+        exit(1)
+    };
+    let ast = match parse(path.clone(), source) {
+        Ok(ast) => ast,
+        Err(errors) => report(&errors),
+    };
+    let output = output.unwrap_or_else(|| {
+        let stem = path.strip_suffix(".cry").unwrap_or(&path);
+        format!("{stem}.cryc")
+    });
+    let bytes = cryc::encode(&compile(&ast), &ast.span.source);
+    if let Err(err) = fs::write(&output, bytes) {
+        println!(
+            "{}",
+            format!("CRYSTAL.Error: Cannot write '{output}': {err}.").bright_red()
+        );
+        exit(1)
+    }
+    println!("{}", format!("Built '{output}'").cyan());
+}
+
+// Prints the bytecode of a .cry file, or of a .cryc file as it was built
+fn disassemble(path: String) {
+    let script = if path.ends_with(".cryc") {
+        load_bytecode(&path, "disassemble")
+    } else {
+        let Ok(source) = read_to_string(&path) else {
+            println!(
+                "{}",
+                format!("CRYSTAL.Error: File '{path}' not found.").bright_red()
+            );
+            exit(1)
+        };
+        match parse(path, source) {
+            Ok(ast) => compile(&ast),
+            Err(errors) => report(&errors),
+        }
+    };
+    print!("{}", disasm::disassemble(&script));
+}
+
+// Reads and parses a .cry file for a backend, or exits reporting why it can't
+fn parse_file(path: &str) -> Node {
+    let Ok(source) = read_to_string(path) else {
+        println!(
+            "{}",
+            format!("CRYSTAL.Error: File '{path}' not found.").bright_red()
+        );
+        exit(1)
+    };
+    match parse(path.to_string(), source) {
+        Ok(ast) => ast,
+        Err(errors) => report(&errors),
+    }
+}
+
+// Lists every construct a backend can't handle and exits
+fn report_unsupported(unsupported: &[Diagnostic]) -> ! {
+    for diagnostic in unsupported {
+        eprintln!("{}\n", diagnostic.render("CRY.UnsupportedError"));
+    }
+    if unsupported.len() > 1 {
+        eprintln!(
+            "{}",
+            format!("CRYSTAL.Error: {} errors found.", unsupported.len()).bright_red()
+        );
+    }
+    exit(1)
+}
+
+// Lowers a .cry file to C
+fn transpile(path: &str) -> String {
+    cgen::emit(&parse_file(path))
+}
+
+// Writes C source and the runtime header it includes into one directory
+fn write_c(c_path: &Path, c: &str) {
+    let header = c_path.with_file_name(cgen::RUNTIME_HEADER);
+    for (path, text) in [(c_path, c), (header.as_path(), cgen::RUNTIME)] {
+        if let Err(err) = fs::write(path, text) {
+            println!(
+                "{}",
+                format!("CRYSTAL.Error: Cannot write '{}': {err}.", path.display()).bright_red()
+            );
+            exit(1)
+        }
+    }
+}
+
+// Writes a .cry file as C99 source, next to the runtime header it needs
+fn emit_c(path: String, output: Option<String>) {
+    let c = transpile(&path);
+    let output = output.unwrap_or_else(|| {
+        let stem = path.strip_suffix(".cry").unwrap_or(&path);
+        format!("{stem}.c")
+    });
+    write_c(Path::new(&output), &c);
+    println!(
+        "{}",
+        format!("Emitted '{output}' and its '{}'", cgen::RUNTIME_HEADER).cyan()
+    );
+}
+
+// Builds a native executable from a .cry file with the system C compiler ($CC, or cc)
+fn compile_native(path: String, output: Option<String>) {
+    let c = transpile(&path);
+    let output = output.unwrap_or_else(|| {
+        let stem = path.strip_suffix(".cry").unwrap_or(&path);
+        stem.to_string()
+    });
+    let dir = env::temp_dir().join(format!("crystal-native-{}", process::id()));
+    let c_path = dir.join("program.c");
+    if let Err(err) = fs::create_dir_all(&dir) {
+        println!(
+            "{}",
+            format!("CRYSTAL.Error: Cannot write '{}': {err}.", dir.display()).bright_red()
+        );
+        exit(1)
+    }
+    write_c(&c_path, &c);
+    let cc = env::var("CC").unwrap_or_else(|_| String::from("cc"));
+    let status = process::Command::new(&cc)
+        .args(["-std=c99", "-O2", "-o", &output])
+        .arg(&c_path)
+        .arg("-lm")
+        .status();
+    fs::remove_dir_all(&dir).ok();
+    match status {
+        Ok(status) if status.success() => {
+            println!("{}", format!("Compiled '{output}'").cyan());
+        }
+        Ok(_) => {
+            println!(
+                "{}",
+                format!("CRYSTAL.Error: '{cc}' could not compile '{path}'.").bright_red()
+            );
+            exit(1)
+        }
+        Err(err) => {
+            println!(
+                "{}",
+                format!("CRYSTAL.Error: Cannot run the C compiler '{cc}': {err}.").bright_red()
+            );
+            exit(1)
+        }
+    }
+}
+
+// Writes the numeric subset of a .cry file as a WebAssembly module, or as its text
+// format with --wat
+fn emit_wasm(path: String, output: Option<String>, text: bool) {
+    let module = wasm::emit(&parse_file(&path))
+        .unwrap_or_else(|unsupported| report_unsupported(&unsupported));
+    let output = output.unwrap_or_else(|| {
+        let stem = path.strip_suffix(".cry").unwrap_or(&path);
+        format!("{stem}.{}", if text { "wat" } else { "wasm" })
+    });
+    let bytes = if text {
+        module.text().into_bytes()
+    } else {
+        module.binary()
+    };
+    if let Err(err) = fs::write(&output, bytes) {
+        println!(
+            "{}",
+            format!("CRYSTAL.Error: Cannot write '{output}': {err}.").bright_red()
+        );
+        exit(1)
+    }
+    println!("{}", format!("Emitted '{output}'").cyan());
+}
+
+// Prints every error and exits with the code of the first one
+fn report(errors: &[CrystalError]) -> ! {
+    for err in errors {
+        eprintln!("{}\n", err.render());
+    }
+    if errors.len() > 1 {
+        eprintln!(
+            "{}",
+            format!("CRYSTAL.Error: {} errors found.", errors.len()).bright_red()
+        );
+    }
+    exit(errors.first().map_or(1, CrystalError::exit_code))
+}
+
+// Lexes and parses a whole program, collecting every syntax error in it and every match
+// in a function that misses arms
+fn parse(name: String, source: String) -> Result<Node, Vec<CrystalError>> {
+    let tokens = Lexer::new(name, source)
+        .tokenize()
+        .map_err(|err| vec![err])?;
+    let ast = Parser::new(tokens).parse()?;
+    patterns::check(&ast)?;
+    Ok(ast)
+}
+
+// Runs a parsed program against the given stdout/stdin, returning its final memory
+fn execute(ast: &Node, io: Io) -> Result<Env, CrystalError> {
+    let mut virtual_brain = Env::with_io(io);
+    run_program(ast, &mut virtual_brain)?;
+    Ok(virtual_brain)
+}
+
+// Compiles a parsed program to bytecode and runs it on the VM, returning its globals
+fn execute_vm(ast: &Node, io: Io) -> Result<Env, CrystalError> {
+    let mut vm = Vm::new(io);
+    vm.run(compile(ast))?;
+    Ok(vm.env)
 }
 
 fn unknown_cmd(cmd: String) {
@@ -124,8 +349,35 @@ fn help() {
 {title}
 Command List:
 
-{run_cmd} {path_q}
+{run_cmd} {path_q} {debug_q} {vm_q}
 - run a .cry file. if path unspecified, runs ./app.cry
+- --debug prints the final memory and AST afterwards.
+- --vm compiles to bytecode and runs it on the VM instead.
+
+{build_cmd} {path_q} {output_q}
+- compile a .cry file to a .cryc bytecode file.
+- if output unspecified, writes next to the source.
+- run it with 'crystal run FILE.cryc', which skips parsing.
+
+{disasm_cmd} {path_q}
+- print the bytecode of a .cry or .cryc file, function by function:
+- offset, source line, op, operands and what they refer to.
+
+{emit_c_cmd} {path_q} {output_q}
+- write a .cry file as C99 source, plus the crystal.h runtime it includes.
+
+{emit_wasm_cmd} {path_q} {output_q} {wat_q}
+- write the numeric subset of a .cry file as a WebAssembly module.
+- ints, floats, bools, functions and control flow; each top-level final
+  is an exported global. --wat writes the text format instead.
+
+{compile_cmd} {native_q} {path_q} {output_q}
+- build a native executable from a .cry file with the system C compiler.
+- uses $CC if set, else cc.
+
+{repl_cmd}
+- start an interactive CRYSTAL session.
+- type :help inside it for REPL commands.
 
 {new_cmd} {name_q}
 - create a new CRYSTAL project. 
@@ -137,48 +389,156 @@ Command List:
 - head to https://github.com/smarbo/crystal-lang
 ",
         run_cmd = "crystal run".bold().green(),
+        build_cmd = "crystal build".bold().green(),
+        disasm_cmd = "crystal disasm".bold().green(),
+        emit_c_cmd = "crystal emit-c".bold().green(),
+        emit_wasm_cmd = "crystal emit-wasm".bold().green(),
+        compile_cmd = "crystal compile".bold().green(),
+        repl_cmd = "crystal repl".bold().magenta(),
         new_cmd = "crystal new".bold().blue(),
         help_cmd = "crystal help".bold().yellow(),
         path_q = "?PATH?".bold().blink(),
+        debug_q = "?--debug?".bold().blink(),
+        vm_q = "?--vm?".bold().blink(),
+        native_q = "--native".bold(),
+        wat_q = "?--wat?".bold().blink(),
         name_q = "?NAME?".bold().blink(),
+        output_q = "?-o OUTPUT?".bold().blink(),
         title = "Welcome to CRYSTAL-Lang.".bold().cyan(),
     );
-    println!("{}", format!("{help_text}"));
+    println!("{help_text}");
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 enum Command {
-    Run(String),
+    Run(String, bool, bool),
+    Build(String, Option<String>),
+    Disasm(String),
+    EmitC(String, Option<String>),
+    EmitWasm(String, Option<String>, bool),
+    Compile(String, Option<String>, bool),
+    Repl,
     New(String),
     None,
-    Unknown,
+    Unknown(String),
+}
+
+// The source path and the value of -o of `build`-like commands
+fn path_and_output(run_args: &[String]) -> (String, Option<String>) {
+    let output = run_args
+        .iter()
+        .position(|arg| arg == "-o")
+        .and_then(|i| run_args.get(i + 1))
+        .cloned();
+    let path = run_args[1..]
+        .iter()
+        .enumerate()
+        // Skip flags and the value of -o; run_args[i] is the arg before
+        .find(|(i, arg)| !arg.starts_with('-') && run_args[*i] != "-o")
+        .map(|(_, arg)| arg.clone())
+        .unwrap_or_else(|| String::from("app.cry"));
+    (path, output)
 }
 
 fn main() {
     let run_args = get_args();
-    let cmd = if run_args.len() >= 1 {
+    let cmd = if !run_args.is_empty() {
         match run_args[0].as_str() {
-            "run" => Command::Run(if run_args.len() > 1 {
-                run_args[1].clone()
-            } else {
-                String::from("app.cry")
-            }),
+            "run" => {
+                let debug = run_args.iter().any(|arg| arg == "--debug");
+                let on_vm = run_args.iter().any(|arg| arg == "--vm");
+                let path = run_args[1..]
+                    .iter()
+                    .find(|arg| !arg.starts_with("--"))
+                    .cloned()
+                    .unwrap_or_else(|| String::from("app.cry"));
+                Command::Run(path, debug, on_vm)
+            }
+            "build" => {
+                let (path, output) = path_and_output(&run_args);
+                Command::Build(path, output)
+            }
+            "emit-c" => {
+                let (path, output) = path_and_output(&run_args);
+                Command::EmitC(path, output)
+            }
+            "emit-wasm" => {
+                let (path, output) = path_and_output(&run_args);
+                let text = run_args.iter().any(|arg| arg == "--wat");
+                Command::EmitWasm(path, output, text)
+            }
+            "compile" => {
+                let (path, output) = path_and_output(&run_args);
+                let native = run_args.iter().any(|arg| arg == "--native");
+                Command::Compile(path, output, native)
+            }
+            "disasm" => Command::Disasm(
+                run_args
+                    .get(1)
+                    .cloned()
+                    .unwrap_or_else(|| String::from("app.cry")),
+            ),
+            "repl" => Command::Repl,
             "new" => Command::New(if run_args.len() > 1 {
                 run_args[1].clone()
             } else {
                 String::from("untitled_app")
             }),
             "help" => Command::None,
-            _ => Command::Unknown,
+            other => Command::Unknown(other.to_string()),
         }
     } else {
         Command::None
     };
 
     match cmd {
-        Command::Run(f) => run(f),
+        Command::Run(..) | Command::Repl => on_interpreter_stack(cmd),
+        cmd => dispatch(cmd),
+    }
+}
+
+fn dispatch(cmd: Command) {
+    match cmd {
+        Command::Run(f, debug, on_vm) => run(f, debug, on_vm),
+        Command::Build(path, output) => build(path, output),
+        Command::Disasm(path) => disassemble(path),
+        Command::EmitC(path, output) => emit_c(path, output),
+        Command::EmitWasm(path, output, text) => emit_wasm(path, output, text),
+        Command::Compile(path, output, true) => compile_native(path, output),
+        Command::Compile(..) => {
+            println!(
+                "{}",
+                "CRYSTAL.Error: 'crystal compile' only builds native executables for now: \
+                 pass --native, or use 'crystal build' for bytecode."
+                    .bright_red()
+            );
+            exit(1)
+        }
+        Command::Repl => Repl::new().run(),
         Command::New(name) => new_project(name),
-        Command::Unknown => unknown_cmd(run_args[0].clone()),
+        Command::Unknown(cmd) => unknown_cmd(cmd),
         Command::None => help(),
     }
 }
+
+// Runs a command that interprets Crystal code on the biggest stack the system allows
+fn on_interpreter_stack(cmd: Command) {
+    for size in INTERPRETER_STACK_SIZES {
+        limit_call_depth(MAX_CALL_DEPTH / (INTERPRETER_STACK_SIZES[0] / size));
+        let task = cmd.clone();
+        let spawned = thread::Builder::new()
+            .stack_size(size)
+            .spawn(move || dispatch(task));
+        if let Ok(interpreter) = spawned {
+            if interpreter.join().is_err() {
+                exit(101)
+            }
+            return;
+        }
+    }
+    println!(
+        "{}",
+        "CRYSTAL.Error: Not enough memory to start the interpreter.".bright_red()
+    );
+    exit(1)
+}
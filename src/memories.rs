@@ -1,114 +1,1553 @@
-use std::collections::HashMap;
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fmt,
+    rc::Rc,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 use super::{
-    lexer::{MathToken, Token},
-    parser::ASTNode,
+    builtins::{self, Io, Runtime},
+    error::CrystalError,
+    lexer::{CompareToken, MathToken, Token},
+    parser::{ASTNode, Node},
+    patterns,
+    span::Span,
+    vm::Closure,
 };
-#[derive(Debug)]
+
+#[derive(Clone)]
 pub enum Memory {
-    Number(f64, bool),
-    String(String, bool),
-}
-
-pub type Context = HashMap<String, Box<Memory>>;
-
-pub fn binary_op(bop: ASTNode, context: &Context) -> f64 {
-    println!("Executing binary operation.");
-    if let ASTNode::BinaryOp { left, op, right } = bop {
-        if let ASTNode::Number(x) = *left {
-            if let ASTNode::Number(y) = *right {
-                match op {
-                    Token::Arithmetic(MathToken::Plus) => x + y,
-                    Token::Arithmetic(MathToken::Minus) => x - y,
-                    Token::Arithmetic(MathToken::Divide) => x / y,
-                    Token::Arithmetic(MathToken::Multiply) => x * y,
-                    _ => panic!("CRY.ERROR: Invalid binary operation"),
+    Int(i64),
+    Float(f64),
+    String(String),
+    Bool(bool),
+    Function(Rc<Function>),
+    // A function compiled for the VM
+    Closure(Rc<Closure>),
+    // A builtin used as a value, e.g. `map(xs, len)`, found again by name when called
+    Builtin(String),
+    // Shared by reference, so `push` through one name is seen through every other
+    List(Rc<RefCell<Vec<Memory>>>),
+    // Shared by reference like lists
+    Map(Rc<RefCell<Dict>>),
+    // What a `struct` declaration binds its name to
+    StructType(Rc<StructType>),
+    // Shared by reference like lists
+    Struct(Rc<RefCell<Instance>>),
+    // What an `enum` declaration binds its name to
+    Enum(Rc<EnumType>),
+    // A variant with fields, called like a function to build a value
+    Constructor(Rc<EnumType>, usize),
+    Variant(Rc<Variant>),
+    Nil,
+}
+
+impl Memory {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Memory::Int(_) => "int",
+            Memory::Float(_) => "float",
+            Memory::String(_) => "string",
+            Memory::Bool(_) => "bool",
+            Memory::Function(_) | Memory::Closure(_) | Memory::Builtin(_) => "function",
+            Memory::List(_) => "list",
+            Memory::Map(_) => "map",
+            Memory::StructType(_) => "struct type",
+            Memory::Struct(_) => "struct",
+            Memory::Enum(_) => "enum",
+            Memory::Constructor(..) => "variant constructor",
+            Memory::Variant(_) => "variant",
+            Memory::Nil => "nil",
+        }
+    }
+
+    // Only `false` and `nil` count as false in conditions and logic
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Memory::Bool(false) | Memory::Nil)
+    }
+
+    pub fn list(items: Vec<Memory>) -> Memory {
+        Memory::List(Rc::new(RefCell::new(items)))
+    }
+
+    pub fn map(map: Dict) -> Memory {
+        Memory::Map(Rc::new(RefCell::new(map)))
+    }
+
+    // Either kind of number as a float, for mixed arithmetic and comparisons
+    pub fn as_float(&self) -> Option<f64> {
+        match self {
+            Memory::Int(n) => Some(*n as f64),
+            Memory::Float(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    // How the value is written in Crystal source, e.g. strings keep their quotes
+    pub fn repr(&self) -> String {
+        self.show(true, &mut Vec::new())
+    }
+
+    // The value as text, quoting strings if `quoted`. `seen` holds the lists, maps and
+    // structs being shown, so one that holds itself shows as `[...]` where it repeats.
+    fn show(&self, quoted: bool, seen: &mut Vec<*const ()>) -> String {
+        match self {
+            Memory::String(s) if quoted => format!("{s:?}"),
+            Memory::List(items) => nested(address(items), "[...]".to_string(), seen, |seen| {
+                let items: Vec<_> = items
+                    .borrow()
+                    .iter()
+                    .map(|item| item.show(true, seen))
+                    .collect();
+                format!("[{}]", items.join(", "))
+            }),
+            Memory::Map(map) => nested(address(map), "{...}".to_string(), seen, |seen| {
+                let entries: Vec<_> = map
+                    .borrow()
+                    .iter()
+                    .map(|(key, value)| format!("{key:?}: {}", value.show(true, seen)))
+                    .collect();
+                format!("{{{}}}", entries.join(", "))
+            }),
+            Memory::Struct(shared) => {
+                let instance = shared.borrow();
+                let repeat = format!("{} {{ ... }}", instance.name);
+                nested(address(shared), repeat, seen, |seen| {
+                    let fields: Vec<_> = instance
+                        .fields
+                        .iter()
+                        .map(|(field, value)| format!("{field}: {}", value.show(true, seen)))
+                        .collect();
+                    format!("{} {{ {} }}", instance.name, fields.join(", "))
+                })
+            }
+            Memory::Variant(variant) if !variant.values.is_empty() => {
+                let values: Vec<_> = variant
+                    .values
+                    .iter()
+                    .map(|value| value.show(true, seen))
+                    .collect();
+                format!("{}({})", variant.name(), values.join(", "))
+            }
+            other => other.to_string(),
+        }
+    }
+}
+
+// Where a shared value lives, which identifies it whatever its contents
+fn address<T>(shared: &Rc<T>) -> *const () {
+    Rc::as_ptr(shared) as *const ()
+}
+
+// Shows a list, map or struct with `inner`, or as `repeat` inside itself
+fn nested(
+    address: *const (),
+    repeat: String,
+    seen: &mut Vec<*const ()>,
+    inner: impl FnOnce(&mut Vec<*const ()>) -> String,
+) -> String {
+    if seen.contains(&address) {
+        return repeat;
+    }
+    seen.push(address);
+    let shown = inner(seen);
+    seen.pop();
+    shown
+}
+
+// Values compare by contents, except functions, which are only equal to themselves
+impl PartialEq for Memory {
+    fn eq(&self, other: &Self) -> bool {
+        equal(self, other, &mut HashSet::new())
+    }
+}
+
+// The pairs of lists, maps and structs already being compared. They are taken to be equal
+// there, so values that hold themselves compare without recursing forever.
+type Pairs = HashSet<(*const (), *const ())>;
+
+fn equal(a: &Memory, b: &Memory, seen: &mut Pairs) -> bool {
+    match (a, b) {
+        (Memory::Int(x), Memory::Int(y)) => x == y,
+        (Memory::Float(x), Memory::Float(y)) => x == y,
+        // 1 == 1.0: numbers compare by value whichever kind they are, in lists and maps too
+        (Memory::Int(x), Memory::Float(y)) | (Memory::Float(y), Memory::Int(x)) => *x as f64 == *y,
+        (Memory::String(x), Memory::String(y)) => x == y,
+        (Memory::Bool(x), Memory::Bool(y)) => x == y,
+        (Memory::Function(x), Memory::Function(y)) => x == y,
+        (Memory::Closure(x), Memory::Closure(y)) => x == y,
+        (Memory::Builtin(x), Memory::Builtin(y)) => x == y,
+        (Memory::List(x), Memory::List(y)) => {
+            Rc::ptr_eq(x, y) || compared(address(x), address(y), seen) || {
+                let (x, y) = (x.borrow(), y.borrow());
+                x.len() == y.len() && x.iter().zip(y.iter()).all(|(x, y)| equal(x, y, seen))
+            }
+        }
+        (Memory::Map(x), Memory::Map(y)) => {
+            Rc::ptr_eq(x, y)
+                || compared(address(x), address(y), seen)
+                || same_entries(&x.borrow(), &y.borrow(), seen)
+        }
+        (Memory::StructType(x), Memory::StructType(y)) => x == y,
+        (Memory::Struct(x), Memory::Struct(y)) => {
+            Rc::ptr_eq(x, y) || compared(address(x), address(y), seen) || {
+                let (x, y) = (x.borrow(), y.borrow());
+                x.name == y.name && same_entries(&x.fields, &y.fields, seen)
+            }
+        }
+        (Memory::Enum(x), Memory::Enum(y)) => x == y,
+        (Memory::Constructor(x, i), Memory::Constructor(y, j)) => x == y && i == j,
+        // Variants of two separately declared enums are never equal, even with the same names
+        (Memory::Variant(x), Memory::Variant(y)) => {
+            Rc::ptr_eq(&x.def, &y.def)
+                && x.index == y.index
+                && x.values
+                    .iter()
+                    .zip(&y.values)
+                    .all(|(x, y)| equal(x, y, seen))
+        }
+        (Memory::Nil, Memory::Nil) => true,
+        _ => false,
+    }
+}
+
+// Whether two containers are already being compared, noting them if not
+fn compared(x: *const (), y: *const (), seen: &mut Pairs) -> bool {
+    !seen.insert((x, y))
+}
+
+// Maps with the same entries are equal whatever order they were built in
+fn same_entries(x: &Dict, y: &Dict, seen: &mut Pairs) -> bool {
+    x.len() == y.len()
+        && x.iter()
+            .all(|(key, value)| y.get(key).is_some_and(|other| equal(value, other, seen)))
+}
+
+// The kind of value around how it is written, which stays finite for a list that holds itself
+impl fmt::Debug for Memory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            Memory::Int(_) => "Int",
+            Memory::Float(_) => "Float",
+            Memory::String(_) => "String",
+            Memory::Bool(_) => "Bool",
+            Memory::Function(_) => "Function",
+            Memory::Closure(_) => "Closure",
+            Memory::Builtin(_) => "Builtin",
+            Memory::List(_) => "List",
+            Memory::Map(_) => "Map",
+            Memory::StructType(_) => "StructType",
+            Memory::Struct(_) => "Struct",
+            Memory::Enum(_) => "Enum",
+            Memory::Constructor(..) => "Constructor",
+            Memory::Variant(_) => "Variant",
+            Memory::Nil => return f.write_str("Nil"),
+        };
+        write!(f, "{kind}({})", self.repr())
+    }
+}
+
+impl fmt::Display for Memory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Memory::Int(n) => write!(f, "{n}"),
+            // Very large and very small floats use exponent form, as in `1e16` or `5e-324`
+            Memory::Float(n) if n.is_finite() && *n != 0.0 && !(1e-4..1e16).contains(&n.abs()) => {
+                write!(f, "{n:e}")
+            }
+            // Whole floats keep a `.0` so they can't be mistaken for ints
+            Memory::Float(n) if n.fract() == 0.0 => write!(f, "{n:.1}"),
+            Memory::Float(n) => write!(f, "{n}"),
+            Memory::String(s) => f.write_str(s),
+            Memory::Bool(b) => write!(f, "{b}"),
+            Memory::Function(func) => write!(f, "{func:?}"),
+            Memory::Closure(closure) => write!(f, "{closure:?}"),
+            Memory::Builtin(name) => write!(f, "<builtin {name}>"),
+            Memory::StructType(def) => write!(f, "<struct {}>", def.name),
+            Memory::Enum(def) => write!(f, "<enum {}>", def.name),
+            Memory::Constructor(def, index) => write!(f, "<variant {}>", def.variants[*index].0),
+            Memory::Variant(variant) if variant.values.is_empty() => f.write_str(variant.name()),
+            Memory::Nil => f.write_str("nil"),
+            nested => f.write_str(&nested.show(false, &mut Vec::new())),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct StructType {
+    pub name: String,
+    pub fields: Vec<String>,
+}
+
+// An enum's variants, each with its field names
+#[derive(Debug, PartialEq)]
+pub struct EnumType {
+    pub name: String,
+    pub variants: Vec<(String, Vec<String>)>,
+}
+
+// A value of an enum: which variant it is, and the values of that variant's fields
+#[derive(Debug)]
+pub struct Variant {
+    pub def: Rc<EnumType>,
+    pub index: usize,
+    pub values: Vec<Memory>,
+}
+
+impl Variant {
+    pub fn name(&self) -> &str {
+        &self.def.variants[self.index].0
+    }
+}
+
+// A struct value; fields keep their declaration order
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instance {
+    pub name: String,
+    pub fields: Dict,
+}
+
+// String-keyed map that iterates in insertion order
+#[derive(Debug, Clone, Default)]
+pub struct Dict {
+    entries: Vec<(String, Memory)>,
+    positions: HashMap<String, usize>,
+}
+
+impl Dict {
+    pub fn get(&self, key: &str) -> Option<&Memory> {
+        self.positions.get(key).map(|&i| &self.entries[i].1)
+    }
+
+    // Updating a key keeps its original place in the order
+    pub fn insert(&mut self, key: String, value: Memory) {
+        match self.positions.get(&key) {
+            Some(&i) => self.entries[i].1 = value,
+            None => {
+                self.positions.insert(key.clone(), self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.positions.contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Memory)> {
+        self.entries.iter().map(|(key, value)| (key, value))
+    }
+}
+
+impl PartialEq for Dict {
+    fn eq(&self, other: &Self) -> bool {
+        same_entries(self, other, &mut HashSet::new())
+    }
+}
+
+// A user-defined function and the scope it closes over
+pub struct Function {
+    pub name: Option<String>,
+    pub params: Vec<String>,
+    pub body: Rc<Node>,
+    pub closure: Rc<RefCell<Scope>>,
+}
+
+// Functions are only equal to themselves
+impl PartialEq for Function {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+// The closure is left out: it usually contains the function itself
+impl fmt::Debug for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "<fn {name}>"),
+            None => f.write_str("<fn>"),
+        }
+    }
+}
+
+// A named slot in the virtual brain, and what declared it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Binding {
+    pub value: Memory,
+    pub declaration: Declaration,
+}
+
+impl Binding {
+    // Only `let` bindings, parameters and loop variables can be assigned
+    pub fn is_mut(&self) -> bool {
+        self.declaration == Declaration::Let
+    }
+}
+
+// How a name was bound. A `fn`, `struct` or `enum` may be declared again to replace one
+// of the same kind, as when fixing a function in the REPL.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Declaration {
+    Let,
+    Final,
+    Function,
+    Struct,
+    Enum,
+    // The variants an `enum` declaration binds
+    Variant,
+}
+
+impl Declaration {
+    // Whether this declaration may rebind a name declared `earlier` in the same scope
+    pub fn may_replace(self, earlier: Declaration) -> bool {
+        match earlier {
+            Declaration::Let => true,
+            Declaration::Final => false,
+            earlier => earlier == self,
+        }
+    }
+
+    // How the declaration is written, as `:vars` and disassembly show it
+    pub fn keyword(self) -> &'static str {
+        match self {
+            Declaration::Let => "let",
+            Declaration::Final => "final",
+            Declaration::Function => "fn",
+            Declaration::Struct => "struct",
+            Declaration::Enum => "enum",
+            Declaration::Variant => "variant",
+        }
+    }
+
+    // The error for redeclaring a name this declared
+    pub fn redeclared(self, name: &str) -> String {
+        let what = match self {
+            Declaration::Let | Declaration::Final => "final variable",
+            Declaration::Function => "function",
+            Declaration::Struct => "struct",
+            Declaration::Enum => "enum",
+            Declaration::Variant => "variant",
+        };
+        format!("Cannot redeclare {what} '{name}' in the same scope")
+    }
+}
+
+pub type Context = HashMap<String, Binding>;
+
+// One link of the environment chain; every block and call gets its own
+#[derive(Debug, Default)]
+pub struct Scope {
+    pub context: Context,
+    pub parent: Option<Rc<RefCell<Scope>>>,
+}
+
+impl Scope {
+    pub fn child(parent: &Rc<RefCell<Scope>>) -> Rc<RefCell<Scope>> {
+        Rc::new(RefCell::new(Scope {
+            context: Context::new(),
+            parent: Some(parent.clone()),
+        }))
+    }
+}
+
+// Deepest chain of nested calls before the interpreter gives up
+pub const MAX_CALL_DEPTH: usize = 10_000;
+
+// The limit of this run, lower than MAX_CALL_DEPTH when the interpreter got a smaller stack
+static CALL_DEPTH_LIMIT: AtomicUsize = AtomicUsize::new(MAX_CALL_DEPTH);
+
+pub fn limit_call_depth(limit: usize) {
+    CALL_DEPTH_LIMIT.store(limit, Ordering::Relaxed);
+}
+
+pub fn call_depth_limit() -> usize {
+    CALL_DEPTH_LIMIT.load(Ordering::Relaxed)
+}
+
+#[derive(Debug, Default)]
+pub struct Env {
+    pub scope: Rc<RefCell<Scope>>,
+    pub io: Io,
+    depth: usize,
+}
+
+impl Env {
+    pub fn with_io(io: Io) -> Self {
+        Env {
+            io,
+            ..Env::default()
+        }
+    }
+
+    // Finds the innermost binding called `ident` and applies `f` to it
+    fn find<T>(&self, ident: &str, f: impl FnOnce(&mut Binding) -> T) -> Option<T> {
+        let mut scope = self.scope.clone();
+        loop {
+            let parent = {
+                let mut current = scope.borrow_mut();
+                if let Some(binding) = current.context.get_mut(ident) {
+                    return Some(f(binding));
                 }
-            } else if let ASTNode::Identifier(yi) = *right {
-                if let Memory::Number(v, _) = **context.get(&yi).unwrap() {
-                    match op {
-                        Token::Arithmetic(MathToken::Plus) => x + v,
-                        Token::Arithmetic(MathToken::Minus) => x - v,
-                        Token::Arithmetic(MathToken::Divide) => x / v,
-                        Token::Arithmetic(MathToken::Multiply) => x * v,
-                        _ => panic!("CRY.ERROR: Invalid binary operation"),
-                    }
+                current.parent.clone()
+            };
+            scope = parent?;
+        }
+    }
+
+    pub fn get(&self, ident: &str) -> Option<Binding> {
+        self.find(ident, |binding| binding.clone())
+    }
+
+    // Binds `ident` in the innermost scope. A `let` may shadow an earlier binding of the
+    // same scope, but a `final` there can't be redeclared.
+    pub fn define(
+        &mut self,
+        ident: &str,
+        value: Memory,
+        declaration: Declaration,
+        span: &Span,
+    ) -> Result<(), CrystalError> {
+        let mut scope = self.scope.borrow_mut();
+        if let Some(earlier) = scope.context.get(ident) {
+            if !declaration.may_replace(earlier.declaration) {
+                return Err(CrystalError::mutability(
+                    earlier.declaration.redeclared(ident),
+                    span.clone(),
+                ));
+            }
+        }
+        let binding = Binding { value, declaration };
+        scope.context.insert(ident.to_string(), binding);
+        Ok(())
+    }
+
+    // Overwrites an existing `let` binding
+    pub fn assign(&mut self, ident: &str, value: Memory, span: &Span) -> Result<(), CrystalError> {
+        let assigned = self.find(ident, |binding| {
+            if binding.is_mut() {
+                binding.value = value;
+            }
+            binding.is_mut()
+        });
+        match assigned {
+            Some(true) => Ok(()),
+            Some(false) => Err(CrystalError::mutability(
+                format!("Cannot modify final variable '{ident}'"),
+                span.clone(),
+            )),
+            None => Err(CrystalError::name(
+                format!("Memory '{ident}' not found"),
+                span.clone(),
+            )),
+        }
+    }
+
+    // Runs `f` with `scope` as the innermost scope, restoring the current one afterwards
+    pub fn in_scope<T>(&mut self, scope: Rc<RefCell<Scope>>, f: impl FnOnce(&mut Env) -> T) -> T {
+        let outer = std::mem::replace(&mut self.scope, scope);
+        let result = f(self);
+        self.scope = outer;
+        result
+    }
+}
+
+// How a statement finished
+#[derive(Debug, PartialEq)]
+pub enum Flow {
+    Normal,
+    // A bare expression statement and its value
+    Value(Memory),
+    // A `return` unwinding to the enclosing call
+    Return(Memory),
+    // `break`/`continue` unwinding to the enclosing loop
+    Break,
+    Continue,
+}
+
+// Runs every statement of a program in order
+pub fn run_program(ast: &Node, env: &mut Env) -> Result<(), CrystalError> {
+    if let ASTNode::Program(nodes) = &ast.node {
+        exec_statements(nodes, env)?;
+    }
+    Ok(())
+}
+
+// Runs statements in the current scope, stopping early at a `return`, `break` or `continue`
+pub fn exec_statements(nodes: &[Node], env: &mut Env) -> Result<Flow, CrystalError> {
+    for node in nodes {
+        match exec(node, env)? {
+            Flow::Normal | Flow::Value(_) => {}
+            jump => return Ok(jump),
+        }
+    }
+    Ok(Flow::Normal)
+}
+
+// Runs one statement
+pub fn exec(node: &Node, env: &mut Env) -> Result<Flow, CrystalError> {
+    match &node.node {
+        ASTNode::Let(ident, val) => {
+            let mem = eval(val, env)?;
+            env.define(ident, mem, Declaration::Let, &node.span)?;
+        }
+        ASTNode::Final(ident, val) => {
+            let mem = eval(val, env)?;
+            env.define(ident, mem, Declaration::Final, &node.span)?;
+        }
+        ASTNode::Assign { ident, value } => {
+            let value = eval(value, env)?;
+            env.assign(ident, value, &node.span)?;
+        }
+        ASTNode::IndexAssign {
+            target,
+            index,
+            value,
+        } => {
+            writable(target, env)?;
+            let collection = eval(target, env)?;
+            let i = eval(index, env)?;
+            let value = eval(value, env)?;
+            set_index(collection, i, value, &target.span, &index.span)?;
+        }
+        ASTNode::StructDef { name, fields } => {
+            let def = StructType {
+                name: name.clone(),
+                fields: fields.clone(),
+            };
+            let def = Memory::StructType(Rc::new(def));
+            env.define(name, def, Declaration::Struct, &node.span)?;
+        }
+        ASTNode::EnumDef { name, variants } => {
+            for (i, (ident, value)) in enum_bindings(name, variants).into_iter().enumerate() {
+                let declaration = if i < variants.len() {
+                    Declaration::Variant
                 } else {
-                    panic!("Identifier {yi} is not found in context.");
+                    Declaration::Enum
+                };
+                env.define(&ident, value, declaration, &node.span)?;
+            }
+        }
+        ASTNode::Match { subject, arms } => {
+            let (body, scope) = patterns::select(node, subject, arms, env)?;
+            return env.in_scope(scope, |env| exec(body, env));
+        }
+        ASTNode::FieldAssign {
+            target,
+            field,
+            value,
+        } => {
+            writable(target, env)?;
+            let instance = eval(target, env)?;
+            let value = eval(value, env)?;
+            set_field(instance, field, value, &node.span, &target.span)?;
+        }
+        ASTNode::CompoundIndexAssign {
+            target,
+            index,
+            op,
+            value,
+        } => {
+            writable(target, env)?;
+            let collection = eval(target, env)?;
+            let i = eval(index, env)?;
+            let current = get_index(collection.clone(), i.clone(), &target.span, &index.span)?;
+            let rhs = eval(value, env)?;
+            let value = compound(op, current, rhs, &node.span)?;
+            set_index(collection, i, value, &target.span, &index.span)?;
+        }
+        ASTNode::CompoundFieldAssign {
+            target,
+            field,
+            op,
+            value,
+        } => {
+            writable(target, env)?;
+            let instance = eval(target, env)?;
+            let current = get_field(instance.clone(), field, &node.span, &target.span)?;
+            let rhs = eval(value, env)?;
+            let value = compound(op, current, rhs, &node.span)?;
+            set_field(instance, field, value, &node.span, &target.span)?;
+        }
+        ASTNode::CompoundAssign { ident, op, value } => {
+            let Some(binding) = env.get(ident) else {
+                return Err(CrystalError::name(
+                    format!("Memory '{ident}' not found"),
+                    node.span.clone(),
+                ));
+            };
+            if !binding.is_mut() {
+                return Err(CrystalError::mutability(
+                    format!("Cannot modify final variable '{ident}'"),
+                    node.span.clone(),
+                ));
+            }
+            let rhs = eval(value, env)?;
+            let new_value = compound(op, binding.value, rhs, &node.span)?;
+            env.assign(ident, new_value, &node.span)?;
+        }
+        ASTNode::FunDef {
+            name: Some(name), ..
+        } => {
+            let function = eval(node, env)?;
+            env.define(name, function, Declaration::Function, &node.span)?;
+        }
+        ASTNode::Block(nodes) => {
+            let scope = Scope::child(&env.scope);
+            return env.in_scope(scope, |env| exec_statements(nodes, env));
+        }
+        ASTNode::Return(value) => {
+            let value = match value {
+                Some(value) => eval(value, env)?,
+                None => Memory::Nil,
+            };
+            return Ok(Flow::Return(value));
+        }
+        ASTNode::Break => return Ok(Flow::Break),
+        ASTNode::Continue => return Ok(Flow::Continue),
+        ASTNode::If {
+            cond,
+            then,
+            otherwise,
+        } => {
+            if condition(cond, env)? {
+                return exec(then, env);
+            } else if let Some(otherwise) = otherwise {
+                return exec(otherwise, env);
+            }
+        }
+        ASTNode::While { cond, body } => {
+            while condition(cond, env)? {
+                match exec(body, env)? {
+                    Flow::Break => break,
+                    Flow::Return(value) => return Ok(Flow::Return(value)),
+                    _ => {}
                 }
-            } else {
-                panic!("CRY.ERROR: Invalid right expression in binary expression");
-            }
-        } else if let ASTNode::Identifier(xi) = *left {
-            if let Memory::Number(v, _) = **context.get(&xi).unwrap() {
-                if let ASTNode::Number(y) = *right {
-                    match op {
-                        Token::Arithmetic(MathToken::Plus) => v + y,
-                        Token::Arithmetic(MathToken::Minus) => v - y,
-                        Token::Arithmetic(MathToken::Divide) => v / y,
-                        Token::Arithmetic(MathToken::Multiply) => v * y,
-                        _ => panic!("CRY.ERROR: Invalid binary operation"),
-                    }
-                } else if let ASTNode::Identifier(yi) = *right {
-                    if let Memory::Number(yv, _) = **context.get(&yi).unwrap() {
-                        match op {
-                            Token::Arithmetic(MathToken::Plus) => v + yv,
-                            Token::Arithmetic(MathToken::Minus) => v - yv,
-                            Token::Arithmetic(MathToken::Divide) => v / yv,
-                            Token::Arithmetic(MathToken::Multiply) => v * yv,
-                            _ => panic!("CRY.ERROR: Invalid binary operation"),
-                        }
-                    } else {
-                        panic!("CRY.ERROR: Invalid binary operation");
+            }
+        }
+        ASTNode::For {
+            ident,
+            value,
+            iterable,
+            body,
+        } => {
+            // What each pass binds to `ident` and, when there is one, to `value`
+            let passes: Box<dyn Iterator<Item = (Memory, Memory)>> = match &iterable.node {
+                ASTNode::Range { start, end } => {
+                    if value.is_some() {
+                        return Err(CrystalError::type_error(
+                            "A range gives one loop variable, not two",
+                            iterable.span.clone(),
+                        ));
                     }
-                } else {
-                    panic!("CRY.ERROR: Invalid binary operation");
+                    let (start, end) = (int(start, env)?, int(end, env)?);
+                    Box::new((start..end).map(|i| (Memory::Int(i), Memory::Nil)))
+                }
+                _ => {
+                    let collection = eval(iterable, env)?;
+                    let passes = passes(collection, value.is_some(), &iterable.span)?;
+                    Box::new(passes.into_iter())
+                }
+            };
+            for (first, second) in passes {
+                // Each pass gets fresh bindings, so closures capture that pass's values
+                let scope = Scope::child(&env.scope);
+                let bind = |ident: &String, value| {
+                    scope.borrow_mut().context.insert(
+                        ident.clone(),
+                        Binding {
+                            value,
+                            declaration: Declaration::Let,
+                        },
+                    )
+                };
+                bind(ident, first);
+                if let Some(value) = value {
+                    bind(value, second);
+                }
+                match env.in_scope(scope, |env| exec(body, env))? {
+                    Flow::Break => break,
+                    Flow::Return(value) => return Ok(Flow::Return(value)),
+                    _ => {}
                 }
+            }
+        }
+        _ => return eval(node, env).map(Flow::Value),
+    }
+    Ok(Flow::Normal)
+}
+
+// Evaluates an `if`/`while` condition or logic operand by its truthiness
+fn condition(node: &Node, env: &mut Env) -> Result<bool, CrystalError> {
+    Ok(eval(node, env)?.is_truthy())
+}
+
+fn int(node: &Node, env: &mut Env) -> Result<i64, CrystalError> {
+    let value = eval(node, env)?;
+    expect_int(value, &node.span)
+}
+
+pub fn expect_int(value: Memory, span: &Span) -> Result<i64, CrystalError> {
+    match value {
+        Memory::Int(n) => Ok(n),
+        other => Err(CrystalError::type_error(
+            format!("Expected an int, found {}", other.type_name()),
+            span.clone(),
+        )),
+    }
+}
+
+// The (item, value) pairs a `for` over a list or map binds; `two` is true for `for a, b in`.
+// The pairs are a snapshot, so changing the collection in the body can't loop forever.
+pub fn passes(
+    collection: Memory,
+    two: bool,
+    span: &Span,
+) -> Result<Vec<(Memory, Memory)>, CrystalError> {
+    let passes = match collection {
+        Memory::List(items) if two => (0..).map(Memory::Int).zip(items.borrow().clone()).collect(),
+        Memory::List(items) => items
+            .borrow()
+            .iter()
+            .map(|item| (item.clone(), Memory::Nil))
+            .collect(),
+        Memory::Map(map) => map
+            .borrow()
+            .iter()
+            .map(|(key, value)| (Memory::String(key.clone()), value.clone()))
+            .collect(),
+        other => {
+            return Err(CrystalError::type_error(
+                format!("Cannot iterate over a {}", other.type_name()),
+                span.clone(),
+            ))
+        }
+    };
+    Ok(passes)
+}
+
+// What an `enum` declaration binds: every variant by its own name (a value if it has no
+// fields, else a constructor), then the enum itself
+pub fn enum_bindings(name: &str, variants: &[(String, Vec<String>)]) -> Vec<(String, Memory)> {
+    let def = Rc::new(EnumType {
+        name: name.to_string(),
+        variants: variants.to_vec(),
+    });
+    let mut bindings: Vec<_> = variants
+        .iter()
+        .enumerate()
+        .map(|(index, (variant, fields))| {
+            let value = if fields.is_empty() {
+                Memory::Variant(Rc::new(Variant {
+                    def: def.clone(),
+                    index,
+                    values: Vec::new(),
+                }))
             } else {
-                panic!("CRY.ERROR: Invalid binary operation");
+                Memory::Constructor(def.clone(), index)
+            };
+            (variant.clone(), value)
+        })
+        .collect();
+    bindings.push((name.to_string(), Memory::Enum(def)));
+    bindings
+}
+
+// Fails if `node` names, or indexes into, a `final` binding
+pub fn writable(node: &Node, env: &Env) -> Result<(), CrystalError> {
+    match &node.node {
+        ASTNode::Identifier(ident) => match env.get(ident) {
+            Some(binding) if !binding.is_mut() => Err(CrystalError::mutability(
+                format!("Cannot modify final variable '{ident}'"),
+                node.span.clone(),
+            )),
+            _ => Ok(()),
+        },
+        ASTNode::Index { target, .. } | ASTNode::Field { target, .. } => writable(target, env),
+        _ => Ok(()),
+    }
+}
+
+// Checks an index value against a length, giving the position it refers to
+fn position(index: &Memory, len: usize, span: &Span) -> Result<usize, CrystalError> {
+    let Memory::Int(i) = index else {
+        return Err(CrystalError::type_error(
+            format!("Index must be an int, found {}", index.type_name()),
+            span.clone(),
+        ));
+    };
+    usize::try_from(*i)
+        .ok()
+        .filter(|&i| i < len)
+        .ok_or_else(|| {
+            CrystalError::runtime(
+                format!("Index {i} out of range for length {len}"),
+                span.clone(),
+            )
+        })
+}
+
+pub fn map_key(key: Memory, span: &Span) -> Result<String, CrystalError> {
+    match key {
+        Memory::String(key) => Ok(key),
+        other => Err(CrystalError::type_error(
+            format!("Map keys must be strings, found {}", other.type_name()),
+            span.clone(),
+        )),
+    }
+}
+
+// `xs[i]`, `xs[a..b]`, `s[i]`, `s[a..b]` and `m[key]`
+fn index(target: &Node, index: &Node, env: &mut Env) -> Result<Memory, CrystalError> {
+    let value = eval(target, env)?;
+    if let (ASTNode::Range { start, end }, false) = (&index.node, matches!(value, Memory::Map(_))) {
+        indexable_len(&value, &target.span)?;
+        let (start, end) = (int(start, env)?, int(end, env)?);
+        return get_slice(value, start, end, &target.span, &index.span);
+    }
+    let i = eval(index, env)?;
+    get_index(value, i, &target.span, &index.span)
+}
+
+// Length of a list or string, the values that can be indexed by position
+pub fn indexable_len(value: &Memory, span: &Span) -> Result<usize, CrystalError> {
+    match value {
+        Memory::List(items) => Ok(items.borrow().len()),
+        Memory::String(s) => Ok(s.chars().count()),
+        other => Err(CrystalError::type_error(
+            format!("Cannot index into a {}", other.type_name()),
+            span.clone(),
+        )),
+    }
+}
+
+pub fn get_index(
+    value: Memory,
+    i: Memory,
+    target_span: &Span,
+    index_span: &Span,
+) -> Result<Memory, CrystalError> {
+    if let Memory::Map(map) = &value {
+        let key = map_key(i, index_span)?;
+        let found = map.borrow().get(&key).cloned();
+        return found.ok_or_else(|| {
+            CrystalError::runtime(format!("Key {key:?} not found in map"), index_span.clone())
+        });
+    }
+    let len = indexable_len(&value, target_span)?;
+    let i = position(&i, len, index_span)?;
+    match value {
+        Memory::List(items) => Ok(items.borrow()[i].clone()),
+        Memory::String(s) => Ok(Memory::String(
+            s.chars().nth(i).map(String::from).unwrap_or_default(),
+        )),
+        _ => unreachable!("checked by indexable_len"),
+    }
+}
+
+pub fn get_slice(
+    value: Memory,
+    start: i64,
+    end: i64,
+    target_span: &Span,
+    index_span: &Span,
+) -> Result<Memory, CrystalError> {
+    let len = indexable_len(&value, target_span)?;
+    if start < 0 || start > end || end as usize > len {
+        return Err(CrystalError::runtime(
+            format!("Slice {start}..{end} out of range for length {len}"),
+            index_span.clone(),
+        ));
+    }
+    let (start, end) = (start as usize, end as usize);
+    Ok(match value {
+        Memory::List(items) => Memory::list(items.borrow()[start..end].to_vec()),
+        Memory::String(s) => Memory::String(s.chars().skip(start).take(end - start).collect()),
+        _ => unreachable!("checked by indexable_len"),
+    })
+}
+
+pub fn set_index(
+    collection: Memory,
+    i: Memory,
+    value: Memory,
+    target_span: &Span,
+    index_span: &Span,
+) -> Result<(), CrystalError> {
+    match collection {
+        Memory::List(items) => {
+            let len = items.borrow().len();
+            let i = position(&i, len, index_span)?;
+            items.borrow_mut()[i] = value;
+        }
+        Memory::Map(map) => {
+            let key = map_key(i, index_span)?;
+            map.borrow_mut().insert(key, value);
+        }
+        other => {
+            return Err(CrystalError::type_error(
+                format!("Cannot assign to an index of a {}", other.type_name()),
+                target_span.clone(),
+            ))
+        }
+    }
+    Ok(())
+}
+
+pub fn get_field(
+    value: Memory,
+    field: &str,
+    span: &Span,
+    target_span: &Span,
+) -> Result<Memory, CrystalError> {
+    match value {
+        Memory::Variant(variant) => {
+            let (name, fields) = &variant.def.variants[variant.index];
+            match fields.iter().position(|f| f == field) {
+                Some(i) => Ok(variant.values[i].clone()),
+                None => Err(CrystalError::name(
+                    format!("Variant '{name}' has no field '{field}'"),
+                    span.clone(),
+                )),
             }
-        } else if let ASTNode::Number(n) = *right {
-            match op {
-                Token::Arithmetic(MathToken::Plus) => binary_op(*left, context) + n,
-                Token::Arithmetic(MathToken::Minus) => binary_op(*left, context) - n,
-                Token::Arithmetic(MathToken::Divide) => binary_op(*left, context) / n,
-                Token::Arithmetic(MathToken::Multiply) => binary_op(*left, context) * n,
-                _ => panic!("CRY.ERROR: Invalid binary operation"),
-            }
-        } else if let ASTNode::Identifier(ri) = *right {
-            if let Memory::Number(rv, _) = **context.get(&ri).unwrap() {
-                match op {
-                    Token::Arithmetic(MathToken::Plus) => binary_op(*left, context) + rv,
-                    Token::Arithmetic(MathToken::Minus) => binary_op(*left, context) - rv,
-                    Token::Arithmetic(MathToken::Divide) => binary_op(*left, context) / rv,
-                    Token::Arithmetic(MathToken::Multiply) => binary_op(*left, context) * rv,
-                    _ => panic!("CRY.ERROR: Invalid binary operation"),
+        }
+        Memory::Struct(instance) => {
+            let instance = instance.borrow();
+            instance.fields.get(field).cloned().ok_or_else(|| {
+                CrystalError::name(
+                    format!("Struct '{}' has no field '{field}'", instance.name),
+                    span.clone(),
+                )
+            })
+        }
+        other => Err(CrystalError::type_error(
+            format!("Cannot read field '{field}' of a {}", other.type_name()),
+            target_span.clone(),
+        )),
+    }
+}
+
+pub fn set_field(
+    instance: Memory,
+    field: &str,
+    value: Memory,
+    span: &Span,
+    target_span: &Span,
+) -> Result<(), CrystalError> {
+    let Memory::Struct(instance) = instance else {
+        return Err(CrystalError::type_error(
+            format!("Cannot set field '{field}' on a {}", instance.type_name()),
+            target_span.clone(),
+        ));
+    };
+    let mut instance = instance.borrow_mut();
+    if !instance.fields.contains(field) {
+        return Err(CrystalError::name(
+            format!("Struct '{}' has no field '{field}'", instance.name),
+            span.clone(),
+        ));
+    }
+    instance.fields.insert(field.to_string(), value);
+    Ok(())
+}
+
+// Checks a struct literal against its declaration before any field value is evaluated.
+// `def` is what `name` is bound to; `field_spans` locate each given field's value.
+pub fn struct_type(
+    def: Option<Memory>,
+    name: &str,
+    given: &[&String],
+    field_spans: &[&Span],
+    span: &Span,
+) -> Result<Rc<StructType>, CrystalError> {
+    let def = match def {
+        Some(Memory::StructType(def)) => def,
+        Some(other) => {
+            return Err(CrystalError::type_error(
+                format!("'{name}' is a {}, not a struct", other.type_name()),
+                span.clone(),
+            ))
+        }
+        None => {
+            return Err(CrystalError::name(
+                format!("Struct '{name}' not found"),
+                span.clone(),
+            ))
+        }
+    };
+    if let Some(i) = given.iter().position(|f| !def.fields.contains(f)) {
+        return Err(CrystalError::name(
+            format!("Struct '{name}' has no field '{}'", given[i]),
+            field_spans[i].clone(),
+        ));
+    }
+    if let Some(field) = def.fields.iter().find(|f| !given.contains(f)) {
+        return Err(CrystalError::type_error(
+            format!("Missing field '{field}' in '{name}'"),
+            span.clone(),
+        ));
+    }
+    Ok(def)
+}
+
+// Builds a struct from values given in source order, storing them in declaration order
+pub fn build_struct(def: &StructType, given: Vec<(String, Memory)>) -> Memory {
+    let mut given: HashMap<_, _> = given.into_iter().collect();
+    let mut values = Dict::default();
+    for field in &def.fields {
+        let value = given.remove(field).unwrap_or(Memory::Nil);
+        values.insert(field.clone(), value);
+    }
+    Memory::Struct(Rc::new(RefCell::new(Instance {
+        name: def.name.clone(),
+        fields: values,
+    })))
+}
+
+// Builds an enum value by calling its variant constructor
+pub fn construct(
+    def: &Rc<EnumType>,
+    index: usize,
+    args: Vec<Memory>,
+    span: &Span,
+) -> Result<Memory, CrystalError> {
+    let (name, fields) = &def.variants[index];
+    if args.len() != fields.len() {
+        return Err(CrystalError::runtime(
+            format!(
+                "Variant '{name}' takes {} argument(s) but {} were given",
+                fields.len(),
+                args.len()
+            ),
+            span.clone(),
+        ));
+    }
+    Ok(Memory::Variant(Rc::new(Variant {
+        def: def.clone(),
+        index,
+        values: args,
+    })))
+}
+
+// Calls a function value with already evaluated arguments
+impl Runtime for Env {
+    fn io(&mut self) -> &mut Io {
+        &mut self.io
+    }
+
+    fn call(
+        &mut self,
+        callee: &Memory,
+        args: Vec<Memory>,
+        span: &Span,
+    ) -> Result<Memory, CrystalError> {
+        call(callee, args, self, span)
+    }
+}
+
+pub fn call(
+    callee: &Memory,
+    args: Vec<Memory>,
+    env: &mut Env,
+    span: &Span,
+) -> Result<Memory, CrystalError> {
+    match callee {
+        Memory::Constructor(def, index) => return construct(def, *index, args, span),
+        Memory::Builtin(name) => return builtins::call(name, args, env, span),
+        _ => {}
+    }
+    let Memory::Function(function) = callee else {
+        return Err(CrystalError::type_error(
+            format!("A {} is not callable", callee.type_name()),
+            span.clone(),
+        ));
+    };
+    if args.len() != function.params.len() {
+        return Err(CrystalError::runtime(
+            format!(
+                "{function:?} takes {} argument(s) but {} were given",
+                function.params.len(),
+                args.len()
+            ),
+            span.clone(),
+        ));
+    }
+    if env.depth >= call_depth_limit() {
+        return Err(CrystalError::runtime(
+            "Maximum call depth exceeded",
+            span.clone(),
+        ));
+    }
+
+    let scope = Scope::child(&function.closure);
+    for (param, arg) in function.params.iter().zip(args) {
+        scope.borrow_mut().context.insert(
+            param.clone(),
+            Binding {
+                value: arg,
+                declaration: Declaration::Let,
+            },
+        );
+    }
+    let ASTNode::Block(body) = &function.body.node else {
+        return Ok(Memory::Nil);
+    };
+
+    env.depth += 1;
+    let flow = env.in_scope(scope, |env| exec_statements(body, env));
+    env.depth -= 1;
+    match flow? {
+        Flow::Return(value) => Ok(value),
+        _ => Ok(Memory::Nil),
+    }
+}
+
+// Evaluates an expression node down to a value
+pub fn eval(node: &Node, env: &mut Env) -> Result<Memory, CrystalError> {
+    match &node.node {
+        ASTNode::Int(n) => Ok(Memory::Int(*n)),
+        ASTNode::Float(n) => Ok(Memory::Float(*n)),
+        ASTNode::String(s) => Ok(Memory::String(s.clone())),
+        ASTNode::Bool(b) => Ok(Memory::Bool(*b)),
+        ASTNode::Interpolation(parts) => {
+            let mut text = String::new();
+            for part in parts {
+                text.push_str(&eval(part, env)?.to_string());
+            }
+            Ok(Memory::String(text))
+        }
+        ASTNode::Nil => Ok(Memory::Nil),
+        ASTNode::Identifier(ident) => match env.get(ident) {
+            Some(binding) => Ok(binding.value.clone()),
+            None if builtins::lookup(ident).is_some() => Ok(Memory::Builtin(ident.clone())),
+            None => Err(CrystalError::name(
+                format!("Memory '{ident}' not found"),
+                node.span.clone(),
+            )),
+        },
+        ASTNode::UnaryOp { op, operand } => {
+            let value = eval(operand, env)?;
+            unary_op(op, value, &node.span)
+        }
+        // `and`/`or` only evaluate their right side when it decides the result
+        ASTNode::BinaryOp {
+            left,
+            op: op @ (Token::And | Token::Or),
+            right,
+        } => {
+            let lhs = condition(left, env)?;
+            if (*op == Token::And) != lhs {
+                return Ok(Memory::Bool(lhs));
+            }
+            Ok(Memory::Bool(condition(right, env)?))
+        }
+        ASTNode::BinaryOp { left, op, right } => {
+            let lhs = eval(left, env)?;
+            let rhs = eval(right, env)?;
+            binary_op(op, lhs, rhs, &node.span)
+        }
+        ASTNode::List(items) => {
+            let items = items
+                .iter()
+                .map(|item| eval(item, env))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(Memory::list(items))
+        }
+        ASTNode::Map(entries) => {
+            let mut map = Dict::default();
+            for (key, value) in entries {
+                let key = map_key(eval(key, env)?, &key.span)?;
+                map.insert(key, eval(value, env)?);
+            }
+            Ok(Memory::map(map))
+        }
+        ASTNode::Index { target, index: i } => index(target, i, env),
+        ASTNode::StructLit { name, fields } => {
+            let def = env.get(name).map(|binding| binding.value);
+            let given: Vec<_> = fields.iter().map(|(field, _)| field).collect();
+            let spans: Vec<_> = fields.iter().map(|(_, value)| &value.span).collect();
+            let def = struct_type(def, name, &given, &spans, &node.span)?;
+            let mut values = Vec::new();
+            for (field, value) in fields {
+                values.push((field.clone(), eval(value, env)?));
+            }
+            Ok(build_struct(&def, values))
+        }
+        ASTNode::Match { subject, arms } => {
+            let (body, scope) = patterns::select(node, subject, arms, env)?;
+            env.in_scope(scope, |env| eval(body, env))
+        }
+        ASTNode::Field { target, field } => {
+            let value = eval(target, env)?;
+            get_field(value, field, &node.span, &target.span)
+        }
+        ASTNode::FunCall(callee, args) => {
+            let ASTNode::Identifier(name) = &callee.node else {
+                let callee = eval(callee, env)?;
+                let args = args
+                    .iter()
+                    .map(|arg| eval(arg, env))
+                    .collect::<Result<Vec<_>, _>>()?;
+                return call(&callee, args, env, &node.span);
+            };
+            // Memories shadow builtins, so a program may define its own `print`
+            let callee = env.get(name).map(|binding| binding.value);
+            if callee.is_none() && builtins::mutates_first_arg(name) {
+                if let Some(first) = args.first() {
+                    writable(first, env)?;
                 }
-            } else {
-                panic!("CRY.ERROR: Invalid right side binary expression.");
-            }
-        } else {
-            println!("{left:?} ++++++ {op:?} +++++++++ {right:?}");
-            panic!("CRY.ERROR: Invalid type detected in binary operation");
-        }
-    } else if let ASTNode::Number(n) = bop {
-        n
-    } else if let ASTNode::Identifier(ident) = bop {
-        // TODO Add ident compound assignment
-        1f64
-    } else {
-        panic!("CRY.ERROR: Invalid binary operation");
-    }
-}
-
-pub fn ast_to_memory(node: ASTNode, is_mut: bool, context: &Context) -> Memory {
-    match node {
-        ASTNode::String(s) => Memory::String(s, is_mut),
-        ASTNode::Number(n) => Memory::Number(n, is_mut),
-        ASTNode::BinaryOp { left, op, right } => Memory::Number(
-            binary_op(ASTNode::BinaryOp { left, op, right }, context),
-            is_mut,
-        ),
-        _ => Memory::String("This Memory is invalid.".to_string(), is_mut),
+            }
+            let args = args
+                .iter()
+                .map(|arg| eval(arg, env))
+                .collect::<Result<Vec<_>, _>>()?;
+            match (callee, builtins::lookup(name)) {
+                (Some(callee), _) => call(&callee, args, env, &node.span),
+                (None, Some(builtin)) => builtin(args, env, &node.span),
+                (None, None) => Err(CrystalError::name(
+                    format!("Function '{name}' not found"),
+                    node.span.clone(),
+                )),
+            }
+        }
+        ASTNode::FunDef { name, params, body } => Ok(Memory::Function(Rc::new(Function {
+            name: name.clone(),
+            params: params.clone(),
+            body: body.clone(),
+            closure: env.scope.clone(),
+        }))),
+        ASTNode::Range { .. } => Err(CrystalError::type_error(
+            "A range can only be used in a for loop",
+            node.span.clone(),
+        )),
+        _ => Err(CrystalError::runtime(
+            "Statement used where a value was expected",
+            node.span.clone(),
+        )),
+    }
+}
+
+// `-x` and `not x`
+pub fn unary_op(op: &Token, value: Memory, span: &Span) -> Result<Memory, CrystalError> {
+    match (op, value) {
+        (Token::Arithmetic(MathToken::Minus), Memory::Int(n)) => n
+            .checked_neg()
+            .map(Memory::Int)
+            .ok_or_else(|| CrystalError::runtime("Integer overflow in '-'", span.clone())),
+        (Token::Arithmetic(MathToken::Minus), Memory::Float(n)) => Ok(Memory::Float(-n)),
+        (Token::Not, value) => Ok(Memory::Bool(!value.is_truthy())),
+        (_, value) => Err(CrystalError::type_error(
+            format!("Cannot negate a {}", value.type_name()),
+            span.clone(),
+        )),
+    }
+}
+
+// The new value of `x op= y`, from the current value of `x`
+fn compound(op: &Token, current: Memory, rhs: Memory, span: &Span) -> Result<Memory, CrystalError> {
+    let Some(base) = op.compound_base() else {
+        return Err(CrystalError::runtime(
+            "Invalid binary operation",
+            span.clone(),
+        ));
+    };
+    binary_op(&base, current, rhs, span)
+}
+
+pub fn binary_op(
+    op: &Token,
+    lhs: Memory,
+    rhs: Memory,
+    span: &Span,
+) -> Result<Memory, CrystalError> {
+    if let Token::Compare(compare) = op {
+        return compare_op(compare, lhs, rhs, span);
+    }
+    let Token::Arithmetic(math) = op else {
+        return Err(CrystalError::runtime(
+            "Invalid binary operation",
+            span.clone(),
+        ));
+    };
+    match (lhs, rhs) {
+        (Memory::String(x), Memory::String(y)) if *math == MathToken::Plus => {
+            Ok(Memory::String(x + &y))
+        }
+        (Memory::Int(x), Memory::Int(y)) => int_op(math, x, y, span),
+        (lhs, rhs) => match (lhs.as_float(), rhs.as_float()) {
+            (Some(x), Some(y)) => float_op(math, x, y, span),
+            _ => Err(CrystalError::type_error(
+                format!(
+                    "Cannot apply '{math}' to {} and {}",
+                    lhs.type_name(),
+                    rhs.type_name()
+                ),
+                span.clone(),
+            )),
+        },
+    }
+}
+
+// Int arithmetic stays in ints, except `/` and negative powers which give floats
+fn int_op(math: &MathToken, x: i64, y: i64, span: &Span) -> Result<Memory, CrystalError> {
+    if y == 0
+        && matches!(
+            math,
+            MathToken::Divide | MathToken::IntDivide | MathToken::Modulo
+        )
+    {
+        return Err(CrystalError::runtime("Division by zero", span.clone()));
+    }
+    let result = match math {
+        MathToken::Plus => x.checked_add(y),
+        MathToken::Minus => x.checked_sub(y),
+        MathToken::Multiply => x.checked_mul(y),
+        MathToken::IntDivide => x.checked_div(y),
+        MathToken::Modulo => x.checked_rem(y),
+        MathToken::Divide => return float_op(math, x as f64, y as f64, span),
+        MathToken::Power if y < 0 => return float_op(math, x as f64, y as f64, span),
+        MathToken::Power => u32::try_from(y).ok().and_then(|y| x.checked_pow(y)),
+        _ => {
+            return Err(CrystalError::runtime(
+                "Invalid binary operation",
+                span.clone(),
+            ))
+        }
+    };
+    result
+        .map(Memory::Int)
+        .ok_or_else(|| CrystalError::runtime(format!("Integer overflow in '{math}'"), span.clone()))
+}
+
+// Float arithmetic; results that would be inf or NaN are errors instead
+fn float_op(math: &MathToken, x: f64, y: f64, span: &Span) -> Result<Memory, CrystalError> {
+    if y == 0.0
+        && matches!(
+            math,
+            MathToken::Divide | MathToken::IntDivide | MathToken::Modulo
+        )
+    {
+        return Err(CrystalError::runtime("Division by zero", span.clone()));
+    }
+    let result = match math {
+        MathToken::Plus => x + y,
+        MathToken::Minus => x - y,
+        MathToken::Multiply => x * y,
+        MathToken::Divide => x / y,
+        MathToken::IntDivide => (x / y).trunc(),
+        MathToken::Modulo => x % y,
+        MathToken::Power => x.powf(y),
+        _ => {
+            return Err(CrystalError::runtime(
+                "Invalid binary operation",
+                span.clone(),
+            ))
+        }
+    };
+    if result.is_nan() {
+        return Err(CrystalError::runtime(
+            format!("'{math}' has no real result for {x} and {y}"),
+            span.clone(),
+        ));
+    }
+    if result.is_infinite() {
+        return Err(CrystalError::runtime(
+            format!("Float overflow in '{math}'"),
+            span.clone(),
+        ));
+    }
+    Ok(Memory::Float(result))
+}
+
+fn compare_op(
+    op: &CompareToken,
+    lhs: Memory,
+    rhs: Memory,
+    span: &Span,
+) -> Result<Memory, CrystalError> {
+    let ordering = match (&lhs, &rhs) {
+        (Memory::Int(x), Memory::Int(y)) => Some(x.cmp(y)),
+        (Memory::String(x), Memory::String(y)) => Some(x.cmp(y)),
+        _ => match (lhs.as_float(), rhs.as_float()) {
+            (Some(x), Some(y)) => x.partial_cmp(&y),
+            _ => None,
+        },
+    };
+    let result = match op {
+        CompareToken::Equal => lhs == rhs,
+        CompareToken::NotEqual => lhs != rhs,
+        _ => {
+            let Some(ordering) = ordering else {
+                return Err(CrystalError::type_error(
+                    format!(
+                        "Cannot compare {} and {} with '{op}'",
+                        lhs.type_name(),
+                        rhs.type_name()
+                    ),
+                    span.clone(),
+                ));
+            };
+            match op {
+                CompareToken::Less => ordering.is_lt(),
+                CompareToken::LessEq => ordering.is_le(),
+                CompareToken::Greater => ordering.is_gt(),
+                _ => ordering.is_ge(),
+            }
+        }
+    };
+    Ok(Memory::Bool(result))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Declaration, Memory};
+
+    #[test]
+    fn floats_always_print_as_floats() {
+        let shown = |n: f64| Memory::Float(n).to_string();
+        assert_eq!(shown(2.0), "2.0");
+        assert_eq!(shown(-0.0), "-0.0");
+        assert_eq!(shown(0.1 + 0.2), "0.30000000000000004");
+        assert_eq!(shown(9999999999999998.0), "9999999999999998.0");
+        assert_eq!(shown(1e16), "1e16");
+        assert_eq!(shown(-1.5e20), "-1.5e20");
+        assert_eq!(shown(0.0001), "0.0001");
+        assert_eq!(shown(0.00001), "1e-5");
+        assert_eq!(shown(5e-324), "5e-324");
+        assert_eq!(shown(f64::MAX), "1.7976931348623157e308");
+    }
+
+    #[test]
+    fn only_lets_and_the_same_kind_can_be_redeclared() {
+        use Declaration::*;
+        assert!(Final.may_replace(Let));
+        assert!(Function.may_replace(Function));
+        assert!(Struct.may_replace(Struct));
+        assert!(!Let.may_replace(Final));
+        assert!(!Final.may_replace(Final));
+        assert!(!Let.may_replace(Function));
+        assert!(!Function.may_replace(Struct));
+        assert_eq!(
+            Enum.redeclared("Shape"),
+            "Cannot redeclare enum 'Shape' in the same scope"
+        );
+        assert_eq!(
+            Final.redeclared("x"),
+            "Cannot redeclare final variable 'x' in the same scope"
+        );
+    }
+
+    #[test]
+    fn numbers_compare_by_value_inside_lists_and_maps() {
+        let list = Memory::list;
+        assert_eq!(list(vec![Memory::Int(1)]), list(vec![Memory::Float(1.0)]));
+        assert_ne!(list(vec![Memory::Int(1)]), list(vec![Memory::Float(1.5)]));
+        let nested = list(vec![list(vec![Memory::Float(2.0)])]);
+        assert_eq!(nested, list(vec![list(vec![Memory::Int(2)])]));
+        assert_ne!(Memory::Int(1), Memory::String("1".to_string()));
     }
 }
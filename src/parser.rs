@@ -1,178 +1,1137 @@
-use core::panic;
+use std::rc::Rc;
 
-use super::lexer::{MathToken, Token};
+use super::{
+    error::CrystalError,
+    lexer::{MathToken, TemplatePart, Token},
+    span::{Span, Spanned},
+};
+
+pub type Node = Spanned<ASTNode>;
 
 // ASTNode Enum
 #[derive(Debug, PartialEq, Clone)]
 pub enum ASTNode {
-    Program(Vec<ASTNode>),
-    Let(String, Box<ASTNode>),
-    Final(String, Box<ASTNode>),
-    Number(f64),
+    Program(Vec<Node>),
+    Let(String, Box<Node>),
+    Final(String, Box<Node>),
+    Int(i64),
+    Float(f64),
     Identifier(String),
     String(String),
-    FunCall(String, Box<ASTNode>),
+    Bool(bool),
+    Nil,
+    // "text ${expr} text": the pieces are joined into one string
+    Interpolation(Vec<Node>),
+    FunCall(Box<Node>, Vec<Node>),
+    List(Vec<Node>),
+    // { key: value, ... }
+    Map(Vec<(Node, Node)>),
+    // struct Name { field, ... }
+    StructDef {
+        name: String,
+        fields: Vec<String>,
+    },
+    // Name { field: value, ... }
+    StructLit {
+        name: String,
+        fields: Vec<(String, Node)>,
+    },
+    // enum Name { Variant(field, ...), Unit, ... }
+    EnumDef {
+        name: String,
+        variants: Vec<(String, Vec<String>)>,
+    },
+    // match subject { pattern => body, ... }
+    Match {
+        subject: Box<Node>,
+        arms: Vec<MatchArm>,
+    },
+    // `target.field`
+    Field {
+        target: Box<Node>,
+        field: String,
+    },
+    // `target[index]`; an index that is a Range slices instead
+    Index {
+        target: Box<Node>,
+        index: Box<Node>,
+    },
     BinaryOp {
-        left: Box<ASTNode>,
+        left: Box<Node>,
+        op: Token,
+        right: Box<Node>,
+    },
+    UnaryOp {
+        op: Token,
+        operand: Box<Node>,
+    },
+    // `x = value;` on an existing `let` binding
+    Assign {
+        ident: String,
+        value: Box<Node>,
+    },
+    // `target[index] = value;`
+    IndexAssign {
+        target: Box<Node>,
+        index: Box<Node>,
+        value: Box<Node>,
+    },
+    // `target.field = value;`
+    FieldAssign {
+        target: Box<Node>,
+        field: String,
+        value: Box<Node>,
+    },
+    // `target[index] += value;`, evaluating the target and index once
+    CompoundIndexAssign {
+        target: Box<Node>,
+        index: Box<Node>,
+        op: Token,
+        value: Box<Node>,
+    },
+    // `target.field += value;`, evaluating the target once
+    CompoundFieldAssign {
+        target: Box<Node>,
+        field: String,
         op: Token,
-        right: Box<ASTNode>,
+        value: Box<Node>,
     },
     CompoundAssign {
         ident: String,
         op: Token,
-        value: Box<ASTNode>,
+        value: Box<Node>,
     },
+    Block(Vec<Node>),
+    // `fn name(a, b) { ... }` as a statement, or `fn(a, b) { ... }` as a value
+    FunDef {
+        name: Option<String>,
+        params: Vec<String>,
+        body: Rc<Node>,
+    },
+    Return(Option<Box<Node>>),
+    // `if cond { } else ...`; an `else if` chain nests another If in `otherwise`
+    If {
+        cond: Box<Node>,
+        then: Box<Node>,
+        otherwise: Option<Box<Node>>,
+    },
+    While {
+        cond: Box<Node>,
+        body: Box<Node>,
+    },
+    // `for ident in ...` or `for ident, value in ...`
+    For {
+        ident: String,
+        value: Option<String>,
+        iterable: Box<Node>,
+        body: Box<Node>,
+    },
+    // `start..end`, counting up from start and stopping before end
+    Range {
+        start: Box<Node>,
+        end: Box<Node>,
+    },
+    Break,
+    Continue,
+}
+
+// The left side of a match arm
+#[derive(Debug, PartialEq, Clone)]
+pub enum Pattern {
+    // `_`
+    Wildcard,
+    // A name that binds the value, unless it names a variant without fields
+    Binding(String),
+    // An Int, Float, String, Bool or Nil node
+    Literal(ASTNode),
+    // `Variant(pattern, ...)`
+    Variant(String, Vec<Spanned<Pattern>>),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct MatchArm {
+    pub pattern: Spanned<Pattern>,
+    pub body: Node,
 }
 
 pub struct Parser {
-    tokens: Vec<Token>,
+    tokens: Vec<Spanned<Token>>,
     position: usize,
+    errors: Vec<CrystalError>,
+    function_depth: usize,
+    loop_depth: usize,
+    // Off in `if`/`while`/`for` headers, where `name {` starts the body, not a struct literal
+    struct_literals: bool,
 }
 
 impl Parser {
-    pub fn new(tokens: Vec<Token>) -> Self {
+    // `tokens` must end with the lexer's `Token::Eof`
+    pub fn new(mut tokens: Vec<Spanned<Token>>) -> Self {
+        tokens.retain(|token| !token.node.is_trivia());
         Parser {
             tokens,
             position: 0,
+            errors: Vec::new(),
+            function_depth: 0,
+            loop_depth: 0,
+            struct_literals: true,
         }
     }
 
     pub fn current_token(&self) -> &Token {
-        self.tokens.get(self.position).unwrap_or(&Token::EOF)
+        self.tokens
+            .get(self.position)
+            .map(|t| &t.node)
+            .unwrap_or(&Token::Eof)
+    }
+
+    pub fn current_span(&self) -> Span {
+        let index = self.position.min(self.tokens.len() - 1);
+        self.tokens[index].span.clone()
+    }
+
+    // Span of the most recently consumed token
+    pub fn previous_span(&self) -> Span {
+        let index = self.position.saturating_sub(1).min(self.tokens.len() - 1);
+        self.tokens[index].span.clone()
+    }
+
+    pub fn peek_token(&self) -> &Token {
+        self.tokens
+            .get(self.position + 1)
+            .map(|t| &t.node)
+            .unwrap_or(&Token::Eof)
     }
 
     pub fn advance(&mut self) {
         self.position += 1;
     }
 
-    pub fn parse(&mut self) -> ASTNode {
+    fn error(&self, message: &str) -> CrystalError {
+        CrystalError::parse(message, self.current_span())
+    }
+
+    // Reports a missing token right after the last one consumed
+    fn error_after(&self, message: &str) -> CrystalError {
+        CrystalError::parse(message, self.previous_span().after())
+    }
+
+    // Parses the whole program, or fails with every syntax error found in it
+    pub fn parse(&mut self) -> Result<Node, Vec<CrystalError>> {
+        let (program, errors) = self.parse_recovering();
+        if errors.is_empty() {
+            Ok(program)
+        } else {
+            Err(errors)
+        }
+    }
+
+    // Parses the whole program, skipping past broken statements instead of stopping at them.
+    // The returned Program holds every statement that parsed cleanly.
+    pub fn parse_recovering(&mut self) -> (Node, Vec<CrystalError>) {
+        let start = self.current_span();
         let mut program = Vec::new();
-        while self.current_token() != &Token::EOF {
-            program.push(self.statement());
+        while self.current_token() != &Token::Eof {
+            let position = self.position;
+            match self.statement() {
+                Ok(node) => program.push(node),
+                Err(err) => {
+                    self.errors.push(err);
+                    self.synchronize(position);
+                }
+            }
+        }
+        let span = start.to(&self.previous_span());
+        let errors = std::mem::take(&mut self.errors);
+        (Spanned::new(ASTNode::Program(program), span), errors)
+    }
+
+    // Panic-mode recovery: skip to just past the next ';' or up to the next statement keyword,
+    // or up to the '}' of the enclosing block, which `block` then consumes. Braces the broken
+    // statement opened are skipped along with it. Always consumes at least one token so a
+    // statement that fails immediately can't loop.
+    fn synchronize(&mut self, statement_start: usize) {
+        if self.position == statement_start {
+            let stray_semicolon = *self.current_token() == Token::Semicolon;
+            self.advance();
+            if stray_semicolon {
+                return;
+            }
+        }
+        let mut depth = self.tokens[statement_start..self.position.min(self.tokens.len())]
+            .iter()
+            .fold(0usize, |depth, token| match token.node {
+                Token::LBrace => depth + 1,
+                Token::RBrace => depth.saturating_sub(1),
+                _ => depth,
+            });
+        loop {
+            match self.current_token() {
+                Token::Eof => return,
+                Token::RBrace if depth == 0 => return,
+                Token::Let
+                | Token::Final
+                | Token::Fn
+                | Token::Struct
+                | Token::Enum
+                | Token::Match
+                | Token::If
+                | Token::While
+                | Token::For
+                    if depth == 0 =>
+                {
+                    return
+                }
+                Token::Semicolon if depth == 0 => {
+                    self.advance();
+                    return;
+                }
+                Token::LBrace => depth += 1,
+                Token::RBrace => depth -= 1,
+                _ => {}
+            }
+            self.advance();
         }
-        ASTNode::Program(program)
     }
 
-    pub fn statement(&mut self) -> ASTNode {
+    pub fn statement(&mut self) -> Result<Node, CrystalError> {
         match self.current_token() {
             Token::Let => self.let_statement(),
             Token::Final => self.final_statement(),
+            Token::Fn if matches!(self.peek_token(), Token::Identifier(_)) => self.function(),
+            Token::Struct => self.struct_statement(),
+            Token::Enum => self.enum_statement(),
+            Token::Match => {
+                let node = self.match_expression(true)?;
+                if *self.current_token() == Token::Semicolon {
+                    self.advance();
+                }
+                Ok(node)
+            }
+            Token::Return => self.return_statement(),
+            Token::If => self.if_statement(),
+            Token::While => self.while_statement(),
+            Token::For => self.for_statement(),
+            Token::Break | Token::Continue => self.loop_jump(),
+            // A bare block opens a new scope; a map literal can't start a statement
+            Token::LBrace => self.block(),
             _ => self.assignment_or_expression(),
         }
     }
 
-    pub fn let_statement(&mut self) -> ASTNode {
+    // if cond { ... } [else if cond { ... }]* [else { ... }]
+    pub fn if_statement(&mut self) -> Result<Node, CrystalError> {
+        let start = self.current_span();
+        self.advance();
+        let cond = self.header()?;
+        let then = self.block()?;
+        let otherwise = if *self.current_token() == Token::Else {
+            self.advance();
+            if *self.current_token() == Token::If {
+                Some(Box::new(self.if_statement()?))
+            } else {
+                Some(Box::new(self.block()?))
+            }
+        } else {
+            None
+        };
+        Ok(Spanned::new(
+            ASTNode::If {
+                cond: Box::new(cond),
+                then: Box::new(then),
+                otherwise,
+            },
+            start.to(&self.previous_span()),
+        ))
+    }
+
+    pub fn while_statement(&mut self) -> Result<Node, CrystalError> {
+        let start = self.current_span();
+        self.advance();
+        let cond = self.header()?;
+        let body = self.loop_body()?;
+        Ok(Spanned::new(
+            ASTNode::While {
+                cond: Box::new(cond),
+                body: Box::new(body),
+            },
+            start.to(&self.previous_span()),
+        ))
+    }
+
+    // for ident in start..end { ... }, for item in list { ... } or for key, value in map { ... }
+    pub fn for_statement(&mut self) -> Result<Node, CrystalError> {
+        let start = self.current_span();
+        self.advance();
+        let Token::Identifier(ident) = self.current_token().clone() else {
+            return Err(self.error("Expected loop variable after 'for'"));
+        };
+        self.advance();
+        let value = if *self.current_token() == Token::Comma {
+            self.advance();
+            let Token::Identifier(value) = self.current_token().clone() else {
+                return Err(self.error("Expected second loop variable after ','"));
+            };
+            if value == ident {
+                return Err(self.error(&format!("Duplicate loop variable '{value}'")));
+            }
+            self.advance();
+            Some(value)
+        } else {
+            None
+        };
+        if *self.current_token() != Token::In {
+            return Err(self.error_after("Expected 'in' after loop variable"));
+        }
+        self.advance();
+        let iterable = self.header()?;
+        let body = self.loop_body()?;
+        Ok(Spanned::new(
+            ASTNode::For {
+                ident,
+                value,
+                iterable: Box::new(iterable),
+                body: Box::new(body),
+            },
+            start.to(&self.previous_span()),
+        ))
+    }
+
+    fn loop_body(&mut self) -> Result<Node, CrystalError> {
+        self.loop_depth += 1;
+        let body = self.block();
+        self.loop_depth -= 1;
+        body
+    }
+
+    // break; or continue;
+    pub fn loop_jump(&mut self) -> Result<Node, CrystalError> {
+        let span = self.current_span();
+        let (node, keyword) = match self.current_token() {
+            Token::Break => (ASTNode::Break, "break"),
+            _ => (ASTNode::Continue, "continue"),
+        };
+        if self.loop_depth == 0 {
+            return Err(self.error(&format!("'{keyword}' outside of a loop")));
+        }
+        self.advance();
+        if *self.current_token() != Token::Semicolon {
+            return Err(self.error_after(&format!("Expected ';' after '{keyword}'")));
+        }
+        self.advance();
+        Ok(Spanned::new(node, span))
+    }
+
+    // fn [name](params) { body }
+    pub fn function(&mut self) -> Result<Node, CrystalError> {
+        let start = self.current_span();
+        self.advance();
+        let name = match self.current_token() {
+            Token::Identifier(name) => {
+                let name = name.clone();
+                self.advance();
+                Some(name)
+            }
+            _ => None,
+        };
+
+        if *self.current_token() != Token::LParen {
+            return Err(self.error_after("Expected '(' after function name"));
+        }
+        self.advance();
+        let mut params = Vec::new();
+        while *self.current_token() != Token::RParen {
+            let Token::Identifier(param) = self.current_token().clone() else {
+                return Err(self.error("Expected parameter name"));
+            };
+            if params.contains(&param) {
+                return Err(self.error(&format!("Duplicate parameter '{param}'")));
+            }
+            params.push(param);
+            self.advance();
+            match self.current_token() {
+                Token::Comma => self.advance(),
+                Token::RParen => {}
+                _ => return Err(self.error_after("Expected ',' or ')' after parameter")),
+            }
+        }
+        self.advance();
+
+        // `break` inside a function body can't reach a loop around the definition
+        let loop_depth = std::mem::take(&mut self.loop_depth);
+        self.function_depth += 1;
+        let body = self.block();
+        self.function_depth -= 1;
+        self.loop_depth = loop_depth;
+        let body = body?;
+
+        Ok(Spanned::new(
+            ASTNode::FunDef {
+                name,
+                params,
+                body: Rc::new(body),
+            },
+            start.to(&self.previous_span()),
+        ))
+    }
+
+    // { statements }
+    pub fn block(&mut self) -> Result<Node, CrystalError> {
+        let start = self.current_span();
+        if *self.current_token() != Token::LBrace {
+            return Err(self.error_after("Expected '{' to start a block"));
+        }
+        self.advance();
+        let mut statements = Vec::new();
+        while *self.current_token() != Token::RBrace {
+            if *self.current_token() == Token::Eof {
+                return Err(self.error("Expected '}' to close block"));
+            }
+            // A broken statement is reported and skipped, up to this block's '}' at most
+            let position = self.position;
+            match self.statement() {
+                Ok(node) => statements.push(node),
+                Err(err) => {
+                    self.errors.push(err);
+                    self.synchronize(position);
+                }
+            }
+        }
+        self.advance();
+        Ok(Spanned::new(
+            ASTNode::Block(statements),
+            start.to(&self.previous_span()),
+        ))
+    }
+
+    pub fn return_statement(&mut self) -> Result<Node, CrystalError> {
+        let start = self.current_span();
+        if self.function_depth == 0 {
+            return Err(self.error("'return' outside of a function"));
+        }
+        self.advance();
+        let value = if *self.current_token() == Token::Semicolon {
+            None
+        } else {
+            Some(Box::new(self.expression()?))
+        };
+        if *self.current_token() != Token::Semicolon {
+            return Err(self.error_after("Expected ';' after return"));
+        }
+        self.advance();
+        Ok(Spanned::new(
+            ASTNode::Return(value),
+            start.to(&self.previous_span()),
+        ))
+    }
+
+    // struct Name { field, ... }
+    pub fn struct_statement(&mut self) -> Result<Node, CrystalError> {
+        let start = self.current_span();
+        self.advance();
+        let Token::Identifier(name) = self.current_token().clone() else {
+            return Err(self.error("Expected struct name after 'struct'"));
+        };
+        self.advance();
+        if *self.current_token() != Token::LBrace {
+            return Err(self.error_after("Expected '{' after struct name"));
+        }
+        self.advance();
+        let mut fields = Vec::new();
+        while *self.current_token() != Token::RBrace {
+            let Token::Identifier(field) = self.current_token().clone() else {
+                return Err(self.error("Expected field name"));
+            };
+            if fields.contains(&field) {
+                return Err(self.error(&format!("Duplicate field '{field}'")));
+            }
+            fields.push(field);
+            self.advance();
+            match self.current_token() {
+                Token::Comma => self.advance(),
+                Token::RBrace => {}
+                _ => return Err(self.error_after("Expected ',' or '}' after field")),
+            }
+        }
+        self.advance();
+        Ok(Spanned::new(
+            ASTNode::StructDef { name, fields },
+            start.to(&self.previous_span()),
+        ))
+    }
+
+    // enum Name { Variant(field, ...), Unit, ... }
+    pub fn enum_statement(&mut self) -> Result<Node, CrystalError> {
+        let start = self.current_span();
+        self.advance();
+        let Token::Identifier(name) = self.current_token().clone() else {
+            return Err(self.error("Expected enum name after 'enum'"));
+        };
+        self.advance();
+        if *self.current_token() != Token::LBrace {
+            return Err(self.error_after("Expected '{' after enum name"));
+        }
+        self.advance();
+        let mut variants: Vec<(String, Vec<String>)> = Vec::new();
+        while *self.current_token() != Token::RBrace {
+            let Token::Identifier(variant) = self.current_token().clone() else {
+                return Err(self.error("Expected variant name"));
+            };
+            if variants.iter().any(|(name, _)| *name == variant) {
+                return Err(self.error(&format!("Duplicate variant '{variant}'")));
+            }
+            self.advance();
+            let mut fields = Vec::new();
+            if *self.current_token() == Token::LParen {
+                self.advance();
+                while *self.current_token() != Token::RParen {
+                    let Token::Identifier(field) = self.current_token().clone() else {
+                        return Err(self.error("Expected field name"));
+                    };
+                    if fields.contains(&field) {
+                        return Err(self.error(&format!("Duplicate field '{field}'")));
+                    }
+                    fields.push(field);
+                    self.advance();
+                    match self.current_token() {
+                        Token::Comma => self.advance(),
+                        Token::RParen => {}
+                        _ => return Err(self.error_after("Expected ',' or ')' after field")),
+                    }
+                }
+                self.advance();
+            }
+            variants.push((variant, fields));
+            match self.current_token() {
+                Token::Comma => self.advance(),
+                Token::RBrace => {}
+                _ => return Err(self.error_after("Expected ',' or '}' after variant")),
+            }
+        }
+        self.advance();
+        Ok(Spanned::new(
+            ASTNode::EnumDef { name, variants },
+            start.to(&self.previous_span()),
+        ))
+    }
+
+    // match subject { pattern => body, ... }; as a statement, bodies may also be blocks
+    pub fn match_expression(&mut self, statement: bool) -> Result<Node, CrystalError> {
+        let start = self.current_span();
+        self.advance();
+        let subject = self.header()?;
+        if *self.current_token() != Token::LBrace {
+            return Err(self.error_after("Expected '{' after match subject"));
+        }
+        self.advance();
+        let mut arms = Vec::new();
+        while *self.current_token() != Token::RBrace {
+            if *self.current_token() == Token::Eof {
+                return Err(self.error("Expected '}' to close match"));
+            }
+            let pattern = self.pattern()?;
+            if *self.current_token() != Token::FatArrow {
+                return Err(self.error_after("Expected '=>' after pattern"));
+            }
+            self.advance();
+            let is_block = statement && *self.current_token() == Token::LBrace;
+            let body = if is_block {
+                self.block()?
+            } else {
+                self.expression()?
+            };
+            arms.push(MatchArm { pattern, body });
+            match self.current_token() {
+                Token::Comma => self.advance(),
+                Token::RBrace => {}
+                _ if is_block => {}
+                _ => return Err(self.error_after("Expected ',' or '}' after match arm")),
+            }
+        }
+        self.advance();
+        Ok(Spanned::new(
+            ASTNode::Match {
+                subject: Box::new(subject),
+                arms,
+            },
+            start.to(&self.previous_span()),
+        ))
+    }
+
+    fn pattern(&mut self) -> Result<Spanned<Pattern>, CrystalError> {
+        let span = self.current_span();
+        let pattern = match self.current_token().clone() {
+            Token::Identifier(name) if name == "_" => Pattern::Wildcard,
+            Token::Identifier(name) if *self.peek_token() == Token::LParen => {
+                self.advance();
+                self.advance();
+                let mut fields = Vec::new();
+                while *self.current_token() != Token::RParen {
+                    fields.push(self.pattern()?);
+                    match self.current_token() {
+                        Token::Comma => self.advance(),
+                        Token::RParen => {}
+                        _ => return Err(self.error_after("Expected ',' or ')' after pattern")),
+                    }
+                }
+                self.advance();
+                return Ok(Spanned::new(
+                    Pattern::Variant(name, fields),
+                    span.to(&self.previous_span()),
+                ));
+            }
+            Token::Identifier(name) => Pattern::Binding(name),
+            Token::Int(n) => Pattern::Literal(ASTNode::Int(n)),
+            Token::Float(n) => Pattern::Literal(ASTNode::Float(n)),
+            Token::String(s) => Pattern::Literal(ASTNode::String(s)),
+            Token::True => Pattern::Literal(ASTNode::Bool(true)),
+            Token::False => Pattern::Literal(ASTNode::Bool(false)),
+            Token::Nil => Pattern::Literal(ASTNode::Nil),
+            Token::Arithmetic(MathToken::Minus) => {
+                self.advance();
+                let literal = match *self.current_token() {
+                    Token::Int(n) => ASTNode::Int(-n),
+                    Token::Float(n) => ASTNode::Float(-n),
+                    _ => return Err(self.error("Expected a number after '-' in pattern")),
+                };
+                Pattern::Literal(literal)
+            }
+            token => return Err(self.error(&format!("Expected pattern, found {token}"))),
+        };
+        self.advance();
+        Ok(Spanned::new(pattern, span.to(&self.previous_span())))
+    }
+
+    pub fn let_statement(&mut self) -> Result<Node, CrystalError> {
+        let start = self.current_span();
         self.advance();
         if let Token::Identifier(name) = self.current_token().clone() {
             self.advance();
             if *self.current_token() != Token::Equals {
-                panic!("CRY.ERROR: Expected '=' after identifier");
+                return Err(self.error_after("Expected '=' after identifier"));
             }
             self.advance();
-            let value = self.expression();
+            let value = self.expression()?;
             if *self.current_token() != Token::Semicolon {
-                panic!("CRY.ERROR: Expected ';' after expression");
+                return Err(self.error_after("Expected ';' after expression"));
             }
             self.advance();
-            ASTNode::Let(name, Box::new(value))
+            Ok(Spanned::new(
+                ASTNode::Let(name, Box::new(value)),
+                start.to(&self.previous_span()),
+            ))
         } else {
-            panic!("CRY.ERROR: Expected identifier after 'let'");
+            Err(self.error("Expected identifier after 'let'"))
         }
     }
 
-    pub fn final_statement(&mut self) -> ASTNode {
+    pub fn final_statement(&mut self) -> Result<Node, CrystalError> {
+        let start = self.current_span();
         self.advance();
         if let Token::Identifier(name) = self.current_token().clone() {
             self.advance();
             if *self.current_token() != Token::Equals {
-                panic!("CRY.ERROR: Expected '=' after identifier");
+                return Err(self.error_after("Expected '=' after identifier"));
             }
             self.advance();
-            let value = self.expression();
+            let value = self.expression()?;
             if *self.current_token() != Token::Semicolon {
-                panic!("CRY.ERROR: Expected ';' after expression");
+                return Err(self.error_after("Expected ';' after expression"));
             }
             self.advance();
-            ASTNode::Final(name, Box::new(value))
+            Ok(Spanned::new(
+                ASTNode::Final(name, Box::new(value)),
+                start.to(&self.previous_span()),
+            ))
         } else {
-            panic!("CRY.ERROR: Expected identifier after 'final'");
+            Err(self.error("Expected identifier after 'final'"))
         }
     }
 
-    pub fn expression(&mut self) -> ASTNode {
-        let mut left = self.term();
-        println!("{:?}", self.tokens);
-        while matches!(self.current_token(), Token::Arithmetic(..)) {
+    pub fn expression(&mut self) -> Result<Node, CrystalError> {
+        let struct_literals = std::mem::replace(&mut self.struct_literals, true);
+        let expr = self.expression_bp(0);
+        self.struct_literals = struct_literals;
+        expr
+    }
+
+    // The expression before a statement's `{ body }`
+    fn header(&mut self) -> Result<Node, CrystalError> {
+        self.struct_literals = false;
+        let expr = self.expression_bp(0);
+        self.struct_literals = true;
+        expr
+    }
+
+    // Precedence climbing: keeps folding infix operators that bind tighter than `min_bp`
+    fn expression_bp(&mut self, min_bp: u8) -> Result<Node, CrystalError> {
+        let mut left = self.postfix()?;
+        while let Some(bp) = infix_binding_power(self.current_token()) {
+            if bp <= min_bp {
+                break;
+            }
             let op = self.current_token().clone();
             self.advance();
-            let right = self.term();
-            left = ASTNode::BinaryOp {
-                left: Box::new(left),
-                op,
-                right: Box::new(right),
+            // `**` is right associative: 2 ** 3 ** 2 is 2 ** (3 ** 2)
+            let right_bp = if op == Token::Arithmetic(MathToken::Power) {
+                bp - 1
+            } else {
+                bp
+            };
+            let right = self.expression_bp(right_bp)?;
+            let span = left.span.to(&right.span);
+            let node = if op == Token::DotDot {
+                ASTNode::Range {
+                    start: Box::new(left),
+                    end: Box::new(right),
+                }
+            } else {
+                ASTNode::BinaryOp {
+                    left: Box::new(left),
+                    op,
+                    right: Box::new(right),
+                }
+            };
+            left = Spanned::new(node, span);
+        }
+        Ok(left)
+    }
+
+    pub fn assignment_or_expression(&mut self) -> Result<Node, CrystalError> {
+        let start = self.current_span();
+        if let Token::Identifier(ident) = self.current_token().clone() {
+            if *self.peek_token() == Token::Equals {
+                // Assignment: ident = expression;
+                self.advance();
+                self.advance();
+                let value = self.expression()?;
+                if *self.current_token() != Token::Semicolon {
+                    return Err(self.error_after("Expected ';' after expression"));
+                }
+                self.advance();
+                return Ok(Spanned::new(
+                    ASTNode::Assign {
+                        ident,
+                        value: Box::new(value),
+                    },
+                    start.to(&self.previous_span()),
+                ));
+            }
+            if is_compound_op(self.peek_token()) {
+                // Compound assignment: ident op= expression;
+                self.advance();
+                let op = self.current_token().clone();
+                self.advance();
+                let value = self.expression()?;
+                if *self.current_token() != Token::Semicolon {
+                    return Err(self.error_after("Expected ';' after expression"));
+                }
+                self.advance();
+                return Ok(Spanned::new(
+                    ASTNode::CompoundAssign {
+                        ident,
+                        op,
+                        value: Box::new(value),
+                    },
+                    start.to(&self.previous_span()),
+                ));
+            }
+        }
+
+        let expr = self.expression()?;
+        let op = self.current_token().clone();
+        if op == Token::Equals || is_compound_op(&op) {
+            if !matches!(expr.node, ASTNode::Index { .. } | ASTNode::Field { .. }) {
+                return Err(CrystalError::parse(
+                    "Can only assign to a variable, an index or a field",
+                    expr.span,
+                ));
+            }
+            self.advance();
+            let value = Box::new(self.expression()?);
+            if *self.current_token() != Token::Semicolon {
+                return Err(self.error_after("Expected ';' after expression"));
+            }
+            self.advance();
+            let node = match (expr.node, op) {
+                (ASTNode::Index { target, index }, Token::Equals) => ASTNode::IndexAssign {
+                    target,
+                    index,
+                    value,
+                },
+                (ASTNode::Field { target, field }, Token::Equals) => ASTNode::FieldAssign {
+                    target,
+                    field,
+                    value,
+                },
+                (ASTNode::Index { target, index }, op) => ASTNode::CompoundIndexAssign {
+                    target,
+                    index,
+                    op,
+                    value,
+                },
+                (ASTNode::Field { target, field }, op) => ASTNode::CompoundFieldAssign {
+                    target,
+                    field,
+                    op,
+                    value,
+                },
+                _ => unreachable!("checked above"),
             };
+            return Ok(Spanned::new(node, start.to(&self.previous_span())));
+        }
+        if *self.current_token() != Token::Semicolon {
+            return Err(self.error_after("Expected ';' after expression"));
+        }
+        self.advance();
+        Ok(expr)
+    }
+
+    // Comma separated expressions between parentheses: (a, b, ...)
+    fn arguments(&mut self) -> Result<Vec<Node>, CrystalError> {
+        self.advance();
+        let mut args = Vec::new();
+        while *self.current_token() != Token::RParen {
+            args.push(self.expression()?);
+            match self.current_token() {
+                Token::Comma => self.advance(),
+                Token::RParen => {}
+                _ => return Err(self.error_after("Expected ',' or ')' after argument")),
+            }
+        }
+        self.advance();
+        Ok(args)
+    }
+
+    // [a, b, ...], allowing a trailing comma
+    fn list(&mut self) -> Result<Node, CrystalError> {
+        let start = self.current_span();
+        self.advance();
+        let mut items = Vec::new();
+        while *self.current_token() != Token::RBracket {
+            items.push(self.expression()?);
+            match self.current_token() {
+                Token::Comma => self.advance(),
+                Token::RBracket => {}
+                _ => return Err(self.error_after("Expected ',' or ']' after list item")),
+            }
         }
-        left
-    }
-
-    pub fn assignment_or_expression(&mut self) -> ASTNode {
-        let expr = self.expression();
-        match expr {
-            ASTNode::BinaryOp {
-                ref left,
-                ref op,
-                ref right,
-            } => {
-                match op {
-                    Token::Arithmetic(MathToken::PlusEq)
-                    | Token::Arithmetic(MathToken::MinusEq)
-                    | Token::Arithmetic(MathToken::MultiplyEq)
-                    | Token::Arithmetic(MathToken::DivideEq) => {
-                        // Expecting compound assignment
-                        if let ASTNode::Identifier(ident) = *left.clone() {
-                            let value = right.clone();
-                            if *self.current_token() != Token::Semicolon {
-                                panic!("CRY.ERROR: Expected ';' after expression");
-                            }
-                            self.advance();
-                            ASTNode::CompoundAssign {
-                                ident,
-                                op: op.clone(),
-                                value: Box::new(*value),
-                            }
-                        } else {
-                            panic!("CRY.ERROR: Expected identifier for compound assignment");
-                        }
+        self.advance();
+        Ok(Spanned::new(
+            ASTNode::List(items),
+            start.to(&self.previous_span()),
+        ))
+    }
+
+    // {key: value, ...}, allowing a trailing comma
+    fn map(&mut self) -> Result<Node, CrystalError> {
+        let start = self.current_span();
+        self.advance();
+        let mut entries = Vec::new();
+        while *self.current_token() != Token::RBrace {
+            let key = self.expression()?;
+            if *self.current_token() != Token::Colon {
+                return Err(self.error_after("Expected ':' after map key"));
+            }
+            self.advance();
+            entries.push((key, self.expression()?));
+            match self.current_token() {
+                Token::Comma => self.advance(),
+                Token::RBrace => {}
+                _ => return Err(self.error_after("Expected ',' or '}' after map entry")),
+            }
+        }
+        self.advance();
+        Ok(Spanned::new(
+            ASTNode::Map(entries),
+            start.to(&self.previous_span()),
+        ))
+    }
+
+    // A term followed by any number of `[index]`s and `.field`s
+    fn postfix(&mut self) -> Result<Node, CrystalError> {
+        let mut node = self.term()?;
+        loop {
+            if *self.current_token() == Token::Dot {
+                self.advance();
+                let Token::Identifier(field) = self.current_token().clone() else {
+                    return Err(self.error("Expected field name after '.'"));
+                };
+                self.advance();
+                let span = node.span.to(&self.previous_span());
+                node = Spanned::new(
+                    ASTNode::Field {
+                        target: Box::new(node),
+                        field,
+                    },
+                    span,
+                );
+                continue;
+            }
+            if *self.current_token() == Token::LParen {
+                let args = self.arguments()?;
+                let span = node.span.to(&self.previous_span());
+                node = Spanned::new(ASTNode::FunCall(Box::new(node), args), span);
+                continue;
+            }
+            if *self.current_token() != Token::LBracket {
+                return Ok(node);
+            }
+            self.advance();
+            let index = self.expression()?;
+            if *self.current_token() != Token::RBracket {
+                return Err(self.error_after("Expected ']' after index"));
+            }
+            self.advance();
+            let span = node.span.to(&self.previous_span());
+            node = Spanned::new(
+                ASTNode::Index {
+                    target: Box::new(node),
+                    index: Box::new(index),
+                },
+                span,
+            );
+        }
+    }
+
+    // Name { field: value, ... }, with the name already consumed
+    fn struct_literal(&mut self, name: String, start: Span) -> Result<Node, CrystalError> {
+        self.advance();
+        let mut fields: Vec<(String, Node)> = Vec::new();
+        while *self.current_token() != Token::RBrace {
+            let Token::Identifier(field) = self.current_token().clone() else {
+                return Err(self.error("Expected field name"));
+            };
+            if fields.iter().any(|(name, _)| *name == field) {
+                return Err(self.error(&format!("Field '{field}' given twice")));
+            }
+            self.advance();
+            if *self.current_token() != Token::Colon {
+                return Err(self.error_after("Expected ':' after field name"));
+            }
+            self.advance();
+            fields.push((field, self.expression()?));
+            match self.current_token() {
+                Token::Comma => self.advance(),
+                Token::RBrace => {}
+                _ => return Err(self.error_after("Expected ',' or '}' after field")),
+            }
+        }
+        self.advance();
+        Ok(Spanned::new(
+            ASTNode::StructLit { name, fields },
+            start.to(&self.previous_span()),
+        ))
+    }
+
+    fn template(&mut self, parts: Vec<TemplatePart>, span: Span) -> Result<Node, CrystalError> {
+        let mut nodes = Vec::new();
+        for part in parts {
+            match part {
+                TemplatePart::Text(text) => {
+                    nodes.push(Spanned::new(ASTNode::String(text), span.clone()))
+                }
+                TemplatePart::Code(tokens) => {
+                    let mut parser = Parser::new(tokens);
+                    parser.function_depth = self.function_depth;
+                    if *parser.current_token() == Token::Eof {
+                        return Err(parser.error("Expected expression inside '${}'"));
                     }
-                    _ => expr.clone(), // Return the original expression node if not a compound assignment
+                    let expr = parser.expression()?;
+                    if *parser.current_token() != Token::Eof {
+                        return Err(parser.error("Expected '}' to close '${'"));
+                    }
+                    nodes.push(expr);
                 }
             }
-            _ => expr, // Return the original expression node if not a binary operation
         }
+        Ok(Spanned::new(ASTNode::Interpolation(nodes), span))
     }
 
-    pub fn term(&mut self) -> ASTNode {
-        match self.current_token() {
-            Token::Number(n) => {
-                let number = *n;
+    pub fn term(&mut self) -> Result<Node, CrystalError> {
+        let span = self.current_span();
+        let node = match self.current_token() {
+            Token::Int(n) => ASTNode::Int(*n),
+            Token::Float(n) => ASTNode::Float(*n),
+            Token::Identifier(i) if self.struct_literals && *self.peek_token() == Token::LBrace => {
+                let name = i.clone();
                 self.advance();
-                ASTNode::Number(number)
+                return self.struct_literal(name, span);
             }
-            Token::Identifier(i) => {
-                let ident = i.clone();
+            Token::Identifier(i) => ASTNode::Identifier(i.clone()),
+            Token::String(v) => ASTNode::String(v.clone()),
+            Token::Template(parts) => {
+                let parts = parts.clone();
                 self.advance();
-                ASTNode::Identifier(ident)
+                return self.template(parts, span);
             }
-            Token::String(v) => {
-                let value = v.clone();
+            Token::True => ASTNode::Bool(true),
+            Token::False => ASTNode::Bool(false),
+            Token::Nil => ASTNode::Nil,
+            Token::Arithmetic(MathToken::Minus) | Token::Not => {
+                let op = self.current_token().clone();
+                let bp = if op == Token::Not {
+                    NOT_BINDING_POWER
+                } else {
+                    UNARY_BINDING_POWER
+                };
                 self.advance();
-                ASTNode::String(value)
+                let operand = self.expression_bp(bp)?;
+                let span = span.to(&operand.span);
+                return Ok(Spanned::new(
+                    ASTNode::UnaryOp {
+                        op,
+                        operand: Box::new(operand),
+                    },
+                    span,
+                ));
             }
-
-            _ => {
-                println!("{:?}", self.tokens);
-                panic!("CRY.ERROR: Unexpected token: {:?}", self.current_token())
+            Token::Fn => return self.function(),
+            Token::Match => return self.match_expression(false),
+            Token::LBracket => return self.list(),
+            Token::LBrace => return self.map(),
+            Token::LParen => {
+                self.advance();
+                let inner = self.expression()?;
+                if *self.current_token() != Token::RParen {
+                    return Err(self.error_after("Expected ')' to close '('"));
+                }
+                self.advance();
+                return Ok(Spanned::new(inner.node, span.to(&self.previous_span())));
             }
-        }
+            token => return Err(self.error(&format!("Expected expression, found {token}"))),
+        };
+        self.advance();
+        Ok(Spanned::new(node, span))
+    }
+}
+
+const UNARY_BINDING_POWER: u8 = 30;
+// `not a == b` negates the whole comparison but stops at `and`/`or`
+const NOT_BINDING_POWER: u8 = 3;
+
+// How tightly an infix operator binds, or None if the token can't continue an expression
+fn infix_binding_power(token: &Token) -> Option<u8> {
+    match token {
+        Token::Or => Some(1),
+        Token::And => Some(2),
+        Token::Compare(_) => Some(4),
+        Token::DotDot => Some(5),
+        Token::Arithmetic(MathToken::Plus | MathToken::Minus) => Some(10),
+        Token::Arithmetic(
+            MathToken::Multiply | MathToken::Divide | MathToken::IntDivide | MathToken::Modulo,
+        ) => Some(20),
+        // Tighter than unary minus, so -2 ** 2 is -(2 ** 2)
+        Token::Arithmetic(MathToken::Power) => Some(40),
+        _ => None,
     }
 }
+
+fn is_compound_op(token: &Token) -> bool {
+    matches!(
+        token,
+        Token::Arithmetic(
+            MathToken::PlusEq | MathToken::MinusEq | MathToken::MultiplyEq | MathToken::DivideEq
+        )
+    )
+}
@@ -0,0 +1,540 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use super::{
+    error::CrystalError,
+    memories::{eval, Binding, Declaration, EnumType, Env, Memory, Scope, Variant},
+    parser::{ASTNode, MatchArm, Node, Pattern},
+    span::{Span, Spanned},
+};
+
+// A pattern with its names looked up, so variants are told apart from bindings
+#[derive(Debug, Clone)]
+enum Pat {
+    Wild,
+    Bind(String),
+    Bool(bool),
+    // Any other literal; there are too many of these for a match to list them all
+    Literal(Memory),
+    Variant(Rc<EnumType>, usize, Vec<Pat>),
+}
+
+// Evaluates the subject and picks the first arm it matches, returning that arm's body
+// and a scope holding what its pattern bound
+pub fn select<'a>(
+    node: &Node,
+    subject: &Node,
+    arms: &'a [MatchArm],
+    env: &mut Env,
+) -> Result<(&'a Node, Rc<RefCell<Scope>>), CrystalError> {
+    let value = eval(subject, env)?;
+    let patterns: Vec<_> = arms.iter().map(|arm| &arm.pattern).collect();
+    let lookup = |name: &str| env.get(name).map(|binding| binding.value);
+    let (arm, bindings) = choose(&patterns, value, &lookup, &node.span, &subject.span)?;
+    let scope = Scope::child(&env.scope);
+    for (name, value) in bindings {
+        scope.borrow_mut().context.insert(
+            name,
+            Binding {
+                value,
+                declaration: Declaration::Let,
+            },
+        );
+    }
+    Ok((&arms[arm].body, scope))
+}
+
+// Checks the patterns cover every value, then gives the index of the first one `value`
+// matches and what it binds. `lookup` finds what a name in a pattern currently refers to.
+pub fn choose(
+    patterns: &[&Spanned<Pattern>],
+    value: Memory,
+    lookup: &dyn Fn(&str) -> Option<Memory>,
+    span: &Span,
+    subject_span: &Span,
+) -> Result<(usize, Vec<(String, Memory)>), CrystalError> {
+    let pats = patterns
+        .iter()
+        .map(|pattern| {
+            let mut names = Vec::new();
+            resolve(pattern, lookup, &mut names)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let missing = missing_arms(&pats);
+    if !missing.is_empty() {
+        return Err(not_exhaustive(&missing, span));
+    }
+
+    for (arm, pat) in pats.iter().enumerate() {
+        let mut bindings = Vec::new();
+        if matches(pat, &value, &mut bindings) {
+            return Ok((arm, bindings));
+        }
+    }
+    Err(CrystalError::runtime(
+        format!("No match arm matches {}", value.repr()),
+        subject_span.clone(),
+    ))
+}
+
+fn not_exhaustive(missing: &[String], span: &Span) -> CrystalError {
+    CrystalError::type_error(
+        format!("Match is not exhaustive, missing {}", missing.join(", ")),
+        span.clone(),
+    )
+}
+
+// What a name in a pattern refers to, as far as can be told without running the program
+#[derive(Clone)]
+enum Meaning {
+    Variant(Memory),
+    // A function, struct or enum, which a bare name in a pattern binds over
+    Other,
+    // A variable, which only names a variant if it holds one when the match runs
+    Unknown,
+}
+
+// Reports every `match` inside a function that misses arms before anything runs, so a
+// function that is never called can't hide one. A match outside functions is checked
+// when it runs, and one whose names depend on variables is left to that check too.
+pub fn check(program: &Node) -> Result<(), Vec<CrystalError>> {
+    let mut checker = Checker {
+        scopes: Vec::new(),
+        functions: 0,
+        errors: Vec::new(),
+    };
+    checker.visit(program);
+    if checker.errors.is_empty() {
+        Ok(())
+    } else {
+        Err(checker.errors)
+    }
+}
+
+struct Checker {
+    // Every name declared in each enclosing block, wherever in the block it is declared,
+    // since a function body sees declarations made after it
+    scopes: Vec<HashMap<String, Meaning>>,
+    functions: usize,
+    errors: Vec<CrystalError>,
+}
+
+impl Checker {
+    fn meaning(&self, name: &str) -> Option<&Meaning> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name))
+    }
+
+    fn within(&mut self, scope: HashMap<String, Meaning>, nodes: &[&Node]) {
+        self.scopes.push(scope);
+        for node in nodes {
+            self.visit(node);
+        }
+        self.scopes.pop();
+    }
+
+    fn block(&mut self, nodes: &[Node]) {
+        let mut scope = HashMap::new();
+        let mut declare = |name: &String, meaning| {
+            // Two declarations of one name leave it to whichever runs last
+            if scope.insert(name.clone(), meaning).is_some() {
+                scope.insert(name.clone(), Meaning::Unknown);
+            }
+        };
+        for node in nodes {
+            match &node.node {
+                ASTNode::Let(name, _) | ASTNode::Final(name, _) => declare(name, Meaning::Unknown),
+                ASTNode::FunDef {
+                    name: Some(name), ..
+                }
+                | ASTNode::StructDef { name, .. } => declare(name, Meaning::Other),
+                ASTNode::EnumDef { name, variants } => {
+                    let def = Rc::new(EnumType {
+                        name: name.clone(),
+                        variants: variants.clone(),
+                    });
+                    for (index, (variant, fields)) in variants.iter().enumerate() {
+                        let value = if fields.is_empty() {
+                            Memory::Variant(Rc::new(Variant {
+                                def: def.clone(),
+                                index,
+                                values: Vec::new(),
+                            }))
+                        } else {
+                            Memory::Constructor(def.clone(), index)
+                        };
+                        declare(variant, Meaning::Variant(value));
+                    }
+                    declare(name, Meaning::Other);
+                }
+                _ => {}
+            }
+        }
+        self.within(scope, &nodes.iter().collect::<Vec<_>>());
+    }
+
+    fn matching(&mut self, node: &Node, arms: &[MatchArm]) {
+        let mut variants = HashMap::new();
+        for name in arms.iter().flat_map(|arm| mentioned(&arm.pattern)) {
+            match self.meaning(&name) {
+                Some(Meaning::Unknown) => return,
+                Some(Meaning::Variant(value)) => {
+                    variants.insert(name, value.clone());
+                }
+                Some(Meaning::Other) | None => {}
+            }
+        }
+        let lookup = |name: &str| variants.get(name).cloned();
+        // A pattern that can't be resolved fails when the match runs, with a better error
+        let Ok(pats) = arms
+            .iter()
+            .map(|arm| resolve(&arm.pattern, &lookup, &mut Vec::new()))
+            .collect::<Result<Vec<_>, _>>()
+        else {
+            return;
+        };
+        let missing = missing_arms(&pats);
+        if !missing.is_empty() {
+            self.errors.push(not_exhaustive(&missing, &node.span));
+        }
+    }
+
+    fn visit(&mut self, node: &Node) {
+        let unknown = |names: &[&String]| {
+            names
+                .iter()
+                .map(|name| (name.to_string(), Meaning::Unknown))
+                .collect::<HashMap<_, _>>()
+        };
+        match &node.node {
+            ASTNode::Program(nodes) | ASTNode::Block(nodes) => self.block(nodes),
+            ASTNode::FunDef { params, body, .. } => {
+                self.functions += 1;
+                let params: Vec<_> = params.iter().collect();
+                self.within(unknown(&params), &[body]);
+                self.functions -= 1;
+            }
+            ASTNode::For {
+                ident,
+                value,
+                iterable,
+                body,
+            } => {
+                self.visit(iterable);
+                let names: Vec<_> = std::iter::once(ident).chain(value).collect();
+                self.within(unknown(&names), &[body]);
+            }
+            ASTNode::Match { subject, arms } => {
+                self.visit(subject);
+                if self.functions > 0 {
+                    self.matching(node, arms);
+                }
+                for arm in arms {
+                    let names = mentioned(&arm.pattern);
+                    let names: Vec<_> = names.iter().collect();
+                    self.within(unknown(&names), &[&arm.body]);
+                }
+            }
+            ASTNode::Let(_, value)
+            | ASTNode::Final(_, value)
+            | ASTNode::Assign { value, .. }
+            | ASTNode::CompoundAssign { value, .. }
+            | ASTNode::UnaryOp { operand: value, .. }
+            | ASTNode::Field { target: value, .. }
+            | ASTNode::Return(Some(value)) => self.visit(value),
+            ASTNode::FunCall(callee, args) => {
+                self.visit(callee);
+                args.iter().for_each(|arg| self.visit(arg));
+            }
+            ASTNode::List(items) | ASTNode::Interpolation(items) => {
+                items.iter().for_each(|item| self.visit(item))
+            }
+            ASTNode::Map(entries) => {
+                for (key, value) in entries {
+                    self.visit(key);
+                    self.visit(value);
+                }
+            }
+            ASTNode::StructLit { fields, .. } => {
+                fields.iter().for_each(|(_, value)| self.visit(value))
+            }
+            ASTNode::Index { target, index }
+            | ASTNode::BinaryOp {
+                left: target,
+                right: index,
+                ..
+            }
+            | ASTNode::Range {
+                start: target,
+                end: index,
+            }
+            | ASTNode::FieldAssign {
+                target,
+                value: index,
+                ..
+            }
+            | ASTNode::CompoundFieldAssign {
+                target,
+                value: index,
+                ..
+            }
+            | ASTNode::While {
+                cond: target,
+                body: index,
+            } => {
+                self.visit(target);
+                self.visit(index);
+            }
+            ASTNode::IndexAssign {
+                target,
+                index,
+                value,
+            }
+            | ASTNode::CompoundIndexAssign {
+                target,
+                index,
+                value,
+                ..
+            } => {
+                self.visit(target);
+                self.visit(index);
+                self.visit(value);
+            }
+            ASTNode::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                self.visit(cond);
+                self.visit(then);
+                if let Some(otherwise) = otherwise {
+                    self.visit(otherwise);
+                }
+            }
+            ASTNode::Int(_)
+            | ASTNode::Float(_)
+            | ASTNode::Identifier(_)
+            | ASTNode::String(_)
+            | ASTNode::Bool(_)
+            | ASTNode::Nil
+            | ASTNode::StructDef { .. }
+            | ASTNode::EnumDef { .. }
+            | ASTNode::Return(None)
+            | ASTNode::Break
+            | ASTNode::Continue => {}
+        }
+    }
+}
+
+// Every name a pattern could bind, in order. Whether a name binds or refers to a unit
+// variant is only known once it is looked up.
+pub fn binding_names(pattern: &Spanned<Pattern>, names: &mut Vec<String>) {
+    match &pattern.node {
+        Pattern::Binding(name) if !names.contains(name) => names.push(name.clone()),
+        Pattern::Variant(_, fields) => {
+            for field in fields {
+                binding_names(field, names);
+            }
+        }
+        _ => {}
+    }
+}
+
+// Every name a pattern mentions, as a binding or as a variant
+pub fn mentioned(pattern: &Spanned<Pattern>) -> Vec<String> {
+    match &pattern.node {
+        Pattern::Binding(name) => vec![name.clone()],
+        Pattern::Variant(name, fields) => std::iter::once(name.clone())
+            .chain(fields.iter().flat_map(mentioned))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn resolve(
+    pattern: &Spanned<Pattern>,
+    lookup: &dyn Fn(&str) -> Option<Memory>,
+    names: &mut Vec<String>,
+) -> Result<Pat, CrystalError> {
+    let pat = match &pattern.node {
+        Pattern::Wildcard => Pat::Wild,
+        Pattern::Binding(name) => match lookup(name) {
+            // A bare name is a variant only if it is bound to the field-less variant it names
+            Some(Memory::Variant(variant))
+                if variant.values.is_empty() && variant.name() == name =>
+            {
+                Pat::Variant(variant.def.clone(), variant.index, Vec::new())
+            }
+            _ => {
+                if names.contains(name) {
+                    return Err(CrystalError::name(
+                        format!("'{name}' is bound twice in this pattern"),
+                        pattern.span.clone(),
+                    ));
+                }
+                names.push(name.clone());
+                Pat::Bind(name.clone())
+            }
+        },
+        Pattern::Literal(ASTNode::Bool(b)) => Pat::Bool(*b),
+        Pattern::Literal(literal) => Pat::Literal(match literal {
+            ASTNode::Int(n) => Memory::Int(*n),
+            ASTNode::Float(n) => Memory::Float(*n),
+            ASTNode::String(s) => Memory::String(s.clone()),
+            _ => Memory::Nil,
+        }),
+        Pattern::Variant(name, fields) => {
+            let (def, index) = match lookup(name) {
+                Some(Memory::Constructor(def, index)) => (def, index),
+                Some(Memory::Variant(variant)) if variant.name() == name => {
+                    (variant.def.clone(), variant.index)
+                }
+                _ => {
+                    return Err(CrystalError::name(
+                        format!("Enum variant '{name}' not found"),
+                        pattern.span.clone(),
+                    ))
+                }
+            };
+            let expected = def.variants[index].1.len();
+            if fields.len() != expected {
+                return Err(CrystalError::type_error(
+                    format!(
+                        "Variant '{name}' has {expected} field(s) but the pattern gives {}",
+                        fields.len()
+                    ),
+                    pattern.span.clone(),
+                ));
+            }
+            let fields = fields
+                .iter()
+                .map(|field| resolve(field, lookup, names))
+                .collect::<Result<Vec<_>, _>>()?;
+            Pat::Variant(def, index, fields)
+        }
+    };
+    Ok(pat)
+}
+
+fn matches(pat: &Pat, value: &Memory, bindings: &mut Vec<(String, Memory)>) -> bool {
+    match (pat, value) {
+        (Pat::Wild, _) => true,
+        (Pat::Bind(name), value) => {
+            bindings.push((name.clone(), value.clone()));
+            true
+        }
+        (Pat::Bool(b), Memory::Bool(value)) => b == value,
+        // Numbers match by value, so `1` matches `1.0` as with `==`
+        (Pat::Literal(literal), value) => literal == value,
+        (Pat::Variant(def, index, fields), Memory::Variant(variant)) => {
+            Rc::ptr_eq(def, &variant.def)
+                && *index == variant.index
+                && fields
+                    .iter()
+                    .zip(&variant.values)
+                    .all(|(field, value)| matches(field, value, bindings))
+        }
+        _ => false,
+    }
+}
+
+// A value shape that a column of patterns can start with
+enum Ctor {
+    Variant(Rc<EnumType>, usize),
+    Bool(bool),
+}
+
+// Every constructor of the type a column's patterns test, with its name and field count.
+// Empty when the patterns don't pin down a type with a listable set of values.
+fn signature(rows: &[Vec<Pat>]) -> Vec<(Ctor, String, usize)> {
+    for row in rows {
+        match &row[0] {
+            Pat::Variant(def, ..) => {
+                let variants = def.variants.iter().enumerate();
+                return variants
+                    .map(|(i, (name, fields))| {
+                        (Ctor::Variant(def.clone(), i), name.clone(), fields.len())
+                    })
+                    .collect();
+            }
+            Pat::Bool(_) => {
+                return vec![
+                    (Ctor::Bool(true), "true".to_string(), 0),
+                    (Ctor::Bool(false), "false".to_string(), 0),
+                ]
+            }
+            _ => {}
+        }
+    }
+    Vec::new()
+}
+
+// The rows that can match a value built with `ctor`, with its fields spliced in place of
+// the first column
+fn specialize(rows: &[Vec<Pat>], ctor: &Ctor, arity: usize) -> Vec<Vec<Pat>> {
+    rows.iter()
+        .filter_map(|row| {
+            let head: Vec<Pat> = match (&row[0], ctor) {
+                (Pat::Wild | Pat::Bind(_), _) => vec![Pat::Wild; arity],
+                (Pat::Variant(def, i, fields), Ctor::Variant(of, j))
+                    if Rc::ptr_eq(def, of) && i == j =>
+                {
+                    fields.clone()
+                }
+                (Pat::Bool(b), Ctor::Bool(c)) if b == c => Vec::new(),
+                _ => return None,
+            };
+            Some(head.into_iter().chain(row[1..].iter().cloned()).collect())
+        })
+        .collect()
+}
+
+// Writes a constructor applied to the first `arity` parts of a witness
+fn apply(name: &str, arity: usize, mut witness: Vec<String>) -> Vec<String> {
+    let rest = witness.split_off(arity);
+    let head = if arity == 0 {
+        name.to_string()
+    } else {
+        format!("{name}({})", witness.join(", "))
+    };
+    std::iter::once(head).chain(rest).collect()
+}
+
+// A row of values, written as patterns, that no row of `rows` matches; None if every
+// value is matched
+fn witness(rows: &[Vec<Pat>], width: usize) -> Option<Vec<String>> {
+    if width == 0 {
+        return rows.is_empty().then(Vec::new);
+    }
+    let signature = signature(rows);
+    if signature.is_empty() {
+        let rest: Vec<Vec<Pat>> = rows
+            .iter()
+            .filter(|row| matches!(row[0], Pat::Wild | Pat::Bind(_)))
+            .map(|row| row[1..].to_vec())
+            .collect();
+        let mut witness = witness(&rest, width - 1)?;
+        witness.insert(0, "_".to_string());
+        return Some(witness);
+    }
+    signature.into_iter().find_map(|(ctor, name, arity)| {
+        let specialized = specialize(rows, &ctor, arity);
+        let witness = witness(&specialized, arity + width - 1)?;
+        Some(apply(&name, arity, witness))
+    })
+}
+
+// The arms a match would need to cover every value, one per missing top-level case
+fn missing_arms(pats: &[Pat]) -> Vec<String> {
+    let rows: Vec<Vec<Pat>> = pats.iter().map(|pat| vec![pat.clone()]).collect();
+    let signature = signature(&rows);
+    if signature.is_empty() {
+        return witness(&rows, 1).into_iter().flatten().collect();
+    }
+    signature
+        .into_iter()
+        .filter_map(|(ctor, name, arity)| {
+            let witness = witness(&specialize(&rows, &ctor, arity), arity)?;
+            apply(&name, arity, witness).into_iter().next()
+        })
+        .collect()
+}
@@ -0,0 +1,404 @@
+use colored::*;
+use std::{env, fs, io::Write, path::PathBuf};
+
+use super::{
+    builtins::Io,
+    error::CrystalError,
+    lexer::Lexer,
+    memories::{exec, Env, Flow, Memory},
+    parser::{ASTNode, Node, Parser},
+    patterns,
+};
+
+// Result of trying to compile what has been typed so far
+enum Input {
+    Complete(Node),
+    Incomplete,
+    Failed(Vec<CrystalError>),
+}
+
+pub struct Repl {
+    virtual_brain: Env,
+    history: Vec<String>,
+    history_file: Option<PathBuf>,
+    entries: usize,
+}
+
+impl Repl {
+    pub fn new() -> Self {
+        let history_file =
+            env::var_os("HOME").map(|home| PathBuf::from(home).join(".crystal_history"));
+        Repl::with_io(Io::default(), history_file)
+    }
+
+    // A REPL reading and printing through `io`, keeping its history in `history_file`
+    pub fn with_io(io: Io, history_file: Option<PathBuf>) -> Self {
+        let history = history_file
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|text| text.lines().map(String::from).collect())
+            .unwrap_or_default();
+        Repl {
+            virtual_brain: Env::with_io(io),
+            history,
+            history_file,
+            entries: 0,
+        }
+    }
+
+    pub fn run(&mut self) {
+        writeln!(
+            self.out(),
+            "{} {}",
+            "CRYSTAL-Lang REPL.".bold().cyan(),
+            "Type :help for commands, :quit to leave.".dimmed()
+        )
+        .ok();
+        let mut buffer = String::new();
+        loop {
+            let prompt = if buffer.is_empty() { "cry> " } else { "...> " };
+            write!(self.out(), "{}", prompt.bold().cyan()).ok();
+            self.out().flush().ok();
+
+            let mut line = String::new();
+            match self.virtual_brain.io.stdin.read_line(&mut line) {
+                Ok(0) | Err(_) => {
+                    writeln!(self.out()).ok();
+                    break;
+                }
+                Ok(_) => {}
+            }
+            let line = line.trim_end_matches(['\n', '\r']);
+
+            if buffer.is_empty() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                if let Some(command) = line.trim().strip_prefix(':') {
+                    if !self.meta(command) {
+                        break;
+                    }
+                    continue;
+                }
+            }
+
+            // A blank line ends multi-line input even if it is still incomplete
+            let force = !buffer.is_empty() && line.trim().is_empty();
+            buffer.push_str(line);
+            buffer.push('\n');
+
+            match self.compile(&buffer, force) {
+                Input::Incomplete => continue,
+                Input::Complete(ast) => self.execute(&ast),
+                Input::Failed(errors) => report(&errors),
+            }
+            self.remember(buffer.trim());
+            buffer.clear();
+        }
+    }
+
+    fn out(&mut self) -> &mut dyn Write {
+        &mut self.virtual_brain.io.stdout
+    }
+
+    fn next_name(&mut self) -> String {
+        self.entries += 1;
+        format!("<repl:{}>", self.entries)
+    }
+
+    fn compile(&mut self, input: &str, force: bool) -> Input {
+        let name = self.next_name();
+        match parse(&name, input) {
+            Ok(ast) => Input::Complete(ast),
+            Err(errors) if !force && ends_early(input, &errors) => {
+                // Let the last statement go without its ';', as in `1 + 2` or `let x = 5`
+                match parse(&name, &format!("{};", input.trim_end())) {
+                    Ok(ast) => Input::Complete(ast),
+                    Err(_) => Input::Incomplete,
+                }
+            }
+            Err(errors) => Input::Failed(errors),
+        }
+    }
+
+    fn execute(&mut self, ast: &Node) {
+        let ASTNode::Program(nodes) = &ast.node else {
+            return;
+        };
+        for node in nodes {
+            match exec(node, &mut self.virtual_brain) {
+                Ok(Flow::Value(Memory::Nil)) => {}
+                Ok(Flow::Value(value)) => {
+                    writeln!(self.out(), "{}", value.repr().bright_green()).ok();
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    report(&[err]);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn remember(&mut self, entry: &str) {
+        let entry = entry.replace('\n', " ");
+        if let Some(path) = &self.history_file {
+            let line = format!("{entry}\n");
+            let written = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| file.write_all(line.as_bytes()));
+            if written.is_err() {
+                self.history_file = None;
+            }
+        }
+        self.history.push(entry);
+    }
+
+    // Handles a `:command`; returns false when the REPL should exit
+    fn meta(&mut self, command: &str) -> bool {
+        let (name, arg) = command
+            .split_once(char::is_whitespace)
+            .map(|(name, arg)| (name, arg.trim()))
+            .unwrap_or((command, ""));
+        // `:ast` and `:tokens` with no code look at the previous entry
+        let code = if arg.is_empty() {
+            self.history.last().cloned().unwrap_or_default()
+        } else {
+            arg.to_string()
+        };
+
+        match name {
+            "help" => repl_help(self.out()),
+            "quit" | "exit" | "q" => return false,
+            "vars" => {
+                let scope = self.virtual_brain.scope.borrow();
+                let out = &mut self.virtual_brain.io.stdout;
+                let mut names: Vec<_> = scope.context.iter().collect();
+                names.sort_by(|a, b| a.0.cmp(b.0));
+                if names.is_empty() {
+                    writeln!(out, "{}", "No memories yet.".dimmed()).ok();
+                }
+                for (ident, binding) in names {
+                    let keyword = binding.declaration.keyword();
+                    writeln!(
+                        out,
+                        "{} {} = {}",
+                        keyword.bold().blue(),
+                        ident,
+                        binding.value.repr().bright_green()
+                    )
+                    .ok();
+                }
+            }
+            "ast" => {
+                let name = self.next_name();
+                match Lexer::new(name, code).tokenize() {
+                    Ok(tokens) => {
+                        let (ast, errors) = Parser::new(tokens).parse_recovering();
+                        writeln!(self.out(), "{ast:#?}").ok();
+                        report(&errors);
+                    }
+                    Err(err) => report(&[err]),
+                }
+            }
+            "tokens" => {
+                let name = self.next_name();
+                match Lexer::new(name, code).tokenize() {
+                    Ok(tokens) => {
+                        for token in tokens {
+                            let at = format!("{}:{}", token.span.line, token.span.column);
+                            writeln!(self.out(), "{:>6}  {:?}", at.dimmed(), token.node).ok();
+                        }
+                    }
+                    Err(err) => report(&[err]),
+                }
+            }
+            "reset" => {
+                self.virtual_brain.scope = Default::default();
+                writeln!(self.out(), "{}", "Context cleared.".cyan()).ok();
+            }
+            "history" => {
+                let out = &mut self.virtual_brain.io.stdout;
+                for (i, entry) in self.history.iter().enumerate() {
+                    writeln!(out, "{:>4}  {entry}", (i + 1).to_string().dimmed()).ok();
+                }
+            }
+            _ => {
+                let message =
+                    format!("CRYSTAL.Error: Unknown command ':{name}'. Type :help for commands.");
+                writeln!(self.out(), "{}", message.bright_red()).ok();
+            }
+        }
+        true
+    }
+}
+
+fn parse(name: &str, input: &str) -> Result<Node, Vec<CrystalError>> {
+    let tokens = Lexer::new(name, input.to_string())
+        .tokenize()
+        .map_err(|err| vec![err])?;
+    let ast = Parser::new(tokens).parse()?;
+    patterns::check(&ast)?;
+    Ok(ast)
+}
+
+// True when parsing only failed because the input ran out, so more lines may fix it
+fn ends_early(input: &str, errors: &[CrystalError]) -> bool {
+    let end = input.trim_end().chars().count();
+    errors
+        .iter()
+        .all(|err| matches!(err, CrystalError::Parse(_)) && err.diagnostic().span.start >= end)
+}
+
+fn report(errors: &[CrystalError]) {
+    for err in errors {
+        eprintln!("{}", err.render());
+    }
+}
+
+fn repl_help(out: &mut dyn Write) {
+    let commands = [
+        (":help", "show this list"),
+        (":vars", "show every memory in the current context"),
+        (
+            ":ast ?CODE?",
+            "print the AST of CODE (or the previous entry)",
+        ),
+        (
+            ":tokens ?CODE?",
+            "print the tokens of CODE (or the previous entry)",
+        ),
+        (":reset", "forget every memory"),
+        (":history", "show previous entries"),
+        (":quit", "leave the REPL"),
+    ];
+    writeln!(out, "{}", "REPL Commands:".bold().cyan()).ok();
+    for (command, about) in commands {
+        writeln!(out, "{}  {about}", format!("{command:<15}").bold().green()).ok();
+    }
+    writeln!(
+        out,
+        "\nBare expressions print their value. Unfinished statements continue\non the next line; an empty line ends them."
+    )
+    .ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        fs,
+        io::{self, Cursor, Write},
+        rc::Rc,
+    };
+
+    use super::{Input, Repl};
+    use crate::builtins::Io;
+
+    // A stdout the test can still read after handing it to the REPL
+    #[derive(Clone, Default)]
+    struct Captured(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn repl(stdout: &Captured, stdin: &str) -> Repl {
+        colored::control::set_override(false);
+        let stdin = Cursor::new(stdin.as_bytes().to_vec());
+        Repl::with_io(Io::new(Box::new(stdout.clone()), Box::new(stdin)), None)
+    }
+
+    // Types `input` into a fresh REPL and gives what it printed, one line per entry with
+    // the prompts dropped
+    fn session(input: &str) -> Vec<String> {
+        let stdout = Captured::default();
+        repl(&stdout, input).run();
+        let printed = String::from_utf8(stdout.0.borrow().clone()).expect("utf-8 output");
+        printed
+            .lines()
+            .skip(1)
+            .map(|line| line.replace("cry> ", "").replace("...> ", ""))
+            .filter(|line| !line.is_empty())
+            .collect()
+    }
+
+    #[test]
+    fn a_missing_semicolon_is_allowed_on_the_last_statement() {
+        assert_eq!(session("1 + 2\nlet x = 5\nx * 2\n"), ["3", "10"]);
+    }
+
+    #[test]
+    fn unfinished_statements_continue_on_the_next_line() {
+        let input = "fn add(a, b) {\n    return a + b;\n}\nadd(1,\n2)\n";
+        assert_eq!(session(input), ["3"]);
+    }
+
+    #[test]
+    fn an_empty_line_ends_unfinished_input() {
+        // The broken entry is reported and dropped, so the next line starts a new one
+        assert_eq!(session("let x = [1,\n\n1 + 1\n"), ["2"]);
+    }
+
+    #[test]
+    fn vars_lists_memories_until_reset() {
+        let input = "let x = 5;\nfinal y = \"hi\";\n:vars\n:reset\n:vars\nx\n";
+        assert_eq!(
+            session(input),
+            [
+                "let x = 5",
+                "final y = \"hi\"",
+                "Context cleared.",
+                "No memories yet."
+            ]
+        );
+    }
+
+    #[test]
+    fn compile_waits_only_when_the_input_ran_out() {
+        let mut repl = repl(&Captured::default(), "");
+        assert!(matches!(repl.compile("1 + 2\n", false), Input::Complete(_)));
+        assert!(matches!(
+            repl.compile("if true {\n", false),
+            Input::Incomplete
+        ));
+        assert!(matches!(
+            repl.compile("let = 1;\n", false),
+            Input::Failed(_)
+        ));
+        assert!(matches!(
+            repl.compile("if true {\n\n", true),
+            Input::Failed(_)
+        ));
+    }
+
+    #[test]
+    fn history_is_read_and_appended_to_the_given_file() {
+        let path = std::env::temp_dir().join(format!("crystal-{}-history", std::process::id()));
+        fs::write(&path, "old entry\n").expect("writable temp dir");
+        let stdout = Captured::default();
+        let stdin = Cursor::new(b"1 + 1\n:history\n".to_vec());
+        Repl::with_io(
+            Io::new(Box::new(stdout.clone()), Box::new(stdin)),
+            Some(path.clone()),
+        )
+        .run();
+        let written = fs::read_to_string(&path).expect("the history file is still there");
+        fs::remove_file(&path).ok();
+        assert_eq!(written, "old entry\n1 + 1\n");
+        let printed = String::from_utf8(stdout.0.borrow().clone()).expect("utf-8 output");
+        assert!(
+            printed.contains("   1  old entry\n   2  1 + 1\n"),
+            "{printed}"
+        );
+    }
+}
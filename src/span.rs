@@ -0,0 +1,113 @@
+use std::{fmt, rc::Rc};
+
+// A named piece of Crystal source, shared by every span that points into it
+pub struct Source {
+    pub name: String,
+    pub text: String,
+}
+
+impl Source {
+    pub fn new(name: impl Into<String>, text: impl Into<String>) -> Rc<Self> {
+        Rc::new(Source {
+            name: name.into(),
+            text: text.into(),
+        })
+    }
+
+    // Text of a 1-based line, without its line terminator
+    pub fn line(&self, line: usize) -> &str {
+        self.text
+            .lines()
+            .nth(line.saturating_sub(1))
+            .unwrap_or("")
+            .trim_end_matches('\r')
+    }
+}
+
+// Location of a token or node: char offsets plus the 1-based line/column of its start
+#[derive(Clone)]
+pub struct Span {
+    pub source: Rc<Source>,
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    pub fn new(source: Rc<Source>, start: usize, end: usize, line: usize, column: usize) -> Self {
+        Span {
+            source,
+            start,
+            end,
+            line,
+            column,
+        }
+    }
+
+    // Span starting where `self` starts and ending where `other` ends
+    pub fn to(&self, other: &Span) -> Span {
+        Span {
+            source: self.source.clone(),
+            start: self.start,
+            end: other.end.max(self.end),
+            line: self.line,
+            column: self.column,
+        }
+    }
+
+    // Zero-width span just past the end of a single-line span, for "expected X after Y"
+    pub fn after(&self) -> Span {
+        Span {
+            source: self.source.clone(),
+            start: self.end,
+            end: self.end,
+            line: self.line,
+            column: self.column + self.len(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.end.saturating_sub(self.start)
+    }
+}
+
+impl PartialEq for Span {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.source, &other.source)
+            && self.start == other.start
+            && self.end == other.end
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.source.name, self.line, self.column)
+    }
+}
+
+impl fmt::Debug for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Span({self})")
+    }
+}
+
+// Any value paired with the span it was read from
+#[derive(Clone, PartialEq)]
+pub struct Spanned<T> {
+    pub node: T,
+    pub span: Span,
+}
+
+impl<T> Spanned<T> {
+    pub fn new(node: T, span: Span) -> Self {
+        Spanned { node, span }
+    }
+}
+
+// Spans are left out of debug dumps so `{ast:#?}` stays readable
+impl<T: fmt::Debug> fmt::Debug for Spanned<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.node.fmt(f)
+    }
+}
@@ -0,0 +1,530 @@
+use std::{cell::RefCell, collections::HashMap, fmt, rc::Rc};
+
+use super::{
+    builtins::{self, Io, Runtime},
+    bytecode::{Op, Place, Proto},
+    error::CrystalError,
+    lexer::{MathToken, Token},
+    memories::{
+        binary_op, build_struct, call_depth_limit, construct, enum_bindings, expect_int, get_field,
+        get_index, get_slice, indexable_len, map_key, passes, set_field, set_index, struct_type,
+        unary_op, Dict, Env, Memory, StructType,
+    },
+    patterns,
+    span::Span,
+};
+
+// A variable that closures can share; empty until its declaration runs
+pub type Cell = Rc<RefCell<Option<Memory>>>;
+
+fn cell() -> Cell {
+    Rc::new(RefCell::new(None))
+}
+
+// A compiled function and the variables it captured
+pub struct Closure {
+    pub proto: Rc<Proto>,
+    pub upvalues: Vec<Cell>,
+}
+
+// Closures are only equal to themselves
+impl PartialEq for Closure {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl fmt::Debug for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.proto.name {
+            Some(name) => write!(f, "<fn {name}>"),
+            None => f.write_str("<fn>"),
+        }
+    }
+}
+
+struct Frame {
+    closure: Rc<Closure>,
+    ip: usize,
+    slots: Vec<Cell>,
+    // Height of the value stack when the call started
+    base: usize,
+}
+
+// Runs compiled programs. Globals live in an `Env`, so they behave exactly as the
+// tree-walker's top-level scope does.
+pub struct Vm {
+    pub env: Env,
+    stack: Vec<Memory>,
+    frames: Vec<Frame>,
+}
+
+impl Vm {
+    pub fn new(io: Io) -> Self {
+        Vm {
+            env: Env::with_io(io),
+            stack: Vec::new(),
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn run(&mut self, script: Rc<Proto>) -> Result<(), CrystalError> {
+        let closure = Rc::new(Closure {
+            proto: script,
+            upvalues: Vec::new(),
+        });
+        self.enter(closure, Vec::new());
+        self.execute(0)?;
+        Ok(())
+    }
+
+    fn enter(&mut self, closure: Rc<Closure>, args: Vec<Memory>) {
+        let mut slots: Vec<Cell> = (0..closure.proto.slots).map(|_| cell()).collect();
+        for (slot, arg) in slots.iter_mut().zip(args) {
+            *slot = Rc::new(RefCell::new(Some(arg)));
+        }
+        self.frames.push(Frame {
+            closure,
+            ip: 0,
+            slots,
+            base: self.stack.len(),
+        });
+    }
+
+    fn pop(&mut self) -> Memory {
+        self.stack.pop().expect("the compiler balances the stack")
+    }
+
+    fn pop_n(&mut self, count: u32) -> Vec<Memory> {
+        let at = self.stack.len() - count as usize;
+        self.stack.split_off(at)
+    }
+
+    fn frame(&mut self) -> &mut Frame {
+        self.frames.last_mut().expect("running inside a frame")
+    }
+
+    // Calls a value. A closure only gets its frame pushed, and gives None; anything else
+    // gives its result straight away.
+    fn invoke(
+        &mut self,
+        callee: &Memory,
+        args: Vec<Memory>,
+        span: &Span,
+    ) -> Result<Option<Memory>, CrystalError> {
+        match callee {
+            Memory::Constructor(def, index) => return construct(def, *index, args, span).map(Some),
+            Memory::Builtin(name) => return builtins::call(name, args, self, span).map(Some),
+            _ => {}
+        }
+        let Memory::Closure(closure) = callee else {
+            return Err(CrystalError::type_error(
+                format!("A {} is not callable", callee.type_name()),
+                span.clone(),
+            ));
+        };
+        if args.len() != closure.proto.arity as usize {
+            return Err(CrystalError::runtime(
+                format!(
+                    "{closure:?} takes {} argument(s) but {} were given",
+                    closure.proto.arity,
+                    args.len()
+                ),
+                span.clone(),
+            ));
+        }
+        // The program itself is the first frame, and isn't a call
+        if self.frames.len() > call_depth_limit() {
+            return Err(CrystalError::runtime(
+                "Maximum call depth exceeded",
+                span.clone(),
+            ));
+        }
+        self.enter(closure.clone(), args);
+        Ok(None)
+    }
+
+    // What a name refers to, looked up where the compiler placed it. A captured variable
+    // that isn't bound yet falls back to the global of that name.
+    fn find(&self, name: &str, place: Place) -> Option<Memory> {
+        let frame = self.frames.last().expect("running inside a frame");
+        let bound = match place {
+            Place::Local(slot) => frame.slots[slot as usize].borrow().clone(),
+            Place::Upvalue(i) => frame.closure.upvalues[i as usize].borrow().clone(),
+            Place::Global => None,
+        };
+        bound.or_else(|| self.env.get(name).map(|binding| binding.value))
+    }
+
+    fn global(&self, name: &str, span: &Span) -> Result<Memory, CrystalError> {
+        match self.env.get(name) {
+            Some(binding) => Ok(binding.value),
+            None if builtins::lookup(name).is_some() => Ok(Memory::Builtin(name.to_string())),
+            None => Err(CrystalError::name(
+                format!("Memory '{name}' not found"),
+                span.clone(),
+            )),
+        }
+    }
+
+    fn check_global_writable(&self, name: &str, span: &Span) -> Result<(), CrystalError> {
+        match self.env.get(name) {
+            Some(binding) if !binding.is_mut() => Err(CrystalError::mutability(
+                format!("Cannot modify final variable '{name}'"),
+                span.clone(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // Runs until the frame at index `stop` returns, giving what it returned
+    fn execute(&mut self, stop: usize) -> Result<Memory, CrystalError> {
+        let mut proto = self.frame().closure.proto.clone();
+        loop {
+            let frame = self.frames.last_mut().expect("running inside a frame");
+            let ip = frame.ip;
+            frame.ip += 1;
+            let chunk = &proto.chunk;
+            let op = chunk.code[ip];
+            let span = chunk.span(ip);
+            let aux = |index: u32| &chunk.spans[index as usize];
+
+            match op {
+                Op::Const(i) => self.stack.push(chunk.constants[i as usize].clone()),
+                Op::Nil => self.stack.push(Memory::Nil),
+                Op::Pop => {
+                    self.pop();
+                }
+                Op::GetLocal(slot) => {
+                    let value = frame.slots[slot as usize].borrow().clone();
+                    self.stack.push(value.unwrap_or(Memory::Nil));
+                }
+                Op::SetLocal(slot) => {
+                    let value = self.pop();
+                    *self.frame().slots[slot as usize].borrow_mut() = Some(value);
+                }
+                Op::GetUpvalue(i) => {
+                    let bound = frame.closure.upvalues[i as usize].borrow().clone();
+                    let value = match bound {
+                        Some(value) => value,
+                        None => self.global(&proto.captures[i as usize].name, span)?,
+                    };
+                    self.stack.push(value);
+                }
+                Op::SetUpvalue(i) => {
+                    let value = self.pop();
+                    let upvalue = self.frame().closure.upvalues[i as usize].clone();
+                    let mut upvalue = upvalue.borrow_mut();
+                    match upvalue.as_mut() {
+                        Some(bound) => *bound = value,
+                        None => self
+                            .env
+                            .assign(&proto.captures[i as usize].name, value, span)?,
+                    }
+                }
+                Op::GetGlobal(name) => {
+                    let value = self.global(chunk.name(name), span)?;
+                    self.stack.push(value);
+                }
+                Op::SetGlobal(name) => {
+                    let value = self.pop();
+                    self.env.assign(chunk.name(name), value, span)?;
+                }
+                Op::GetGlobalMut(name) => {
+                    let name = chunk.name(name);
+                    let value = self.global(name, span)?;
+                    self.check_global_writable(name, span)?;
+                    self.stack.push(value);
+                }
+                Op::DefineGlobal(name, declaration) => {
+                    let value = self.pop();
+                    self.env
+                        .define(chunk.name(name), value, declaration, span)?;
+                }
+                Op::Fresh(first, count) => {
+                    for slot in &mut frame.slots[first as usize..(first + count) as usize] {
+                        *slot = cell();
+                    }
+                }
+                Op::Find(name, place) => {
+                    let found = self.find(chunk.name(name), place);
+                    let is_found = found.is_some();
+                    self.stack.push(found.unwrap_or(Memory::Nil));
+                    self.stack.push(Memory::Bool(is_found));
+                }
+                Op::Fail(fault) => return Err(chunk.faults[fault as usize].error(span)),
+                Op::Math(math) => {
+                    let (rhs, lhs) = (self.pop(), self.pop());
+                    let value = binary_op(&Token::Arithmetic(math), lhs, rhs, span)?;
+                    self.stack.push(value);
+                }
+                Op::Compare(compare) => {
+                    let (rhs, lhs) = (self.pop(), self.pop());
+                    let value = binary_op(&Token::Compare(compare), lhs, rhs, span)?;
+                    self.stack.push(value);
+                }
+                Op::Neg | Op::Not => {
+                    let op = match op {
+                        Op::Neg => Token::Arithmetic(MathToken::Minus),
+                        _ => Token::Not,
+                    };
+                    let value = self.pop();
+                    self.stack.push(unary_op(&op, value, span)?);
+                }
+                Op::Truthy => {
+                    let value = self.pop();
+                    self.stack.push(Memory::Bool(value.is_truthy()));
+                }
+                Op::Jump(target) => frame.ip = target as usize,
+                Op::JumpIfFalse(target) => {
+                    if !self.pop().is_truthy() {
+                        self.frame().ip = target as usize;
+                    }
+                }
+                Op::CallNamed(name, argc) => {
+                    let args = self.pop_n(argc);
+                    let (found, callee) = (self.pop(), self.pop());
+                    let name = chunk.name(name);
+                    let value = match (found, builtins::lookup(name)) {
+                        (Memory::Bool(true), _) => self.invoke(&callee, args, span)?,
+                        (_, Some(builtin)) => Some(builtin(args, self, span)?),
+                        (_, None) => {
+                            return Err(CrystalError::name(
+                                format!("Function '{name}' not found"),
+                                span.clone(),
+                            ))
+                        }
+                    };
+                    if let Some(value) = value {
+                        self.stack.push(value);
+                    }
+                }
+                Op::Call(argc) => {
+                    let args = self.pop_n(argc);
+                    let callee = self.pop();
+                    if let Some(value) = self.invoke(&callee, args, span)? {
+                        self.stack.push(value);
+                    }
+                }
+                Op::Guard(root, final_local) => {
+                    let shadowed = self.stack.last() == Some(&Memory::Bool(true));
+                    let root = chunk.name(root);
+                    if !shadowed && final_local {
+                        return Err(CrystalError::mutability(
+                            format!("Cannot modify final variable '{root}'"),
+                            span.clone(),
+                        ));
+                    }
+                    if !shadowed {
+                        self.check_global_writable(root, span)?;
+                    }
+                }
+                Op::Writable(root) => self.check_global_writable(chunk.name(root), span)?,
+                Op::Return => {
+                    let value = self.pop();
+                    let frame = self.frames.pop().expect("returning from a frame");
+                    self.stack.truncate(frame.base);
+                    if self.frames.len() == stop {
+                        return Ok(value);
+                    }
+                    self.stack.push(value);
+                }
+                Op::List(count) => {
+                    let items = self.pop_n(count);
+                    self.stack.push(Memory::list(items));
+                }
+                Op::Map(count) => {
+                    let mut entries = self.pop_n(count * 2).into_iter();
+                    let mut map = Dict::default();
+                    while let (Some(Memory::String(key)), Some(value)) =
+                        (entries.next(), entries.next())
+                    {
+                        map.insert(key, value);
+                    }
+                    self.stack.push(Memory::map(map));
+                }
+                Op::Key => {
+                    let key = self.pop();
+                    let key = map_key(key, span)?;
+                    self.stack.push(Memory::String(key));
+                }
+                Op::Concat(count) => {
+                    let parts = self.pop_n(count);
+                    let text: String = parts.iter().map(Memory::to_string).collect();
+                    self.stack.push(Memory::String(text));
+                }
+                Op::Index(target) => {
+                    let (i, value) = (self.pop(), self.pop());
+                    let value = get_index(value, i, aux(target), span)?;
+                    self.stack.push(value);
+                }
+                Op::SetIndex(target) => {
+                    let (value, i, collection) = (self.pop(), self.pop(), self.pop());
+                    set_index(collection, i, value, aux(target), span)?;
+                }
+                Op::Sliceable(target) => {
+                    let value = self.stack.last().expect("the target is on the stack");
+                    if let Memory::Map(_) = value {
+                        return Err(CrystalError::type_error(
+                            "A range can only be used in a for loop",
+                            span.clone(),
+                        ));
+                    }
+                    indexable_len(value, aux(target))?;
+                }
+                Op::Slice(target) => {
+                    let (end, start, value) = (self.pop(), self.pop(), self.pop());
+                    let (Memory::Int(start), Memory::Int(end)) = (start, end) else {
+                        unreachable!("bounds are checked by ExpectInt");
+                    };
+                    let value = get_slice(value, start, end, aux(target), span)?;
+                    self.stack.push(value);
+                }
+                Op::GetField(field, target) => {
+                    let value = self.pop();
+                    let value = get_field(value, chunk.name(field), span, aux(target))?;
+                    self.stack.push(value);
+                }
+                Op::SetField(field, target) => {
+                    let (value, instance) = (self.pop(), self.pop());
+                    set_field(instance, chunk.name(field), value, span, aux(target))?;
+                }
+                Op::CheckStruct(literal) => {
+                    let (found, def) = (self.pop(), self.pop());
+                    let def = (found == Memory::Bool(true)).then_some(def);
+                    let literal = &chunk.literals[literal as usize];
+                    let given: Vec<_> = literal.fields.iter().map(|(field, _)| field).collect();
+                    let spans: Vec<_> = literal.fields.iter().map(|(_, at)| aux(*at)).collect();
+                    let def = struct_type(def, &literal.name, &given, &spans, span)?;
+                    self.stack.push(Memory::StructType(def));
+                }
+                Op::MakeStruct(literal) => {
+                    let literal = &chunk.literals[literal as usize];
+                    let values = self.pop_n(literal.fields.len() as u32);
+                    let Memory::StructType(def) = self.pop() else {
+                        unreachable!("pushed by CheckStruct");
+                    };
+                    let names = literal.fields.iter().map(|(field, _)| field.clone());
+                    self.stack
+                        .push(build_struct(&def, names.zip(values).collect()));
+                }
+                Op::Struct(def) => {
+                    let (name, fields) = chunk.structs[def as usize].clone();
+                    let def = StructType { name, fields };
+                    self.stack.push(Memory::StructType(Rc::new(def)));
+                }
+                Op::Enum(def) => {
+                    let (name, variants) = &chunk.enums[def as usize];
+                    // Pushed last to first, so they are bound in declaration order
+                    for (_, value) in enum_bindings(name, variants).into_iter().rev() {
+                        self.stack.push(value);
+                    }
+                }
+                Op::Closure(index) => {
+                    let proto = chunk.protos[index as usize].clone();
+                    let upvalues = proto
+                        .captures
+                        .iter()
+                        .map(|capture| match capture.local {
+                            true => frame.slots[capture.index as usize].clone(),
+                            false => frame.closure.upvalues[capture.index as usize].clone(),
+                        })
+                        .collect();
+                    let closure = Closure { proto, upvalues };
+                    self.stack.push(Memory::Closure(Rc::new(closure)));
+                }
+                Op::ExpectInt => {
+                    let value = self.pop();
+                    self.stack.push(Memory::Int(expect_int(value, span)?));
+                }
+                Op::Iter(two) => {
+                    let collection = self.pop();
+                    let (firsts, seconds) = passes(collection, two, span)?.into_iter().unzip();
+                    self.stack.push(Memory::list(firsts));
+                    self.stack.push(Memory::list(seconds));
+                }
+                Op::ForNext(state, exit) => {
+                    let state = &frame.slots[state as usize..state as usize + 3];
+                    let count = state[2].borrow().clone();
+                    let (Some(Memory::Int(i)), Some(Memory::List(firsts))) =
+                        (count, state[0].borrow().clone())
+                    else {
+                        unreachable!("set up by Iter");
+                    };
+                    let Some(first) = firsts.borrow().get(i as usize).cloned() else {
+                        frame.ip = exit as usize;
+                        continue;
+                    };
+                    let second = match state[1].borrow().as_ref() {
+                        Some(Memory::List(seconds)) => seconds.borrow()[i as usize].clone(),
+                        _ => Memory::Nil,
+                    };
+                    *state[2].borrow_mut() = Some(Memory::Int(i + 1));
+                    self.stack.push(first);
+                    self.stack.push(second);
+                }
+                Op::RangeNext(state, exit) => {
+                    let state = &frame.slots[state as usize..state as usize + 2];
+                    let (Some(Memory::Int(i)), Some(Memory::Int(end))) =
+                        (state[0].borrow().clone(), state[1].borrow().clone())
+                    else {
+                        unreachable!("set up by ExpectInt");
+                    };
+                    if i >= end {
+                        frame.ip = exit as usize;
+                        continue;
+                    }
+                    *state[0].borrow_mut() = Some(Memory::Int(i + 1));
+                    self.stack.push(Memory::Int(i));
+                }
+                Op::Match(table, subject) => {
+                    let value = self.pop();
+                    let table = &chunk.matches[table as usize];
+                    let found: HashMap<&str, Memory> = table
+                        .lookups
+                        .iter()
+                        .filter_map(|(name, place)| Some((name.as_str(), self.find(name, *place)?)))
+                        .collect();
+                    let lookup = |name: &str| found.get(name).cloned();
+                    let patterns: Vec<_> = table.arms.iter().map(|arm| &arm.pattern).collect();
+                    let (arm, bindings) =
+                        patterns::choose(&patterns, value, &lookup, span, aux(subject))?;
+                    let arm = &table.arms[arm];
+                    for name in &arm.names {
+                        let bound = bindings.iter().find(|(bound, _)| bound == name);
+                        let value = match bound {
+                            Some((_, value)) => value.clone(),
+                            // The name was a unit variant, which reads the same
+                            None => lookup(name).unwrap_or(Memory::Nil),
+                        };
+                        self.stack.push(value);
+                    }
+                    self.frame().ip = arm.target as usize;
+                }
+            }
+
+            // Calls and returns change the running function
+            if matches!(op, Op::CallNamed(..) | Op::Call(_) | Op::Return) {
+                proto = self.frame().closure.proto.clone();
+            }
+        }
+    }
+}
+
+impl Runtime for Vm {
+    fn io(&mut self) -> &mut Io {
+        &mut self.env.io
+    }
+
+    fn call(
+        &mut self,
+        callee: &Memory,
+        args: Vec<Memory>,
+        span: &Span,
+    ) -> Result<Memory, CrystalError> {
+        match self.invoke(callee, args, span)? {
+            Some(value) => Ok(value),
+            None => self.execute(self.frames.len() - 1),
+        }
+    }
+}
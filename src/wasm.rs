@@ -0,0 +1,1533 @@
+use std::{collections::HashMap, fmt::Write};
+
+use super::{
+    builtins,
+    diagnostic::Diagnostic,
+    lexer::{CompareToken, MathToken, Token},
+    parser::{ASTNode, Node},
+    span::Span,
+};
+
+// The wasm value types a module uses; bools are i32s
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum ValType {
+    I32,
+    I64,
+    F64,
+}
+
+impl ValType {
+    fn byte(self) -> u8 {
+        match self {
+            ValType::I32 => 0x7f,
+            ValType::I64 => 0x7e,
+            ValType::F64 => 0x7c,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ValType::I32 => "i32",
+            ValType::I64 => "i64",
+            ValType::F64 => "f64",
+        }
+    }
+}
+
+// What a value of the numeric subset is known to be before the program runs
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum Ty {
+    Int,
+    Float,
+    Bool,
+}
+
+impl Ty {
+    fn val(self) -> ValType {
+        match self {
+            Ty::Int => ValType::I64,
+            Ty::Float => ValType::F64,
+            Ty::Bool => ValType::I32,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Ty::Int => "int",
+            Ty::Float => "float",
+            Ty::Bool => "bool",
+        }
+    }
+}
+
+// A numeric instruction without immediates: its opcode and its name in the text format
+#[derive(Clone, Copy, Debug)]
+struct Opcode(u8, &'static str);
+
+const I32_EQZ: Opcode = Opcode(0x45, "i32.eqz");
+const I32_EQ: Opcode = Opcode(0x46, "i32.eq");
+const I32_NE: Opcode = Opcode(0x47, "i32.ne");
+const I64_EQZ: Opcode = Opcode(0x50, "i64.eqz");
+const I64_EQ: Opcode = Opcode(0x51, "i64.eq");
+const I64_NE: Opcode = Opcode(0x52, "i64.ne");
+const I64_LT_S: Opcode = Opcode(0x53, "i64.lt_s");
+const I64_GT_S: Opcode = Opcode(0x55, "i64.gt_s");
+const I64_LE_S: Opcode = Opcode(0x57, "i64.le_s");
+const I64_GE_S: Opcode = Opcode(0x59, "i64.ge_s");
+const F64_EQ: Opcode = Opcode(0x61, "f64.eq");
+const F64_NE: Opcode = Opcode(0x62, "f64.ne");
+const F64_LT: Opcode = Opcode(0x63, "f64.lt");
+const F64_GT: Opcode = Opcode(0x64, "f64.gt");
+const F64_LE: Opcode = Opcode(0x65, "f64.le");
+const F64_GE: Opcode = Opcode(0x66, "f64.ge");
+const I32_AND: Opcode = Opcode(0x71, "i32.and");
+const I32_OR: Opcode = Opcode(0x72, "i32.or");
+const I64_ADD: Opcode = Opcode(0x7c, "i64.add");
+const I64_SUB: Opcode = Opcode(0x7d, "i64.sub");
+const I64_MUL: Opcode = Opcode(0x7e, "i64.mul");
+const I64_DIV_S: Opcode = Opcode(0x7f, "i64.div_s");
+const I64_REM_S: Opcode = Opcode(0x81, "i64.rem_s");
+const I64_AND: Opcode = Opcode(0x83, "i64.and");
+const I64_XOR: Opcode = Opcode(0x85, "i64.xor");
+const I64_SHR_S: Opcode = Opcode(0x87, "i64.shr_s");
+const F64_ABS: Opcode = Opcode(0x99, "f64.abs");
+const F64_NEG: Opcode = Opcode(0x9a, "f64.neg");
+const F64_TRUNC: Opcode = Opcode(0x9d, "f64.trunc");
+const F64_ADD: Opcode = Opcode(0xa0, "f64.add");
+const F64_SUB: Opcode = Opcode(0xa1, "f64.sub");
+const F64_MUL: Opcode = Opcode(0xa2, "f64.mul");
+const F64_DIV: Opcode = Opcode(0xa3, "f64.div");
+const F64_CONVERT_I64_S: Opcode = Opcode(0xb9, "f64.convert_i64_s");
+
+#[derive(Clone, Copy, Debug)]
+enum Instr {
+    Unreachable,
+    Block,
+    Loop,
+    If(Option<ValType>),
+    Else,
+    End,
+    Br(u32),
+    BrIf(u32),
+    Return,
+    Call(u32),
+    Drop,
+    LocalGet(u32),
+    LocalSet(u32),
+    LocalTee(u32),
+    GlobalGet(u32),
+    GlobalSet(u32),
+    I32Const(i32),
+    I64Const(i64),
+    F64Const(f64),
+    Op(Opcode),
+}
+
+impl Instr {
+    fn encode(self, out: &mut Vec<u8>) {
+        match self {
+            Instr::Unreachable => out.push(0x00),
+            Instr::Block => out.extend([0x02, 0x40]),
+            Instr::Loop => out.extend([0x03, 0x40]),
+            Instr::If(result) => out.extend([0x04, result.map_or(0x40, ValType::byte)]),
+            Instr::Else => out.push(0x05),
+            Instr::End => out.push(0x0b),
+            Instr::Br(depth) => immediate(out, 0x0c, depth),
+            Instr::BrIf(depth) => immediate(out, 0x0d, depth),
+            Instr::Return => out.push(0x0f),
+            Instr::Call(function) => immediate(out, 0x10, function),
+            Instr::Drop => out.push(0x1a),
+            Instr::LocalGet(local) => immediate(out, 0x20, local),
+            Instr::LocalSet(local) => immediate(out, 0x21, local),
+            Instr::LocalTee(local) => immediate(out, 0x22, local),
+            Instr::GlobalGet(global) => immediate(out, 0x23, global),
+            Instr::GlobalSet(global) => immediate(out, 0x24, global),
+            Instr::I32Const(n) => {
+                out.push(0x41);
+                signed(out, n.into());
+            }
+            Instr::I64Const(n) => {
+                out.push(0x42);
+                signed(out, n);
+            }
+            Instr::F64Const(n) => {
+                out.push(0x44);
+                out.extend(n.to_le_bytes());
+            }
+            Instr::Op(Opcode(opcode, _)) => out.push(opcode),
+        }
+    }
+
+    // The instruction in the text format, naming functions and globals
+    fn text(self, module: &Module) -> String {
+        match self {
+            Instr::Unreachable => "unreachable".to_string(),
+            Instr::Block => "block".to_string(),
+            Instr::Loop => "loop".to_string(),
+            Instr::If(None) => "if".to_string(),
+            Instr::If(Some(result)) => format!("if (result {})", result.name()),
+            Instr::Else => "else".to_string(),
+            Instr::End => "end".to_string(),
+            Instr::Br(depth) => format!("br {depth}"),
+            Instr::BrIf(depth) => format!("br_if {depth}"),
+            Instr::Return => "return".to_string(),
+            Instr::Call(function) => format!("call ${}", module.functions[function as usize].name),
+            Instr::Drop => "drop".to_string(),
+            Instr::LocalGet(local) => format!("local.get {local}"),
+            Instr::LocalSet(local) => format!("local.set {local}"),
+            Instr::LocalTee(local) => format!("local.tee {local}"),
+            Instr::GlobalGet(global) => {
+                format!("global.get ${}", module.globals[global as usize].name)
+            }
+            Instr::GlobalSet(global) => {
+                format!("global.set ${}", module.globals[global as usize].name)
+            }
+            Instr::I32Const(n) => format!("i32.const {n}"),
+            Instr::I64Const(n) => format!("i64.const {n}"),
+            Instr::F64Const(n) => format!("f64.const {n:?}"),
+            Instr::Op(Opcode(_, name)) => name.to_string(),
+        }
+    }
+}
+
+fn immediate(out: &mut Vec<u8>, opcode: u8, index: u32) {
+    out.push(opcode);
+    unsigned(out, index.into());
+}
+
+// LEB128, as every integer in a module is written
+fn unsigned(out: &mut Vec<u8>, mut n: u64) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            return out.push(byte);
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn signed(out: &mut Vec<u8>, mut n: i64) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        let done = (n == 0 && byte & 0x40 == 0) || (n == -1 && byte & 0x40 != 0);
+        if done {
+            return out.push(byte);
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn count(out: &mut Vec<u8>, n: usize) {
+    unsigned(out, n as u64);
+}
+
+fn section(out: &mut Vec<u8>, id: u8, content: &[u8]) {
+    out.push(id);
+    count(out, content.len());
+    out.extend(content);
+}
+
+struct Function {
+    name: String,
+    params: Vec<ValType>,
+    result: Option<ValType>,
+    // Locals after the parameters
+    locals: Vec<ValType>,
+    body: Vec<Instr>,
+}
+
+impl Function {
+    fn signature(&self) -> (Vec<ValType>, Option<ValType>) {
+        (self.params.clone(), self.result)
+    }
+}
+
+// Every global is mutable and starts at zero; the program gives it its value
+struct Global {
+    name: String,
+    ty: ValType,
+}
+
+// A module with no imports or memory: the program runs as its start function, leaving
+// each top-level final in an exported global
+pub struct Module {
+    functions: Vec<Function>,
+    globals: Vec<Global>,
+    exports: Vec<(String, u32)>,
+    start: u32,
+}
+
+impl Module {
+    // The distinct function types, in the order functions first use them
+    fn types(&self) -> Vec<(Vec<ValType>, Option<ValType>)> {
+        let mut types = Vec::new();
+        for function in &self.functions {
+            let signature = function.signature();
+            if !types.contains(&signature) {
+                types.push(signature);
+            }
+        }
+        types
+    }
+
+    pub fn binary(&self) -> Vec<u8> {
+        let mut out = b"\0asm".to_vec();
+        out.extend(1u32.to_le_bytes());
+
+        let types = self.types();
+        let mut content = Vec::new();
+        count(&mut content, types.len());
+        for (params, result) in &types {
+            content.push(0x60);
+            count(&mut content, params.len());
+            content.extend(params.iter().map(|param| param.byte()));
+            count(&mut content, usize::from(result.is_some()));
+            content.extend(result.map(ValType::byte));
+        }
+        section(&mut out, 1, &content);
+
+        let mut content = Vec::new();
+        count(&mut content, self.functions.len());
+        for function in &self.functions {
+            let signature = function.signature();
+            let index = types.iter().position(|ty| *ty == signature);
+            count(&mut content, index.expect("every type is listed"));
+        }
+        section(&mut out, 3, &content);
+
+        if !self.globals.is_empty() {
+            let mut content = Vec::new();
+            count(&mut content, self.globals.len());
+            for global in &self.globals {
+                content.extend([global.ty.byte(), 0x01]);
+                zero(global.ty).encode(&mut content);
+                Instr::End.encode(&mut content);
+            }
+            section(&mut out, 6, &content);
+        }
+
+        if !self.exports.is_empty() {
+            let mut content = Vec::new();
+            count(&mut content, self.exports.len());
+            for (name, global) in &self.exports {
+                count(&mut content, name.len());
+                content.extend(name.bytes());
+                content.push(0x03);
+                unsigned(&mut content, (*global).into());
+            }
+            section(&mut out, 7, &content);
+        }
+
+        let mut content = Vec::new();
+        unsigned(&mut content, self.start.into());
+        section(&mut out, 8, &content);
+
+        let mut content = Vec::new();
+        count(&mut content, self.functions.len());
+        for function in &self.functions {
+            let mut code = Vec::new();
+            // Locals are declared in runs of one type
+            let mut runs: Vec<(usize, ValType)> = Vec::new();
+            for &local in &function.locals {
+                match runs.last_mut() {
+                    Some((n, ty)) if *ty == local => *n += 1,
+                    _ => runs.push((1, local)),
+                }
+            }
+            count(&mut code, runs.len());
+            for (n, ty) in runs {
+                count(&mut code, n);
+                code.push(ty.byte());
+            }
+            for instr in &function.body {
+                instr.encode(&mut code);
+            }
+            Instr::End.encode(&mut code);
+            count(&mut content, code.len());
+            content.extend(code);
+        }
+        section(&mut out, 10, &content);
+        out
+    }
+
+    // The same module in the WebAssembly text format
+    pub fn text(&self) -> String {
+        let mut wat = String::from("(module\n");
+        for global in &self.globals {
+            let ty = global.ty.name();
+            let _ = writeln!(wat, "  (global ${} (mut {ty}) ({ty}.const 0))", global.name);
+        }
+        for (name, global) in &self.exports {
+            let global = &self.globals[*global as usize].name;
+            let _ = writeln!(wat, "  (export \"{name}\" (global ${global}))");
+        }
+        let start = &self.functions[self.start as usize].name;
+        let _ = writeln!(wat, "  (start ${start})");
+        for function in &self.functions {
+            let _ = write!(wat, "  (func ${}", function.name);
+            if !function.params.is_empty() {
+                let params: Vec<_> = function.params.iter().map(|ty| ty.name()).collect();
+                let _ = write!(wat, " (param {})", params.join(" "));
+            }
+            if let Some(result) = function.result {
+                let _ = write!(wat, " (result {})", result.name());
+            }
+            wat.push('\n');
+            if !function.locals.is_empty() {
+                let locals: Vec<_> = function.locals.iter().map(|ty| ty.name()).collect();
+                let _ = writeln!(wat, "    (local {})", locals.join(" "));
+            }
+            let mut indent = 2;
+            for &instr in &function.body {
+                if matches!(instr, Instr::Else | Instr::End) {
+                    indent -= 1;
+                }
+                let _ = writeln!(wat, "{}{}", "  ".repeat(indent), instr.text(self));
+                if matches!(
+                    instr,
+                    Instr::Block | Instr::Loop | Instr::If(_) | Instr::Else
+                ) {
+                    indent += 1;
+                }
+            }
+            wat.push_str("  )\n");
+        }
+        wat.push_str(")\n");
+        wat
+    }
+}
+
+fn zero(ty: ValType) -> Instr {
+    match ty {
+        ValType::I32 => Instr::I32Const(0),
+        ValType::I64 => Instr::I64Const(0),
+        ValType::F64 => Instr::F64Const(0.0),
+    }
+}
+
+// The checked operations the interpreter does in Rust, as functions of the module.
+// Each traps where the interpreter would raise an error.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Helper {
+    Add,
+    Sub,
+    Mul,
+    Neg,
+    Rem,
+    Pow,
+    Check,
+    Divide,
+    IntDivide,
+}
+
+// A variable of a block scope, as a wasm local
+struct Local {
+    name: String,
+    index: u32,
+    ty: Ty,
+    is_final: bool,
+}
+
+struct GlobalVar {
+    index: u32,
+    ty: Ty,
+    is_final: bool,
+}
+
+// What a name refers to where it is used
+enum Name {
+    Local(u32, Ty, bool),
+    Global(u32, Ty, bool),
+    Function,
+    Missing,
+}
+
+// What the returns of a function met so far give
+#[derive(Clone, Copy, PartialEq)]
+enum Returns {
+    Unknown,
+    // Fixed when the function calls itself before returning a value
+    Nothing,
+    Value(Ty),
+}
+
+// A function being emitted: one top-level function for one list of argument types, or
+// the program itself
+struct Frame {
+    index: u32,
+    name: String,
+    params: Vec<ValType>,
+    // Every local, parameters first
+    locals: Vec<ValType>,
+    scopes: Vec<Vec<Local>>,
+    code: Vec<Instr>,
+    // How many blocks, loops and ifs are open
+    depth: u32,
+    // The block depths `break` and `continue` of each enclosing loop branch to
+    loops: Vec<(u32, u32)>,
+    returns: Returns,
+    // Where `return;` was emitted before the function was known to return a value
+    bare_returns: Vec<usize>,
+    // A spare local of each type, to reorder the top of the stack
+    spare: HashMap<ValType, u32>,
+}
+
+impl Frame {
+    fn new(index: u32, name: String, params: Vec<ValType>) -> Self {
+        Frame {
+            index,
+            name,
+            locals: params.clone(),
+            params,
+            scopes: Vec::new(),
+            code: Vec::new(),
+            depth: 0,
+            loops: Vec::new(),
+            returns: Returns::Unknown,
+            bare_returns: Vec::new(),
+            spare: HashMap::new(),
+        }
+    }
+}
+
+// Compiles the numeric subset of the language to a WebAssembly module, or lists every
+// construct outside it. Values are typed before the program runs: ints are i64s, floats
+// f64s and bools i32s, and a variable keeps the type it was declared with. A function
+// is emitted once for each list of argument types it is called with. Errors the
+// interpreter would raise trap instead.
+pub fn emit(ast: &Node) -> Result<Module, Vec<Diagnostic>> {
+    let mut emitter = Emitter {
+        functions: vec![None],
+        globals: Vec::new(),
+        global_vars: HashMap::new(),
+        exports: Vec::new(),
+        definitions: HashMap::new(),
+        instances: HashMap::new(),
+        results: HashMap::new(),
+        declared: 0,
+        helpers: HashMap::new(),
+        frames: vec![Frame::new(0, "main".to_string(), Vec::new())],
+        unsupported: Vec::new(),
+    };
+    if let ASTNode::Program(nodes) = &ast.node {
+        for node in nodes {
+            emitter.statement(node);
+        }
+    }
+    let main = emitter.frames.pop().expect("the program is always emitted");
+    emitter.finish(main, None);
+    if !emitter.unsupported.is_empty() {
+        return Err(emitter.unsupported);
+    }
+    Ok(Module {
+        functions: emitter
+            .functions
+            .into_iter()
+            .map(|function| function.expect("every function is finished"))
+            .collect(),
+        globals: emitter.globals,
+        exports: emitter.exports,
+        start: 0,
+    })
+}
+
+struct Emitter<'a> {
+    // Indexed by function index; None while a function is being emitted
+    functions: Vec<Option<Function>>,
+    globals: Vec<Global>,
+    global_vars: HashMap<String, GlobalVar>,
+    exports: Vec<(String, u32)>,
+    // The top-level functions declared so far, by name
+    definitions: HashMap<String, (&'a [String], &'a Node)>,
+    // Each function emitted for a list of argument types, with how many top-level
+    // declarations it could see, as a function called later may see more
+    instances: HashMap<(String, Vec<Ty>, usize), u32>,
+    results: HashMap<u32, Option<Ty>>,
+    declared: usize,
+    helpers: HashMap<Helper, u32>,
+    frames: Vec<Frame>,
+    unsupported: Vec<Diagnostic>,
+}
+
+impl<'a> Emitter<'a> {
+    fn frame(&mut self) -> &mut Frame {
+        self.frames.last_mut().expect("always emitting a function")
+    }
+
+    fn push(&mut self, instr: Instr) {
+        self.frame().code.push(instr);
+    }
+
+    fn op(&mut self, opcode: Opcode) {
+        self.push(Instr::Op(opcode));
+    }
+
+    fn unsupported(&mut self, what: &str, span: &Span) {
+        let message = format!("{what} are not supported by the WebAssembly backend");
+        self.unsupported
+            .push(Diagnostic::new(message, span.clone()));
+    }
+
+    fn unsupported_because(&mut self, message: String, span: &Span) {
+        self.unsupported
+            .push(Diagnostic::new(message, span.clone()));
+    }
+
+    // Stands in for a value outside the subset, so emitting can go on to find more
+    fn placeholder(&mut self) -> Ty {
+        self.push(Instr::I64Const(0));
+        Ty::Int
+    }
+
+    // Drops the values an operation was given and traps, as the interpreter fails there.
+    // Code after a trap is never run, so any type will do for its result.
+    fn fail(&mut self, operands: usize) -> Ty {
+        for _ in 0..operands {
+            self.push(Instr::Drop);
+        }
+        self.push(Instr::Unreachable);
+        Ty::Int
+    }
+
+    fn spare(&mut self, ty: ValType) -> u32 {
+        let frame = self.frame();
+        if let Some(&local) = frame.spare.get(&ty) {
+            return local;
+        }
+        frame.locals.push(ty);
+        let local = frame.locals.len() as u32 - 1;
+        frame.spare.insert(ty, local);
+        local
+    }
+
+    fn finish(&mut self, mut frame: Frame, result: Option<Ty>) {
+        if result.is_some() {
+            // Returning nil, or ending without a return, gives nil: not a number
+            for at in frame.bare_returns {
+                frame.code[at] = Instr::Unreachable;
+            }
+            frame.code.push(Instr::Unreachable);
+        }
+        let locals = frame.locals.split_off(frame.params.len());
+        self.functions[frame.index as usize] = Some(Function {
+            name: frame.name,
+            params: frame.params,
+            result: result.map(Ty::val),
+            locals,
+            body: frame.code,
+        });
+    }
+
+    fn resolve(&self, name: &str) -> Name {
+        let frame = self.frames.last().expect("always emitting a function");
+        for scope in frame.scopes.iter().rev() {
+            if let Some(local) = scope.iter().rev().find(|local| local.name == name) {
+                return Name::Local(local.index, local.ty, local.is_final);
+            }
+        }
+        if let Some(global) = self.global_vars.get(name) {
+            return Name::Global(global.index, global.ty, global.is_final);
+        }
+        if self.definitions.contains_key(name) {
+            return Name::Function;
+        }
+        Name::Missing
+    }
+
+    // Binds the value on the stack to `name` in the innermost scope
+    fn declare(&mut self, name: &str, is_final: bool, ty: Ty, span: &Span) {
+        if self.frame().scopes.is_empty() {
+            return self.declare_global(name, is_final, ty, span);
+        }
+        let scope = self.frame().scopes.last().expect("inside a block");
+        let latest = scope.iter().rev().find(|local| local.name == name);
+        if latest.is_some_and(|local| local.is_final) {
+            self.fail(1);
+            return;
+        }
+        let frame = self.frame();
+        frame.locals.push(ty.val());
+        let index = frame.locals.len() as u32 - 1;
+        frame.code.push(Instr::LocalSet(index));
+        frame
+            .scopes
+            .last_mut()
+            .expect("inside a block")
+            .push(Local {
+                name: name.to_string(),
+                index,
+                ty,
+                is_final,
+            });
+    }
+
+    fn declare_global(&mut self, name: &str, is_final: bool, ty: Ty, span: &Span) {
+        self.declared += 1;
+        if self.definitions.contains_key(name) {
+            self.fail(1);
+            return;
+        }
+        let index = match self.global_vars.get_mut(name) {
+            Some(global) if global.is_final => {
+                self.fail(1);
+                return;
+            }
+            Some(global) if global.ty != ty => {
+                let message = format!(
+                    "Redeclaring the {} '{name}' as a {} is not supported by the WebAssembly backend",
+                    global.ty.name(),
+                    ty.name()
+                );
+                return self.unsupported_because(message, span);
+            }
+            Some(global) => {
+                global.is_final = is_final;
+                global.index
+            }
+            None => {
+                self.globals.push(Global {
+                    name: name.to_string(),
+                    ty: ty.val(),
+                });
+                let index = self.globals.len() as u32 - 1;
+                let global = GlobalVar {
+                    index,
+                    ty,
+                    is_final,
+                };
+                self.global_vars.insert(name.to_string(), global);
+                index
+            }
+        };
+        self.push(Instr::GlobalSet(index));
+        if is_final && !self.exports.iter().any(|(export, _)| export == name) {
+            self.exports.push((name.to_string(), index));
+        }
+    }
+
+    // Stores the value on the stack into an existing variable
+    fn assign(&mut self, name: &str, ty: Ty, span: &Span) {
+        let (set, current) = match self.resolve(name) {
+            Name::Local(index, current, false) => (Instr::LocalSet(index), current),
+            Name::Global(index, current, false) => (Instr::GlobalSet(index), current),
+            _ => {
+                self.fail(1);
+                return;
+            }
+        };
+        if current != ty {
+            let message = format!(
+                "Assigning a {} to the {} '{name}' is not supported by the WebAssembly backend",
+                ty.name(),
+                current.name()
+            );
+            return self.unsupported_because(message, span);
+        }
+        self.push(set);
+    }
+
+    fn statements(&mut self, nodes: &'a [Node]) {
+        self.frame().scopes.push(Vec::new());
+        for node in nodes {
+            self.statement(node);
+        }
+        self.frame().scopes.pop();
+    }
+
+    fn statement(&mut self, node: &'a Node) {
+        let span = &node.span;
+        match &node.node {
+            ASTNode::Let(ident, value) => {
+                let ty = self.value(value);
+                self.declare(ident, false, ty, span);
+            }
+            ASTNode::Final(ident, value) => {
+                let ty = self.value(value);
+                self.declare(ident, true, ty, span);
+            }
+            ASTNode::Assign { ident, value } => {
+                let ty = self.value(value);
+                self.assign(ident, ty, span);
+            }
+            ASTNode::CompoundAssign { ident, op, value } => {
+                let current = match self.resolve(ident) {
+                    Name::Local(index, ty, false) => {
+                        self.push(Instr::LocalGet(index));
+                        ty
+                    }
+                    Name::Global(index, ty, false) => {
+                        self.push(Instr::GlobalGet(index));
+                        ty
+                    }
+                    _ => {
+                        self.fail(0);
+                        return;
+                    }
+                };
+                let Some(Token::Arithmetic(base)) = op.compound_base() else {
+                    self.fail(1);
+                    return;
+                };
+                let ty = self.value(value);
+                let ty = self.math(base, current, ty, span);
+                self.assign(ident, ty, span);
+            }
+            ASTNode::FunDef {
+                name: Some(name),
+                params,
+                body,
+            } => {
+                if self.frames.len() > 1 || !self.frame().scopes.is_empty() {
+                    return self.unsupported("Functions inside blocks or functions", span);
+                }
+                if self.global_vars.contains_key(name) {
+                    let message = format!(
+                        "A function sharing the name of the variable '{name}' is not supported \
+                         by the WebAssembly backend"
+                    );
+                    return self.unsupported_because(message, span);
+                }
+                if self.definitions.contains_key(name) {
+                    self.fail(0);
+                    return;
+                }
+                self.definitions
+                    .insert(name.clone(), (params.as_slice(), body.as_ref()));
+                self.declared += 1;
+            }
+            ASTNode::Block(nodes) => self.statements(nodes),
+            ASTNode::Return(value) => {
+                if self.frames.len() == 1 {
+                    if let Some(value) = value {
+                        self.value(value);
+                        self.push(Instr::Drop);
+                    }
+                    return self.push(Instr::Return);
+                }
+                match value {
+                    Some(value) => {
+                        let ty = self.value(value);
+                        self.returns(ty, span);
+                    }
+                    None => self.return_nil(),
+                }
+            }
+            ASTNode::Break | ASTNode::Continue if self.frame().loops.is_empty() => {
+                if self.frames.len() == 1 {
+                    self.push(Instr::Return);
+                } else {
+                    self.return_nil();
+                }
+            }
+            ASTNode::Break | ASTNode::Continue => {
+                let frame = self.frame();
+                let (exit, next) = *frame.loops.last().expect("inside a loop");
+                let target = if matches!(node.node, ASTNode::Break) {
+                    exit
+                } else {
+                    next
+                };
+                let depth = frame.depth - target;
+                self.push(Instr::Br(depth));
+            }
+            ASTNode::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                let ty = self.value(cond);
+                self.truthy(ty);
+                self.open(Instr::If(None));
+                self.statement(then);
+                if let Some(otherwise) = otherwise {
+                    self.push(Instr::Else);
+                    self.statement(otherwise);
+                }
+                self.close();
+            }
+            ASTNode::While { cond, body } => {
+                let exit = self.open(Instr::Block);
+                let next = self.open(Instr::Loop);
+                let ty = self.value(cond);
+                self.truthy(ty);
+                self.op(I32_EQZ);
+                let depth = self.frame().depth - exit;
+                self.push(Instr::BrIf(depth));
+                self.looping(exit, next, body);
+                self.push(Instr::Br(0));
+                self.close();
+                self.close();
+            }
+            ASTNode::For {
+                ident,
+                value,
+                iterable,
+                body,
+            } => self.for_loop(ident, value.is_some(), iterable, body),
+            ASTNode::FunCall(callee, args) => match &callee.node {
+                ASTNode::Identifier(name) => {
+                    if self.call(name, args, span).is_some() {
+                        self.push(Instr::Drop);
+                    }
+                }
+                _ => {
+                    self.value(node);
+                    self.push(Instr::Drop);
+                }
+            },
+            ASTNode::StructDef { .. }
+            | ASTNode::FieldAssign { .. }
+            | ASTNode::CompoundFieldAssign { .. } => self.unsupported("Structs", span),
+            ASTNode::EnumDef { .. } => self.unsupported("Enums", span),
+            ASTNode::IndexAssign { .. } | ASTNode::CompoundIndexAssign { .. } => {
+                self.unsupported("Index expressions", span)
+            }
+            _ => {
+                self.value(node);
+                self.push(Instr::Drop);
+            }
+        }
+    }
+
+    // Opens a block, loop or if, returning the depth its label is at
+    fn open(&mut self, instr: Instr) -> u32 {
+        self.push(instr);
+        let frame = self.frame();
+        frame.depth += 1;
+        frame.depth
+    }
+
+    fn close(&mut self) {
+        self.push(Instr::End);
+        self.frame().depth -= 1;
+    }
+
+    fn looping(&mut self, exit: u32, next: u32, body: &'a Node) {
+        self.frame().loops.push((exit, next));
+        self.statement(body);
+        self.frame().loops.pop();
+    }
+
+    fn returns(&mut self, ty: Ty, span: &Span) {
+        let frame = self.frame();
+        let name = frame.name.clone();
+        match frame.returns {
+            Returns::Unknown => frame.returns = Returns::Value(ty),
+            Returns::Value(known) if known == ty => {}
+            Returns::Value(known) => {
+                let message = format!(
+                    "'{name}' returning both a {} and a {} is not supported by the WebAssembly \
+                     backend",
+                    known.name(),
+                    ty.name()
+                );
+                return self.unsupported_because(message, span);
+            }
+            Returns::Nothing => {
+                let message = format!(
+                    "'{name}' calling itself before it first returns a value is not supported by \
+                     the WebAssembly backend"
+                );
+                return self.unsupported_because(message, span);
+            }
+        }
+        self.push(Instr::Return);
+    }
+
+    fn return_nil(&mut self) {
+        let frame = self.frame();
+        match frame.returns {
+            Returns::Value(_) => frame.code.push(Instr::Unreachable),
+            _ => {
+                frame.bare_returns.push(frame.code.len());
+                frame.code.push(Instr::Return);
+            }
+        }
+    }
+
+    fn for_loop(&mut self, ident: &str, two: bool, iterable: &'a Node, body: &'a Node) {
+        let ASTNode::Range { start, end } = &iterable.node else {
+            self.value(iterable);
+            self.fail(1);
+            return;
+        };
+        if two {
+            self.fail(0);
+            return;
+        }
+        let mut bounds = Vec::new();
+        for bound in [start, end] {
+            if self.value(bound) != Ty::Int {
+                self.fail(1);
+                return;
+            }
+            let frame = self.frame();
+            frame.locals.push(ValType::I64);
+            let local = frame.locals.len() as u32 - 1;
+            frame.code.push(Instr::LocalSet(local));
+            bounds.push(local);
+        }
+        let (i, to) = (bounds[0], bounds[1]);
+        let exit = self.open(Instr::Block);
+        self.open(Instr::Loop);
+        self.push(Instr::LocalGet(i));
+        self.push(Instr::LocalGet(to));
+        self.op(I64_GE_S);
+        self.push(Instr::BrIf(1));
+        // The body gets its own copy of the counter, which it may change
+        let next = self.open(Instr::Block);
+        self.push(Instr::LocalGet(i));
+        self.frame().scopes.push(Vec::new());
+        self.declare(ident, false, Ty::Int, &iterable.span);
+        self.looping(exit, next, body);
+        self.frame().scopes.pop();
+        self.close();
+        self.push(Instr::LocalGet(i));
+        self.push(Instr::I64Const(1));
+        self.op(I64_ADD);
+        self.push(Instr::LocalSet(i));
+        self.push(Instr::Br(0));
+        self.close();
+        self.close();
+    }
+
+    // Leaves an i32 on the stack: only false and nil are falsy, and neither is a number
+    fn truthy(&mut self, ty: Ty) {
+        if ty != Ty::Bool {
+            self.push(Instr::Drop);
+            self.push(Instr::I32Const(1));
+        }
+    }
+
+    // Emits an expression, leaving its value on the stack, and gives its type
+    fn value(&mut self, node: &'a Node) -> Ty {
+        let span = &node.span;
+        match &node.node {
+            ASTNode::Int(n) => {
+                self.push(Instr::I64Const(*n));
+                Ty::Int
+            }
+            ASTNode::Float(n) => {
+                self.push(Instr::F64Const(*n));
+                Ty::Float
+            }
+            ASTNode::Bool(b) => {
+                self.push(Instr::I32Const(i32::from(*b)));
+                Ty::Bool
+            }
+            ASTNode::Identifier(ident) => match self.resolve(ident) {
+                Name::Local(index, ty, _) => {
+                    self.push(Instr::LocalGet(index));
+                    ty
+                }
+                Name::Global(index, ty, _) => {
+                    self.push(Instr::GlobalGet(index));
+                    ty
+                }
+                Name::Function => {
+                    self.unsupported("Functions as values", span);
+                    self.placeholder()
+                }
+                Name::Missing if builtins::lookup(ident).is_some() => {
+                    self.unsupported("Functions as values", span);
+                    self.placeholder()
+                }
+                Name::Missing => self.fail(0),
+            },
+            // Both give a bool: the left side's if it decides, else the right side's
+            ASTNode::BinaryOp {
+                left,
+                op: op @ (Token::And | Token::Or),
+                right,
+            } => {
+                let ty = self.value(left);
+                self.truthy(ty);
+                self.open(Instr::If(Some(ValType::I32)));
+                if *op == Token::And {
+                    let ty = self.value(right);
+                    self.truthy(ty);
+                    self.push(Instr::Else);
+                    self.push(Instr::I32Const(0));
+                } else {
+                    self.push(Instr::I32Const(1));
+                    self.push(Instr::Else);
+                    let ty = self.value(right);
+                    self.truthy(ty);
+                }
+                self.close();
+                Ty::Bool
+            }
+            ASTNode::BinaryOp { left, op, right } => {
+                let lhs = self.value(left);
+                let rhs = self.value(right);
+                match op {
+                    // A negative power of an int is a float, so the exponent has to be a
+                    // literal to be known to keep it an int
+                    Token::Arithmetic(MathToken::Power)
+                        if (lhs, rhs) == (Ty::Int, Ty::Int)
+                            && !matches!(right.node, ASTNode::Int(n) if n >= 0) =>
+                    {
+                        let message = "'**' with an exponent that may be negative is not supported by the WebAssembly backend".to_string();
+                        self.unsupported_because(message, &right.span);
+                        Ty::Int
+                    }
+                    Token::Arithmetic(math) => self.math(*math, lhs, rhs, span),
+                    Token::Compare(compare) => self.compare(*compare, lhs, rhs),
+                    _ => self.fail(2),
+                }
+            }
+            ASTNode::UnaryOp { op, operand } => {
+                let ty = self.value(operand);
+                match (op, ty) {
+                    (Token::Not, ty) => {
+                        self.truthy(ty);
+                        self.op(I32_EQZ);
+                        Ty::Bool
+                    }
+                    (_, Ty::Int) => {
+                        self.helper_call(Helper::Neg);
+                        Ty::Int
+                    }
+                    (_, Ty::Float) => {
+                        self.op(F64_NEG);
+                        Ty::Float
+                    }
+                    (_, Ty::Bool) => self.fail(1),
+                }
+            }
+            ASTNode::FunCall(callee, args) => {
+                let ASTNode::Identifier(name) = &callee.node else {
+                    self.unsupported("Functions as values", &callee.span);
+                    return self.placeholder();
+                };
+                match self.call(name, args, span) {
+                    Some(ty) => ty,
+                    None => {
+                        let message =
+                            format!("Using the nil '{name}' returns is not supported by the WebAssembly backend");
+                        self.unsupported_because(message, span);
+                        self.placeholder()
+                    }
+                }
+            }
+            ASTNode::Range { .. } => self.fail(0),
+            _ => {
+                let what = match &node.node {
+                    ASTNode::String(_) | ASTNode::Interpolation(_) => "Strings",
+                    ASTNode::Nil => "Nil values",
+                    ASTNode::List(_) => "Lists",
+                    ASTNode::Map(_) => "Maps",
+                    ASTNode::StructLit { .. } | ASTNode::Field { .. } => "Structs",
+                    ASTNode::Index { .. } => "Index expressions",
+                    ASTNode::Match { .. } => "Match expressions",
+                    ASTNode::FunDef { .. } => "Functions as values",
+                    _ => return self.fail(0),
+                };
+                self.unsupported(what, span);
+                self.placeholder()
+            }
+        }
+    }
+
+    // Makes floats of the two operands on the stack
+    fn floats(&mut self, lhs: Ty, rhs: Ty) {
+        if lhs == Ty::Int {
+            let spare = self.spare(rhs.val());
+            self.push(Instr::LocalSet(spare));
+            self.op(F64_CONVERT_I64_S);
+            self.push(Instr::LocalGet(spare));
+        }
+        if rhs == Ty::Int {
+            self.op(F64_CONVERT_I64_S);
+        }
+    }
+
+    // Int arithmetic stays in ints, except `/` which gives floats
+    fn math(&mut self, math: MathToken, lhs: Ty, rhs: Ty, span: &Span) -> Ty {
+        if lhs == Ty::Bool || rhs == Ty::Bool {
+            return self.fail(2);
+        }
+        if lhs == Ty::Int && rhs == Ty::Int {
+            match math {
+                MathToken::Plus => self.helper_call(Helper::Add),
+                MathToken::Minus => self.helper_call(Helper::Sub),
+                MathToken::Multiply => self.helper_call(Helper::Mul),
+                // Traps on zero and on overflow by itself
+                MathToken::IntDivide => self.op(I64_DIV_S),
+                MathToken::Modulo => self.helper_call(Helper::Rem),
+                // The exponent is a literal, so never negative
+                MathToken::Power => self.helper_call(Helper::Pow),
+                MathToken::Divide => {
+                    self.floats(lhs, rhs);
+                    self.helper_call(Helper::Divide);
+                    return Ty::Float;
+                }
+                _ => return self.fail(2),
+            }
+            return Ty::Int;
+        }
+        self.floats(lhs, rhs);
+        let opcode = match math {
+            MathToken::Plus => F64_ADD,
+            MathToken::Minus => F64_SUB,
+            MathToken::Multiply => F64_MUL,
+            MathToken::Divide => {
+                self.helper_call(Helper::Divide);
+                return Ty::Float;
+            }
+            MathToken::IntDivide => {
+                self.helper_call(Helper::IntDivide);
+                return Ty::Float;
+            }
+            MathToken::Modulo | MathToken::Power => {
+                let message =
+                    format!("'{math}' on floats is not supported by the WebAssembly backend");
+                self.unsupported_because(message, span);
+                return Ty::Float;
+            }
+            _ => return self.fail(2),
+        };
+        self.op(opcode);
+        self.helper_call(Helper::Check);
+        Ty::Float
+    }
+
+    // 1 == 1.0: numbers compare by value whichever kind they are
+    fn compare(&mut self, compare: CompareToken, lhs: Ty, rhs: Ty) -> Ty {
+        let equality = matches!(compare, CompareToken::Equal | CompareToken::NotEqual);
+        match (lhs, rhs) {
+            (Ty::Bool, Ty::Bool) if equality => {
+                self.op(if compare == CompareToken::Equal {
+                    I32_EQ
+                } else {
+                    I32_NE
+                });
+            }
+            (Ty::Bool, _) | (_, Ty::Bool) if equality => {
+                self.push(Instr::Drop);
+                self.push(Instr::Drop);
+                self.push(Instr::I32Const(i32::from(
+                    compare == CompareToken::NotEqual,
+                )));
+            }
+            (Ty::Bool, _) | (_, Ty::Bool) => return self.fail(2),
+            (Ty::Int, Ty::Int) => self.op(match compare {
+                CompareToken::Equal => I64_EQ,
+                CompareToken::NotEqual => I64_NE,
+                CompareToken::Less => I64_LT_S,
+                CompareToken::LessEq => I64_LE_S,
+                CompareToken::Greater => I64_GT_S,
+                CompareToken::GreaterEq => I64_GE_S,
+            }),
+            _ => {
+                self.floats(lhs, rhs);
+                self.op(match compare {
+                    CompareToken::Equal => F64_EQ,
+                    CompareToken::NotEqual => F64_NE,
+                    CompareToken::Less => F64_LT,
+                    CompareToken::LessEq => F64_LE,
+                    CompareToken::Greater => F64_GT,
+                    CompareToken::GreaterEq => F64_GE,
+                });
+            }
+        }
+        Ty::Bool
+    }
+
+    // Calls a top-level function by name, giving the type of what it returns, or None
+    // if it returns nothing
+    fn call(&mut self, name: &str, args: &'a [Node], span: &Span) -> Option<Ty> {
+        let types: Vec<_> = args.iter().map(|arg| self.value(arg)).collect();
+        match self.resolve(name) {
+            Name::Function => {}
+            // Memories shadow builtins; calling one that isn't a function fails
+            Name::Local(..) | Name::Global(..) => return Some(self.fail(args.len())),
+            Name::Missing if builtins::lookup(name).is_some() => {
+                let message =
+                    format!("The builtin '{name}' is not supported by the WebAssembly backend");
+                self.unsupported_because(message, span);
+                return Some(self.fail(args.len()));
+            }
+            Name::Missing => return Some(self.fail(args.len())),
+        }
+        let (params, body) = self.definitions[name];
+        if params.len() != args.len() {
+            return Some(self.fail(args.len()));
+        }
+        let key = (name.to_string(), types, self.declared);
+        let index = match self.instances.get(&key) {
+            Some(&index) => index,
+            None => self.instantiate(key, params, body),
+        };
+        self.push(Instr::Call(index));
+        if let Some(result) = self.results.get(&index) {
+            return *result;
+        }
+        // A call of a function still being emitted, from inside itself
+        let frame = self
+            .frames
+            .iter_mut()
+            .find(|frame| frame.index == index)
+            .expect("an unfinished function is being emitted");
+        match frame.returns {
+            Returns::Value(ty) => Some(ty),
+            _ => {
+                frame.returns = Returns::Nothing;
+                None
+            }
+        }
+    }
+
+    fn instantiate(
+        &mut self,
+        key: (String, Vec<Ty>, usize),
+        params: &'a [String],
+        body: &'a Node,
+    ) -> u32 {
+        let index = self.functions.len() as u32;
+        self.functions.push(None);
+        let types: Vec<_> = key.1.iter().map(|ty| ty.name()).collect();
+        let name = format!("{}<{}>", key.0, types.join(":"));
+        let mut frame = Frame::new(index, name, key.1.iter().map(|ty| ty.val()).collect());
+        // Parameters and the body's own declarations share the call's scope
+        let scope = params
+            .iter()
+            .zip(&key.1)
+            .enumerate()
+            .map(|(i, (param, &ty))| Local {
+                name: param.clone(),
+                index: i as u32,
+                ty,
+                is_final: false,
+            })
+            .collect();
+        frame.scopes.push(scope);
+        self.instances.insert(key, index);
+        self.frames.push(frame);
+        if let ASTNode::Block(nodes) = &body.node {
+            for node in nodes {
+                self.statement(node);
+            }
+        }
+        let frame = self.frames.pop().expect("just pushed");
+        let result = match frame.returns {
+            Returns::Value(ty) => Some(ty),
+            _ => None,
+        };
+        self.results.insert(index, result);
+        self.finish(frame, result);
+        index
+    }
+
+    fn helper_call(&mut self, helper: Helper) {
+        let index = self.helper(helper);
+        self.push(Instr::Call(index));
+    }
+
+    fn helper(&mut self, helper: Helper) -> u32 {
+        if let Some(&index) = self.helpers.get(&helper) {
+            return index;
+        }
+        use Instr::*;
+        use ValType::{F64, I64};
+        let trap_if = [If(None), Unreachable, End];
+        let (name, params, result, locals, mut body) = match helper {
+            // Signed overflow: the result's sign differs from both operands'
+            Helper::Add => (
+                "add",
+                vec![I64, I64],
+                I64,
+                vec![I64],
+                vec![
+                    LocalGet(0),
+                    LocalGet(1),
+                    Op(I64_ADD),
+                    LocalTee(2),
+                    LocalGet(0),
+                    Op(I64_XOR),
+                    LocalGet(2),
+                    LocalGet(1),
+                    Op(I64_XOR),
+                    Op(I64_AND),
+                    I64Const(0),
+                    Op(I64_LT_S),
+                ],
+            ),
+            // The operands' signs differ, and the result's differs from the first
+            Helper::Sub => (
+                "sub",
+                vec![I64, I64],
+                I64,
+                vec![I64],
+                vec![
+                    LocalGet(0),
+                    LocalGet(1),
+                    Op(I64_SUB),
+                    LocalSet(2),
+                    LocalGet(0),
+                    LocalGet(1),
+                    Op(I64_XOR),
+                    LocalGet(0),
+                    LocalGet(2),
+                    Op(I64_XOR),
+                    Op(I64_AND),
+                    I64Const(0),
+                    Op(I64_LT_S),
+                ],
+            ),
+            // Dividing back must give the other operand; i64.MIN / -1 traps by itself
+            Helper::Mul => (
+                "mul",
+                vec![I64, I64],
+                I64,
+                vec![I64],
+                vec![
+                    LocalGet(0),
+                    LocalGet(1),
+                    Op(I64_MUL),
+                    LocalSet(2),
+                    LocalGet(0),
+                    Op(I64_EQZ),
+                    Op(I32_EQZ),
+                    If(None),
+                    LocalGet(2),
+                    LocalGet(0),
+                    Op(I64_DIV_S),
+                    LocalGet(1),
+                    Op(I64_NE),
+                ],
+            ),
+            Helper::Neg => (
+                "neg",
+                vec![I64],
+                I64,
+                Vec::new(),
+                vec![LocalGet(0), I64Const(i64::MIN), Op(I64_EQ)],
+            ),
+            // i64.rem_s traps on zero, but gives 0 for i64.MIN % -1 where Rust overflows
+            Helper::Rem => (
+                "rem",
+                vec![I64, I64],
+                I64,
+                Vec::new(),
+                vec![
+                    LocalGet(0),
+                    I64Const(i64::MIN),
+                    Op(I64_EQ),
+                    LocalGet(1),
+                    I64Const(-1),
+                    Op(I64_EQ),
+                    Op(I32_AND),
+                ],
+            ),
+            // Exponentiation by squaring, as i64::checked_pow; the exponent must fit a u32
+            Helper::Pow => (
+                "pow",
+                vec![I64, I64],
+                I64,
+                vec![I64],
+                vec![
+                    LocalGet(1),
+                    I64Const(0),
+                    Op(I64_LT_S),
+                    LocalGet(1),
+                    I64Const(u32::MAX.into()),
+                    Op(I64_GT_S),
+                    Op(I32_OR),
+                ],
+            ),
+            Helper::Check => (
+                "check",
+                vec![F64],
+                F64,
+                Vec::new(),
+                vec![
+                    LocalGet(0),
+                    LocalGet(0),
+                    Op(F64_NE),
+                    LocalGet(0),
+                    Op(F64_ABS),
+                    F64Const(f64::INFINITY),
+                    Op(F64_EQ),
+                    Op(I32_OR),
+                ],
+            ),
+            Helper::Divide | Helper::IntDivide => (
+                if helper == Helper::Divide {
+                    "divide"
+                } else {
+                    "int_divide"
+                },
+                vec![F64, F64],
+                F64,
+                Vec::new(),
+                vec![LocalGet(1), F64Const(0.0), Op(F64_EQ)],
+            ),
+        };
+        body.extend(trap_if);
+        match helper {
+            Helper::Add | Helper::Sub => body.push(LocalGet(2)),
+            Helper::Mul => body.extend([End, LocalGet(2)]),
+            Helper::Neg => body.extend([I64Const(0), LocalGet(0), Op(I64_SUB)]),
+            Helper::Rem => body.extend([LocalGet(0), LocalGet(1), Op(I64_REM_S)]),
+            Helper::Pow => {
+                let mul = self.helper(Helper::Mul);
+                body.extend([
+                    I64Const(1),
+                    LocalSet(2),
+                    Block,
+                    Loop,
+                    LocalGet(1),
+                    Op(I64_EQZ),
+                    BrIf(1),
+                    LocalGet(1),
+                    I64Const(1),
+                    Op(I64_AND),
+                    Op(I64_EQZ),
+                    Op(I32_EQZ),
+                    If(None),
+                    LocalGet(2),
+                    LocalGet(0),
+                    Call(mul),
+                    LocalSet(2),
+                    End,
+                    LocalGet(1),
+                    I64Const(1),
+                    Op(I64_SHR_S),
+                    LocalTee(1),
+                    Op(I64_EQZ),
+                    BrIf(1),
+                    LocalGet(0),
+                    LocalGet(0),
+                    Call(mul),
+                    LocalSet(0),
+                    Br(0),
+                    End,
+                    End,
+                    LocalGet(2),
+                ]);
+            }
+            Helper::Check => body.push(LocalGet(0)),
+            Helper::Divide | Helper::IntDivide => {
+                let check = self.helper(Helper::Check);
+                body.extend([LocalGet(0), LocalGet(1), Op(F64_DIV)]);
+                if helper == Helper::IntDivide {
+                    body.push(Op(F64_TRUNC));
+                }
+                body.push(Call(check));
+            }
+        }
+        let index = self.functions.len() as u32;
+        self.functions.push(Some(Function {
+            name: format!("crystal/{name}"),
+            params,
+            result: Some(result),
+            locals,
+            body,
+        }));
+        self.helpers.insert(helper, index);
+        index
+    }
+}
@@ -0,0 +1,43 @@
+// The CLI where the system is short of memory, which mustn't give Rust panics or aborts
+#![cfg(unix)]
+
+mod common;
+
+use std::process::{Command, Output};
+
+use common::{scratch, text};
+
+// Runs the crystal binary with its virtual memory limited to `kib`
+fn crystal_limited(kib: u32, args: &str) -> Output {
+    let binary = env!("CARGO_BIN_EXE_crystal-lang");
+    Command::new("sh")
+        .arg("-c")
+        .arg(format!("ulimit -v {kib} && exec '{binary}' {args}"))
+        .output()
+        .expect("sh runs")
+}
+
+#[test]
+fn help_needs_no_interpreter_stack() {
+    let output = crystal_limited(100_000, "help");
+    assert_eq!(output.status.code(), Some(0));
+    assert!(text(&output.stdout).contains("Command List"));
+    assert!(!text(&output.stderr).contains("panicked"));
+}
+
+#[test]
+fn a_smaller_stack_allows_fewer_nested_calls() {
+    let source = scratch("forever.cry");
+    std::fs::write(
+        &source,
+        "fn forever(n) {\n    return 1 + forever(n + 1);\n}\nforever(0);\n",
+    )
+    .expect("writable temp dir");
+    let path = source.to_str().expect("utf-8 path");
+    for backend in ["", "--vm"] {
+        let output = crystal_limited(200_000, &format!("run {backend} '{path}'"));
+        assert!(text(&output.stderr).contains("Maximum call depth exceeded"));
+        assert_eq!(output.status.code(), Some(7));
+    }
+    std::fs::remove_file(&source).ok();
+}
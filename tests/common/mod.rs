@@ -0,0 +1,58 @@
+// Helpers shared by the integration tests, which drive the real `crystal` binary
+// Each test crate uses only some of these
+#![allow(dead_code)]
+
+pub mod wasm;
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+// Every sample program, in a stable order
+pub fn programs() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    let mut programs: Vec<_> = fs::read_dir(dir)
+        .expect("tests/programs exists")
+        .map(|entry| entry.expect("readable entry").path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "cry"))
+        .collect();
+    programs.sort();
+    programs
+}
+
+pub fn crystal(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_crystal-lang"))
+        .args(args)
+        .output()
+        .expect("the crystal binary runs")
+}
+
+pub fn text(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+// Fails unless two runs look the same to a user: output, errors and exit code
+pub fn assert_same(expected: &Output, actual: &Output, what: &str) {
+    assert_eq!(
+        text(&expected.stdout),
+        text(&actual.stdout),
+        "stdout differs for {what}"
+    );
+    assert_eq!(
+        text(&expected.stderr),
+        text(&actual.stderr),
+        "stderr differs for {what}"
+    );
+    assert_eq!(
+        expected.status.code(),
+        actual.status.code(),
+        "exit code differs for {what}"
+    );
+}
+
+// A path in the temp dir that no other test process uses
+pub fn scratch(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("crystal-{}-{name}", std::process::id()))
+}
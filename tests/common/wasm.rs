@@ -0,0 +1,520 @@
+// A WebAssembly validator for the modules `crystal emit-wasm` writes, so the tests can
+// check them without a wasm engine. It follows the validation algorithm of the spec's
+// appendix for the sections and instructions such modules use, and rejects the rest.
+
+use std::collections::HashSet;
+
+const I32: u8 = 0x7f;
+const I64: u8 = 0x7e;
+const F32: u8 = 0x7d;
+const F64: u8 = 0x7c;
+
+// What a valid module offers its embedder
+#[derive(Debug)]
+pub struct Summary {
+    pub functions: usize,
+    // Each exported global's name and value type
+    pub globals: Vec<(String, &'static str)>,
+}
+
+pub fn validate(bytes: &[u8]) -> Result<Summary, String> {
+    let mut reader = Reader { bytes, at: 0 };
+    if reader.take(4)? != b"\0asm" {
+        return Err("bad magic number".to_string());
+    }
+    if reader.take(4)? != [1, 0, 0, 0] {
+        return Err("unknown binary version".to_string());
+    }
+
+    let mut types: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+    let mut functions: Vec<u32> = Vec::new();
+    let mut globals: Vec<(u8, bool)> = Vec::new();
+    let mut exported = Vec::new();
+    let mut last_id = 0;
+    let mut has_code = false;
+    while !reader.done() {
+        let id = reader.byte()?;
+        let size = reader.u32()? as usize;
+        let mut section = Reader {
+            bytes: reader.take(size)?,
+            at: 0,
+        };
+        if id != 0 {
+            if id <= last_id {
+                return Err(format!("section {id} is out of order"));
+            }
+            last_id = id;
+        }
+        match id {
+            // A custom section: a name, then anything
+            0 => {
+                section.name()?;
+                continue;
+            }
+            1 => {
+                for _ in 0..section.u32()? {
+                    if section.byte()? != 0x60 {
+                        return Err("a type is not a function type".to_string());
+                    }
+                    let params = section.value_types()?;
+                    let results = section.value_types()?;
+                    types.push((params, results));
+                }
+            }
+            3 => {
+                for _ in 0..section.u32()? {
+                    let ty = section.u32()?;
+                    if ty as usize >= types.len() {
+                        return Err(format!("unknown type {ty}"));
+                    }
+                    functions.push(ty);
+                }
+            }
+            6 => {
+                for _ in 0..section.u32()? {
+                    let ty = section.value_type()?;
+                    let mutable = match section.byte()? {
+                        0 => false,
+                        1 => true,
+                        other => return Err(format!("bad mutability {other:#x}")),
+                    };
+                    let init = match section.byte()? {
+                        0x41 => section.s64(32).map(|_| I32)?,
+                        0x42 => section.s64(64).map(|_| I64)?,
+                        0x44 => section.take(8).map(|_| F64)?,
+                        other => return Err(format!("{other:#x} is not a constant instruction")),
+                    };
+                    if init != ty || section.byte()? != 0x0b {
+                        return Err("a global's initializer has the wrong type".to_string());
+                    }
+                    globals.push((ty, mutable));
+                }
+            }
+            7 => {
+                let mut names = HashSet::new();
+                for _ in 0..section.u32()? {
+                    let name = section.name()?;
+                    if !names.insert(name.clone()) {
+                        return Err(format!("'{name}' is exported twice"));
+                    }
+                    let (kind, index) = (section.byte()?, section.u32()? as usize);
+                    match kind {
+                        0x00 if index < functions.len() => {}
+                        0x03 if index < globals.len() => {
+                            exported.push((name, type_name(globals[index].0)));
+                        }
+                        _ => return Err(format!("'{name}' exports something unknown")),
+                    }
+                }
+            }
+            8 => {
+                let start = section.u32()? as usize;
+                let ty = functions.get(start).ok_or("unknown start function")?;
+                let (params, results) = &types[*ty as usize];
+                if !params.is_empty() || !results.is_empty() {
+                    return Err("the start function takes or returns values".to_string());
+                }
+            }
+            10 => {
+                has_code = true;
+                if section.u32()? as usize != functions.len() {
+                    return Err("the code and function sections disagree".to_string());
+                }
+                for (index, &ty) in functions.iter().enumerate() {
+                    let size = section.u32()? as usize;
+                    let mut body = Reader {
+                        bytes: section.take(size)?,
+                        at: 0,
+                    };
+                    let (params, results) = &types[ty as usize];
+                    let mut locals = params.clone();
+                    for _ in 0..body.u32()? {
+                        let n = body.u32()?;
+                        let ty = body.value_type()?;
+                        if locals.len() + n as usize > 50_000 {
+                            return Err("too many locals".to_string());
+                        }
+                        locals.extend(std::iter::repeat_n(ty, n as usize));
+                    }
+                    let module = Context {
+                        types: &types,
+                        functions: &functions,
+                        globals: &globals,
+                        locals,
+                        results: results.clone(),
+                    };
+                    module
+                        .check(&mut body)
+                        .map_err(|err| format!("function {index}: {err}"))?;
+                }
+            }
+            other => return Err(format!("section {other} is not expected")),
+        }
+        if !section.done() {
+            return Err(format!("section {id} has trailing bytes"));
+        }
+    }
+    if !functions.is_empty() && !has_code {
+        return Err("functions have no code".to_string());
+    }
+    Ok(Summary {
+        functions: functions.len(),
+        globals: exported,
+    })
+}
+
+fn type_name(ty: u8) -> &'static str {
+    match ty {
+        I32 => "i32",
+        I64 => "i64",
+        F32 => "f32",
+        _ => "f64",
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn done(&self) -> bool {
+        self.at == self.bytes.len()
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        let byte = *self.bytes.get(self.at).ok_or("unexpected end")?;
+        self.at += 1;
+        Ok(byte)
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let end = self
+            .at
+            .checked_add(n)
+            .filter(|&end| end <= self.bytes.len());
+        let end = end.ok_or("unexpected end")?;
+        let taken = &self.bytes[self.at..end];
+        self.at = end;
+        Ok(taken)
+    }
+
+    // Unsigned LEB128, at most five bytes, with no bits past the 32nd
+    fn u32(&mut self) -> Result<u32, String> {
+        let mut n = 0u64;
+        for i in 0..5 {
+            let byte = self.byte()?;
+            n |= u64::from(byte & 0x7f) << (7 * i);
+            if byte & 0x80 == 0 {
+                return u32::try_from(n).map_err(|_| "integer too large".to_string());
+            }
+        }
+        Err("integer representation too long".to_string())
+    }
+
+    // Signed LEB128 of a `bits`-bit integer
+    fn s64(&mut self, bits: u32) -> Result<i64, String> {
+        let mut n = 0i128;
+        let mut shift = 0;
+        loop {
+            let byte = self.byte()?;
+            n |= i128::from(byte & 0x7f) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 128 && byte & 0x40 != 0 {
+                    n |= -1i128 << shift;
+                }
+                break;
+            }
+            if shift >= bits.div_ceil(7) * 7 {
+                return Err("integer representation too long".to_string());
+            }
+        }
+        let limit = 1i128 << (bits - 1);
+        if n < -limit || n >= limit {
+            return Err("integer too large".to_string());
+        }
+        Ok(n as i64)
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let len = self.u32()? as usize;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| "malformed UTF-8 name".to_string())
+    }
+
+    fn value_type(&mut self) -> Result<u8, String> {
+        match self.byte()? {
+            ty @ (I32 | I64 | F32 | F64) => Ok(ty),
+            other => Err(format!("{other:#x} is not a value type")),
+        }
+    }
+
+    fn value_types(&mut self) -> Result<Vec<u8>, String> {
+        (0..self.u32()?).map(|_| self.value_type()).collect()
+    }
+}
+
+// An operand of unknown type, as after an instruction that never returns
+const ANY: Option<u8> = None;
+
+struct Frame {
+    is_loop: bool,
+    params: Vec<u8>,
+    results: Vec<u8>,
+    height: usize,
+    unreachable: bool,
+    // An `if` without its `else` yet
+    is_if: bool,
+}
+
+impl Frame {
+    // The types a branch to this frame's label carries
+    fn label(&self) -> &[u8] {
+        if self.is_loop {
+            &self.params
+        } else {
+            &self.results
+        }
+    }
+}
+
+struct Context<'m> {
+    types: &'m [(Vec<u8>, Vec<u8>)],
+    functions: &'m [u32],
+    globals: &'m [(u8, bool)],
+    locals: Vec<u8>,
+    results: Vec<u8>,
+}
+
+struct Stack {
+    operands: Vec<Option<u8>>,
+    frames: Vec<Frame>,
+}
+
+impl Stack {
+    fn push(&mut self, ty: u8) {
+        self.operands.push(Some(ty));
+    }
+
+    fn pop(&mut self) -> Result<Option<u8>, String> {
+        let frame = self.frames.last().ok_or("no open block")?;
+        if self.operands.len() == frame.height {
+            if frame.unreachable {
+                return Ok(ANY);
+            }
+            return Err("the stack is empty".to_string());
+        }
+        Ok(self.operands.pop().expect("above the frame's height"))
+    }
+
+    fn expect(&mut self, ty: u8) -> Result<(), String> {
+        match self.pop()? {
+            Some(actual) if actual != ty => Err(format!(
+                "expected {} but found {}",
+                type_name(ty),
+                type_name(actual)
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn expect_all(&mut self, types: &[u8]) -> Result<(), String> {
+        for &ty in types.iter().rev() {
+            self.expect(ty)?;
+        }
+        Ok(())
+    }
+
+    fn open(&mut self, is_loop: bool, is_if: bool, params: Vec<u8>, results: Vec<u8>) {
+        let height = self.operands.len();
+        for &ty in &params {
+            self.push(ty);
+        }
+        self.frames.push(Frame {
+            is_loop,
+            params,
+            results,
+            height,
+            unreachable: false,
+            is_if,
+        });
+    }
+
+    fn close(&mut self) -> Result<Frame, String> {
+        let results = self.frames.last().ok_or("no open block")?.results.clone();
+        self.expect_all(&results)?;
+        let frame = self.frames.pop().expect("checked above");
+        if self.operands.len() != frame.height {
+            return Err("a block leaves extra values".to_string());
+        }
+        Ok(frame)
+    }
+
+    fn unreachable(&mut self) {
+        let frame = self.frames.last_mut().expect("inside the function");
+        self.operands.truncate(frame.height);
+        frame.unreachable = true;
+    }
+
+    fn label(&self, depth: u32) -> Result<Vec<u8>, String> {
+        let i = self
+            .frames
+            .len()
+            .checked_sub(depth as usize + 1)
+            .ok_or(format!("unknown label {depth}"))?;
+        Ok(self.frames[i].label().to_vec())
+    }
+}
+
+// The operand and result types of the numeric instructions without immediates
+fn numeric(opcode: u8) -> Option<(&'static [u8], u8)> {
+    let signature: (&[u8], u8) = match opcode {
+        0x45 => (&[I32], I32),
+        0x46..=0x4f => (&[I32, I32], I32),
+        0x50 => (&[I64], I32),
+        0x51..=0x5a => (&[I64, I64], I32),
+        0x5b..=0x60 => (&[F32, F32], I32),
+        0x61..=0x66 => (&[F64, F64], I32),
+        0x67..=0x69 => (&[I32], I32),
+        0x6a..=0x78 => (&[I32, I32], I32),
+        0x79..=0x7b => (&[I64], I64),
+        0x7c..=0x8a => (&[I64, I64], I64),
+        0x8b..=0x91 => (&[F32], F32),
+        0x92..=0x98 => (&[F32, F32], F32),
+        0x99..=0x9f => (&[F64], F64),
+        0xa0..=0xa6 => (&[F64, F64], F64),
+        0xa7 => (&[I64], I32),
+        0xac | 0xad => (&[I32], I64),
+        0xb0 | 0xb1 => (&[F32], I64),
+        0xb2 | 0xb3 => (&[F64], I64),
+        0xb7 | 0xb8 => (&[I32], F64),
+        0xb9 | 0xba => (&[I64], F64),
+        0xbb => (&[F32], F64),
+        _ => return None,
+    };
+    Some(signature)
+}
+
+impl Context<'_> {
+    fn block_type(&self, reader: &mut Reader) -> Result<Vec<u8>, String> {
+        match reader.byte()? {
+            0x40 => Ok(Vec::new()),
+            ty @ (I32 | I64 | F32 | F64) => Ok(vec![ty]),
+            other => Err(format!("block type {other:#x} is not expected")),
+        }
+    }
+
+    fn check(&self, reader: &mut Reader) -> Result<(), String> {
+        let mut stack = Stack {
+            operands: Vec::new(),
+            frames: Vec::new(),
+        };
+        stack.open(false, false, Vec::new(), self.results.clone());
+        while !stack.frames.is_empty() {
+            let opcode = reader.byte()?;
+            match opcode {
+                0x00 => stack.unreachable(),
+                0x01 => {}
+                0x02 | 0x03 => {
+                    let results = self.block_type(reader)?;
+                    stack.open(opcode == 0x03, false, Vec::new(), results);
+                }
+                0x04 => {
+                    let results = self.block_type(reader)?;
+                    stack.expect(I32)?;
+                    stack.open(false, true, Vec::new(), results);
+                }
+                0x05 => {
+                    let frame = stack.close()?;
+                    if !frame.is_if {
+                        return Err("else outside an if".to_string());
+                    }
+                    stack.open(false, false, frame.params, frame.results);
+                }
+                0x0b => {
+                    let frame = stack.close()?;
+                    // An if without else gives back its parameters when the test fails
+                    if frame.is_if && frame.params != frame.results {
+                        return Err("an if without else must not produce values".to_string());
+                    }
+                    for ty in frame.results {
+                        stack.push(ty);
+                    }
+                }
+                0x0c => {
+                    let label = stack.label(reader.u32()?)?;
+                    stack.expect_all(&label)?;
+                    stack.unreachable();
+                }
+                0x0d => {
+                    let label = stack.label(reader.u32()?)?;
+                    stack.expect(I32)?;
+                    stack.expect_all(&label)?;
+                    for ty in label {
+                        stack.push(ty);
+                    }
+                }
+                0x0f => {
+                    stack.expect_all(&self.results)?;
+                    stack.unreachable();
+                }
+                0x10 => {
+                    let function = reader.u32()? as usize;
+                    let ty = self.functions.get(function).ok_or("unknown function")?;
+                    let (params, results) = &self.types[*ty as usize];
+                    stack.expect_all(params)?;
+                    for &ty in results {
+                        stack.push(ty);
+                    }
+                }
+                0x1a => {
+                    stack.pop()?;
+                }
+                0x20..=0x22 => {
+                    let local = reader.u32()? as usize;
+                    let ty = *self.locals.get(local).ok_or("unknown local")?;
+                    if opcode != 0x20 {
+                        stack.expect(ty)?;
+                    }
+                    if opcode != 0x21 {
+                        stack.push(ty);
+                    }
+                }
+                0x23 | 0x24 => {
+                    let global = reader.u32()? as usize;
+                    let (ty, mutable) = *self.globals.get(global).ok_or("unknown global")?;
+                    if opcode == 0x23 {
+                        stack.push(ty);
+                    } else if !mutable {
+                        return Err("global.set of an immutable global".to_string());
+                    } else {
+                        stack.expect(ty)?;
+                    }
+                }
+                0x41 => {
+                    reader.s64(32)?;
+                    stack.push(I32);
+                }
+                0x42 => {
+                    reader.s64(64)?;
+                    stack.push(I64);
+                }
+                0x44 => {
+                    reader.take(8)?;
+                    stack.push(F64);
+                }
+                _ => {
+                    let (params, result) =
+                        numeric(opcode).ok_or(format!("opcode {opcode:#x} is not expected"))?;
+                    stack.expect_all(params)?;
+                    stack.push(result);
+                }
+            }
+        }
+        if !reader.done() {
+            return Err("code after the function's end".to_string());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,79 @@
+// `crystal build` output must run exactly like the source it was built from
+mod common;
+
+use std::fs;
+
+use common::{assert_same, crystal, programs, scratch, text};
+
+#[test]
+fn built_programs_run_like_their_source() {
+    for (i, program) in programs().iter().enumerate() {
+        let path = program.to_str().expect("utf-8 path");
+        let built = scratch(&format!("{i}.cryc"));
+        let built = built.to_str().expect("utf-8 path");
+        let output = crystal(&["build", path, "-o", built]);
+        assert!(output.status.success(), "could not build {path}");
+
+        let from_source = crystal(&["run", path]);
+        let from_bytecode = crystal(&["run", built]);
+        fs::remove_file(built).ok();
+        assert_same(&from_source, &from_bytecode, path);
+    }
+}
+
+// Builds a small program and returns the bytes of its .cryc file
+fn built_bytes(name: &str) -> Vec<u8> {
+    let source = scratch(&format!("{name}.cry"));
+    let built = scratch(&format!("{name}.cryc"));
+    fs::write(&source, "println(\"hi\");\n").expect("writable temp dir");
+    let output = crystal(&[
+        "build",
+        source.to_str().expect("utf-8 path"),
+        "-o",
+        built.to_str().expect("utf-8 path"),
+    ]);
+    assert!(output.status.success());
+    let bytes = fs::read(&built).expect("build wrote the file");
+    fs::remove_file(source).ok();
+    fs::remove_file(built).ok();
+    bytes
+}
+
+// Runs a .cryc file holding `bytes`, returning what it printed
+fn run_bytes(name: &str, bytes: &[u8]) -> (String, Option<i32>) {
+    let path = scratch(&format!("{name}.cryc"));
+    fs::write(&path, bytes).expect("writable temp dir");
+    let output = crystal(&["run", path.to_str().expect("utf-8 path")]);
+    fs::remove_file(path).ok();
+    (text(&output.stdout), output.status.code())
+}
+
+#[test]
+fn files_start_with_magic_and_version() {
+    let bytes = built_bytes("magic");
+    assert_eq!(&bytes[..4], b"CRYC");
+    assert_eq!(u16::from_le_bytes([bytes[4], bytes[5]]), 1);
+    assert_eq!(run_bytes("magic", &bytes), ("hi\n".to_string(), Some(0)));
+}
+
+#[test]
+fn other_format_versions_are_rejected() {
+    let mut bytes = built_bytes("version");
+    bytes[4..6].copy_from_slice(&2u16.to_le_bytes());
+    let (stdout, code) = run_bytes("version", &bytes);
+    assert!(stdout.contains("built for bytecode format 2"), "{stdout}");
+    assert_eq!(code, Some(1));
+}
+
+#[test]
+fn corrupted_files_are_rejected() {
+    let mut bytes = built_bytes("corrupt");
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    let (stdout, code) = run_bytes("corrupt", &bytes);
+    assert!(stdout.contains("checksum mismatch"), "{stdout}");
+    assert_eq!(code, Some(1));
+
+    let (stdout, _) = run_bytes("truncated", &bytes[..bytes.len() / 2]);
+    assert!(stdout.contains("truncated"), "{stdout}");
+}
@@ -0,0 +1,60 @@
+// `crystal disasm` shows what the compiler produced, the same for source and bytecode
+mod common;
+
+use std::fs;
+
+use common::{crystal, scratch, text};
+
+const PROGRAM: &str = "final greeting = \"hi\";
+fn twice(x) {
+    return x * 2;
+}
+println(greeting, twice(21));
+";
+
+#[test]
+fn lists_each_function_with_resolved_operands() {
+    let source = scratch("disasm.cry");
+    fs::write(&source, PROGRAM).expect("writable temp dir");
+    let output = crystal(&["disasm", source.to_str().expect("utf-8 path")]);
+    fs::remove_file(&source).ok();
+    assert!(output.status.success());
+
+    let listing = text(&output.stdout);
+    let rows: Vec<_> = listing.lines().map(str::split_whitespace).collect();
+    let has = |row: &[&str]| {
+        rows.iter()
+            .any(|words| words.clone().collect::<Vec<_>>() == row)
+    };
+    assert!(listing.starts_with("== <script> =="), "{listing}");
+    assert!(
+        has(&["0000", "1", "Const", "0", ";", "\"hi\""]),
+        "{listing}"
+    );
+    assert!(
+        has(&["0001", "|", "DefineGlobal", "0", ";", "final", "greeting"]),
+        "{listing}"
+    );
+    assert!(
+        listing.contains("== fn twice == 1 argument(s)"),
+        "{listing}"
+    );
+    assert!(has(&["0002", "|", "Math", ";", "*"]), "{listing}");
+}
+
+#[test]
+fn bytecode_files_disassemble_like_their_source() {
+    let source = scratch("disasm-built.cry");
+    let built = scratch("disasm-built.cryc");
+    fs::write(&source, PROGRAM).expect("writable temp dir");
+    let (source, built) = (
+        source.to_str().expect("utf-8 path"),
+        built.to_str().expect("utf-8 path"),
+    );
+    assert!(crystal(&["build", source, "-o", built]).status.success());
+    let from_source = crystal(&["disasm", source]);
+    let from_bytecode = crystal(&["disasm", built]);
+    fs::remove_file(source).ok();
+    fs::remove_file(built).ok();
+    assert_eq!(text(&from_source.stdout), text(&from_bytecode.stdout));
+}
@@ -0,0 +1,47 @@
+// What the tree-walker prints for every sample program, checked against the files beside
+// it: `name.stdout`, `name.stderr` and `name.status`. The other backends are tested
+// against the tree-walker, so these files pin down the language itself
+mod common;
+
+use std::{fs, path::Path, process::Command};
+
+use common::{programs, text};
+
+fn expected(program: &Path, extension: &str) -> String {
+    let path = program.with_extension(extension);
+    fs::read_to_string(&path).unwrap_or_else(|_| panic!("{} is missing", path.display()))
+}
+
+#[test]
+fn the_tree_walker_prints_the_expected_output() {
+    let root = env!("CARGO_MANIFEST_DIR");
+    for program in programs() {
+        // Run from the crate root with a relative path, so error locations don't depend
+        // on where the repository is checked out
+        let relative = program
+            .strip_prefix(root)
+            .expect("samples live in the crate");
+        let path = relative.to_str().expect("utf-8 path");
+        let output = Command::new(env!("CARGO_BIN_EXE_crystal-lang"))
+            .current_dir(root)
+            .args(["run", path])
+            .output()
+            .expect("the crystal binary runs");
+        assert_eq!(
+            text(&output.stdout),
+            expected(&program, "stdout"),
+            "stdout differs for {path}"
+        );
+        assert_eq!(
+            text(&output.stderr),
+            expected(&program, "stderr"),
+            "stderr differs for {path}"
+        );
+        let status = expected(&program, "status");
+        assert_eq!(
+            output.status.code(),
+            Some(status.trim().parse().expect("an exit code")),
+            "exit code differs for {path}"
+        );
+    }
+}
@@ -0,0 +1,90 @@
+// Builds every sample program into a native executable, which must behave exactly like
+// the interpreter
+mod common;
+
+use std::{fs, process::Command};
+
+use common::{assert_same, crystal, programs, scratch, text};
+
+// The native tests need a C compiler; without one they have nothing to check
+fn have_cc() -> bool {
+    Command::new("cc").arg("--version").output().is_ok()
+}
+
+#[test]
+fn native_binaries_agree_with_the_interpreter() {
+    if !have_cc() {
+        eprintln!("no C compiler found, skipping");
+        return;
+    }
+    for program in programs() {
+        let path = program.to_str().expect("utf-8 path");
+        let binary = scratch(&format!(
+            "native-{}",
+            program.file_stem().expect("a file name").to_string_lossy()
+        ));
+        let binary = binary.to_str().expect("utf-8 path");
+        let built = crystal(&["compile", "--native", path, "-o", binary]);
+        assert!(built.status.success(), "{path}: {}", text(&built.stderr));
+        let native = Command::new(binary).output().expect("the binary runs");
+        fs::remove_file(binary).ok();
+        assert_same(&crystal(&["run", path]), &native, path);
+    }
+}
+
+#[test]
+fn emit_c_writes_the_runtime_header_alongside() {
+    let source = scratch("emit.cry");
+    let output = scratch("emit.c");
+    fs::write(&source, "final answer = 6 * 7;\nprintln(answer);\n").expect("writable temp dir");
+    let emitted = crystal(&[
+        "emit-c",
+        source.to_str().expect("utf-8 path"),
+        "-o",
+        output.to_str().expect("utf-8 path"),
+    ]);
+    assert!(emitted.status.success(), "{}", text(&emitted.stderr));
+    let c = fs::read_to_string(&output).expect("the C file was written");
+    let header = output.with_file_name("crystal.h");
+    assert!(c.contains("#include \"crystal.h\""));
+    assert!(c.contains("int main(void)"));
+    assert!(fs::read_to_string(&header).is_ok_and(|h| h.contains("CryValue")));
+    for path in [&source, &output, &header] {
+        fs::remove_file(path).ok();
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn garbage_is_reclaimed_in_long_loops() {
+    if !have_cc() {
+        eprintln!("no C compiler found, skipping");
+        return;
+    }
+    let source = scratch("garbage.cry");
+    let binary = scratch("native-garbage");
+    fs::write(
+        &source,
+        "let i = 0;\nwhile i < 3000000 {\n    let s = \"item ${i}\";\n    let l = [s, s];\n    i += 1;\n}\nprintln(i);\n",
+    )
+    .expect("writable temp dir");
+    let binary = binary.to_str().expect("utf-8 path");
+    let built = crystal(&[
+        "compile",
+        "--native",
+        source.to_str().expect("utf-8 path"),
+        "-o",
+        binary,
+    ]);
+    assert!(built.status.success(), "{}", text(&built.stderr));
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(format!("ulimit -v 200000 && exec '{binary}'"))
+        .output()
+        .expect("sh runs");
+    for path in [source.to_str().expect("utf-8 path"), binary] {
+        fs::remove_file(path).ok();
+    }
+    assert!(output.status.success(), "{}", text(&output.stderr));
+    assert_eq!(text(&output.stdout), "3000000\n");
+}
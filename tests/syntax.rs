@@ -0,0 +1,130 @@
+// Errors found before a program runs: every real mistake is reported once, with its
+// message and where it is
+mod common;
+
+use std::fs;
+
+use common::{crystal, scratch, text};
+
+// Runs `source` and gives each error it reported as "Title: message @ line:column",
+// with the exit code
+fn errors(name: &str, source: &str) -> (Vec<String>, Option<i32>) {
+    let path = scratch(&format!("{name}.cry"));
+    fs::write(&path, source).expect("writable temp dir");
+    let output = crystal(&["run", path.to_str().expect("utf-8 path")]);
+    fs::remove_file(&path).ok();
+    let stderr = text(&output.stderr);
+    let mut errors = Vec::new();
+    let mut lines = stderr.lines();
+    while let Some(line) = lines.next() {
+        if !line.starts_with("CRY.") {
+            continue;
+        }
+        let at = lines.next().expect("a location follows the title");
+        let at = at.rsplit(':').take(2).collect::<Vec<_>>();
+        errors.push(format!("{line} @ {}:{}", at[1], at[0]));
+    }
+    (errors, output.status.code())
+}
+
+#[test]
+fn an_error_inside_a_body_is_reported_once() {
+    let (errors, code) = errors(
+        "body",
+        "fn f() { let x = ; let y = 2; }\nif true { let a = 1 +; } else { let b = ; }\nlet c = ;\n",
+    );
+    assert_eq!(
+        errors,
+        [
+            "CRY.ParseError: Expected expression, found ';' @ 1:18",
+            "CRY.ParseError: Expected expression, found ';' @ 2:22",
+            "CRY.ParseError: Expected expression, found ';' @ 2:41",
+            "CRY.ParseError: Expected expression, found ';' @ 3:9",
+        ]
+    );
+    assert_eq!(code, Some(3));
+}
+
+#[test]
+fn recovery_skips_the_braces_a_broken_statement_opened() {
+    let (errors, _) = errors(
+        "braces",
+        "let m = { \"a\": , \"b\": 2 };\nwhile true {\n    let q = 1\n}\nlet ok = 1;\n",
+    );
+    assert_eq!(
+        errors,
+        [
+            "CRY.ParseError: Expected expression, found ',' @ 1:16",
+            "CRY.ParseError: Expected ';' after expression @ 3:14",
+        ]
+    );
+}
+
+#[test]
+fn each_broken_statement_gets_its_own_message() {
+    let (errors, code) = errors(
+        "messages",
+        "let a = xs[1;\nlet b = p.;\nprintln(1 2);\nlet c = (1 + 2;\nlet d = [1 2];\nlen(xs) += 1;\n",
+    );
+    assert_eq!(
+        errors,
+        [
+            "CRY.ParseError: Expected ']' after index @ 1:13",
+            "CRY.ParseError: Expected field name after '.' @ 2:11",
+            "CRY.ParseError: Expected ',' or ')' after argument @ 3:10",
+            "CRY.ParseError: Expected ')' to close '(' @ 4:15",
+            "CRY.ParseError: Expected ',' or ']' after list item @ 5:11",
+            "CRY.ParseError: Can only assign to a variable, an index or a field @ 6:1",
+        ]
+    );
+    assert_eq!(code, Some(3));
+}
+
+#[test]
+fn a_lex_error_is_reported_alone() {
+    let (errors, code) = errors("lex", "let a = ;\nlet b = 0b102;\nlet c = ;\n");
+    assert_eq!(
+        errors,
+        ["CRY.LexError: Invalid digit '2' in binary literal @ 2:9"]
+    );
+    assert_eq!(code, Some(2));
+}
+
+#[test]
+fn doc_comments_are_skipped_by_the_parser() {
+    let (errors, code) = errors(
+        "docs",
+        "/// Adds one\nfn inc(x) {\n    /// The result\n    return x + 1;\n}\nprintln(inc(1));\n",
+    );
+    assert!(errors.is_empty(), "{errors:?}");
+    assert_eq!(code, Some(0));
+}
+
+#[test]
+fn matches_in_functions_are_checked_before_they_are_called() {
+    let (errors, code) = errors(
+        "exhaustive",
+        "enum Light { Red, Amber, Green }\nfn f(l) { return match l { Red => 1 }; }\n\
+         let g = fn(b) { return match b { true => 1 }; };\nprintln(\"never\");\n",
+    );
+    assert_eq!(
+        errors,
+        [
+            "CRY.TypeError: Match is not exhaustive, missing Amber, Green @ 2:18",
+            "CRY.TypeError: Match is not exhaustive, missing false @ 3:24",
+        ]
+    );
+    assert_eq!(code, Some(5));
+}
+
+#[test]
+fn matches_on_names_only_known_at_run_time_are_left_to_run_time() {
+    let (errors, code) = errors(
+        "variables",
+        "fn g(Red) { return match 5 { Red => Red }; }\n\
+         fn h(x) { let Red = x; return match x { Red => 0 }; }\n\
+         enum Light { Red, Amber, Green }\nprintln(g(2), h(3));\n",
+    );
+    assert!(errors.is_empty(), "{errors:?}");
+    assert_eq!(code, Some(0));
+}
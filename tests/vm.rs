@@ -0,0 +1,41 @@
+// Runs every sample program on the tree-walker and on the VM, which must agree on
+// everything a user can see
+mod common;
+
+use common::{assert_same, crystal, programs, text};
+
+#[test]
+fn vm_agrees_with_the_tree_walker() {
+    let programs = programs();
+    assert!(!programs.is_empty(), "no sample programs found");
+    for program in programs {
+        let path = program.to_str().expect("utf-8 path");
+        let walked = crystal(&["run", path]);
+        let compiled = crystal(&["run", "--vm", path]);
+        assert_same(&walked, &compiled, path);
+    }
+}
+
+#[test]
+fn samples_cover_success_and_failure() {
+    let codes: Vec<_> = programs()
+        .iter()
+        .map(|program| crystal(&["run", program.to_str().expect("utf-8 path")]))
+        .map(|output| output.status.code())
+        .collect();
+    assert!(codes.contains(&Some(0)));
+    assert!(codes.iter().any(|code| *code != Some(0)));
+}
+
+#[test]
+fn compound_assignment_evaluates_its_target_once() {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/programs/compound.cry");
+    for args in [vec!["run", path], vec!["run", "--vm", path]] {
+        let output = crystal(&args);
+        assert_eq!(
+            text(&output.stdout),
+            "[11, 20, 30] 1\n[[1, 2], [30, 4]] 3\nCounter { count: 3 } 5\n"
+        );
+        assert_eq!(output.status.code(), Some(0));
+    }
+}
@@ -0,0 +1,197 @@
+// Emits WebAssembly modules from the numeric sample programs, checks them with the
+// bundled validator, and runs them against the interpreter where node is installed
+mod common;
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use common::{crystal, scratch, text, wasm::validate};
+
+// The samples of the numeric subset, which print nothing: what they give is their finals
+fn samples() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs/wasm");
+    let mut samples: Vec<_> = fs::read_dir(dir)
+        .expect("tests/programs/wasm exists")
+        .map(|entry| entry.expect("readable entry").path())
+        .collect();
+    samples.sort();
+    samples
+}
+
+// The top-level finals of a sample, in order
+fn finals(source: &str) -> Vec<String> {
+    source
+        .lines()
+        .filter_map(|line| line.strip_prefix("final "))
+        .map(|line| line.split(' ').next().expect("a name").to_string())
+        .collect()
+}
+
+fn emit(source: &Path, output: &Path, args: &[&str]) -> Vec<u8> {
+    let mut all = vec![
+        "emit-wasm",
+        source.to_str().expect("utf-8 path"),
+        "-o",
+        output.to_str().expect("utf-8 path"),
+    ];
+    all.extend(args);
+    let emitted = crystal(&all);
+    assert!(emitted.status.success(), "{}", text(&emitted.stderr));
+    let bytes = fs::read(output).expect("the module was written");
+    fs::remove_file(output).ok();
+    bytes
+}
+
+fn have_node() -> bool {
+    Command::new("node").arg("--version").output().is_ok()
+}
+
+// Instantiates a module in node and prints its exported globals as `println` would,
+// or the error instantiating it raised
+const RUN: &str = r#"
+const [path, ...types] = process.argv.slice(1);
+try {
+    const module = new WebAssembly.Module(require("fs").readFileSync(path));
+    const { exports } = new WebAssembly.Instance(module);
+    const shown = Object.values(exports).map(({ value }, i) => {
+        if (types[i] === "i32") return value ? "true" : "false";
+        if (types[i] === "f64" && Number.isInteger(value)) return value.toFixed(1);
+        return String(value);
+    });
+    console.log(shown.join(" "));
+} catch (err) {
+    console.log(err.constructor.name);
+}
+"#;
+
+#[test]
+fn emitted_modules_pass_the_validator() {
+    for sample in samples() {
+        let source = fs::read_to_string(&sample).expect("readable sample");
+        let bytes = emit(&sample, &scratch("valid.wasm"), &[]);
+        let summary = validate(&bytes).unwrap_or_else(|err| panic!("{sample:?}: {err}"));
+        let exported: Vec<_> = summary.globals.iter().map(|(name, _)| name).collect();
+        assert_eq!(exported, finals(&source).iter().collect::<Vec<_>>());
+    }
+}
+
+#[test]
+fn exported_globals_agree_with_the_interpreter() {
+    if !have_node() {
+        eprintln!("no node found, skipping");
+        return;
+    }
+    let overflow = scratch("overflow.cry");
+    fs::write(
+        &overflow,
+        "final big = 9223372036854775807;\nfinal over = big + 1;\n",
+    )
+    .expect("writable temp dir");
+    let mut samples = samples();
+    samples.push(overflow.clone());
+    for sample in samples {
+        let source = fs::read_to_string(&sample).expect("readable sample");
+        let bytes = emit(&sample, &scratch("run.wasm"), &[]);
+        let summary = validate(&bytes).unwrap_or_else(|err| panic!("{sample:?}: {err}"));
+        let module = scratch("run-node.wasm");
+        fs::write(&module, &bytes).expect("writable temp dir");
+        let mut node = Command::new("node");
+        node.args(["-e", RUN, module.to_str().expect("utf-8 path")]);
+        node.args(summary.globals.iter().map(|(_, ty)| ty));
+        let node = node.output().expect("node runs");
+        fs::remove_file(&module).ok();
+
+        // The interpreter prints the same finals at the end of the program
+        let printing = scratch("printing.cry");
+        let names = finals(&source).join(", ");
+        fs::write(&printing, format!("{source}\nprintln({names});\n")).expect("writable temp dir");
+        let interpreted = crystal(&["run", printing.to_str().expect("utf-8 path")]);
+        fs::remove_file(&printing).ok();
+        if interpreted.status.success() {
+            assert_eq!(text(&node.stdout), text(&interpreted.stdout), "{sample:?}");
+        } else {
+            // An error in the interpreter is a trap in the module
+            assert_eq!(text(&node.stdout), "RuntimeError\n", "{sample:?}");
+        }
+    }
+    fs::remove_file(&overflow).ok();
+}
+
+#[test]
+fn wat_output_names_functions_and_globals() {
+    let sample = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs/wasm/functions.cry");
+    let wat = emit(&sample, &scratch("text.wat"), &["--wat"]);
+    let wat = text(&wat);
+    assert!(wat.starts_with("(module\n"));
+    assert!(wat.contains("(export \"fibonacci\" (global $fibonacci))"));
+    assert!(wat.contains("(func $fib<int> (param i64) (result i64)"));
+    assert!(wat.contains("(func $mean<float:int> (param f64 i64) (result f64)"));
+    assert!(wat.contains("call $crystal/add"));
+    assert!(wat.contains("(start $main)"));
+}
+
+#[test]
+fn the_validator_rejects_broken_modules() {
+    let sample = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs/wasm/numbers.cry");
+    let bytes = emit(&sample, &scratch("broken.wasm"), &[]);
+    assert!(validate(&bytes).is_ok());
+    assert!(validate(&bytes[..bytes.len() - 1]).is_err());
+    let mut version = bytes.clone();
+    version[4] = 2;
+    assert!(validate(&version).is_err());
+
+    // A function that claims an i64 but gives an f64
+    let mut module = b"\0asm\x01\0\0\0".to_vec();
+    module.extend([0x01, 0x05, 0x01, 0x60, 0x00, 0x01, 0x7e]);
+    module.extend([0x03, 0x02, 0x01, 0x00]);
+    module.extend([0x0a, 0x0d, 0x01, 0x0b, 0x00, 0x44]);
+    module.extend(1.5f64.to_le_bytes());
+    module.push(0x0b);
+    let err = validate(&module).expect_err("the body has the wrong type");
+    assert!(err.contains("expected i64 but found f64"), "{err}");
+}
+
+#[test]
+fn programs_outside_the_subset_are_reported() {
+    let source = scratch("outside.cry");
+    let output = scratch("outside.wasm");
+    fs::write(&source, "let name = \"crystal\";\nprintln([1, 2]);\n").expect("writable temp dir");
+    let emitted = crystal(&[
+        "emit-wasm",
+        source.to_str().expect("utf-8 path"),
+        "-o",
+        output.to_str().expect("utf-8 path"),
+    ]);
+    fs::remove_file(&source).ok();
+    let stderr = text(&emitted.stderr);
+    assert_eq!(emitted.status.code(), Some(1));
+    assert!(stderr.contains("Strings are not supported by the WebAssembly backend"));
+    assert!(stderr.contains("Lists are not supported by the WebAssembly backend"));
+    assert!(stderr.contains("The builtin 'println' is not supported"));
+    assert!(!output.exists());
+}
+
+#[test]
+fn int_powers_need_a_literal_exponent() {
+    let source = scratch("power.cry");
+    let output = scratch("power.wasm");
+    fs::write(&source, "final a = 2 ** 3;\nfinal b = 2 ** -1;\n").expect("writable temp dir");
+    let emitted = crystal(&[
+        "emit-wasm",
+        source.to_str().expect("utf-8 path"),
+        "-o",
+        output.to_str().expect("utf-8 path"),
+    ]);
+    fs::remove_file(&source).ok();
+    let stderr = text(&emitted.stderr);
+    assert_eq!(emitted.status.code(), Some(1));
+    assert!(stderr.contains(
+        "'**' with an exponent that may be negative is not supported by the WebAssembly backend"
+    ));
+    assert!(stderr.contains("2:16"));
+    assert_eq!(stderr.matches("not supported").count(), 1);
+    assert!(!output.exists());
+}